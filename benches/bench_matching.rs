@@ -154,6 +154,45 @@ fn rule_match_parsed_el(c: &mut Criterion) {
     group.finish();
 }
 
+/// Compares the token index used by `Blocker` against checking every filter against every request.
+fn token_index(c: &mut Criterion) {
+    use adblock::filters::network::NetworkMatchable;
+
+    let mut group = c.benchmark_group("token-index");
+
+    let rules = rules_from_lists(&[
+        String::from("data/easylist.to/easylist/easylist.txt"),
+    ]);
+    // The exhaustive scan is slow, so only a sample of the requests is used
+    let requests = load_requests();
+    let requests_parsed: Vec<_> = requests.into_iter().step_by(1000).map(|r| { Request::from_urls(&r.url, &r.frameUrl, &r.cpt) }).filter_map(Result::ok).collect();
+    let requests_len = requests_parsed.len() as u64;
+    let blocker = get_blocker(&rules);
+    let (filters, _) = adblock::lists::parse_filters(&rules, false, Default::default());
+    let filters = adblock::optimizer::apply_badfilters(filters);
+
+    group.throughput(Throughput::Elements(requests_len));
+    group.sample_size(10);
+
+    let indexed_requests = requests_parsed.clone();
+    group.bench_function(
+        "indexed",
+        move |b| {
+            b.iter(|| bench_matching_only(&blocker, &indexed_requests))
+        },
+    );
+    group.bench_function(
+        "exhaustive",
+        move |b| {
+            b.iter(|| {
+                requests_parsed.iter().filter(|request| filters.iter().any(|f| f.matches(request))).count()
+            })
+        },
+    );
+
+    group.finish();
+}
+
 fn rule_match_parsed_elep_slimlist(c: &mut Criterion) {
     let mut group = c.benchmark_group("rule-match-parsed");
 
@@ -384,6 +423,7 @@ criterion_group!(
     rule_match,
     rule_match_parsed_el,
     rule_match_parsed_elep_slimlist,
+    token_index,
     rule_match_browserlike_comparable,
     serialization,
     deserialization
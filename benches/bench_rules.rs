@@ -64,6 +64,17 @@ fn bench_parsing_impl(lists: &Vec<Vec<String>>) -> usize {
     dummy
 }
 
+fn bench_network_only_parsing_impl(lists: &Vec<Vec<String>>) -> usize {
+    let mut dummy = 0;
+
+    for list in lists {
+        let network_filters = adblock::lists::parse_network_filters(list, false, Default::default());
+        dummy += network_filters.len() % 1000000;
+    }
+
+    dummy
+}
+
 fn list_parse(c: &mut Criterion) {
     let mut group = c.benchmark_group("parse-filters");
 
@@ -80,6 +91,11 @@ fn list_parse(c: &mut Criterion) {
         |b| b.iter(|| bench_parsing_impl(&DEFAULT_RULES_LISTS)),
     );
 
+    group.bench_function(
+        "network filters only",
+        |b| b.iter(|| bench_network_only_parsing_impl(&DEFAULT_RULES_LISTS)),
+    );
+
     group.finish();
 }
 
@@ -114,5 +130,36 @@ fn blocker_new(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, blocker_new, list_parse, string_hashing, string_tokenize);
+fn list_optimize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("optimize");
+
+    group.throughput(Throughput::Elements(1));
+    group.sample_size(10);
+
+    let list_combinations = [
+        ("el", vec![
+            String::from("data/easylist.to/easylist/easylist.txt"),
+        ]),
+        ("el+ep", vec![
+            String::from("data/easylist.to/easylist/easylist.txt"),
+            String::from("data/easylist.to/easylist/easyprivacy.txt"),
+        ]),
+    ];
+
+    for (name, lists) in list_combinations.iter() {
+        let (network_filters, _) = adblock::lists::parse_filters(&rules_from_lists(lists), false, Default::default());
+
+        let (_, stats) = adblock::optimizer::optimize_with_stats(network_filters.clone());
+        println!("{}: {:?}", name, stats);
+
+        group.bench_function(
+            *name,
+            |b| b.iter(|| adblock::optimizer::optimize_with_stats(network_filters.clone())),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, blocker_new, list_parse, list_optimize, string_hashing, string_tokenize);
 criterion_main!(benches);
@@ -49,6 +49,41 @@ fn request_parsing_throughput(c: &mut Criterion) {
     group.finish();
 }
 
+fn request_prenormalized_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("throughput-request");
+
+    // Prepare URLs the way a caller of `from_prenormalized` would hold them
+    let normalize = |url: &str| {
+        parse_url(url).map(|parsed| {
+            let url = parsed.url.to_ascii_lowercase();
+            url.split('#').next().unwrap().to_owned()
+        })
+    };
+    let requests: Vec<_> = load_requests().into_iter()
+        .filter_map(|r| normalize(&r.url).map(|url| (url, normalize(&r.frameUrl).unwrap_or_default(), r.cpt)))
+        .collect();
+    let requests_len = requests.len() as u64;
+
+    group.throughput(Throughput::Elements(requests_len));
+    group.sample_size(10);
+
+    group.bench_function(
+        "create-prenormalized",
+        move |b| {
+            b.iter(|| {
+                let mut successful = 0;
+                requests.iter().for_each(|(url, source_url, cpt)| {
+                    if Request::from_prenormalized(url, source_url, cpt).is_ok() {
+                        successful += 1;
+                    }
+                })
+            })
+        },
+    );
+
+    group.finish();
+}
+
 fn request_extract_hostname(c: &mut Criterion) {
     let mut group = c.benchmark_group("throughput-request");
 
@@ -146,6 +181,7 @@ criterion_group!(
     benches,
     request_new_throughput,
     request_extract_hostname,
-    request_parsing_throughput
+    request_parsing_throughput,
+    request_prenormalized_throughput
 );
 criterion_main!(benches);
@@ -2,13 +2,15 @@
 
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::sync::Arc;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "object-pooling")]
 use lifeguard::Pool;
 
-use crate::filters::network::{NetworkFilter, NetworkMatchable};
+use crate::filters::network::{CompiledRegex, FilterPart, NetworkFilter, NetworkFilterMask, NetworkMatchable};
 use crate::request::Request;
 use crate::utils::{fast_hash, Hash};
 use crate::optimizer;
@@ -23,8 +25,9 @@ pub struct BlockerOptions {
 /// matched a blocking filter.
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub enum Redirection {
-    /// Redirect to a stub resource loaded from the blocker's resource library. The field contains
-    /// the body of the redirect to be injected.
+    /// Redirect to a stub resource loaded from the blocker's resource library, or given inline by
+    /// a `$redirect=data:...` filter. The field contains the body of the redirect to be injected,
+    /// as a base64 `data:` URL.
     Resource(String),
     /// Redirect to a remote resource. The field contains the URL of the replacement resource to be
     /// loaded. These will only occur if previously enabled in `ParseOptions`.
@@ -32,6 +35,7 @@ pub enum Redirection {
 }
 
 #[derive(Debug, Serialize)]
+#[non_exhaustive]
 pub struct BlockerResult {
     pub matched: bool,
     /// Important is used to signal that a rule with the `important` option
@@ -53,7 +57,16 @@ pub struct BlockerResult {
     /// Note that the presence of a redirect does _not_ imply that the request
     /// should be blocked. The `redirect-rule` option can produce a redirection
     /// that's only applied if another blocking filter matches a request.
+    ///
+    /// If several redirect filters match, a `redirect-url` is preferred over a resource, then the
+    /// filter with the highest `NetworkFilter::specificity`, then the one with the lowest
+    /// `NetworkFilter::get_id`, and finally the lexicographically lowest redirect value.
     pub redirect: Option<Redirection>,
+    /// The name of the resource from the blocker's resource library used for `redirect`, e.g.
+    /// `noopjs` for a `$redirect=noopjs` filter, so that it can be looked up separately. This is
+    /// set whenever `redirect` is, including if the request isn't blocked, except when the
+    /// redirect comes from an inline `data:` URL or a `redirect-url` option.
+    pub redirect_resource: Option<String>,
     /// Exception is `Some` when the blocker matched on an exception rule.
     /// Effectively this means that there was a match, but the request should
     /// not be blocked. It is a non-empty string if the blocker was initialized
@@ -68,6 +81,11 @@ pub struct BlockerResult {
     /// parsing the provided URLs when using the simpler
     /// [`crate::engine::Engine::check_network_urls`] method.
     pub error: Option<String>,
+    /// Whether the element which loaded a blocked request should be collapsed rather than leaving
+    /// a placeholder, because a cosmetic rule on the source page hides elements of that kind.
+    /// This is only ever set by [`crate::engine::Engine`] once enabled with
+    /// [`crate::engine::Engine::set_collapse_hints`].
+    pub should_collapse: bool,
 }
 
 impl Default for BlockerResult {
@@ -76,13 +94,128 @@ impl Default for BlockerResult {
             matched: false,
             important: false,
             redirect: None,
+            redirect_resource: None,
             exception: None,
             filter: None,
             error: None,
+            should_collapse: false,
+        }
+    }
+}
+
+/// Maximum number of regexes compiled by `Blocker::validate`.
+const VALIDATION_REGEX_SAMPLE: usize = 100;
+
+/// Problems found in a `Blocker` by `Blocker::validate`. Filters are identified by their original
+/// text if the blocker was built in debug mode.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Filters whose pattern is a set with no members. Optimizations should never produce these,
+    /// and it's unclear what they should match.
+    pub empty_fused_filters: Vec<String>,
+    /// Resources named by `$redirect` filters that haven't been loaded, in sorted order.
+    /// Requests matching these filters won't be redirected.
+    pub missing_redirect_resources: Vec<String>,
+    /// Sampled filters whose regex failed to compile.
+    pub regex_errors: Vec<String>,
+    /// Number of regexes compiled from the sample, at most 100.
+    pub regexes_checked: usize,
+}
+
+impl ValidationReport {
+    /// `true` if no problems were found.
+    pub fn is_valid(&self) -> bool {
+        self.empty_fused_filters.is_empty()
+            && self.missing_redirect_resources.is_empty()
+            && self.regex_errors.is_empty()
+    }
+}
+
+/// Counts of the regexes a `Blocker` compiles for its active filters, as returned by
+/// `Blocker::regex_stats`. Regexes are compiled lazily, so these may not all exist yet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RegexStats {
+    /// Number of separately compiled regexes. Filters fused together during optimization share
+    /// a single `RegexSet`, which is counted once.
+    pub regexes: usize,
+    /// Total number of patterns across all compiled regexes, counting each member of a
+    /// `RegexSet` individually.
+    pub patterns: usize,
+    /// Number of compiled regexes that come from complete regex filters like `/ads[0-9]+/`.
+    pub complete_regexes: usize,
+}
+
+/// Time spent in different phases of checking a single request, as returned by
+/// `Blocker::check_with_timing`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CheckTiming {
+    /// Total time spent checking the request.
+    pub total: Duration,
+    /// Time spent tokenizing the request URL.
+    pub tokenization: Duration,
+    /// Time spent evaluating regex-based filters against the request.
+    pub regex_evaluation: Duration,
+    /// Number of filters that were evaluated against the request.
+    pub filters_checked: usize,
+    /// Number of regex-based filters that were evaluated against the request.
+    pub regex_filters_checked: usize,
+}
+
+/// Receives timing information while a request is checked. `NoTiming` does nothing, so that
+/// untimed checks compile down to the same code as if no collector were used at all.
+pub(crate) trait TimingCollector {
+    fn tokenization(&mut self, tokenize: impl FnOnce());
+    fn filter_matches(&mut self, filter: &NetworkFilter, request: &Request) -> bool;
+}
+
+pub(crate) struct NoTiming;
+
+impl TimingCollector for NoTiming {
+    #[inline(always)]
+    fn tokenization(&mut self, tokenize: impl FnOnce()) {
+        tokenize()
+    }
+
+    #[inline(always)]
+    fn filter_matches(&mut self, filter: &NetworkFilter, request: &Request) -> bool {
+        filter.matches(request)
+    }
+}
+
+impl TimingCollector for CheckTiming {
+    fn tokenization(&mut self, tokenize: impl FnOnce()) {
+        let start = Instant::now();
+        tokenize();
+        self.tokenization += start.elapsed();
+    }
+
+    fn filter_matches(&mut self, filter: &NetworkFilter, request: &Request) -> bool {
+        self.filters_checked += 1;
+        if filter.is_regex() || filter.is_complete_regex() {
+            self.regex_filters_checked += 1;
+            let start = Instant::now();
+            let matches = filter.matches(request);
+            self.regex_evaluation += start.elapsed();
+            matches
+        } else {
+            filter.matches(request)
         }
     }
 }
 
+/// Only allows hostname-anchored filters to match, for `Blocker::check_cname`.
+struct HostnameAnchoredOnly;
+
+impl TimingCollector for HostnameAnchoredOnly {
+    fn tokenization(&mut self, tokenize: impl FnOnce()) {
+        tokenize()
+    }
+
+    fn filter_matches(&mut self, filter: &NetworkFilter, request: &Request) -> bool {
+        filter.is_hostname_anchor() && filter.matches(request)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum BlockerError {
     SerializationError,
@@ -118,13 +251,24 @@ pub struct Blocker {
     pub(crate) filters_tagged: NetworkFilterList,
     pub(crate) filters: NetworkFilterList,
     pub(crate) generic_hide: NetworkFilterList,
+    pub(crate) removeparams: NetworkFilterList,
 
     // Enabled tags are not serialized - when deserializing, tags of the existing
     // instance (the one we are recreating lists into) are maintained
     pub(crate) tags_enabled: HashSet<String>,
     pub(crate) tagged_filters_all: Vec<NetworkFilter>,
 
+    // Disabled filters are not serialized either, and are removed from the lists above until
+    // they are re-enabled
+    pub(crate) disabled_filters: HashMap<Hash, DisabledFilter>,
+
+    // Ids of the filters cancelled by any `$badfilter` rules, so that they can also be applied to
+    // filters added later. Not serialized, so only rules added since deserialization are known.
+    pub(crate) badfilter_ids: HashSet<Hash>,
+
     pub(crate) enable_optimizations: bool,
+    // Not serialized; see `set_loopback_exempt`
+    pub(crate) loopback_exempt: bool,
 
     pub(crate) resources: RedirectResourceStorage,
     // Not serialized
@@ -154,8 +298,39 @@ impl Blocker {
         self.generic_hide.check(hostname_request, &request_tokens, &HashSet::new()).is_some()
     }
 
+    /// Like `check`, but also measures the time spent in different phases of the check. This is
+    /// intended for finding slow rules, and has no effect on the result of the check.
+    pub fn check_with_timing(&self, request: &Request) -> (BlockerResult, CheckTiming) {
+        let start = Instant::now();
+        let mut timing = CheckTiming::default();
+        let result = self.check_parameterised_timed(request, false, false, &mut timing);
+        timing.total = start.elapsed();
+        (result, timing)
+    }
+
+    /// Checks `request` again as if it were sent to `cname_host`, the canonical name that its
+    /// hostname resolves to. Only hostname-anchored filters like `||tracker.com^` are evaluated,
+    /// against a URL with the hostname replaced. Party and `$domain` options still apply to the
+    /// original request's source.
+    ///
+    /// This doesn't take the result of checking the original request into account; it should be
+    /// checked separately.
+    pub fn check_cname(&self, request: &Request, cname_host: &str) -> BlockerResult {
+        self.check_parameterised_timed(&request.with_hostname(cname_host), false, false, &mut HostnameAnchoredOnly)
+    }
+
     pub fn check_parameterised(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
-        if !request.is_supported {
+        self.check_parameterised_timed(request, matched_rule, force_check_exceptions, &mut NoTiming)
+    }
+
+    /// Whether `request` is let through without checking any rules, because its scheme isn't
+    /// supported or it's exempt under `set_loopback_exempt`.
+    pub(crate) fn is_exempt(&self, request: &Request) -> bool {
+        !request.is_supported || (self.loopback_exempt && request.is_loopback())
+    }
+
+    fn check_parameterised_timed<T: TimingCollector>(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool, timer: &mut T) -> BlockerResult {
+        if self.is_exempt(request) {
             return BlockerResult::default();
         }
 
@@ -172,7 +347,7 @@ impl Blocker {
         {
             request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
         }
-        request.get_tokens(&mut request_tokens);
+        timer.tokenization(|| request.get_tokens(&mut request_tokens));
 
         // Check the filters in the following order:
         // 1. $important (not subject to exceptions)
@@ -185,17 +360,17 @@ impl Blocker {
         // Always check important filters
         let important_filter = self
             .importants
-            .check(request, &request_tokens, &NO_TAGS);
+            .check_timed(request, &request_tokens, &NO_TAGS, timer);
 
         // only check the rest of the rules if not previously matched
         let filter = if important_filter.is_none() && !matched_rule {
             #[cfg(feature = "metrics")]
             print!("tagged\t");
-            self.filters_tagged.check(request, &request_tokens, &self.tags_enabled)
+            self.filters_tagged.check_timed(request, &request_tokens, &self.tags_enabled, timer)
                 .or_else(|| {
                     #[cfg(feature = "metrics")]
                     print!("filters\t");
-                    self.filters.check(request, &request_tokens, &NO_TAGS)
+                    self.filters.check_timed(request, &request_tokens, &NO_TAGS, timer)
                 })
         } else {
             important_filter
@@ -206,7 +381,7 @@ impl Blocker {
             None if matched_rule || force_check_exceptions => {
                 #[cfg(feature = "metrics")]
                 print!("exceptions\t");
-                self.exceptions.check(request, &request_tokens, &self.tags_enabled)
+                self.exceptions.check_timed(request, &request_tokens, &self.tags_enabled, timer)
             }
             None => None,
             // If matched an important filter, exceptions don't atter
@@ -217,53 +392,52 @@ impl Blocker {
                 // Set `bug` of request
                 let mut request_bug = request.clone();
                 request_bug.bug = f.bug;
-                self.exceptions.check(&request_bug, &request_tokens, &self.tags_enabled)
+                self.exceptions.check_timed(&request_bug, &request_tokens, &self.tags_enabled, timer)
             }
             Some(_) => {
                 #[cfg(feature = "metrics")]
                 print!("exceptions\t");
-                self.exceptions.check(request, &request_tokens, &self.tags_enabled)
+                self.exceptions.check_timed(request, &request_tokens, &self.tags_enabled, timer)
             }
         };
 
         #[cfg(feature = "metrics")]
         println!();
 
-        let redirect_filters = self.redirects.check_all(request, &request_tokens, &NO_TAGS);
+        let redirect_filters = self.redirects.check_all_timed(request, &request_tokens, &NO_TAGS, timer);
 
         // Extract the highest priority redirect directive.
         // So far, priority specifiers are not supported, which means:
         // 1. Exceptions - can bail immediately if found
         // 2. Redirect URLs
         // 3. Redirect resources
-        let redirect_option = {
-            // (true, s) implies s is a URL.
-            // (false, s) implies s is the name of a resource to lookup.
-            let mut redirect: Option<(bool, &str)> = None;
-            for redirect_filter in redirect_filters {
-                if redirect_filter.is_exception() {
-                    redirect = None;
-                    break;
-                } else if redirect_filter.is_redirect_url() {
-                    // Unconditionally write to `redirect` - it's the highest priority option that
-                    // does not break the loop.
-                    redirect = redirect_filter.redirect.as_ref().map(|s| (true, s.as_str()));
-                } else if redirect.is_none() {
-                    // Otherwise, only write to `redirect` if it hasn't already been set by a
-                    // previous filter.
-                    redirect = redirect_filter.redirect.as_ref().map(|s| (false, s.as_str()));
-                }
-            }
-            redirect
+        // Ties within the same category go to the most specific filter, then to the filter with
+        // the lowest id, then to the lowest redirect value, so that the result doesn't depend on
+        // the order rules were added in.
+        let redirect_option = if redirect_filters.iter().any(|f| f.is_exception()) {
+            None
+        } else {
+            redirect_filters
+                .iter()
+                .filter(|f| f.redirect.is_some())
+                .max_by_key(|f| (f.is_redirect_url(), f.specificity(), Reverse(f.get_id()), Reverse(f.redirect.as_deref())))
+                // (true, s) implies s is a URL.
+                // (false, s) implies s is the name of a resource to lookup.
+                .and_then(|f| f.redirect.as_ref().map(|s| (f.is_redirect_url(), s.as_str())))
         };
 
+        let mut redirect_resource = None;
         let redirect: Option<Redirection> = redirect_option.and_then(|(is_url, redirect_identifier)| {
+            // Inline `data:` URLs are used directly, rather than looking up a resource
+            let inline_resource = if is_url { None } else { RedirectResource::from_data_url(redirect_identifier) };
             if is_url {
                 Some(Redirection::Url(redirect_identifier.to_string()))
+            } else if let Some(resource) = inline_resource.as_ref() {
+                Some(Redirection::Resource(resource.data_url().trim().to_owned()))
             } else if let Some(resource) = self.resources.get_resource(redirect_identifier) {
                 // Only match resource redirects if a matching resource exists
-                let data_url = format!("data:{};base64,{}", resource.content_type, &resource.data);
-                Some(Redirection::Resource(data_url.trim().to_owned()))
+                redirect_resource = Some(redirect_identifier.to_owned());
+                Some(Redirection::Resource(resource.data_url().trim().to_owned()))
             } else {
                 // It's acceptable to pass no redirection if no matching resource is loaded.
                 // TODO - it may be useful to return a status flag to indicate that this occurred.
@@ -279,9 +453,11 @@ impl Blocker {
             matched,
             important: filter.is_some() && filter.as_ref().map(|f| f.is_important()).unwrap_or_else(|| false),
             redirect,
+            redirect_resource,
             exception: exception.as_ref().map(|f| f.to_string()), // copy the exception
             filter: filter.as_ref().map(|f| f.to_string()),       // copy the filter
             error: None,
+            should_collapse: false,
         }
     }
 
@@ -344,6 +520,73 @@ impl Blocker {
         Some(merged)
     }
 
+    /// Like `get_csp_directives`, but each merged policy also explicitly sets every fetch directive
+    /// used by any of the other policies, to the value it would otherwise fall back to through
+    /// `default-src` or a related directive. For example, `default-src 'self'` merged with
+    /// `script-src 'none'` becomes `default-src 'self'; script-src 'self',script-src 'none'`.
+    ///
+    /// This doesn't change the meaning of the header, but it allows consumers which combine all
+    /// directives of the same name into a single policy to do so without losing restrictions.
+    pub fn get_csp_directives_with_fallbacks(&self, request: &Request) -> Option<String> {
+        self.get_csp_directives(request).map(|merged| expand_csp_fallbacks(&merged))
+    }
+
+    /// Given a request and its original URL, before lowercasing, returns the URL with any query
+    /// parameters matched by `$removeparam` filters stripped from it. The remaining parameters
+    /// keep their order, and the fragment is left untouched. Returns `None` if no parameters
+    /// should be removed.
+    pub fn get_removeparam_url(&self, request: &Request, url: &str) -> Option<String> {
+        let (without_fragment, fragment) = match url.find('#') {
+            Some(i) => url.split_at(i),
+            None => (url, ""),
+        };
+        let query_start = without_fragment.find('?')?;
+
+        let mut request_tokens;
+        #[cfg(feature = "object-pooling")]
+        {
+            request_tokens = self.pool.pool.new();
+        }
+        #[cfg(not(feature = "object-pooling"))]
+        {
+            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+        }
+        request.get_tokens(&mut request_tokens);
+
+        let (exceptions, filters): (Vec<_>, Vec<_>) = self.removeparams
+            .check_all(request, &request_tokens, &self.tags_enabled)
+            .into_iter()
+            .partition(|filter| filter.is_exception());
+
+        // Exception filters with empty `removeparam` options keep every parameter
+        if exceptions.iter().any(|exception| exception.removeparam.as_deref() == Some("")) {
+            return None;
+        }
+        let filters = filters.into_iter()
+            .filter(|filter| !exceptions.iter().any(|exception| exception.removeparam == filter.removeparam))
+            .collect::<Vec<_>>();
+        if filters.is_empty() {
+            return None;
+        }
+
+        let params = without_fragment[query_start + 1..].split('&').collect::<Vec<_>>();
+        let kept = params.iter()
+            .filter(|param| !filters.iter().any(|filter| filter.removes_param(param)))
+            .copied()
+            .collect::<Vec<_>>();
+        if kept.len() == params.len() {
+            return None;
+        }
+
+        let mut rewritten = String::from(&without_fragment[..query_start]);
+        if !kept.is_empty() {
+            rewritten.push('?');
+            rewritten.push_str(&kept.join("&"));
+        }
+        rewritten.push_str(fragment);
+        Some(rewritten)
+    }
+
     pub fn new(network_filters: Vec<NetworkFilter>, options: &BlockerOptions) -> Blocker {
         // Capacity of filter subsets estimated based on counts in EasyList and EasyPrivacy - if necessary
         // the Vectors will grow beyond the pre-set capacity, but it is more efficient to allocate all at once
@@ -361,19 +604,22 @@ impl Blocker {
         let mut badfilters = Vec::with_capacity(100);
         // $generichide
         let mut generic_hide = Vec::with_capacity(4000);
+        // $removeparam
+        let mut removeparams = Vec::with_capacity(200);
         // All other filters
         let mut filters = Vec::with_capacity(network_filters.len());
 
         // Injections
         // TODO: resource handling
 
+        let mut badfilter_ids: HashSet<Hash> = HashSet::new();
         if !network_filters.is_empty() {
             for filter in network_filters.iter() {
                 if filter.is_badfilter() {
                     badfilters.push(filter);
                 }
             }
-            let badfilter_ids: HashSet<Hash> = badfilters.iter().map(|f| f.get_id_without_badfilter()).collect();
+            badfilter_ids = badfilters.iter().map(|f| f.get_id_without_badfilter()).collect();
             for filter in network_filters {
                 // skip any bad filters
                 let filter_id = filter.get_id();
@@ -381,6 +627,13 @@ impl Blocker {
                     continue;
                 }
 
+                // Query parameter removal is independent of blocking behavior, and can't be
+                // combined with redirects.
+                if filter.is_removeparam() {
+                    removeparams.push(filter);
+                    continue;
+                }
+
                 // Redirects are independent of blocking behavior.
                 if filter.is_redirect() {
                     redirects.push(filter.clone());
@@ -413,11 +666,15 @@ impl Blocker {
             filters_tagged: NetworkFilterList::new(Vec::new(), options.enable_optimizations),
             filters: NetworkFilterList::new(filters, options.enable_optimizations),
             generic_hide: NetworkFilterList::new(generic_hide, options.enable_optimizations),
+            removeparams: NetworkFilterList::new(removeparams, options.enable_optimizations),
             // Tags special case for enabling/disabling them dynamically
             tags_enabled: HashSet::new(),
             tagged_filters_all,
+            disabled_filters: HashMap::new(),
+            badfilter_ids,
             // Options
             enable_optimizations: options.enable_optimizations,
+            loopback_exempt: false,
 
             resources: RedirectResourceStorage::default(),
             #[cfg(feature = "object-pooling")]
@@ -437,10 +694,13 @@ impl Blocker {
         self.filters_tagged.optimize();
         self.filters.optimize();
         self.generic_hide.optimize();
+        self.removeparams.optimize();
     }
 
     pub fn filter_exists(&self, filter: &NetworkFilter) -> bool {
-        if filter.is_csp() {
+        if filter.is_removeparam() {
+            self.removeparams.filter_exists(filter)
+        } else if filter.is_csp() {
             self.csp.filter_exists(filter)
         } else if filter.is_generic_hide() {
             self.generic_hide.filter_exists(filter)
@@ -457,11 +717,174 @@ impl Blocker {
         }
     }
 
+    /// Counts the regexes that would be compiled for the currently active filters, including
+    /// filters from enabled tags. See `RegexStats`.
+    pub fn regex_stats(&self) -> RegexStats {
+        let lists = [
+            &self.csp,
+            &self.exceptions,
+            &self.importants,
+            &self.redirects,
+            &self.filters_tagged,
+            &self.filters,
+            &self.generic_hide,
+            &self.removeparams,
+        ];
+
+        // Filters with several token sets are stored under several keys
+        let mut seen = HashSet::new();
+        let mut stats = RegexStats::default();
+        lists.iter()
+            .flat_map(|list| list.filter_map.values().flatten())
+            .filter(|filter| seen.insert(Arc::as_ptr(filter)))
+            .filter(|filter| filter.is_regex() || filter.is_complete_regex())
+            .for_each(|filter| {
+                let patterns = match &filter.filter {
+                    // Compiled as `CompiledRegex::MatchAll`, without a regex
+                    FilterPart::Empty => return,
+                    FilterPart::Simple(_) => 1,
+                    FilterPart::AnyOf(patterns) => patterns.len(),
+                };
+                stats.regexes += 1;
+                stats.patterns += patterns;
+                if filter.is_complete_regex() {
+                    stats.complete_regexes += 1;
+                }
+            });
+        stats
+    }
+
+    /// Checks the blocker's internal invariants, e.g. before replacing a blocker that's in use
+    /// with a newly loaded one. Filters from disabled tags are included. Only an evenly spaced
+    /// sample of regexes are compiled, since compiling every one can be slow.
+    pub fn validate(&self) -> ValidationReport {
+        let untagged = [
+            &self.csp,
+            &self.exceptions,
+            &self.importants,
+            &self.redirects,
+            &self.filters,
+            &self.generic_hide,
+            &self.removeparams,
+        ];
+
+        // Filters with several token sets are stored under several keys
+        let mut seen = HashSet::new();
+        let mut filters = untagged.iter()
+            .flat_map(|list| list.filter_map.values().flatten().map(|filter| filter.as_ref()))
+            .chain(self.tagged_filters_all.iter())
+            .filter(|filter| seen.insert(*filter as *const NetworkFilter))
+            .collect::<Vec<_>>();
+        // Keep the regex sample stable across runs
+        filters.sort_by_key(|filter| filter.id);
+
+        let mut report = ValidationReport::default();
+        let mut missing_resources = BTreeSet::new();
+        for filter in filters.iter() {
+            if matches!(&filter.filter, FilterPart::AnyOf(patterns) if patterns.is_empty()) {
+                report.empty_fused_filters.push(filter.to_string());
+            }
+            if let Some(redirect) = filter.redirect.as_ref() {
+                if !filter.is_redirect_url()
+                    && !filter.is_exception()
+                    && RedirectResource::from_data_url(redirect).is_none()
+                    && self.resources.get_resource(redirect).is_none()
+                {
+                    missing_resources.insert(redirect.clone());
+                }
+            }
+        }
+        report.missing_redirect_resources = missing_resources.into_iter().collect();
+
+        let regex_filters = filters.iter()
+            .filter(|filter| (filter.is_regex() || filter.is_complete_regex()) && !matches!(filter.filter, FilterPart::Empty))
+            .collect::<Vec<_>>();
+        let step = (regex_filters.len() / VALIDATION_REGEX_SAMPLE).max(1);
+        for filter in regex_filters.into_iter().step_by(step).take(VALIDATION_REGEX_SAMPLE) {
+            report.regexes_checked += 1;
+            if let CompiledRegex::RegexParsingError(_) = filter.get_regex().as_ref() {
+                report.regex_errors.push(filter.to_string());
+            }
+        }
+
+        report
+    }
+
+    /// Counts the active blocking rules specific to `hostname`. Returns the number of rules
+    /// anchored to the hostname or one of its parent domains (e.g. `||example.com^`), and the
+    /// number of rules restricted to pages on the hostname using the `$domain` option.
+    ///
+    /// Exception and `$generichide` rules are not counted, nor are rules whose tag is disabled.
+    /// Any port in `hostname`, like `example.com:8080`, is ignored.
+    pub fn hostname_rule_counts(&self, hostname: &str) -> (usize, usize) {
+        let (hostname, _) = crate::filters::network::split_hostname_port(hostname);
+        let hostname_hashes = std::iter::once(hostname)
+            .chain(hostname.match_indices('.').map(|(i, _)| &hostname[i + 1..]))
+            .filter(|h| !h.is_empty())
+            .map(fast_hash)
+            .collect::<Vec<_>>();
+
+        // Filters with several token sets are stored under several keys
+        let mut seen = HashSet::new();
+        let mut anchored = 0;
+        let mut domain_scoped = 0;
+
+        [&self.csp, &self.importants, &self.redirects, &self.filters_tagged, &self.filters].iter()
+            .flat_map(|list| list.filter_map.values().flatten())
+            .filter(|filter| seen.insert(Arc::as_ptr(filter)))
+            .filter(|filter| match filter.tag.as_ref() {
+                Some(tag) => self.tags_enabled.contains(tag),
+                None => true,
+            })
+            .for_each(|filter| {
+                if let Some(filter_hostname) = filter.hostname.as_ref().filter(|_| filter.is_hostname_anchor()) {
+                    let (filter_hostname, _) = crate::filters::network::split_hostname_port(filter_hostname);
+                    let is_parent_domain = match hostname.strip_suffix(filter_hostname) {
+                        Some(subdomains) => subdomains.is_empty() || subdomains.ends_with('.'),
+                        None => false,
+                    };
+                    if is_parent_domain {
+                        anchored += 1;
+                    }
+                }
+                if let Some(domains) = filter.opt_domains.as_ref() {
+                    if hostname_hashes.iter().any(|h| utils::bin_lookup(domains, *h)) {
+                        domain_scoped += 1;
+                    }
+                }
+            });
+
+        (anchored, domain_scoped)
+    }
+
+    /// Adds a single filter to the blocker. `$badfilter` rules are applied immediately, removing
+    /// any existing filters they cancel, and are also remembered so that any cancelled filters
+    /// added afterwards are ignored.
+    ///
+    /// Filters which were combined with others that have differing `$domain` options during
+    /// optimization can't be cancelled by a `$badfilter` added later.
     pub fn add_filter(&mut self, filter: NetworkFilter) -> Result<(), BlockerError> {
         if filter.is_badfilter() {
-            Err(BlockerError::BadFilterAddUnsupported)
+            let cancelled_id = filter.get_id_without_badfilter();
+            if !self.badfilter_ids.insert(cancelled_id) {
+                return Err(BlockerError::FilterExists);
+            }
+            self.untagged_lists_mut().iter_mut().for_each(|list| { list.remove_badfiltered(&filter); });
+            let tagged_count = self.tagged_filters_all.len();
+            self.tagged_filters_all.retain(|f| f.get_id() != cancelled_id);
+            if self.tagged_filters_all.len() != tagged_count {
+                let tags_enabled = std::mem::take(&mut self.tags_enabled);
+                self.tags_with_set(tags_enabled);
+            }
+            Ok(())
+        } else if self.badfilter_ids.contains(&filter.get_id()) {
+            // Cancelled filters are skipped, just like when building the blocker
+            Ok(())
         } else if self.filter_exists(&filter) {
             Err(BlockerError::FilterExists)
+        } else if filter.is_removeparam() {
+            self.removeparams.add_filter(filter);
+            Ok(())
         } else if filter.is_csp() {
             self.csp.add_filter(filter);
             Ok(())
@@ -491,6 +914,143 @@ impl Blocker {
         }
     }
 
+    /// Stops `filter` from matching any requests until it is re-enabled using `enable_filter`.
+    /// Filters are identified by their `id`, which is derived from the text of the rule. Returns
+    /// `false` if the filter is not present or has already been disabled.
+    pub fn disable_filter(&mut self, filter: &NetworkFilter) -> bool {
+        if self.disabled_filters.contains_key(&filter.id) {
+            return false;
+        }
+
+        let removed: Vec<(usize, NetworkFilter)> = self.untagged_lists_mut().iter_mut()
+            .enumerate()
+            .filter_map(|(i, list)| list.remove_filter(filter).map(|removed| (i, removed)))
+            .collect();
+
+        let tagged = self.tagged_filters_all.iter().position(|f| f.id == filter.id)
+            .map(|i| self.tagged_filters_all.swap_remove(i));
+        if tagged.is_some() {
+            let tags_enabled = std::mem::take(&mut self.tags_enabled);
+            self.tags_with_set(tags_enabled);
+        }
+
+        if removed.is_empty() && tagged.is_none() {
+            return false;
+        }
+        self.disabled_filters.insert(filter.id, DisabledFilter { removed, tagged });
+        true
+    }
+
+    /// Restores a filter previously disabled using `disable_filter`. Returns `false` if the
+    /// filter was not disabled.
+    pub fn enable_filter(&mut self, filter: &NetworkFilter) -> bool {
+        let disabled = match self.disabled_filters.remove(&filter.id) {
+            Some(disabled) => disabled,
+            None => return false,
+        };
+
+        let lists = self.untagged_lists_mut();
+        for (i, removed) in disabled.removed {
+            lists[i].add_filter(removed);
+        }
+
+        if let Some(tagged) = disabled.tagged {
+            self.tagged_filters_all.push(tagged);
+            let tags_enabled = std::mem::take(&mut self.tags_enabled);
+            self.tags_with_set(tags_enabled);
+        }
+        true
+    }
+
+    /// Finds every filter which is made redundant by a broader hostname-anchored filter in the
+    /// same list. See `SubsumedFilter` for details.
+    pub fn subsumed_filters(&self) -> Vec<SubsumedFilter> {
+        let untagged = [
+            &self.csp,
+            &self.exceptions,
+            &self.importants,
+            &self.redirects,
+            &self.filters,
+            &self.generic_hide,
+            &self.removeparams,
+        ];
+        untagged.iter()
+            .flat_map(|list| list.subsumed_filters())
+            .chain(find_subsumed(self.tagged_filters_all.iter()))
+            .map(|(filter, subsumed_by)| SubsumedFilter { filter: filter.clone(), subsumed_by: subsumed_by.clone() })
+            .collect()
+    }
+
+    /// Removes every filter reported by `subsumed_filters`. This doesn't change which requests
+    /// are blocked, but it does change which filter is reported as matching them. Returns the
+    /// number of filters removed.
+    pub fn remove_subsumed_filters(&mut self) -> usize {
+        let mut removed = 0;
+        for list in self.untagged_lists_mut().iter_mut() {
+            let subsumed = list.subsumed_filters().into_iter()
+                .map(|(filter, _)| filter.clone())
+                .collect::<Vec<_>>();
+            for filter in subsumed {
+                list.remove_filter(&filter);
+                removed += 1;
+            }
+        }
+
+        let subsumed_tagged = find_subsumed(self.tagged_filters_all.iter())
+            .into_iter()
+            .map(|(filter, _)| filter.id)
+            .collect::<HashSet<_>>();
+        if !subsumed_tagged.is_empty() {
+            self.tagged_filters_all.retain(|f| !subsumed_tagged.contains(&f.id));
+            let tags_enabled = std::mem::take(&mut self.tags_enabled);
+            self.tags_with_set(tags_enabled);
+            removed += subsumed_tagged.len();
+        }
+        removed
+    }
+
+    /// All lists of filters, except for `filters_tagged` which is derived from
+    /// `tagged_filters_all`.
+    fn untagged_lists_mut(&mut self) -> [&mut NetworkFilterList; 7] {
+        [
+            &mut self.csp,
+            &mut self.exceptions,
+            &mut self.importants,
+            &mut self.redirects,
+            &mut self.filters,
+            &mut self.generic_hide,
+            &mut self.removeparams,
+        ]
+    }
+
+    /// The original text of every network filter in the blocker, with fused filters split back
+    /// into their constituent rules. Rules are only available if the blocker was built in debug
+    /// mode.
+    pub(crate) fn raw_rules(&self) -> BTreeSet<String> {
+        let untagged = [
+            &self.csp,
+            &self.exceptions,
+            &self.importants,
+            &self.redirects,
+            &self.filters,
+            &self.generic_hide,
+            &self.removeparams,
+        ];
+        untagged.iter()
+            .flat_map(|list| list.filter_map.values().flatten().map(|filter| filter.as_ref()))
+            .chain(self.tagged_filters_all.iter())
+            .filter_map(|filter| filter.raw_line.as_ref())
+            .flat_map(|line| line.split(" <+> ").map(String::from))
+            .collect()
+    }
+
+    /// Sets whether requests to loopback hosts, like `localhost` or `127.0.0.1`, are exempt from
+    /// all network filters. Disabled by default, in which case they are matched like any other
+    /// request.
+    pub fn set_loopback_exempt(&mut self, exempt: bool) {
+        self.loopback_exempt = exempt;
+    }
+
     pub fn use_tags(&mut self, tags: &[&str]) {
         let tag_set: HashSet<String> = tags.iter().map(|&t| String::from(t)).collect();
         self.tags_with_set(tag_set);
@@ -541,6 +1101,9 @@ impl Blocker {
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct NetworkFilterList {
+    /// Filters indexed by their least common token, so that a request only needs to be checked
+    /// against the buckets for its own tokens. Filters without any usable token are kept under the
+    /// `0` token, which every request includes as a fallback.
     #[serde(serialize_with = "crate::data_format::utils::stabilize_hashmap_serialization")]
     pub(crate) filter_map: HashMap<Hash, Vec<Arc<NetworkFilter>>>,
 }
@@ -674,12 +1237,77 @@ impl NetworkFilterList {
         false
     }
 
+    /// Removes all copies of `filter` from the list, returning the removed filter if it was
+    /// present. If it was fused together with other filters during optimization, it is separated
+    /// from them, and the others are kept.
+    pub(crate) fn remove_filter(&mut self, filter: &NetworkFilter) -> Option<NetworkFilter> {
+        let mut removed = None;
+        for filters in self.filter_map.values_mut() {
+            let mut i = 0;
+            while i < filters.len() {
+                if let Some((member, remaining)) = unfuse_filter(&filters[i], filter) {
+                    filters[i] = Arc::new(remaining);
+                    removed = Some(member);
+                    i += 1;
+                } else if filters[i].id == filter.id {
+                    removed = Some(NetworkFilter::clone(&filters.swap_remove(i)));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.filter_map.retain(|_, filters| !filters.is_empty());
+        removed
+    }
+
+    /// Removes every filter cancelled by `badfilter`, including any that were fused with other
+    /// filters during optimization. Returns `true` if anything was removed.
+    pub(crate) fn remove_badfiltered(&mut self, badfilter: &NetworkFilter) -> bool {
+        let cancelled_id = badfilter.get_id_without_badfilter();
+        let mut cancelled = badfilter.clone();
+        cancelled.mask.set(NetworkFilterMask::BAD_FILTER, false);
+
+        let mut removed = false;
+        for filters in self.filter_map.values_mut() {
+            let mut i = 0;
+            while i < filters.len() {
+                if filters[i].get_id() == cancelled_id {
+                    filters.swap_remove(i);
+                    removed = true;
+                } else if let Some((_, remaining)) = unfuse_filter(&filters[i], &cancelled) {
+                    filters[i] = Arc::new(remaining);
+                    removed = true;
+                    i += 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.filter_map.retain(|_, filters| !filters.is_empty());
+        removed
+    }
+
+    /// Finds every filter in the list which is subsumed by another, as described in
+    /// `SubsumedFilter`.
+    fn subsumed_filters(&self) -> Vec<(&NetworkFilter, &NetworkFilter)> {
+        // Filters with several token sets are stored under several keys
+        let mut seen = HashSet::new();
+        find_subsumed(self.filter_map.values()
+            .flatten()
+            .filter(|filter| seen.insert(Arc::as_ptr(filter)))
+            .map(|filter| filter.as_ref()))
+    }
+
     /// Returns the first found filter, if any, that matches the given request. The backing storage
     /// has a non-deterministic order, so this should be used for any category of filters where a
     /// match from each would be functionally equivalent. For example, if two different exception
     /// filters match a certain request, it doesn't matter _which_ one is matched - the request
     /// will be excepted either way.
     pub fn check(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Option<&NetworkFilter> {
+        self.check_timed(request, request_tokens, active_tags, &mut NoTiming)
+    }
+
+    fn check_timed<T: TimingCollector>(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>, timer: &mut T) -> Option<&NetworkFilter> {
         #[cfg(feature = "metrics")]
         let mut filters_checked = 0;
         #[cfg(feature = "metrics")]
@@ -693,8 +1321,8 @@ impl NetworkFilterList {
         }
 
         if let Some(source_hostname_hashes) = request.source_hostname_hashes.as_ref() {
-            for token in source_hostname_hashes {
-                if let Some(filter_bucket) = self.filter_map.get(token) {
+            for token in source_hostname_hashes.iter().copied().chain(request.source_entity_hashes()) {
+                if let Some(filter_bucket) = self.filter_map.get(&token) {
                     #[cfg(feature = "metrics")]
                     {
                         filter_buckets += 1;
@@ -706,7 +1334,7 @@ impl NetworkFilterList {
                             filters_checked += 1;
                         }
                         // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                        if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
+                        if timer.filter_matches(filter, request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
                             #[cfg(feature = "metrics")]
                             print!("true\t{}\t{}\tskipped\t{}\t{}\t", filter_buckets, filters_checked, filter_buckets, filters_checked);
                             return Some(filter);
@@ -731,7 +1359,7 @@ impl NetworkFilterList {
                         filters_checked += 1;
                     }
                     // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                    if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
+                    if timer.filter_matches(filter, request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
                         #[cfg(feature = "metrics")]
                         print!("true\t{}\t{}\t", filter_buckets, filters_checked);
                         return Some(filter);
@@ -751,6 +1379,10 @@ impl NetworkFilterList {
     /// `$csp` filters match a certain request, they may each carry a distinct CSP directive, and
     /// each directive should be combined for the final result.
     pub fn check_all(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Vec<&NetworkFilter> {
+        self.check_all_timed(request, request_tokens, active_tags, &mut NoTiming)
+    }
+
+    fn check_all_timed<T: TimingCollector>(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>, timer: &mut T) -> Vec<&NetworkFilter> {
         #[cfg(feature = "metrics")]
         let mut filters_checked = 0;
         #[cfg(feature = "metrics")]
@@ -766,8 +1398,8 @@ impl NetworkFilterList {
         }
 
         if let Some(source_hostname_hashes) = request.source_hostname_hashes.as_ref() {
-            for token in source_hostname_hashes {
-                if let Some(filter_bucket) = self.filter_map.get(token) {
+            for token in source_hostname_hashes.iter().copied().chain(request.source_entity_hashes()) {
+                if let Some(filter_bucket) = self.filter_map.get(&token) {
                     #[cfg(feature = "metrics")]
                     {
                         filter_buckets += 1;
@@ -779,7 +1411,7 @@ impl NetworkFilterList {
                             filters_checked += 1;
                         }
                         // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                        if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
+                        if timer.filter_matches(filter, request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
                             #[cfg(feature = "metrics")]
                             print!("true\t{}\t{}\tskipped\t{}\t{}\t", filter_buckets, filters_checked, filter_buckets, filters_checked);
                             filters.push(filter);
@@ -804,7 +1436,7 @@ impl NetworkFilterList {
                         filters_checked += 1;
                     }
                     // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                    if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
+                    if timer.filter_matches(filter, request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
                         #[cfg(feature = "metrics")]
                         print!("true\t{}\t{}\t", filter_buckets, filters_checked);
                         filters.push(filter);
@@ -820,6 +1452,166 @@ impl NetworkFilterList {
     }
 }
 
+/// Returns the directives that a CSP fetch directive falls back to when it isn't set, in order of
+/// preference.
+fn csp_fallbacks(directive: &str) -> &'static [&'static str] {
+    match directive {
+        "script-src-elem" | "script-src-attr" => &["script-src", "default-src"],
+        "style-src-elem" | "style-src-attr" => &["style-src", "default-src"],
+        "frame-src" => &["child-src", "default-src"],
+        "worker-src" => &["child-src", "script-src", "default-src"],
+        "child-src" | "connect-src" | "font-src" | "img-src" | "manifest-src" | "media-src"
+            | "object-src" | "prefetch-src" | "script-src" | "style-src" => &["default-src"],
+        _ => &[],
+    }
+}
+
+/// Implements the expansion described in `Blocker::get_csp_directives_with_fallbacks` for a
+/// comma-separated list of policies.
+fn expand_csp_fallbacks(merged: &str) -> String {
+    let policies: Vec<Vec<(String, &str)>> = merged.split(',')
+        .map(|policy| policy.split(';')
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty())
+            .map(|directive| {
+                let name = directive.split_whitespace().next().unwrap_or_default().to_ascii_lowercase();
+                (name, directive)
+            })
+            .collect())
+        .collect();
+
+    let used_fetch_directives: BTreeSet<&str> = policies.iter()
+        .flatten()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| !csp_fallbacks(name).is_empty())
+        .collect();
+
+    policies.iter().map(|policy| {
+        let value_of = |name: &str| policy.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, directive)| directive[name.len()..].trim());
+        let expanded = used_fetch_directives.iter()
+            .filter(|name| value_of(name).is_none())
+            .filter_map(|name| csp_fallbacks(name).iter()
+                .find_map(|fallback| value_of(fallback))
+                .map(|value| format!("{} {}", name, value).trim_end().to_string()));
+        policy.iter()
+            .map(|(_, directive)| directive.to_string())
+            .chain(expanded)
+            .collect::<Vec<_>>()
+            .join("; ")
+    }).collect::<Vec<_>>().join(",")
+}
+
+/// A filter that has been disabled using `Blocker::disable_filter`, along with the lists it was
+/// removed from.
+#[derive(Debug, Clone)]
+pub(crate) struct DisabledFilter {
+    /// Copies of the filter removed from each of `Blocker::untagged_lists_mut`, by index.
+    removed: Vec<(usize, NetworkFilter)>,
+    tagged: Option<NetworkFilter>,
+}
+
+/// A hostname-anchored filter like `||ads.example.com^` which is redundant, because another filter
+/// in the same list, like `||example.com^`, blocks a parent domain with otherwise identical
+/// options. Found using `Blocker::subsumed_filters`.
+#[derive(Debug, Clone)]
+pub struct SubsumedFilter {
+    pub filter: NetworkFilter,
+    pub subsumed_by: NetworkFilter,
+}
+
+/// Finds every filter in `filters` which is subsumed by another, as described in
+/// `SubsumedFilter`. Each filter is paired with the broadest filter that covers it.
+fn find_subsumed<'a>(filters: impl Iterator<Item=&'a NetworkFilter>) -> Vec<(&'a NetworkFilter, &'a NetworkFilter)> {
+    // Only plain `||hostname^` filters are considered, grouped by all of their other options
+    let mut groups: HashMap<String, HashMap<&str, &NetworkFilter>> = HashMap::new();
+    for filter in filters {
+        let hostname = match filter.hostname.as_deref() {
+            Some(hostname) if filter.is_hostname_anchor()
+                && filter.is_right_anchor()
+                && !filter.is_regex()
+                && !filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)
+                && matches!(filter.filter, FilterPart::Empty)
+                && !hostname.contains(':') => hostname,
+            _ => continue,
+        };
+        let key = format!(
+            "{:b}:{:?}:{:?}:{:?}:{:?}:{:?}:{:?}",
+            filter.mask, filter.opt_domains, filter.opt_not_domains, filter.tag, filter.redirect, filter.csp, filter.removeparam,
+        );
+        groups.entry(key).or_default().insert(hostname, filter);
+    }
+
+    let mut subsumed = vec![];
+    for group in groups.values() {
+        for (hostname, filter) in group.iter() {
+            let broadest = hostname.match_indices('.')
+                .rev()
+                .find_map(|(i, _)| group.get(&hostname[i + 1..]));
+            if let Some(subsumed_by) = broadest {
+                subsumed.push((*filter, *subsumed_by));
+            }
+        }
+    }
+    subsumed
+}
+
+/// If `fused` is the result of optimizing `filter` together with other filters, returns `filter`
+/// as it would have been stored before optimization, along with a filter equivalent to `fused`
+/// without it.
+fn unfuse_filter(fused: &NetworkFilter, filter: &NetworkFilter) -> Option<(NetworkFilter, NetworkFilter)> {
+    let patterns = match &fused.filter {
+        FilterPart::AnyOf(patterns) => patterns,
+        _ => return None,
+    };
+    let pattern = match &filter.filter {
+        FilterPart::Simple(pattern) => pattern,
+        _ => return None,
+    };
+    // Fusion always marks the resulting filter as a regex, but otherwise keeps the same mask.
+    // Filters for different hostnames or domains are never fused together, even if they share a
+    // pattern.
+    if fused.mask | NetworkFilterMask::IS_REGEX != filter.mask | NetworkFilterMask::IS_REGEX
+        || fused.tag != filter.tag
+        || fused.hostname != filter.hostname
+        || fused.opt_domains != filter.opt_domains
+        || fused.opt_not_domains != filter.opt_not_domains
+        || fused.redirect != filter.redirect
+        || !patterns.contains(pattern)
+    {
+        return None;
+    }
+
+    let mut remaining = fused.clone();
+    let other_patterns: Vec<String> = patterns.iter().filter(|p| *p != pattern).cloned().collect();
+    remaining.filter = if other_patterns.len() == 1 {
+        FilterPart::Simple(other_patterns[0].clone())
+    } else {
+        FilterPart::AnyOf(other_patterns)
+    };
+    remaining.raw_line = fused.raw_line.as_ref().map(|raw_line| {
+        raw_line.split(" <+> ")
+            .filter(|line| Some(*line) != filter.raw_line.as_deref())
+            .collect::<Vec<_>>()
+            .join(" <+> ")
+    });
+    remaining.regex = Arc::new(std::sync::RwLock::new(None));
+    // The fused filter takes its id from its first member
+    if remaining.id == filter.id {
+        remaining.id = remaining.get_id();
+    }
+
+    let mut member = fused.clone();
+    member.filter = FilterPart::Simple(pattern.clone());
+    member.mask = filter.mask;
+    member.id = filter.id;
+    member.raw_line = fused.raw_line.as_ref().and(filter.raw_line.clone());
+    member.regex = Arc::new(std::sync::RwLock::new(None));
+
+    Some((member, remaining))
+}
+
 /// Inserts a value into the `Vec` under the specified key in the `HashMap`. The entry will be
 /// created if it does not exist. If it already exists, it will be inserted in the `Vec` in a
 /// sorted order.
@@ -1046,6 +1838,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn network_filter_list_remove_fused_filter() {
+        let rules = ["||a.com/ads", "||a.com/banner", "||b.com/ads", "||b.com/banner"];
+        let parse = |line: &str| NetworkFilter::parse(line, true, Default::default()).unwrap();
+        let matches = |filter_list: &NetworkFilterList, url: &str| {
+            let request = Request::from_urls(url, "https://example.com", "image").unwrap();
+            let mut tokens = Vec::new();
+            request.get_tokens(&mut tokens);
+            filter_list.check(&request, &tokens, &HashSet::new()).is_some()
+        };
+
+        let mut filter_list = NetworkFilterList::new(rules.iter().map(|rule| parse(rule)).collect(), true);
+        // Both hostnames share the `/ads` pattern after fusion
+        assert!(filter_list.filter_map.values().flatten().all(|f| matches!(f.filter, FilterPart::AnyOf(_))));
+
+        let removed = filter_list.remove_filter(&parse("||b.com/ads")).unwrap();
+        assert_eq!(removed.hostname.as_deref(), Some("b.com"));
+        assert!(!matches(&filter_list, "https://b.com/ads.png"));
+        assert!(matches(&filter_list, "https://b.com/banner.png"));
+        assert!(matches(&filter_list, "https://a.com/ads.png"));
+        assert!(matches(&filter_list, "https://a.com/banner.png"));
+
+        let mut badfilter = parse("||a.com/ads");
+        badfilter.mask.set(NetworkFilterMask::BAD_FILTER, true);
+        assert!(filter_list.remove_badfiltered(&badfilter));
+        assert!(!matches(&filter_list, "https://a.com/ads.png"));
+        assert!(matches(&filter_list, "https://a.com/banner.png"));
+        assert!(matches(&filter_list, "https://b.com/banner.png"));
+
+        // Hostnames without a member filter are left alone
+        assert!(filter_list.remove_filter(&parse("||c.com/banner")).is_none());
+        assert!(matches(&filter_list, "https://a.com/banner.png"));
+        assert!(matches(&filter_list, "https://b.com/banner.png"));
+    }
+
     fn test_requests_filters(filters: &Vec<&str>, requests: &Vec<(Request, bool)>) {
         let network_filters: Vec<_> = filters
             .into_iter()
@@ -1263,6 +2090,127 @@ mod blocker_tests {
         assert_eq!(matched_rule.error, None);
     }
 
+    #[test]
+    fn redirect_inline_data_url() {
+        let filters = vec![
+            String::from("||foo.com/ad.js$redirect=data:application/javascript,(function()%7B%7D)(),void(0)"),
+            String::from("||foo.com/ad.png$redirect=data:image/gif;base64,R0lGODlhAQABAAAAACw="),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        // No resources are needed for inline redirects
+        let decoded_redirect = |url: &str| {
+            let request = Request::from_urls(url, "https://example.com", "script").unwrap();
+            let result = blocker.check(&request);
+            assert_eq!(result.matched, true);
+            match result.redirect {
+                Some(Redirection::Resource(data_url)) => {
+                    let (content_type, data) = data_url.strip_prefix("data:").unwrap().split_once(";base64,").unwrap();
+                    (content_type.to_owned(), base64::decode(data).unwrap())
+                }
+                other => panic!("Unexpected redirect {:?}", other),
+            }
+        };
+        assert_eq!(decoded_redirect("https://foo.com/ad.js"), ("application/javascript".to_owned(), b"(function(){})(),void(0)".to_vec()));
+        assert_eq!(decoded_redirect("https://foo.com/ad.png"), ("image/gif".to_owned(), base64::decode("R0lGODlhAQABAAAAACw=").unwrap()));
+    }
+
+    #[test]
+    fn redirect_ties() {
+        let filters = vec![
+            String::from("||foo.com$important,redirect-url=http://b.com"),
+            String::from("||foo.com$important,redirect-url=http://a.com"),
+            String::from("/ads.js$important,redirect-url=http://c.com"),
+            String::from("||bar.com^$important,redirect-url=http://e.com"),
+            String::from("||bar.com^$important,redirect-url=http://d.com,domain=example.com"),
+        ];
+        let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
+        let blocker_options = BlockerOptions { enable_optimizations: false };
+
+        let (network_filters, _) = parse_filters(&filters, true, opts);
+        let blocker = Blocker::new(network_filters, &blocker_options);
+        let mut reversed_filters = filters.clone();
+        reversed_filters.reverse();
+        let (network_filters, _) = parse_filters(&reversed_filters, true, opts);
+        let reversed_blocker = Blocker::new(network_filters, &blocker_options);
+
+        let requests = [
+            // Equally specific, so the lowest redirect value wins
+            (Request::from_urls("https://foo.com/ads.js", "https://example.com", "script").unwrap(), "http://a.com"),
+            // `$domain` makes a filter more specific
+            (Request::from_urls("https://bar.com/ads.js", "https://example.com", "script").unwrap(), "http://d.com"),
+            (Request::from_urls("https://bar.com/ads.js", "https://other.com", "script").unwrap(), "http://e.com"),
+        ];
+        for (request, expected) in requests.iter() {
+            let expected = Some(Redirection::Url(expected.to_string()));
+            for _ in 0..3 {
+                assert_eq!(blocker.check(request).redirect, expected, "{}", request.url);
+                assert_eq!(reversed_blocker.check(request).redirect, expected, "{}", request.url);
+            }
+        }
+    }
+
+    #[test]
+    fn sec_fetch_dest_types() {
+        let filters = vec![
+            String::from("||cdn.com/a$script"),
+            String::from("||cdn.com/b$image"),
+            String::from("||cdn.com/c$subdocument"),
+            String::from("||cdn.com/d$media"),
+            String::from("||cdn.com/e$other"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        let cases = [
+            ("https://cdn.com/a", "script", true),
+            ("https://cdn.com/a", "worker", true),
+            ("https://cdn.com/a", "image", false),
+            ("https://cdn.com/b", "image", true),
+            ("https://cdn.com/b", "style", false),
+            ("https://cdn.com/c", "iframe", true),
+            ("https://cdn.com/c", "frame", true),
+            ("https://cdn.com/d", "video", true),
+            ("https://cdn.com/e", "empty", true),
+            ("https://cdn.com/e", "script", false),
+        ];
+        for (url, dest, expected) in cases.iter() {
+            let request = Request::from_urls_with_sec_fetch_dest(url, "https://example.com", dest).unwrap();
+            assert_eq!(blocker.check(&request).matched, *expected, "{} {}", url, dest);
+        }
+    }
+
+    #[test]
+    fn loopback_exempt() {
+        let filters = vec![
+            String::from("||127.0.0.1^"),
+            String::from("||localhost^$third-party"),
+            String::from("/ads.js"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let mut blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        // Each request, and whether it's to a loopback host
+        let requests = [
+            (Request::from_urls("http://127.0.0.1:8080/api", "https://example.com", "xhr").unwrap(), true),
+            (Request::from_urls("http://localhost/ads.js", "https://localhost", "script").unwrap(), true),
+            (Request::from_urls("http://localhost/tracker", "https://example.com", "image").unwrap(), true),
+            (Request::from_urls("https://example.com/ads.js", "https://example.com", "script").unwrap(), false),
+        ];
+        for (request, _) in requests.iter() {
+            assert_eq!(blocker.check(request).matched, true, "{}", request.url);
+        }
+
+        blocker.set_loopback_exempt(true);
+        for (request, is_loopback) in requests.iter() {
+            assert_eq!(blocker.check(request).matched, !is_loopback, "{}", request.url);
+        }
+
+        blocker.set_loopback_exempt(false);
+        assert_eq!(blocker.check(&requests[0].0).matched, true);
+    }
+
     #[test]
     fn redirect_url_not_recognized_without_parse_opt() {
         let filters = vec![
@@ -1369,6 +2317,172 @@ mod blocker_tests {
         assert_eq!(matched_rule.error, None);
     }
 
+    #[test]
+    fn removeparam() {
+        let filters = vec![
+            String::from("||foo.com^$removeparam=utm_source"),
+            String::from("||foo.com^$removeparam=/^utm_/"),
+            String::from("||foo.com^$removeparam=fbclid"),
+            String::from("||bar.com^$removeparam"),
+            String::from("@@||bar.com/keep$removeparam"),
+            String::from("@@||foo.com/fb$removeparam=fbclid"),
+            String::from("||baz.com^$xhr,removeparam=id"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+
+        for enable_optimizations in [false, true] {
+            let blocker = Blocker::new(network_filters.clone(), &BlockerOptions { enable_optimizations });
+
+            let rewrite = |url: &str, request_type: &str| {
+                let request = Request::from_urls(url, "https://example.com", request_type).unwrap();
+                blocker.get_removeparam_url(&request, url)
+            };
+
+            // Repeated parameters are all removed, and the others keep their order and case
+            assert_eq!(
+                rewrite("https://foo.com/page?a=1&utm_source=x&B=2&utm_source=y&utm_medium=z&fbclid=3#top?utm_source=x", "document"),
+                Some(String::from("https://foo.com/page?a=1&B=2#top?utm_source=x")),
+            );
+            assert_eq!(rewrite("https://foo.com/page?utm_source=x", "script"), Some(String::from("https://foo.com/page")));
+            assert_eq!(rewrite("https://foo.com/page?utm_source=x#top", "document"), Some(String::from("https://foo.com/page#top")));
+            assert_eq!(rewrite("https://foo.com/page?a=1", "document"), None);
+            assert_eq!(rewrite("https://foo.com/page#utm_source=x", "document"), None);
+            // Parameter names are case sensitive
+            assert_eq!(rewrite("https://foo.com/page?UTM_SOURCE=x", "document"), None);
+
+            // Exceptions only keep the parameters they name
+            assert_eq!(rewrite("https://foo.com/fb?fbclid=3&utm_source=x", "document"), Some(String::from("https://foo.com/fb?fbclid=3")));
+
+            assert_eq!(rewrite("https://bar.com/?a=1&b=2", "document"), Some(String::from("https://bar.com/")));
+            assert_eq!(rewrite("https://bar.com/keep?a=1&b=2", "document"), None);
+
+            assert_eq!(rewrite("https://baz.com/api?id=1&q=2", "xhr"), Some(String::from("https://baz.com/api?q=2")));
+            assert_eq!(rewrite("https://baz.com/api?id=1&q=2", "document"), None);
+
+            // `$removeparam` rules never block requests
+            let request = Request::from_urls("https://foo.com/page?utm_source=x", "https://example.com", "script").unwrap();
+            assert_eq!(blocker.check(&request).matched, false);
+        }
+    }
+
+    #[test]
+    fn removeparam_dialects() {
+        let url = "https://foo.com/page?id=1&utm_source=a&UTM_Medium=b&ref=c#utm_source=a";
+        let rewrite = |filters: &[&str], url: &str| {
+            let filters = filters.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+            let (network_filters, _) = parse_filters(&filters, true, Default::default());
+            let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+            let request = Request::from_urls(url, "https://example.com", "document").unwrap();
+            blocker.get_removeparam_url(&request, url)
+        };
+
+        // Equivalent rules written for uBlock Origin and for AdGuard
+        for (ubo, adguard, expected) in [
+            ("||foo.com^$queryprune=utm_source", "||foo.com^$removeparam=utm_source", "https://foo.com/page?id=1&UTM_Medium=b&ref=c#utm_source=a"),
+            ("||foo.com^$queryprune=/^utm_[a-z]{1\\,8}=/i", "||foo.com^$removeparam=/^utm_[a-z]{1\\,8}=/ii", "https://foo.com/page?id=1&ref=c#utm_source=a"),
+            ("||foo.com^$queryprune=~/^id=/", "||foo.com^$removeparam=~id", "https://foo.com/page?id=1#utm_source=a"),
+        ].iter() {
+            assert_eq!(rewrite(&[ubo], url), Some(expected.to_string()));
+            assert_eq!(rewrite(&[adguard], url), Some(expected.to_string()));
+        }
+
+        // Exceptions apply to rules written in the other dialect
+        assert_eq!(rewrite(&["||foo.com^$removeparam=utm_source", "@@||foo.com/page$queryprune=utm_source"], url), None);
+    }
+
+    #[test]
+    fn redirect_type_options() {
+        let filters = vec![
+            String::from("||foo.com/ad.js$redirect=noopjs,script"),
+            String::from("||foo.com/ad.gif$image,redirect=1x1.gif"),
+            String::from("@@||foo.com/ad.js$image,redirect=noopjs"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+
+        for enable_optimizations in [false, true] {
+            let mut blocker = Blocker::new(network_filters.clone(), &BlockerOptions { enable_optimizations });
+            blocker.add_resource(&Resource {
+                name: "noopjs".to_string(),
+                aliases: vec![],
+                kind: crate::resources::ResourceType::Mime(crate::resources::MimeType::ApplicationJavascript),
+                content: base64::encode("(function() {})()"),
+            }).unwrap();
+            blocker.add_resource(&Resource {
+                name: "1x1.gif".to_string(),
+                aliases: vec![],
+                kind: crate::resources::ResourceType::Mime(crate::resources::MimeType::ImageGif),
+                content: base64::encode("gif"),
+            }).unwrap();
+
+            let check = |url: &str, request_type: &str| {
+                blocker.check(&Request::from_urls(url, "https://example.com", request_type).unwrap())
+            };
+
+            let matched_rule = check("https://foo.com/ad.js", "script");
+            assert_eq!(matched_rule.matched, true);
+            assert_eq!(matched_rule.redirect, Some(Redirection::Resource("data:application/javascript;base64,KGZ1bmN0aW9uKCkge30pKCk=".to_string())));
+
+            // The exception only applies to images, and the redirect only applies to scripts
+            let matched_rule = check("https://foo.com/ad.js", "image");
+            assert_eq!(matched_rule.matched, false);
+            assert_eq!(matched_rule.redirect, None);
+
+            let matched_rule = check("https://foo.com/ad.gif", "image");
+            assert_eq!(matched_rule.matched, true);
+            assert_eq!(matched_rule.redirect, Some(Redirection::Resource("data:image/gif;base64,Z2lm".to_string())));
+
+            let matched_rule = check("https://foo.com/ad.gif", "script");
+            assert_eq!(matched_rule.matched, false);
+            assert_eq!(matched_rule.redirect, None);
+        }
+    }
+
+    #[test]
+    fn redirect_resource_name() {
+        let filters = vec![
+            String::from("||foo.com/ad.js$script,redirect=noopjs"),
+            String::from("||foo.com/ad.png$image,redirect=data:text/plain;base64,"),
+            String::from("@@||foo.com/ad.js$domain=allowed.com"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let mut blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+        blocker.add_resource(&Resource {
+            name: "noop.js".to_string(),
+            aliases: vec!["noopjs".to_string()],
+            kind: crate::resources::ResourceType::Mime(crate::resources::MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+        }).unwrap();
+
+        let check = |url: &str, source_url: &str, request_type: &str| {
+            blocker.check(&Request::from_urls(url, source_url, request_type).unwrap())
+        };
+
+        let matched_rule = check("https://foo.com/ad.js", "https://example.com", "script");
+        assert_eq!(matched_rule.matched, true);
+        assert_eq!(matched_rule.redirect_resource, Some("noopjs".to_string()));
+        let resource = blocker.get_resource(matched_rule.redirect_resource.as_ref().unwrap()).unwrap();
+        assert_eq!(resource.content_type, "application/javascript");
+        assert_eq!(resource.decoded_data().unwrap(), b"(function() {})()".to_vec());
+        assert_eq!(matched_rule.redirect, Some(Redirection::Resource(resource.data_url())));
+
+        // Excepted requests still report the redirect, as for `redirect-rule`, along with its name
+        let matched_rule = check("https://foo.com/ad.js", "https://allowed.com", "script");
+        assert_eq!(matched_rule.matched, false);
+        assert_eq!(matched_rule.redirect, Some(Redirection::Resource(resource.data_url())));
+        assert_eq!(matched_rule.redirect_resource, Some("noopjs".to_string()));
+
+        // Inline resources have no name
+        let matched_rule = check("https://foo.com/ad.png", "https://example.com", "image");
+        assert_eq!(matched_rule.matched, true);
+        assert_eq!(matched_rule.redirect, Some(Redirection::Resource("data:text/plain;base64,".to_string())));
+        assert_eq!(matched_rule.redirect_resource, None);
+
+        let matched_rule = check("https://foo.com/other.js", "https://example.com", "script");
+        assert_eq!(matched_rule.redirect_resource, None);
+    }
+
     #[test]
     fn redirect_exception() {
         let filters = vec![
@@ -1549,6 +2663,36 @@ mod blocker_tests {
         }
     }
 
+    #[test]
+    fn get_csp_directives_with_fallbacks() {
+        let filters = vec![
+            String::from("$csp=default-src 'self',domain=a.com|b.com"),
+            String::from("$csp=script-src 'none',domain=a.com"),
+            String::from("$csp=img-src data:,domain=c.com"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        let possible_results = [
+            Some(String::from("default-src 'self'; script-src 'self',script-src 'none'")),
+            Some(String::from("script-src 'none',default-src 'self'; script-src 'self'")),
+        ];
+        assert!(possible_results.contains(&blocker.get_csp_directives_with_fallbacks(&Request::from_urls("https://a.com", "https://a.com", "document").unwrap())));
+        // Lone policies are left unchanged
+        assert_eq!(blocker.get_csp_directives_with_fallbacks(&Request::from_urls("https://b.com", "https://b.com", "document").unwrap()), Some(String::from("default-src 'self'")));
+        assert_eq!(blocker.get_csp_directives_with_fallbacks(&Request::from_urls("https://c.com", "https://c.com", "document").unwrap()), Some(String::from("img-src data:")));
+        assert_eq!(blocker.get_csp_directives_with_fallbacks(&Request::from_urls("https://d.com", "https://d.com", "document").unwrap()), None);
+    }
+
+    #[test]
+    fn expand_csp_fallbacks_works() {
+        assert_eq!(expand_csp_fallbacks("default-src 'none'; script-src https://cdn.com,worker-src 'none'"), "default-src 'none'; script-src https://cdn.com; worker-src https://cdn.com,worker-src 'none'");
+        assert_eq!(expand_csp_fallbacks("default-src 'self',frame-src 'none',style-src-elem 'none'"), "default-src 'self'; frame-src 'self'; style-src-elem 'self',frame-src 'none',style-src-elem 'none'");
+        // Policies without any applicable fallback are left as-is
+        assert_eq!(expand_csp_fallbacks("script-src 'none',img-src data:;sandbox"), "script-src 'none',img-src data:; sandbox");
+        assert_eq!(expand_csp_fallbacks("upgrade-insecure-requests,default-src 'self'"), "upgrade-insecure-requests,default-src 'self'");
+    }
+
     #[test]
     fn tags_enable_works() {
         let filters = vec![
@@ -1662,17 +2806,81 @@ mod blocker_tests {
     }
 
     #[test]
-    fn filter_add_badfilter_error() {
-        let blocker_options: BlockerOptions = BlockerOptions {
-            enable_optimizations: false,
-        };
-
-        let mut blocker = Blocker::new(Vec::new(), &blocker_options);
+    fn filter_add_badfilter() {
+        let rules = ["||foo.com^", "adv", "||bar.com^$domain=example.com", "ads$tag=stuff"];
+        let badfilters = ["||foo.com^$badfilter", "adv$badfilter", "||bar.com^$domain=example.com,badfilter", "ads$tag=stuff,badfilter"];
+        let requests = [
+            Request::from_urls("https://foo.com/", "https://example.com", "script").unwrap(),
+            Request::from_urls("https://example.net/adv", "https://example.com", "script").unwrap(),
+            Request::from_urls("https://bar.com/", "https://example.com", "script").unwrap(),
+            Request::from_urls("https://example.net/ads", "https://example.com", "script").unwrap(),
+        ];
+        let parse = |line: &str| NetworkFilter::parse(line, true, Default::default()).unwrap();
+
+        for enable_optimizations in [false, true].iter() {
+            let blocker_options = BlockerOptions { enable_optimizations: *enable_optimizations };
+
+            // Rules first, then the badfilters which cancel them
+            let initial_rules = rules.iter().chain(["adv2", "adv3"].iter()).map(|rule| parse(rule)).collect();
+            let mut blocker = Blocker::new(initial_rules, &blocker_options);
+            blocker.enable_tags(&["stuff"]);
+            if *enable_optimizations {
+                let request = Request::from_urls("https://example.net/adv2", "https://example.com", "script").unwrap();
+                // `adv` is fused together with the other simple patterns
+                assert_eq!(blocker.check(&request).filter.unwrap().split(" <+> ").any(|line| line == "adv"), true);
+            }
+            for request in requests.iter() {
+                assert_eq!(blocker.check(request).matched, true, "{}", request.url);
+            }
+            for badfilter in badfilters.iter() {
+                assert_eq!(blocker.add_filter(parse(badfilter)), Ok(()));
+            }
+            for request in requests.iter() {
+                assert_eq!(blocker.check(request).matched, false, "{}", request.url);
+            }
+            // Filters fused with a cancelled one are unaffected
+            let request = Request::from_urls("https://example.net/adv2", "https://example.com", "script").unwrap();
+            assert_eq!(blocker.check(&request).matched, true);
+            assert_eq!(blocker.add_filter(parse("adv$badfilter")), Err(BlockerError::FilterExists));
+
+            // Badfilters first, then the rules they cancel
+            let mut blocker = Blocker::new(vec![parse("||foo.com^$badfilter")], &blocker_options);
+            blocker.enable_tags(&["stuff"]);
+            for badfilter in badfilters.iter().skip(1) {
+                blocker.add_filter(parse(badfilter)).unwrap();
+            }
+            for rule in rules.iter() {
+                assert_eq!(blocker.add_filter(parse(rule)), Ok(()));
+            }
+            for request in requests.iter() {
+                assert_eq!(blocker.check(request).matched, false, "{}", request.url);
+            }
+            // Similar rules with a different id are still added
+            blocker.add_filter(parse("||bar.com^")).unwrap();
+            assert_eq!(blocker.check(&requests[2]).matched, true);
+        }
+    }
 
-        let filter = NetworkFilter::parse("adv$badfilter", true, Default::default()).unwrap();
-        let added = blocker.add_filter(filter);
-        assert!(added.is_err());
-        assert_eq!(added.err().unwrap(), BlockerError::BadFilterAddUnsupported);
+    #[test]
+    fn remove_filter_sharing_fused_pattern() {
+        let rules = ["||example.com/ads", "||example.com/banner"];
+        let parse = |line: &str| NetworkFilter::parse(line, true, Default::default()).unwrap();
+        let request = Request::from_urls("https://example.com/ads.png", "https://example.net", "image").unwrap();
+
+        for enable_optimizations in [false, true].iter() {
+            let blocker_options = BlockerOptions { enable_optimizations: *enable_optimizations };
+            let initial_rules = || rules.iter().chain(["||other.com/ads"].iter()).map(|rule| parse(rule)).collect();
+
+            let mut blocker = Blocker::new(initial_rules(), &blocker_options);
+            assert_eq!(blocker.disable_filter(&parse("||other.com/ads")), true);
+            assert_eq!(blocker.check(&request).matched, true);
+
+            let mut blocker = Blocker::new(initial_rules(), &blocker_options);
+            assert_eq!(blocker.add_filter(parse("||other.com/ads$badfilter")), Ok(()));
+            assert_eq!(blocker.check(&request).matched, true);
+            let other = Request::from_urls("https://other.com/ads.png", "https://example.net", "image").unwrap();
+            assert_eq!(blocker.check(&other).matched, false);
+        }
     }
 
     #[test]
@@ -1740,6 +2948,81 @@ mod blocker_tests {
         });
     }
 
+    #[test]
+    fn subsumed_filters() {
+        let filters = vec![
+            String::from("||example.com^"),
+            String::from("||ads.example.com^"),
+            String::from("||a.b.example.com^"),
+            String::from("||notexample.com^"),
+            String::from("||ads.example.com^$script"),
+            String::from("||ads.example.com/banner.png"),
+            String::from("||sub.other.com^"),
+            String::from("@@||other.com^"),
+            String::from("@@||cdn.other.com^"),
+            String::from("||example.org^$tag=t"),
+            String::from("||ads.example.org^$tag=t"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let mut blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+        blocker.enable_tags(&["t"]);
+
+        let mut subsumed = blocker.subsumed_filters().into_iter()
+            .map(|s| (s.filter.to_string(), s.subsumed_by.to_string()))
+            .collect::<Vec<_>>();
+        subsumed.sort();
+        assert_eq!(subsumed, vec![
+            (String::from("@@||cdn.other.com^"), String::from("@@||other.com^")),
+            (String::from("||a.b.example.com^"), String::from("||example.com^")),
+            (String::from("||ads.example.com^"), String::from("||example.com^")),
+            (String::from("||ads.example.org^$tag=t"), String::from("||example.org^$tag=t")),
+        ]);
+
+        assert_eq!(blocker.remove_subsumed_filters(), 4);
+        assert_eq!(blocker.subsumed_filters().is_empty(), true);
+        assert_eq!(blocker.filter_exists(&NetworkFilter::parse("||ads.example.com^", true, Default::default()).unwrap()), false);
+        assert_eq!(blocker.filter_exists(&NetworkFilter::parse("||ads.example.com^$script", true, Default::default()).unwrap()), true);
+
+        let request = Request::from_urls("https://ads.example.com/x.png", "https://site.com", "image").unwrap();
+        assert_eq!(blocker.check(&request).filter, Some(String::from("||example.com^")));
+        let request = Request::from_urls("https://ads.example.org/x.js", "https://site.com", "script").unwrap();
+        assert_eq!(blocker.check(&request).filter, Some(String::from("||example.org^$tag=t")));
+        let request = Request::from_urls("https://cdn.other.com/x.js", "https://site.com", "script").unwrap();
+        assert_eq!(blocker.check_parameterised(&request, true, true).exception, Some(String::from("@@||other.com^")));
+    }
+
+    #[test]
+    fn check_cname() {
+        let filters = vec![
+            String::from("||tracker.net^$third-party"),
+            String::from("||pixel.net^$domain=news.com"),
+            String::from("/collect?"),
+            String::from("@@||tracker.net/allowed^"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        let request = Request::from_urls("https://metrics.news.com/x.js", "https://news.com", "script").unwrap();
+        assert_eq!(blocker.check(&request).matched, false);
+        // The cloaked request is first-party, which is kept after uncloaking
+        assert_eq!(blocker.check_cname(&request, "news.tracker.net").matched, false);
+        let result = blocker.check_cname(&request, "edge.pixel.net.");
+        assert_eq!(result.matched, true);
+        assert_eq!(result.filter, Some(String::from("||pixel.net^$domain=news.com")));
+
+        let request = Request::from_urls("https://metrics.blog.com/x.js", "https://shop.com", "script").unwrap();
+        assert_eq!(blocker.check_cname(&request, "news.tracker.net").matched, true);
+        assert_eq!(blocker.check_cname(&request, "edge.pixel.net").matched, false);
+        assert_eq!(blocker.check_cname(&request, "cdn.com").matched, false);
+
+        // Only hostname-anchored filters are considered
+        let request = Request::from_urls("https://metrics.blog.com/collect?id=1", "https://shop.com", "script").unwrap();
+        assert_eq!(blocker.check(&request).matched, true);
+        assert_eq!(blocker.check_cname(&request, "cdn.com").matched, false);
+        let request = Request::from_urls("https://metrics.blog.com/allowed/x.js", "https://shop.com", "script").unwrap();
+        assert_eq!(blocker.check_cname(&request, "tracker.net").matched, false);
+    }
+
     #[test]
     fn exception_force_check() {
         let blocker_options: BlockerOptions = BlockerOptions {
@@ -1801,18 +3084,17 @@ mod legacy_rule_parsing_tests {
     // number of expected EasyList cosmetic rules from old engine is 31144, but is incorrect as it skips a few particularly long rules that are nevertheless valid
     // easyList = { 24478, 31144, 0, 5589 };
     // not handling (and not including) filters with the following options:
-    // - $popup
     // - $elemhide
     // difference from original counts caused by not handling document/subdocument options and possibly miscounting on the blocker side.
     // Printing all non-cosmetic, non-html, non-comment/-empty rules and ones with no unsupported options yields 29142 items
     // This engine also handles 3 rules that old one does not
-    const EASY_LIST: ListCounts = ListCounts { filters: 24065, cosmetic_filters: 31163, exceptions: 5796, duplicates: 0 };
+    const EASY_LIST: ListCounts = ListCounts { filters: 25265, cosmetic_filters: 31163, exceptions: 5896, duplicates: 0 };
     // easyPrivacy = { 11817, 0, 0, 1020 };
     // differences in counts explained by hashset size underreporting as detailed in the next two cases
     const EASY_PRIVACY: ListCounts = ListCounts { filters: 11889, cosmetic_filters: 0, exceptions: 1021, duplicates: 2 };
     // ublockUnbreak = { 4, 8, 0, 94 };
     // differences in counts explained by client.hostAnchoredExceptionHashSet->GetSize() underreporting when compared to client.numHostAnchoredExceptionFilters
-    const UBLOCK_UNBREAK: ListCounts = ListCounts { filters: 4, cosmetic_filters: 8, exceptions: 98, duplicates: 0 };
+    const UBLOCK_UNBREAK: ListCounts = ListCounts { filters: 4, cosmetic_filters: 8, exceptions: 99, duplicates: 0 };
     // braveUnbreak = { 31, 0, 0, 4 };
     // differences in counts explained by client.hostAnchoredHashSet->GetSize() underreporting when compared to client.numHostAnchoredFilters
     const BRAVE_UNBREAK: ListCounts = ListCounts { filters: 32, cosmetic_filters: 0, exceptions: 4, duplicates: 0 };
@@ -1,6 +1,6 @@
 //! Transforms filter rules into content blocking syntax used on iOS and MacOS.
 
-use crate::filters::network::{NetworkFilter, NetworkFilterMask};
+use crate::filters::network::{expand_entity, NetworkFilter, NetworkFilterMask};
 use crate::filters::cosmetic::CosmeticFilter;
 use crate::lists::ParsedFilter;
 
@@ -153,8 +153,10 @@ pub enum CbRuleCreationFailure {
     NetworkGenerichideUnsupported,
     /// Network rules with badfilter options cannot be supported in content blocking syntax.
     NetworkBadFilterUnsupported,
-    /// Network rules with csp options cannot be supported in content blocking syntax.
-    NetworkCspUnsupported,
+    /// Network rules with csp options cannot generally be supported in content blocking syntax.
+    /// Policies that only forbid certain resource types on a site can be approximated with block
+    /// rules; otherwise, the policy's directive text is reported here.
+    NetworkCspUnsupported(String),
     /// Content blocking syntax only supports a subset of regex features, namely:
     /// - Matching any character with “.”.
     /// - Matching ranges with the range syntax [a-b].
@@ -238,6 +240,52 @@ impl Iterator for CbRuleEquivalentIterator {
     }
 }
 
+/// Content blocking syntax has no way to add CSP headers to a response. However, a policy made
+/// up entirely of `<type>-src 'none'` directives, applied by a simple `||hostname^` rule, forbids
+/// every resource of those types on that site; this can be represented by blocking the same
+/// resource types whenever the top-level document is on that site.
+///
+/// Returns `None` if the rule's policy cannot be represented this way.
+fn csp_block_equivalent(v: &NetworkFilter) -> Option<CbRule> {
+    let hostname = match (&v.filter, &v.hostname) {
+        (crate::filters::network::FilterPart::Empty, Some(hostname)) => hostname,
+        _ => return None,
+    };
+    if v.mask.contains(NetworkFilterMask::IS_EXCEPTION) || v.opt_domains.is_some() || v.opt_not_domains.is_some() {
+        return None;
+    }
+
+    let mut resource_types = HashSet::new();
+    for directive in v.csp.as_ref()?.split(';').map(str::trim).filter(|d| !d.is_empty()) {
+        let mut parts = directive.split_whitespace();
+        let resource_type = match parts.next() {
+            Some("script-src") => CbResourceType::Script,
+            Some("img-src") => CbResourceType::Image,
+            Some("style-src") => CbResourceType::StyleSheet,
+            Some("font-src") => CbResourceType::Font,
+            Some("media-src") => CbResourceType::Media,
+            _ => return None,
+        };
+        if parts.next() != Some("'none'") || parts.next().is_some() {
+            return None;
+        }
+        resource_types.insert(resource_type);
+    }
+    if resource_types.is_empty() {
+        return None;
+    }
+
+    Some(CbRule {
+        action: CbAction { typ: CbType::Block, selector: None },
+        trigger: CbTrigger {
+            url_filter: String::from(".*"),
+            if_domain: Some(vec![format!("*{}", hostname)]),
+            resource_type: Some(resource_types),
+            ..Default::default()
+        },
+    })
+}
+
 impl TryFrom<NetworkFilter> for CbRuleEquivalent {
     type Error = CbRuleCreationFailure;
 
@@ -245,7 +293,16 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
         static SPECIAL_CHARS: Lazy<Regex> = Lazy::new(|| Regex::new(r##"([.+?^${}()|\[\]])"##).unwrap());
         static REPLACE_WILDCARDS: Lazy<Regex> = Lazy::new(|| Regex::new(r##"\*"##).unwrap());
         static TRAILING_SEPARATOR: Lazy<Regex> = Lazy::new(|| Regex::new(r##"\^$"##).unwrap());
-        if let Some(raw_line) = v.raw_line {
+        // Content blocking regexes can't express "or the end of the URL", so a separator within
+        // the pattern must match a character. Trailing separators are dropped instead.
+        const SEPARATOR_CLASS: &str = "[^0-9A-Za-z_.%-]";
+        let escape_pattern = |part: &str| -> String {
+            part.split('^')
+                .map(|piece| SPECIAL_CHARS.replace_all(piece, r##"\$1"##).into_owned())
+                .collect::<Vec<_>>()
+                .join(SEPARATOR_CLASS)
+        };
+        if let Some(raw_line) = &v.raw_line {
             if v.redirect.is_some() {
                 return Err(CbRuleCreationFailure::NetworkRedirectUnsupported);
             }
@@ -256,7 +313,9 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                 return Err(CbRuleCreationFailure::NetworkBadFilterUnsupported);
             }
             if v.mask.contains(NetworkFilterMask::IS_CSP) {
-                return Err(CbRuleCreationFailure::NetworkCspUnsupported);
+                return csp_block_equivalent(&v)
+                    .map(Self::SingleRule)
+                    .ok_or_else(|| CbRuleCreationFailure::NetworkCspUnsupported(v.csp.clone().unwrap_or_default()));
             }
             if v.mask.contains(NetworkFilterMask::IS_COMPLETE_REGEX) {
                 return Err(CbRuleCreationFailure::FullRegexUnsupported);
@@ -276,7 +335,7 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                 (crate::filters::network::FilterPart::AnyOf(_), _) => return Err(CbRuleCreationFailure::OptimizedRulesUnsupported),
                 (crate::filters::network::FilterPart::Simple(part), Some(hostname)) => {
                     let without_trailing_separator = TRAILING_SEPARATOR.replace_all(&part, "");
-                    let escaped_special_chars = SPECIAL_CHARS.replace_all(&without_trailing_separator, r##"\$1"##);
+                    let escaped_special_chars = escape_pattern(&without_trailing_separator);
                     let with_fixed_wildcards = REPLACE_WILDCARDS.replace_all(&escaped_special_chars, ".*");
 
                     let mut url_filter = format!("^[^:]+:(//)?([^/]+\\.)?{}", SPECIAL_CHARS.replace_all(&hostname, r##"\$1"##));
@@ -295,7 +354,7 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                 }
                 (crate::filters::network::FilterPart::Simple(part), None) => {
                     let without_trailing_separator = TRAILING_SEPARATOR.replace_all(&part, "");
-                    let escaped_special_chars = SPECIAL_CHARS.replace_all(&without_trailing_separator, r##"\$1"##);
+                    let escaped_special_chars = escape_pattern(&without_trailing_separator);
                     let with_fixed_wildcards = REPLACE_WILDCARDS.replace_all(&escaped_special_chars, ".*");
                     let mut url_filter = if v.mask.contains(NetworkFilterMask::IS_LEFT_ANCHOR) {
                         format!("^{}", with_fixed_wildcards)
@@ -370,7 +429,11 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                         idna::domain_to_ascii(&lowercase).unwrap()
                     };
 
-                    collection.push(format!("*{}", normalized_domain));
+                    // Entities can't be matched directly, so they're expanded into common domains
+                    match expand_entity(&normalized_domain, usize::MAX) {
+                        Some(domains) => collection.extend(domains.into_iter().map(|domain| format!("*{}", domain))),
+                        None => collection.push(format!("*{}", normalized_domain)),
+                    }
                 });
 
                 (non_empty(if_domain), non_empty(unless_domain))
@@ -415,9 +478,11 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                 push_if_flag!(FROM_STYLESHEET, StyleSheet);
                 push_if_flag!(FROM_SUBDOCUMENT, Document);
                 push_if_flag!(FROM_WEBSOCKET);
+                push_if_flag!(FROM_WEBRTC);
                 push_if_flag!(FROM_XMLHTTPREQUEST, Raw);
                 push_if_flag!(FROM_FONT, Font);
-                // TODO - Popup, Document when implemented
+                push_if_flag!(FROM_POPUP, Popup);
+                // TODO - Document when implemented
 
                 if !unsupported_flags.is_empty() && types.is_empty() {
                     return Err(CbRuleCreationFailure::NoSupportedNetworkOptions(unsupported_flags));
@@ -761,7 +826,6 @@ mod ab2cb_tests {
         }]"####);
     }
 
-    /* TODO - `$popup` is currently unsupported by NetworkFilter
     #[test]
     fn popup_tests() {
         test_from_abp("||admngronline.com^$popup,third-party", r####"[{
@@ -769,7 +833,7 @@ mod ab2cb_tests {
                 "type": "block"
             },
             "trigger": {
-                "url-filter": "^https?://admngronline\\.com(?:[\\x00-\\x24\\x26-\\x2C\\x2F\\x3A-\\x40\\x5B-\\x5E\\x60\\x7B-\\x7F]|$)",
+                "url-filter": "^[^:]+:(//)?([^/]+\\.)?admngronline\\.com",
                 "load-type": [
                     "third-party"
                 ],
@@ -783,14 +847,24 @@ mod ab2cb_tests {
                 "type": "block"
             },
             "trigger": {
-                "url-filter": "^https?://bet365\\.com(?:[\\x00-\\x24\\x26-\\x2C\\x2F\\x3A-\\x40\\x5B-\\x5E\\x60\\x7B-\\x7F]|$).*affiliate=",
+                "url-filter": "^[^:]+:(//)?([^/]+\\.)?bet365\\.com[^0-9A-Za-z_.%-].*affiliate=",
+                "resource-type": [
+                    "popup"
+                ]
+            }
+        }]"####);
+        test_from_abp("||bet365.com^$document,popup", r####"[{
+            "action": {
+                "type": "block"
+            },
+            "trigger": {
+                "url-filter": "^[^:]+:(//)?([^/]+\\.)?bet365\\.com",
                 "resource-type": [
                     "popup"
                 ]
             }
         }]"####);
     }
-    */
 
     #[test]
     fn third_party() {
@@ -1083,6 +1157,66 @@ mod ab2cb_tests {
         }]"####);
     }
 
+    #[test]
+    fn entity_domains() {
+        let filter = crate::lists::parse_filter("||ads.com^$domain=example.*|other.org", true, Default::default()).unwrap();
+        let rules = CbRuleEquivalent::try_from(filter).unwrap().into_iter().collect::<Vec<_>>();
+        let if_domain = rules[0].trigger.if_domain.as_ref().unwrap();
+        assert!(if_domain.contains(&"*example.com".to_string()));
+        assert!(if_domain.contains(&"*example.co.uk".to_string()));
+        assert!(if_domain.contains(&"*other.org".to_string()));
+        assert!(!if_domain.iter().any(|domain| domain.ends_with('*')));
+    }
+
+    #[test]
+    fn csp_rules() {
+        test_from_abp("||example.com^$csp=script-src 'none'", r####"[{
+            "action": {
+                "type": "block"
+            },
+            "trigger": {
+                "url-filter": ".*",
+                "if-domain": [
+                    "*example.com"
+                ],
+                "resource-type": [
+                    "script"
+                ]
+            }
+        }]"####);
+        test_from_abp("||example.com^$csp=img-src 'none'; media-src 'none'", r####"[{
+            "action": {
+                "type": "block"
+            },
+            "trigger": {
+                "url-filter": ".*",
+                "if-domain": [
+                    "*example.com"
+                ],
+                "resource-type": [
+                    "image",
+                    "media"
+                ]
+            }
+        }]"####);
+
+        fn unsupported_csp(abp_rule: &str) -> String {
+            let filter = crate::lists::parse_filter(abp_rule, true, Default::default()).expect("Rule under test could not be parsed");
+            match CbRuleEquivalent::try_from(filter) {
+                Err(CbRuleCreationFailure::NetworkCspUnsupported(directive)) => directive,
+                Err(e) => panic!("Expected unsupported CSP failure for {}, got {:?}", abp_rule, e),
+                Ok(_) => panic!("Expected unsupported CSP failure for {}", abp_rule),
+            }
+        }
+
+        assert_eq!(unsupported_csp("||example.com^$csp=script-src 'self' * 'unsafe-inline'"), "script-src 'self' * 'unsafe-inline'");
+        assert_eq!(unsupported_csp("$csp=worker-src 'none',domain=example.com"), "worker-src 'none'");
+        assert_eq!(unsupported_csp("||example.com/page$csp=script-src 'none'"), "script-src 'none'");
+        assert_eq!(unsupported_csp("||example.com^$csp=script-src 'none'; frame-src 'self'"), "script-src 'none'; frame-src 'self'");
+        assert_eq!(unsupported_csp("@@||example.com^$csp=script-src 'none'"), "script-src 'none'");
+        assert_eq!(unsupported_csp("@@||example.com^$csp"), "");
+    }
+
     #[test]
     fn test_ignore_previous_fp_documents() {
         assert_eq!(vec![ignore_previous_fp_documents()], serde_json::from_str::<Vec<CbRule>>(r####"[{
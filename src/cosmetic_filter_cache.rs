@@ -10,11 +10,12 @@
 
 use crate::filters::cosmetic::CosmeticFilter;
 use crate::filters::cosmetic::CosmeticFilterMask;
-use crate::resources::{Resource, ScriptletResourceStorage};
+use crate::resources::{ResolvedScriptlet, Resource, ScriptletCall, ScriptletResourceStorage};
 use crate::utils::Hash;
 
 use std::collections::{HashSet, HashMap};
 
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 
 /// Contains cosmetic filter information intended to be used on a particular URL.
@@ -25,6 +26,10 @@ use serde::{Deserialize, Serialize};
 /// `style_selectors` is a map of CSS selectors on the page to respective non-hide style rules,
 /// i.e. any required styles other than `display: none`.
 ///
+/// `shadow_hide_selectors` is a set of selectors using the `>>>` combinator to hide elements
+/// inside shadow roots. The client must traverse into the corresponding shadow roots to apply
+/// these, since they can't be used from a regular stylesheet.
+///
 /// `exceptions` is a set of any class or id CSS selectors that should not have generic rules
 /// applied. In practice, these should be passed to `class_id_stylesheet` and not used otherwise.
 ///
@@ -34,13 +39,20 @@ use serde::{Deserialize, Serialize};
 /// `generichide` is set to true if there is a corresponding `$generichide` exception network
 /// filter. If so, the page should not query for additional generic rules using
 /// `hidden_class_id_selectors`.
+///
+/// `apply_generic_stylesheet` is set to true if the stylesheet from
+/// `generic_cosmetic_stylesheet` should be injected into the page. Otherwise, either there are no
+/// such generic rules, `generichide` is set, or some of the generic selectors are excepted on this
+/// page and the remaining ones have been included in `hide_selectors` instead.
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct UrlSpecificResources {
     pub hide_selectors: HashSet<String>,
     pub style_selectors: HashMap<String, Vec<String>>,
+    pub shadow_hide_selectors: HashSet<String>,
     pub exceptions: HashSet<String>,
     pub injected_script: String,
     pub generichide: bool,
+    pub apply_generic_stylesheet: bool,
 }
 
 impl UrlSpecificResources {
@@ -48,26 +60,45 @@ impl UrlSpecificResources {
         Self {
             hide_selectors: HashSet::new(),
             style_selectors: HashMap::new(),
+            shadow_hide_selectors: HashSet::new(),
             exceptions: HashSet::new(),
             injected_script: String::new(),
             generichide: false,
+            apply_generic_stylesheet: false,
         }
     }
 }
 
-/// Splits the given hostname-specific rules into three collections:
+/// The kind of rule responsible for hiding a selector, as returned by
+/// `CosmeticFilterCache::would_hide`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HideSource {
+    /// A generic rule that applies to every site, e.g. `##.ad`.
+    Generic,
+    /// A rule for the hostname or one of its parent domains, e.g. `example.com##.ad`.
+    Specific,
+    /// A rule for an entity matching the hostname, e.g. `example.*##.ad`.
+    Entity,
+}
+
+/// Maps CSS selectors to any additional styles that should be applied to them.
+type StyleSelectors = HashMap<String, Vec<String>>;
+
+/// Splits the given hostname-specific rules into four collections:
 /// - a set of CSS selectors that should be hidden on all pages under the hostname
 /// - a mapping from CSS selectors to any additional (i.e. not `display: none`) CSS styles that
 ///   should be applied to those elements
 /// - a list of any scriptlets that should be injected into the page's JavaScript context
-fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, HashMap<String, Vec<String>>, Vec<String>) {
+/// - a set of shadow-piercing selectors that should be hidden on all pages under the hostname
+fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, StyleSelectors, Vec<String>, HashSet<String>) {
     if rules.is_empty() {
-        (HashSet::default(), HashMap::default(), vec![])
+        (HashSet::default(), HashMap::default(), vec![], HashSet::default())
     } else {
         let mut script_rules = Vec::with_capacity(10);
+        let mut shadow_hide_rules = HashSet::new();
 
         let mut hide_rules = HashSet::with_capacity(rules.len());
-        let mut style_rules: HashMap<String, Vec<String>> = HashMap::with_capacity(rules.len());
+        let mut style_rules: StyleSelectors = HashMap::with_capacity(rules.len());
 
         rules.iter()
             .for_each(|rule| {
@@ -85,11 +116,14 @@ fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, H
                     SpecificFilterType::ScriptInject(sel) => {
                         script_rules.push(sel.to_owned());
                     }
+                    SpecificFilterType::ShadowHide(sel) => {
+                        shadow_hide_rules.insert(sel.to_owned());
+                    }
                     _ => unreachable!()
                 }
             });
 
-        (hide_rules, style_rules, script_rules)
+        (hide_rules, style_rules, script_rules, shadow_hide_rules)
     }
 }
 
@@ -121,6 +155,15 @@ pub(crate) struct CosmeticFilterCache {
     /// Rules that are the CSS selector of an element to be hidden on all sites that do not fit
     /// into any of the class or id buckets above, e.g. `##a[href="https://malware.com"]`
     pub(crate) misc_generic_selectors: HashSet<String>,
+    /// A stylesheet hiding all of `misc_generic_selectors`, built the first time it's requested.
+    /// Not serialized, and reset whenever `misc_generic_selectors` changes.
+    #[serde(skip)]
+    pub(crate) generic_stylesheet: OnceCell<String>,
+    /// The element tags targeted by each hide rule, for `hides_elements_with_tags`. Built the
+    /// first time it's needed. Not serialized, and reset whenever any hide rule is added or
+    /// removed.
+    #[serde(skip)]
+    pub(crate) tag_index: OnceCell<TagIndex>,
 
     pub(crate) scriptlets: ScriptletResourceStorage,
 }
@@ -136,6 +179,8 @@ impl CosmeticFilterCache {
             specific_rules: HostnameRuleDb::new(),
 
             misc_generic_selectors: HashSet::new(),
+            generic_stylesheet: OnceCell::new(),
+            tag_index: OnceCell::new(),
 
             scriptlets: Default::default(),
         }
@@ -151,6 +196,8 @@ impl CosmeticFilterCache {
             specific_rules: HostnameRuleDb::new(),
 
             misc_generic_selectors: HashSet::with_capacity(rules.len() / 30),
+            generic_stylesheet: OnceCell::new(),
+            tag_index: OnceCell::new(),
 
             scriptlets: Default::default(),
         };
@@ -168,6 +215,7 @@ impl CosmeticFilterCache {
                 self.add_generic_filter(generic_rule);
             }
             self.specific_rules.store_rule(rule);
+            self.tag_index = OnceCell::new();
         } else {
             self.add_generic_filter(rule);
         }
@@ -203,9 +251,28 @@ impl CosmeticFilterCache {
             }
         } else {
             self.misc_generic_selectors.insert(rule.selector);
+            self.generic_stylesheet = OnceCell::new();
+            self.tag_index = OnceCell::new();
         }
     }
 
+    /// Returns a stylesheet hiding every generic selector that can't be handled by
+    /// `hidden_class_id_selectors`, i.e. the generic rules that apply to every page. This is the
+    /// same for all pages, so it only needs to be fetched once and can be injected into any page
+    /// whose `UrlSpecificResources` has `apply_generic_stylesheet` set.
+    ///
+    /// Each selector is given its own rule, so that one selector that isn't supported by the
+    /// browser can't invalidate the others.
+    pub fn generic_cosmetic_stylesheet(&self) -> &str {
+        self.generic_stylesheet.get_or_init(|| {
+            let mut selectors = self.misc_generic_selectors.iter().collect::<Vec<_>>();
+            selectors.sort();
+            selectors.into_iter()
+                .map(|selector| format!("{}{{display:none !important}}\n", selector))
+                .collect()
+        })
+    }
+
     /// Generic class/id rules are by far the most common type of cosmetic filtering rule, and they
     /// apply to all sites. Rather than injecting all of these rules onto every page, which would
     /// blow up memory usage, we only inject rules based on classes and ids that actually appear on
@@ -269,6 +336,173 @@ impl CosmeticFilterCache {
     /// hostname or set of hostnames (like `example.com##.a-class`). The first category is always
     /// injected into every page, and makes up a relatively small number of rules in practice.
     pub fn hostname_cosmetic_resources(&self, hostname: &str, generichide: bool) -> UrlSpecificResources {
+        let (rules_that_apply, exceptions) = self.hostname_specific_rules_for(hostname);
+
+        let (hostname_hide_selectors, style_selectors, script_injections, shadow_hide_selectors) = hostname_specific_rules(&rules_that_apply[..]);
+
+        // Generic selectors are only included individually if some of them are excepted on this
+        // page, since the generic stylesheet can't be partially applied.
+        let apply_generic_stylesheet = !generichide
+            && !self.misc_generic_selectors.is_empty()
+            && exceptions.hide_exceptions.iter().all(|sel| !self.misc_generic_selectors.contains(sel));
+        let hide_selectors = if generichide || apply_generic_stylesheet {
+            hostname_hide_selectors
+        } else {
+            let mut hide_selectors = self.misc_generic_selectors.difference(&exceptions.hide_exceptions).cloned().collect::<HashSet<_>>();
+            hostname_hide_selectors.into_iter().for_each(|sel| { hide_selectors.insert(sel); });
+            hide_selectors
+        };
+
+        let mut injected_script = String::new();
+        script_injections.iter().for_each(|s| {
+            if let Ok(filled_template) = self.scriptlets.get_scriptlet(&s) {
+                injected_script += &filled_template;
+                injected_script += "\n";
+            }
+        });
+
+        UrlSpecificResources {
+            hide_selectors,
+            style_selectors,
+            shadow_hide_selectors,
+            exceptions: exceptions.hide_exceptions,
+            injected_script,
+            generichide,
+            apply_generic_stylesheet,
+        }
+    }
+
+    /// Like `hostname_cosmetic_resources` with `generichide` unset, but for several hostnames at
+    /// once, such as those of the frames embedded in a page. Each distinct hostname is only
+    /// queried once. Generic selectors are shared by all hosts through
+    /// `generic_cosmetic_stylesheet`, rather than being repeated in each host's `hide_selectors`,
+    /// unless some of them are excepted on that particular host.
+    pub fn resources_for_hosts(&self, hostnames: &[&str]) -> HashMap<String, UrlSpecificResources> {
+        let mut resources = HashMap::with_capacity(hostnames.len());
+        for hostname in hostnames {
+            if !resources.contains_key(*hostname) {
+                resources.insert(hostname.to_string(), self.hostname_cosmetic_resources(hostname, false));
+            }
+        }
+        resources
+    }
+
+    /// Returns every scriptlet that would be injected into pages on `hostname`, with arguments
+    /// substituted, in the same order as in `hostname_cosmetic_resources`'s `injected_script`.
+    /// Scriptlets which aren't present in the resource library are omitted.
+    pub fn scriptlets_for_host(&self, hostname: &str) -> Vec<ResolvedScriptlet> {
+        let (rules_that_apply, _) = self.hostname_specific_rules_for(hostname);
+        let (_, _, script_injections, _) = hostname_specific_rules(&rules_that_apply[..]);
+
+        script_injections.iter()
+            .filter_map(|s| self.scriptlets.resolve_scriptlet(s).ok())
+            .collect()
+    }
+
+    /// Like `scriptlets_for_host`, but returns the name and arguments of each scriptlet rather
+    /// than its code, for clients which provide their own scriptlet implementations. Scriptlets
+    /// are included whether or not they are present in the resource library.
+    pub fn scriptlet_calls_for_host(&self, hostname: &str) -> Vec<ScriptletCall> {
+        let (rules_that_apply, _) = self.hostname_specific_rules_for(hostname);
+        let (_, _, script_injections, _) = hostname_specific_rules(&rules_that_apply[..]);
+
+        script_injections.iter()
+            .filter_map(|s| ScriptletCall::parse(s))
+            .collect()
+    }
+
+    /// Returns true if any hide rule applying to pages on `hostname` targets elements with one of
+    /// the given tag names, ignoring case. Generic rules keyed on a class or id aren't considered,
+    /// since whether they apply depends on the contents of the page.
+    pub fn hides_elements_with_tags(&self, hostname: &str, generichide: bool, tags: &[&str]) -> bool {
+        let index = self.tag_index.get_or_init(|| TagIndex::new(self));
+        if index.is_empty() {
+            return false;
+        }
+        let targets_tags = |selector_tags: &Vec<String>| {
+            selector_tags.iter().any(|selector_tag| tags.iter().any(|tag| tag.eq_ignore_ascii_case(selector_tag)))
+        };
+
+        let (rules_that_apply, exceptions) = self.hostname_specific_rules_for(hostname);
+
+        let specific_match = rules_that_apply.iter().any(|rule| match rule {
+            SpecificFilterType::Hide(sel) => index.specific.get(sel).into_iter().any(targets_tags),
+            _ => false,
+        });
+
+        specific_match || (!generichide && index.generic.iter()
+            .any(|(sel, selector_tags)| !exceptions.hide_exceptions.contains(sel) && targets_tags(selector_tags)))
+    }
+
+    /// Reports whether `selector` would be hidden on pages on `hostname`, and if so, by which kind
+    /// of rule. Hostname-specific rules take precedence over entity rules, which take precedence
+    /// over generic rules. Returns `None` if no rule hides the selector or if it's excepted on
+    /// `hostname`, e.g. by `example.com#@#.ad`.
+    ///
+    /// `$generichide` exceptions are network filters, so they aren't considered here.
+    pub fn would_hide(&self, hostname: &str, selector: &str) -> Option<HideSource> {
+        let (_, exceptions) = self.hostname_specific_rules_for(hostname);
+        if exceptions.hide_exceptions.contains(selector) {
+            return None;
+        }
+
+        let domain_str = {
+            let (start, end) = crate::url_parser::get_host_domain(hostname);
+            &hostname[start..end]
+        };
+        let (request_entities, request_hostnames) = hostname_domain_hashes(hostname, domain_str);
+        let hidden_by = |hashes: &[Hash]| hashes.iter()
+            .filter_map(|hash| self.specific_rules.retrieve(hash))
+            .flatten()
+            .any(|rule| matches!(rule, SpecificFilterType::Hide(sel) if sel == selector));
+        if hidden_by(&request_hostnames) {
+            return Some(HideSource::Specific);
+        }
+        if hidden_by(&request_entities) {
+            return Some(HideSource::Entity);
+        }
+
+        let generic = self.misc_generic_selectors.contains(selector)
+            || selector.strip_prefix('.').map(|class| self.simple_class_rules.contains(class)).unwrap_or(false)
+            || selector.strip_prefix('#').map(|id| self.simple_id_rules.contains(id)).unwrap_or(false)
+            || self.complex_class_rules.values()
+                .chain(self.complex_id_rules.values())
+                .any(|bucket| bucket.iter().any(|sel| sel == selector));
+        if generic {
+            Some(HideSource::Generic)
+        } else {
+            None
+        }
+    }
+
+    /// Returns every class and id referenced by the hostname-specific hide and style rules that
+    /// apply to `hostname`, such as `.ad` or `#promo`, so that a client only needs to observe
+    /// those tokens on the page. Generic rules aren't included, since `hidden_class_id_selectors`
+    /// already handles them using whichever classes and ids are found.
+    pub fn relevant_tokens(&self, hostname: &str) -> HashSet<String> {
+        let (rules_that_apply, _) = self.hostname_specific_rules_for(hostname);
+
+        let mut tokens = HashSet::new();
+        for rule in rules_that_apply {
+            match rule {
+                SpecificFilterType::Hide(sel) | SpecificFilterType::ShadowHide(sel) | SpecificFilterType::Style(sel, _) => {
+                    insert_class_id_tokens(sel, &mut tokens);
+                }
+                _ => (),
+            }
+        }
+        tokens
+    }
+
+    /// Returns the number of hostname-specific hide, style, and scriptlet rules that apply to
+    /// `hostname`, after accounting for any exceptions.
+    pub fn hostname_specific_rule_count(&self, hostname: &str) -> usize {
+        self.hostname_specific_rules_for(hostname).0.len()
+    }
+
+    /// Retrieves all of the hostname-specific rules that apply to `hostname`, with any excepted
+    /// rules removed, along with the set of exceptions that were found.
+    fn hostname_specific_rules_for(&self, hostname: &str) -> (Vec<&SpecificFilterType>, HostnameExceptionsBuilder) {
         let domain_str = {
             let (start, end) = crate::url_parser::get_host_domain(hostname);
             &hostname[start..end]
@@ -289,35 +523,85 @@ impl CosmeticFilterCache {
             exceptions.insert_if_exception(r);
         });
 
-        let rules_that_apply = rules_that_apply.iter().map(|r| r.to_owned()).filter(|r| {
+        let rules_that_apply = rules_that_apply.into_iter().filter(|r| {
             exceptions.allow_specific_rule(r)
         }).collect::<Vec<_>>();
 
-        let (hostname_hide_selectors, style_selectors, script_injections) = hostname_specific_rules(&rules_that_apply[..]);
+        (rules_that_apply, exceptions)
+    }
 
-        let hide_selectors = if generichide {
-            hostname_hide_selectors
-        } else {
-            let mut hide_selectors = self.misc_generic_selectors.difference(&exceptions.hide_exceptions).cloned().collect::<HashSet<_>>();
-            hostname_hide_selectors.into_iter().for_each(|sel| { hide_selectors.insert(sel); });
-            hide_selectors
-        };
+    /// Returns the number of generic CSS selectors currently stored, across all of the generic
+    /// rule buckets.
+    pub fn generic_selector_count(&self) -> usize {
+        self.simple_class_rules.len()
+            + self.simple_id_rules.len()
+            + self.complex_class_rules.values().map(|bucket| bucket.len()).sum::<usize>()
+            + self.complex_id_rules.values().map(|bucket| bucket.len()).sum::<usize>()
+            + self.misc_generic_selectors.len()
+    }
 
-        let mut injected_script = String::new();
-        script_injections.iter().for_each(|s| {
-            if let Ok(filled_template) = self.scriptlets.get_scriptlet(&s) {
-                injected_script += &filled_template;
-                injected_script += "\n";
+    /// Drops generic rules until no more than `limit` generic selectors remain, returning the
+    /// number of selectors that were dropped. Hostname-specific rules are never affected.
+    ///
+    /// Rules are dropped starting with those that cost the most to keep for the least benefit:
+    /// first the miscellaneous generic selectors (which are injected into every page), then
+    /// complex class and id selectors, and finally simple class and id selectors. Within each
+    /// category, longer (i.e. more specific) selectors are dropped first.
+    pub fn limit_generic_rules(&mut self, limit: usize) -> usize {
+        /// Orders selectors so that the ones that should be dropped first come first. Ties are
+        /// broken lexicographically to keep the result deterministic.
+        fn drop_order(a: &str, b: &str) -> std::cmp::Ordering {
+            b.len().cmp(&a.len()).then_with(|| a.cmp(b))
+        }
+
+        let count = self.generic_selector_count();
+        if count <= limit {
+            return 0;
+        }
+        let mut remaining = count - limit;
+
+        let mut misc = self.misc_generic_selectors.iter().cloned().collect::<Vec<_>>();
+        misc.sort_by(|a, b| drop_order(a, b));
+        for selector in misc.into_iter().take(remaining) {
+            self.misc_generic_selectors.remove(&selector);
+            self.generic_stylesheet = OnceCell::new();
+            self.tag_index = OnceCell::new();
+            remaining -= 1;
+        }
+
+        let mut complex = self.complex_class_rules.iter().map(|(k, bucket)| (true, k, bucket))
+            .chain(self.complex_id_rules.iter().map(|(k, bucket)| (false, k, bucket)))
+            .flat_map(|(is_class, k, bucket)| bucket.iter().map(move |sel| (is_class, k.clone(), sel.clone())))
+            .collect::<Vec<_>>();
+        complex.sort_by(|a, b| drop_order(&a.2, &b.2));
+        for (is_class, key, selector) in complex.into_iter().take(remaining) {
+            let rules = if is_class { &mut self.complex_class_rules } else { &mut self.complex_id_rules };
+            // Buckets can hold the same selector more than once, and each copy counts towards the
+            // total, so only one copy is dropped per entry.
+            if let Some(bucket) = rules.get_mut(&key) {
+                if let Some(index) = bucket.iter().position(|sel| sel == &selector) {
+                    bucket.remove(index);
+                    remaining -= 1;
+                }
+                if bucket.is_empty() {
+                    rules.remove(&key);
+                }
             }
-        });
+        }
 
-        UrlSpecificResources {
-            hide_selectors,
-            style_selectors,
-            exceptions: exceptions.hide_exceptions,
-            injected_script,
-            generichide,
+        let mut simple = self.simple_class_rules.iter().map(|class| (true, class.clone()))
+            .chain(self.simple_id_rules.iter().map(|id| (false, id.clone())))
+            .collect::<Vec<_>>();
+        simple.sort_by(|a, b| drop_order(&a.1, &b.1));
+        for (is_class, key) in simple.into_iter().take(remaining) {
+            if is_class {
+                self.simple_class_rules.remove(&key);
+            } else {
+                self.simple_id_rules.remove(&key);
+            }
         }
+
+        count - self.generic_selector_count()
     }
 
     /// Sets the internal resources to be those provided, silently discarding errors.
@@ -354,7 +638,7 @@ impl HostnameExceptionsBuilder {
         use SpecificFilterType as Rule;
 
         match rule {
-            Rule::Hide(_) | Rule::Style(_, _) | Rule::ScriptInject(_) => (),
+            Rule::Hide(_) | Rule::Style(_, _) | Rule::ScriptInject(_) | Rule::ShadowHide(_) => (),
             Rule::Unhide(sel) => {
                 self.hide_exceptions.insert(sel.clone());
             }
@@ -377,7 +661,7 @@ impl HostnameExceptionsBuilder {
     /// doesn't have a corresponding exception rule added previously.
     pub fn allow_specific_rule(&self, rule: &SpecificFilterType) -> bool {
         match rule {
-            SpecificFilterType::Hide(sel) => !self.hide_exceptions.contains(sel),
+            SpecificFilterType::Hide(sel) | SpecificFilterType::ShadowHide(sel) => !self.hide_exceptions.contains(sel),
             SpecificFilterType::Style(sel, style) => !self.style_exceptions.contains(&(sel.to_string(), style.to_string())),
             SpecificFilterType::ScriptInject(sel) => !self.script_inject_exceptions.contains(sel),
             _ => false,
@@ -447,6 +731,15 @@ impl HostnameRuleDb {
             None
         }
     }
+
+    /// Returns the selector of every stored hide rule, for any hostname. Selectors stored for
+    /// several hostnames are repeated.
+    fn hide_selectors(&self) -> impl Iterator<Item = &String> {
+        self.db.values().flatten().filter_map(|rule| match rule {
+            SpecificFilterType::Hide(sel) => Some(sel),
+            _ => None,
+        })
+    }
 }
 
 /// Each variant describes a single rule that is specific to a particular hostname.
@@ -487,6 +780,12 @@ pub enum SpecificFilterType {
     ///
     /// In practice, these rules are extremely rare in filter lists.
     UnhideScriptInject(String),
+
+    /// A hostname-specific hide rule using the shadow-piercing `>>>` combinator, e.g.
+    /// `example.com##my-widget >>> .ad`. Exceptions for these are represented by `Unhide`.
+    ///
+    /// The parameter is the rule's CSS selector.
+    ShadowHide(String),
 }
 
 /// This implementation assumes the given rule has hostname or entity constraints, and that the
@@ -510,6 +809,8 @@ impl From<&CosmeticFilter> for SpecificFilterType {
         } else {
             if unhide {
                 SpecificFilterType::Unhide(rule.selector.clone())
+            } else if rule.mask.contains(CosmeticFilterMask::SHADOW_PIERCING) {
+                SpecificFilterType::ShadowHide(rule.selector.clone())
             } else {
                 SpecificFilterType::Hide(rule.selector.clone())
             }
@@ -526,11 +827,125 @@ impl SpecificFilterType {
             SpecificFilterType::UnhideStyle(sel, style) => SpecificFilterType::Style(sel, style),
             SpecificFilterType::ScriptInject(script) => SpecificFilterType::UnhideScriptInject(script),
             SpecificFilterType::UnhideScriptInject(script) => SpecificFilterType::ScriptInject(script),
+            SpecificFilterType::ShadowHide(sel) => SpecificFilterType::Unhide(sel),
 
         }
     }
 }
 
+/// Adds every class and id selector in `selector` to `tokens`, including their leading `.` or
+/// `#`. Attribute selectors, quoted strings, and the arguments of pseudo-classes which don't take
+/// selectors, like `:has-text(...)`, are skipped.
+fn insert_class_id_tokens(selector: &str, tokens: &mut HashSet<String>) {
+    const SELECTOR_PSEUDO_CLASSES: [&str; 5] = [":not", ":has", ":is", ":where", ":upward"];
+
+    let mut chars = selector.char_indices().peekable();
+    // Whether each enclosing bracket or parenthesis contains a selector
+    let mut nesting: Vec<bool> = vec![];
+    let mut quote = None;
+    while let Some((i, c)) = chars.next() {
+        let in_selector = nesting.iter().all(|contains_selector| *contains_selector);
+        match c {
+            '\\' => { chars.next(); }
+            '"' | '\'' if quote == Some(c) => quote = None,
+            _ if quote.is_some() => (),
+            '"' | '\'' => quote = Some(c),
+            '[' => nesting.push(false),
+            '(' => nesting.push(SELECTOR_PSEUDO_CLASSES.iter().any(|pseudo| selector[..i].ends_with(pseudo))),
+            ']' | ')' => { nesting.pop(); }
+            '.' | '#' if in_selector => {
+                let start = i;
+                let mut end = i + 1;
+                while let Some((j, next)) = chars.peek().copied() {
+                    if next == '\\' {
+                        chars.next();
+                        end = chars.next().map(|(k, escaped)| k + escaped.len_utf8()).unwrap_or(j + 1);
+                    } else if next.is_alphanumeric() || next == '-' || next == '_' || !next.is_ascii() {
+                        chars.next();
+                        end = j + next.len_utf8();
+                    } else {
+                        break;
+                    }
+                }
+                if end > start + 1 {
+                    tokens.insert(selector[start..end].to_owned());
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// The element tags targeted by hide rules, indexed by selector. Only selectors which target at
+/// least one tag are included.
+#[derive(Default)]
+pub(crate) struct TagIndex {
+    /// Tags targeted by each of `CosmeticFilterCache::misc_generic_selectors`.
+    generic: HashMap<String, Vec<String>>,
+    /// Tags targeted by each selector of a hostname-specific hide rule.
+    specific: HashMap<String, Vec<String>>,
+}
+
+impl TagIndex {
+    fn new(cache: &CosmeticFilterCache) -> Self {
+        fn index_selectors<'a>(selectors: impl Iterator<Item = &'a String>) -> HashMap<String, Vec<String>> {
+            selectors
+                .filter_map(|sel| {
+                    let tags = targeted_tags(sel);
+                    if tags.is_empty() { None } else { Some((sel.clone(), tags)) }
+                })
+                .collect()
+        }
+
+        TagIndex {
+            generic: index_selectors(cache.misc_generic_selectors.iter()),
+            specific: index_selectors(cache.specific_rules.hide_selectors()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.generic.is_empty() && self.specific.is_empty()
+    }
+}
+
+/// Returns the lowercased tag names of the rightmost compound selectors in the comma-separated
+/// `selector` list, i.e. the tags of the elements that it would match. Compound selectors without
+/// a tag name, like `.ad`, contribute nothing.
+fn targeted_tags(selector: &str) -> Vec<String> {
+    let mut tags = vec![];
+    let mut add_compound_tag = |compound: &str| {
+        let tag_end = compound.find(|c: char| !c.is_ascii_alphanumeric() && c != '-').unwrap_or(compound.len());
+        if tag_end > 0 {
+            tags.push(compound[..tag_end].to_ascii_lowercase());
+        }
+    };
+
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut last_compound = "";
+    for (i, c) in selector.char_indices() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth = depth.saturating_sub(1),
+            c if depth == 0 && (c.is_whitespace() || ",>+~".contains(c)) => {
+                let compound = selector[start..i].trim();
+                if !compound.is_empty() {
+                    last_compound = compound;
+                }
+                if c == ',' {
+                    add_compound_tag(last_compound);
+                    last_compound = "";
+                }
+                start = i + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    let compound = selector[start..].trim();
+    add_compound_tag(if compound.is_empty() { last_compound } else { compound });
+    tags
+}
+
 fn hostname_domain_hashes(hostname: &str, domain: &str) -> (Vec<Hash>, Vec<Hash>) {
     let request_entities = crate::filters::cosmetic::get_entity_hashes_from_labels(hostname, domain);
     let request_hostnames = crate::filters::cosmetic::get_hostname_hashes_from_labels(hostname, domain);
@@ -591,6 +1006,27 @@ mod cosmetic_cache_tests {
         assert_eq!(out, expected);
     }
 
+    #[test]
+    fn shadow_piercing() {
+        let cfcache = cache_from_rules(vec![
+            "example.com##.ad",
+            "example.com##my-widget >>> .ad",
+            "example.com,~sub.example.com##my-player >>> .overlay",
+        ]);
+
+        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".ad".to_owned());
+        expected.shadow_hide_selectors.insert("my-widget >>> .ad".to_owned());
+        expected.shadow_hide_selectors.insert("my-player >>> .overlay".to_owned());
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        expected.shadow_hide_selectors.remove("my-player >>> .overlay");
+        expected.exceptions.insert("my-player >>> .overlay".to_owned());
+        assert_eq!(out, expected);
+    }
+
     #[test]
     fn style_exceptions() {
         let cfcache = cache_from_rules(vec![
@@ -683,6 +1119,58 @@ mod cosmetic_cache_tests {
         assert_eq!(out, expected);
     }
 
+    #[test]
+    fn scriptlets_for_host() {
+        use crate::resources::{ResourceType, MimeType};
+
+        let mut cfcache = cache_from_rules(vec![
+            "example.com##+js(set-constant, atob, trueFunc)",
+            "example.com##+js(nowebrtc.js)",
+            "example.com##+js(missing.js)",
+            "sub.example.com#@#+js(nowebrtc.js)",
+        ]);
+
+        cfcache.use_resources(&[
+            Resource {
+                name: "set-constant.js".into(),
+                aliases: vec![],
+                kind: ResourceType::Template,
+                content: base64::encode("set({{1}}, {{2}})"),
+            },
+            Resource {
+                name: "nowebrtc.js".into(),
+                aliases: vec![],
+                kind: ResourceType::Mime(
+                    MimeType::ApplicationJavascript,
+                ),
+                content: base64::encode("nowebrtc()"),
+            },
+        ]);
+
+        let expected = vec![
+            ResolvedScriptlet { name: "set-constant".to_owned(), content: "set(atob, trueFunc)".to_owned() },
+            ResolvedScriptlet { name: "nowebrtc".to_owned(), content: "nowebrtc()".to_owned() },
+        ];
+        for _ in 0..3 {
+            assert_eq!(cfcache.scriptlets_for_host("example.com"), expected);
+        }
+        assert_eq!(cfcache.scriptlets_for_host("sub.example.com"), expected[..1].to_vec());
+        assert_eq!(cfcache.scriptlets_for_host("other.com"), vec![]);
+
+        // Scriptlets missing from the resource library are still listed by name
+        let call = |name: &str, args: &[&str]| ScriptletCall { name: name.to_owned(), args: args.iter().map(|a| a.to_string()).collect() };
+        assert_eq!(cfcache.scriptlet_calls_for_host("example.com"), vec![
+            call("set-constant", &["atob", "trueFunc"]),
+            call("nowebrtc", &[]),
+            call("missing", &[]),
+        ]);
+        assert_eq!(cfcache.scriptlet_calls_for_host("sub.example.com"), vec![
+            call("set-constant", &["atob", "trueFunc"]),
+            call("missing", &[]),
+        ]);
+        assert_eq!(cfcache.scriptlet_calls_for_host("other.com"), vec![]);
+    }
+
     #[test]
     fn matching_hidden_class_id_selectors() {
         let rules = [
@@ -765,17 +1253,219 @@ mod cosmetic_cache_tests {
         ];
         let cfcache = CosmeticFilterCache::from_rules(rules.iter().map(|r| CosmeticFilter::parse(r, false).unwrap()).collect::<Vec<_>>());
 
-        let hide_selectors = cfcache.hostname_cosmetic_resources("test.com", false).hide_selectors;
+        // Pages without exceptions use the generic stylesheet instead
+        let resources = cfcache.hostname_cosmetic_resources("test.com", false);
+        assert_eq!(resources.hide_selectors, HashSet::new());
+        assert_eq!(resources.apply_generic_stylesheet, true);
+        assert_eq!(
+            cfcache.generic_cosmetic_stylesheet(),
+            "a[href=\"bad.com\"]{display:none !important}\n\
+            a[href=\"notbad.com\"]{display:none !important}\n\
+            div > p{display:none !important}\n",
+        );
+
+        let resources = cfcache.hostname_cosmetic_resources("example.com", false);
         let mut expected_hides = HashSet::new();
         expected_hides.insert("a[href=\"bad.com\"]".to_owned());
-        expected_hides.insert("div > p".to_owned());
-        expected_hides.insert("a[href=\"notbad.com\"]".to_owned());
-        assert_eq!(hide_selectors, expected_hides);
+        assert_eq!(resources.hide_selectors, expected_hides);
+        assert_eq!(resources.apply_generic_stylesheet, false);
+    }
 
-        let hide_selectors = cfcache.hostname_cosmetic_resources("example.com", false).hide_selectors;
-        let mut expected_hides = HashSet::new();
-        expected_hides.insert("a[href=\"bad.com\"]".to_owned());
-        assert_eq!(hide_selectors, expected_hides);
+    #[test]
+    fn generic_cosmetic_stylesheet() {
+        let mut cfcache = cache_from_rules(vec![
+            "##div[id^=\"ad-\"]",
+            "##a[href=\"bad.com\"]",
+            "##.ad",
+            "example.com##.specific",
+        ]);
+
+        let stylesheet = cfcache.generic_cosmetic_stylesheet().to_owned();
+        assert_eq!(stylesheet, "a[href=\"bad.com\"]{display:none !important}\ndiv[id^=\"ad-\"]{display:none !important}\n");
+        assert_eq!(cfcache.generic_cosmetic_stylesheet(), stylesheet);
+        assert_eq!(cache_from_rules(vec!["##a[href=\"bad.com\"]", "##div[id^=\"ad-\"]"]).generic_cosmetic_stylesheet(), stylesheet);
+
+        // Hostname queries only include hostname-specific selectors
+        let resources = cfcache.hostname_cosmetic_resources("example.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".specific".to_owned());
+        expected.apply_generic_stylesheet = true;
+        assert_eq!(resources, expected);
+
+        cfcache.add_filter(CosmeticFilter::parse("##span[data-ad]", false).unwrap());
+        assert_eq!(cfcache.generic_cosmetic_stylesheet().lines().count(), 3);
+        cfcache.limit_generic_rules(1);
+        assert_eq!(cfcache.generic_cosmetic_stylesheet(), "");
+        assert_eq!(cfcache.hostname_cosmetic_resources("example.com", false).apply_generic_stylesheet, false);
+    }
+
+    #[test]
+    fn hides_elements_with_tags() {
+        let cfcache = cache_from_rules(vec![
+            "##VIDEO.ad",
+            "example.com##.banner img[alt=\"a > b\"]",
+            "example.com##div.ad, span + iframe",
+            "other.com##.imgs",
+            "other.com##img-like",
+        ]);
+
+        assert_eq!(cfcache.hides_elements_with_tags("example.com", false, &["img"]), true);
+        assert_eq!(cfcache.hides_elements_with_tags("example.com", false, &["frame", "iframe"]), true);
+        assert_eq!(cfcache.hides_elements_with_tags("example.com", false, &["span"]), false);
+        assert_eq!(cfcache.hides_elements_with_tags("other.com", false, &["img"]), false);
+        // Generic rules apply unless `$generichide` is present
+        assert_eq!(cfcache.hides_elements_with_tags("other.com", false, &["video"]), true);
+        assert_eq!(cfcache.hides_elements_with_tags("other.com", true, &["video"]), false);
+
+        // Excepted rules don't count, and the index is rebuilt as rules are added
+        let mut cfcache = cfcache;
+        cfcache.add_filter(CosmeticFilter::parse("other.com#@#VIDEO.ad", false).unwrap());
+        cfcache.add_filter(CosmeticFilter::parse("example.com#@#div.ad, span + iframe", false).unwrap());
+        cfcache.add_filter(CosmeticFilter::parse("other.com##picture", false).unwrap());
+        assert_eq!(cfcache.hides_elements_with_tags("other.com", false, &["video"]), false);
+        assert_eq!(cfcache.hides_elements_with_tags("example.com", false, &["video"]), true);
+        assert_eq!(cfcache.hides_elements_with_tags("example.com", false, &["iframe"]), false);
+        assert_eq!(cfcache.hides_elements_with_tags("other.com", false, &["picture"]), true);
+    }
+
+    #[test]
+    fn would_hide() {
+        let cfcache = cache_from_rules(vec![
+            "##.ad",
+            "###banner",
+            "##.promo > div",
+            "##a[href^=\"https://ads.com\"]",
+            "example.com##.sidebar",
+            "example.*##.entity-ad",
+            "example.com,example.*##.both",
+            "sub.example.com#@#.ad",
+            "~other.example.com##.not-other",
+        ]);
+
+        assert_eq!(cfcache.would_hide("example.com", ".ad"), Some(HideSource::Generic));
+        assert_eq!(cfcache.would_hide("example.com", "#banner"), Some(HideSource::Generic));
+        assert_eq!(cfcache.would_hide("example.com", ".promo > div"), Some(HideSource::Generic));
+        assert_eq!(cfcache.would_hide("example.com", "a[href^=\"https://ads.com\"]"), Some(HideSource::Generic));
+        assert_eq!(cfcache.would_hide("www.example.com", ".sidebar"), Some(HideSource::Specific));
+        assert_eq!(cfcache.would_hide("example.co.uk", ".entity-ad"), Some(HideSource::Entity));
+        assert_eq!(cfcache.would_hide("example.com", ".both"), Some(HideSource::Specific));
+        assert_eq!(cfcache.would_hide("example.net", ".both"), Some(HideSource::Entity));
+        assert_eq!(cfcache.would_hide("example.com", ".not-other"), Some(HideSource::Generic));
+
+        // Not hidden, or excepted on this hostname
+        assert_eq!(cfcache.would_hide("example.com", ".content"), None);
+        assert_eq!(cfcache.would_hide("other.com", ".sidebar"), None);
+        assert_eq!(cfcache.would_hide("sub.example.com", ".ad"), None);
+        assert_eq!(cfcache.would_hide("other.example.com", ".not-other"), None);
+    }
+
+    #[test]
+    fn resources_for_hosts() {
+        let cfcache = cache_from_rules(vec![
+            "##div[id^=\"ad-\"]",
+            "##a[href=\"bad.com\"]",
+            "example.com##.specific",
+            "ads.net##.frame-ad",
+            "widget.org#@#a[href=\"bad.com\"]",
+            "widget.org##+js(set-constant.js, a, 1)",
+        ]);
+        let hostnames = ["example.com", "ads.net", "widget.org", "ads.net", "unknown.com"];
+
+        let resources = cfcache.resources_for_hosts(&hostnames);
+        assert_eq!(resources.len(), 4);
+        for hostname in hostnames.iter() {
+            assert_eq!(resources[*hostname], cfcache.hostname_cosmetic_resources(hostname, false), "{}", hostname);
+        }
+
+        // Generic selectors are only included for the host which excepts one of them
+        for hostname in ["example.com", "ads.net", "unknown.com"].iter() {
+            assert_eq!(resources[*hostname].apply_generic_stylesheet, true);
+            assert_eq!(resources[*hostname].hide_selectors.contains("div[id^=\"ad-\"]"), false);
+        }
+        let mut expected = HashSet::new();
+        expected.insert("div[id^=\"ad-\"]".to_owned());
+        assert_eq!(resources["widget.org"].hide_selectors, expected);
+        assert_eq!(resources["widget.org"].apply_generic_stylesheet, false);
+    }
+
+    #[test]
+    fn relevant_tokens() {
+        let cfcache = cache_from_rules(vec![
+            "##.generic",
+            "example.com##.ad",
+            "example.com###promo > a[href*=\".com#x\"]",
+            "example.com##.ad:not(.keep)",
+            "example.com##.banner:style(height: 0)",
+            "example.com#@#.banner:style(height: 0)",
+            "example.com##+js(set-constant.js, a.b, 1)",
+            "other.com##.elsewhere",
+        ]);
+
+        let mut expected = HashSet::new();
+        expected.insert(".ad".to_owned());
+        expected.insert("#promo".to_owned());
+        expected.insert(".keep".to_owned());
+        assert_eq!(cfcache.relevant_tokens("example.com"), expected);
+        assert_eq!(cfcache.relevant_tokens("unknown.com"), HashSet::new());
+
+        let mut tokens = HashSet::new();
+        insert_class_id_tokens(r#"#a\.b .x-1_y[data-x=".z"] :is(#q,.r) :has-text(v1.2)"#, &mut tokens);
+        let mut expected = HashSet::new();
+        for token in [r#"#a\.b"#, ".x-1_y", "#q", ".r"].iter() {
+            expected.insert(token.to_string());
+        }
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    fn limit_generic_rules() {
+        let mut cfcache = cache_from_rules(vec![
+            "##.ad",
+            "###banner",
+            "##.ad > .sponsored-content",
+            "###banner .text",
+            "##div[class^=\"ad-\"]",
+            "##a[href=\"bad.com\"]",
+            "example.com##.specific",
+            "example.com##.specific-too",
+        ]);
+        assert_eq!(cfcache.generic_selector_count(), 6);
+
+        assert_eq!(cfcache.limit_generic_rules(10), 0);
+        assert_eq!(cfcache.generic_selector_count(), 6);
+
+        assert_eq!(cfcache.limit_generic_rules(3), 3);
+        assert_eq!(cfcache.generic_selector_count(), 3);
+        assert!(cfcache.misc_generic_selectors.is_empty());
+        assert!(cfcache.complex_class_rules.is_empty());
+        assert_eq!(cfcache.complex_id_rules.get("banner"), Some(&vec!["#banner .text".to_string()]));
+
+        assert_eq!(cfcache.limit_generic_rules(0), 3);
+        assert_eq!(cfcache.generic_selector_count(), 0);
+
+        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".specific".to_owned());
+        expected.hide_selectors.insert(".specific-too".to_owned());
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn limit_generic_rules_duplicates() {
+        let mut cfcache = cache_from_rules(vec![
+            "##.ad > .sponsored-content",
+            "##.ad > .sponsored-content",
+            "##.ad > .promoted",
+        ]);
+        assert_eq!(cfcache.generic_selector_count(), 3);
+
+        // Each copy of a duplicated selector is counted separately
+        assert_eq!(cfcache.limit_generic_rules(2), 1);
+        assert_eq!(cfcache.generic_selector_count(), 2);
+        assert_eq!(cfcache.complex_class_rules.get("ad"), Some(&vec![".ad > .sponsored-content".to_string(), ".ad > .promoted".to_string()]));
+
+        assert_eq!(cfcache.limit_generic_rules(1), 1);
+        assert_eq!(cfcache.complex_class_rules.get("ad"), Some(&vec![".ad > .promoted".to_string()]));
     }
 
     #[test]
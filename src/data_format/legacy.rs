@@ -195,7 +195,10 @@ impl From<NetworkFilterLegacyDeserializeFmt> for NetworkFilter {
             id: v.id,
             opt_domains_union: v.opt_domains_union,
             opt_not_domains_union: v.opt_not_domains_union,
+            separator_mode: Default::default(),
+            removeparam: None,
             regex: std::sync::Arc::new(std::sync::RwLock::new(None)),
+            removeparam_regex: std::sync::Arc::new(std::sync::RwLock::new(None)),
         }
     }
 }
@@ -336,15 +339,19 @@ impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
             filters: v.part1.filters.into(),
 
             tags_enabled: Default::default(),
+            disabled_filters: Default::default(),
+            badfilter_ids: Default::default(),
             tagged_filters_all: v.part1.tagged_filters_all.into_iter().map(|f| f.into()).collect(),
 
             enable_optimizations: v.part1.enable_optimizations,
+            loopback_exempt: false,
 
             resources: v.part1.resources,
             #[cfg(feature = "object-pooling")]
             pool: Default::default(),
 
             generic_hide: v.rest.generic_hide.into(),
+            removeparams: Default::default(),
         }, CosmeticFilterCache {
             simple_class_rules: v.rest.simple_class_rules,
             simple_id_rules: v.rest.simple_id_rules,
@@ -354,6 +361,8 @@ impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
             specific_rules: v.rest.specific_rules,
 
             misc_generic_selectors: v.rest.misc_generic_selectors,
+            generic_stylesheet: Default::default(),
+            tag_index: Default::default(),
 
             scriptlets: v.rest.scriptlets,
         })
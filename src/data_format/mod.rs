@@ -7,16 +7,24 @@
 
 mod legacy;
 mod v0;
+mod v1;
+mod network_filters;
 
 pub mod utils;
 
 use crate::blocker::Blocker;
 use crate::cosmetic_filter_cache::CosmeticFilterCache;
 
+pub use network_filters::{serialize_filters, deserialize_filters};
+
 /// Newer formats start with this magic byte sequence.
 /// Calculated as the leading 4 bytes of `echo -n 'brave/adblock-rust' | sha512sum`.
 const ADBLOCK_RUST_DAT_MAGIC: [u8; 4] = [0xd1, 0xd9, 0x3a, 0xaf];
 
+/// adblock-rust has always used flate2 1.0.x for the legacy format, which has never changed the
+/// header sequence from these 10 bits when the GzEncoder is left uncustomized.
+const FLATE2_GZ_HEADER_BYTES: [u8; 10] = [31, 139, 8, 0, 0, 0, 0, 0, 0, 255];
+
 /// Provides structural aggregration of referenced adblock engine data to allow for allocation-free
 /// serialization.
 ///
@@ -24,7 +32,7 @@ const ADBLOCK_RUST_DAT_MAGIC: [u8; 4] = [0xd1, 0xd9, 0x3a, 0xaf];
 /// be serialized independently. Instead, use the `serialize` method.
 pub(crate) enum SerializeFormat<'a> {
     Legacy(legacy::SerializeFormat<'a>),
-    V0(v0::SerializeFormat<'a>),
+    V1(v1::SerializeFormat<'a>),
 }
 
 #[derive(Debug)]
@@ -63,14 +71,14 @@ impl<'a> SerializeFormat<'a> {
         if legacy {
             Self::Legacy(legacy::SerializeFormat::from((blocker, cfc)))
         } else {
-            Self::V0(v0::SerializeFormat::from((blocker, cfc)))
+            Self::V1(v1::SerializeFormat::from((blocker, cfc)))
         }
     }
 
     pub(crate) fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
         match self {
             Self::Legacy(v) => v.serialize(),
-            Self::V0(v) => v.serialize(),
+            Self::V1(v) => v.serialize(),
         }
     }
 }
@@ -83,6 +91,7 @@ impl<'a> SerializeFormat<'a> {
 pub(crate) enum DeserializeFormat {
     Legacy(legacy::DeserializeFormat),
     V0(v0::DeserializeFormat),
+    V1(v1::DeserializeFormat),
 }
 
 #[derive(Debug)]
@@ -90,6 +99,7 @@ pub enum DeserializationError {
     RmpSerdeError(rmp_serde::decode::Error),
     UnsupportedFormatVersion(u8),
     NoHeaderFound,
+    HeaderTooShort,
 }
 
 /// Since two different versions of `rmp-serde` are being used, errors must be converted to a
@@ -123,28 +133,51 @@ impl DeserializeFormat {
         match self {
             Self::Legacy(v) => v.into(),
             Self::V0(v) => v.into(),
+            Self::V1(v) => v.into(),
         }
     }
 
     pub(crate) fn deserialize(serialized: &[u8]) -> Result<Self, DeserializationError> {
-        /// adblock-rust has always used flate2 1.0.x for the legacy format, which has never
-        /// changed the header sequence from these 10 bits when the GzEncoder is left uncustomized.
-        const FLATE2_GZ_HEADER_BYTES: [u8; 10] = [31, 139, 8, 0, 0, 0, 0, 0, 0, 255];
-
-        if serialized.starts_with(&FLATE2_GZ_HEADER_BYTES) {
-            Ok(Self::Legacy(legacy::DeserializeFormat::deserialize(serialized)?))
-        } else if serialized.starts_with(&ADBLOCK_RUST_DAT_MAGIC) {
-            let version = serialized[ADBLOCK_RUST_DAT_MAGIC.len()];
-            match version {
-                0 => Ok(Self::V0(v0::DeserializeFormat::deserialize(serialized)?)),
-                v => Err(DeserializationError::UnsupportedFormatVersion(v)),
-            }
-        } else {
-            Err(DeserializationError::NoHeaderFound)
+        match peek_dat_header(serialized)?.version {
+            None => Ok(Self::Legacy(legacy::DeserializeFormat::deserialize(serialized)?)),
+            Some(0) => Ok(Self::V0(v0::DeserializeFormat::deserialize(serialized)?)),
+            Some(1) => Ok(Self::V1(v1::DeserializeFormat::deserialize(serialized)?)),
+            Some(v) => Err(DeserializationError::UnsupportedFormatVersion(v)),
         }
     }
 }
 
+/// Format information read from the start of a serialized `Engine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatHeader {
+    /// The format version, or `None` for the legacy gzip-compressed format from
+    /// `Engine::serialize_compressed`, which has no version number.
+    pub version: Option<u8>,
+}
+
+impl DatHeader {
+    /// Returns `true` if data with this header can be deserialized by this version of the crate.
+    pub fn is_supported(&self) -> bool {
+        matches!(self.version, None | Some(0) | Some(1))
+    }
+}
+
+/// Reads the header of a serialized `Engine` without deserializing any of the data that follows
+/// it, e.g. to check whether the data is compatible before loading it.
+pub fn peek_dat_header(serialized: &[u8]) -> Result<DatHeader, DeserializationError> {
+    if serialized.starts_with(&FLATE2_GZ_HEADER_BYTES) {
+        Ok(DatHeader { version: None })
+    } else if serialized.starts_with(&ADBLOCK_RUST_DAT_MAGIC) {
+        serialized.get(ADBLOCK_RUST_DAT_MAGIC.len())
+            .map(|&version| DatHeader { version: Some(version) })
+            .ok_or(DeserializationError::HeaderTooShort)
+    } else if FLATE2_GZ_HEADER_BYTES.starts_with(serialized) || ADBLOCK_RUST_DAT_MAGIC.starts_with(serialized) {
+        Err(DeserializationError::HeaderTooShort)
+    } else {
+        Err(DeserializationError::NoHeaderFound)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -161,4 +194,63 @@ mod tests {
 
         assert!(result.starts_with(&ADBLOCK_RUST_DAT_MAGIC));
     }
+
+    #[test]
+    fn peek_header() {
+        let engine = crate::engine::Engine::from_rules(&[String::from("||ads.com^")], Default::default());
+
+        let serialized = engine.serialize_raw().unwrap();
+        let header = peek_dat_header(&serialized).unwrap();
+        assert_eq!(header, DatHeader { version: Some(1) });
+        assert_eq!(header.is_supported(), true);
+
+        let serialized = engine.serialize_compressed().unwrap();
+        let header = peek_dat_header(&serialized).unwrap();
+        assert_eq!(header, DatHeader { version: None });
+        assert_eq!(header.is_supported(), true);
+
+        let mut future = ADBLOCK_RUST_DAT_MAGIC.to_vec();
+        future.push(5);
+        let header = peek_dat_header(&future).unwrap();
+        assert_eq!(header, DatHeader { version: Some(5) });
+        assert_eq!(header.is_supported(), false);
+    }
+
+    #[test]
+    fn deserialize_v0() {
+        // Engine serialized by an earlier version of the crate, from the rules `||ads.com^`,
+        // `@@||ads.com/allowed.js`, `/tracker.js$domain=example.com`, `example.com##.ad`, and
+        // `##.banner`
+        let serialized = base64::decode("0dk6rwDcABGRgJGBz48QMSfiVuQnkZ2RzgBrH/+BAasvYWxsb3dlZC5qc8DAwKdhZHMuY29twMDAwM8WJRk2kC4PscDAkYCRgJGAkYLPUHZ4W+o6QFWRnZHOIDMf/4EAwMDAwKdhZHMuY29twMDAwM94rDrv+BXv1sDAz+4Aql/SOfP4kZ2RzgADH/+BAasvdHJhY2tlci5qc5HP7gCqX9I58/jAwMDAwMDAz1um+0v+S4exz+4Aql/SOfP4wJGAkMORgJGmYmFubmVykICAkYHP7gCqX9I58/iRgQCjLmFkkJGA").unwrap();
+        let header = peek_dat_header(&serialized).unwrap();
+        assert_eq!(header, DatHeader { version: Some(0) });
+        assert_eq!(header.is_supported(), true);
+
+        let mut engine = crate::engine::Engine::default();
+        engine.deserialize(&serialized).unwrap();
+        let check = |url: &str, source: &str| engine.check_network_urls(url, source, "script").matched;
+        assert_eq!(check("https://ads.com/ad.js", "https://news.com"), true);
+        assert_eq!(check("https://ads.com/allowed.js", "https://news.com"), false);
+        assert_eq!(check("https://cdn.com/tracker.js", "https://example.com"), true);
+        assert_eq!(check("https://cdn.com/tracker.js", "https://other.com"), false);
+
+        let resources = engine.url_cosmetic_resources("https://example.com");
+        assert_eq!(resources.hide_selectors.contains(".ad"), true);
+        assert_eq!(engine.hidden_class_id_selectors(&["banner".into()], &[], &resources.exceptions), vec![String::from(".banner")]);
+
+        // Engines are now written in the newer format
+        assert_eq!(peek_dat_header(&engine.serialize_raw().unwrap()).unwrap(), DatHeader { version: Some(1) });
+    }
+
+    #[test]
+    fn peek_header_errors() {
+        assert!(matches!(peek_dat_header(&[]), Err(DeserializationError::HeaderTooShort)));
+        assert!(matches!(peek_dat_header(&ADBLOCK_RUST_DAT_MAGIC), Err(DeserializationError::HeaderTooShort)));
+        assert!(matches!(peek_dat_header(&FLATE2_GZ_HEADER_BYTES[..4]), Err(DeserializationError::HeaderTooShort)));
+        assert!(matches!(peek_dat_header(&[0, 1, 2, 3, 4, 5]), Err(DeserializationError::NoHeaderFound)));
+
+        // Truncated data is rejected rather than causing a panic
+        let mut engine = crate::engine::Engine::from_rules(&[], Default::default());
+        assert!(engine.deserialize(&ADBLOCK_RUST_DAT_MAGIC).is_err());
+    }
 }
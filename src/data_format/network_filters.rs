@@ -0,0 +1,137 @@
+//! A standalone format for lists of network filters, e.g. to ship the output of
+//! `optimizer::optimize` to clients which can't afford to parse and optimize full filter lists on
+//! startup.
+//!
+//! Filters are stored as they are, including their masks and fused `FilterPart`s. Regexes are not
+//! stored; they are compiled lazily on first use, just like for freshly parsed filters.
+
+use rmp_serde as rmps;
+
+use crate::filters::network::NetworkFilter;
+
+use super::{DeserializationError, SerializationError};
+
+/// Serialized filters start with this magic byte sequence, which is distinct from the one used for
+/// serialized engines. Calculated as the leading 4 bytes of
+/// `echo -n 'brave/adblock-rust/network-filters' | sha512sum`.
+const NETWORK_FILTERS_MAGIC: [u8; 4] = [0x79, 0x8e, 0x30, 0x1e];
+
+/// Version of the format written by `serialize_filters`, stored right after the magic bytes.
+/// Should be incremented whenever `NetworkFilter`'s serialized form changes incompatibly.
+const NETWORK_FILTERS_VERSION: u8 = 0;
+
+/// Serializes `filters` into a compact binary format, which can be read back using
+/// `deserialize_filters`.
+pub fn serialize_filters(filters: &[NetworkFilter]) -> Result<Vec<u8>, SerializationError> {
+    let mut output = NETWORK_FILTERS_MAGIC.to_vec();
+    output.push(NETWORK_FILTERS_VERSION);
+    rmps::encode::write(&mut output, filters)?;
+    Ok(output)
+}
+
+/// Reads back filters written by `serialize_filters`. Data written using any other format version
+/// is rejected with `DeserializationError::UnsupportedFormatVersion`.
+pub fn deserialize_filters(serialized: &[u8]) -> Result<Vec<NetworkFilter>, DeserializationError> {
+    if !serialized.starts_with(&NETWORK_FILTERS_MAGIC) {
+        return if NETWORK_FILTERS_MAGIC.starts_with(serialized) {
+            Err(DeserializationError::HeaderTooShort)
+        } else {
+            Err(DeserializationError::NoHeaderFound)
+        };
+    }
+    match serialized.get(NETWORK_FILTERS_MAGIC.len()) {
+        Some(&NETWORK_FILTERS_VERSION) => {
+            let filters = rmps::decode::from_read(&serialized[NETWORK_FILTERS_MAGIC.len() + 1..])?;
+            Ok(filters)
+        }
+        Some(&version) => Err(DeserializationError::UnsupportedFormatVersion(version)),
+        None => Err(DeserializationError::HeaderTooShort),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::network::{FilterPart, NetworkMatchable};
+    use crate::lists::parse_filters;
+    use crate::request::Request;
+
+    #[test]
+    fn validate_magic_bytes() {
+        use sha2::Digest;
+
+        let mut hasher = sha2::Sha512::new();
+
+        hasher.update("brave/adblock-rust/network-filters");
+
+        let result = hasher.finalize();
+
+        assert!(result.starts_with(&NETWORK_FILTERS_MAGIC));
+    }
+
+    #[test]
+    fn round_trip_optimized_filters() {
+        let rules = [
+            "/static/ad-",
+            "/static/ad.",
+            "/static/ads/*",
+            "/static/adv/*",
+            "||ads.example.com^$third-party",
+            "||ads.example.com/banner^$image",
+            "||ads.example.com/pixel^$image",
+            "@@||example.com/static/ads/ok$script",
+            "/^https?:\\/\\/tracker\\.[a-z]+\\/t\\?id=/",
+            "||example.net^$removeparam=utm_source",
+        ].iter().map(|rule| rule.to_string()).collect::<Vec<_>>();
+        let (filters, _) = parse_filters(&rules, true, Default::default());
+        let optimized = crate::optimizer::optimize(filters);
+        assert!(optimized.iter().any(|filter| matches!(filter.filter, FilterPart::AnyOf(_))));
+
+        let serialized = serialize_filters(&optimized).unwrap();
+        let deserialized = deserialize_filters(&serialized).unwrap();
+        assert_eq!(deserialized.len(), optimized.len());
+
+        let requests = [
+            ("https://cdn.com/static/ad-1.js", "script"),
+            ("https://cdn.com/static/ads/banner.png", "image"),
+            ("https://cdn.com/static/adv/x", "xhr"),
+            ("https://cdn.com/static/other.js", "script"),
+            ("https://ads.example.com/banner.png", "image"),
+            ("https://ads.example.com/pixel", "image"),
+            ("https://ads.example.com/pixel", "script"),
+            ("https://example.com/static/ads/ok", "script"),
+            ("https://tracker.io/t?id=5", "image"),
+            ("https://tracker.io/x?id=5", "image"),
+            ("https://example.net/?utm_source=a", "document"),
+        ];
+        for (original, deserialized) in optimized.iter().zip(deserialized.iter()) {
+            assert_eq!(original.id, deserialized.id);
+            assert_eq!(original.mask, deserialized.mask);
+            assert_eq!(original.raw_line, deserialized.raw_line);
+            assert_eq!(original.removeparam, deserialized.removeparam);
+            for (url, request_type) in requests.iter() {
+                let request = Request::from_urls(url, "https://example.com", request_type).unwrap();
+                assert_eq!(original.matches(&request), deserialized.matches(&request), "{} {}", original, url);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let (filters, _) = parse_filters(&["||ads.com^".to_string()], false, Default::default());
+        let mut serialized = serialize_filters(&filters).unwrap();
+
+        serialized[NETWORK_FILTERS_MAGIC.len()] = NETWORK_FILTERS_VERSION + 1;
+        assert!(matches!(
+            deserialize_filters(&serialized),
+            Err(DeserializationError::UnsupportedFormatVersion(v)) if v == NETWORK_FILTERS_VERSION + 1
+        ));
+        assert!(matches!(deserialize_filters(&serialized[..2]), Err(DeserializationError::HeaderTooShort)));
+        assert!(matches!(deserialize_filters(&serialized[..4]), Err(DeserializationError::HeaderTooShort)));
+
+        // Serialized engines aren't filter lists
+        let engine = crate::engine::Engine::from_rules(&["||ads.com^".to_string()], Default::default());
+        let serialized = engine.serialize_raw().unwrap();
+        assert!(matches!(deserialize_filters(&serialized), Err(DeserializationError::NoHeaderFound)));
+    }
+}
@@ -1,12 +1,13 @@
-//! Contains representations of data from the adblocking engine in a
-//! forwards-and-backwards-compatible format, as well as utilities for converting these to and from
-//! the actual `Engine` components.
+//! Contains representations of data from the adblocking engine in the version 0 format, as well as
+//! utilities for converting these into the actual `Engine` components.
 //!
-//! Any new fields should be added to the _end_ of both `SerializeFormat` and `DeserializeFormat`.
+//! Engines are no longer serialized in this format, since older versions of the crate can't read
+//! the network filter and cosmetic rule data that was added later. It is only kept so that data
+//! written by those versions can still be loaded; see `v1` for the current format.
 
 use std::collections::{HashSet, HashMap};
 
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use rmp_serde as rmps;
 
 use crate::blocker::{Blocker, NetworkFilterList};
@@ -14,52 +15,7 @@ use crate::resources::{RedirectResourceStorage, ScriptletResourceStorage};
 use crate::filters::network::NetworkFilter;
 use crate::cosmetic_filter_cache::{CosmeticFilterCache, HostnameRuleDb};
 
-use super::{DeserializationError, SerializationError};
-use super::utils::{stabilize_hashmap_serialization, stabilize_hashset_serialization};
-
-/// Provides structural aggregration of referenced adblock engine data to allow for allocation-free
-/// serialization.
-#[derive(Serialize)]
-pub(crate) struct SerializeFormat<'a> {
-    csp: &'a NetworkFilterList,
-    exceptions: &'a NetworkFilterList,
-    importants: &'a NetworkFilterList,
-    redirects: &'a NetworkFilterList,
-    filters_tagged: &'a NetworkFilterList,
-    filters: &'a NetworkFilterList,
-    generic_hide: &'a NetworkFilterList,
-
-    tagged_filters_all: &'a Vec<NetworkFilter>,
-
-    enable_optimizations: bool,
-
-    resources: &'a RedirectResourceStorage,
-
-    #[serde(serialize_with = "stabilize_hashset_serialization")]
-    simple_class_rules: &'a HashSet<String>,
-    #[serde(serialize_with = "stabilize_hashset_serialization")]
-    simple_id_rules: &'a HashSet<String>,
-    #[serde(serialize_with = "stabilize_hashmap_serialization")]
-    complex_class_rules: &'a HashMap<String, Vec<String>>,
-    #[serde(serialize_with = "stabilize_hashmap_serialization")]
-    complex_id_rules: &'a HashMap<String, Vec<String>>,
-
-    specific_rules: &'a HostnameRuleDb,
-
-    #[serde(serialize_with = "stabilize_hashset_serialization")]
-    misc_generic_selectors: &'a HashSet<String>,
-
-    scriptlets: &'a ScriptletResourceStorage,
-}
-
-impl<'a> SerializeFormat<'a> {
-    pub fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
-        let mut output = super::ADBLOCK_RUST_DAT_MAGIC.to_vec();
-        output.push(0);
-        rmps::encode::write(&mut output, &self)?;
-        Ok(output)
-    }
-}
+use super::DeserializationError;
 
 /// Structural representation of adblock engine data that can be built up from deserialization and
 /// used directly to construct new `Engine` components without unnecessary allocation.
@@ -100,38 +56,6 @@ impl DeserializeFormat {
     }
 }
 
-impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
-    fn from(v: (&'a Blocker, &'a CosmeticFilterCache)) -> Self {
-        let (blocker, cfc) = v;
-        Self {
-            csp: &blocker.csp,
-            exceptions: &blocker.exceptions,
-            importants: &blocker.importants,
-            redirects: &blocker.redirects,
-            filters_tagged: &blocker.filters_tagged,
-            filters: &blocker.filters,
-            generic_hide: &blocker.generic_hide,
-
-            tagged_filters_all: &blocker.tagged_filters_all,
-
-            enable_optimizations: blocker.enable_optimizations,
-
-            resources: &blocker.resources,
-
-            simple_class_rules: &cfc.simple_class_rules,
-            simple_id_rules: &cfc.simple_id_rules,
-            complex_class_rules: &cfc.complex_class_rules,
-            complex_id_rules: &cfc.complex_id_rules,
-
-            specific_rules: &cfc.specific_rules,
-
-            misc_generic_selectors: &cfc.misc_generic_selectors,
-
-            scriptlets: &cfc.scriptlets,
-        }
-    }
-}
-
 impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
     fn from(v: DeserializeFormat) -> Self {
         (Blocker {
@@ -142,11 +66,15 @@ impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
             filters_tagged: v.filters_tagged,
             filters: v.filters,
             generic_hide: v.generic_hide,
+            removeparams: Default::default(),
 
             tags_enabled: Default::default(),
+            disabled_filters: Default::default(),
+            badfilter_ids: Default::default(),
             tagged_filters_all: v.tagged_filters_all,
 
             enable_optimizations: v.enable_optimizations,
+            loopback_exempt: false,
 
             resources: v.resources,
             #[cfg(feature = "object-pooling")]
@@ -161,6 +89,8 @@ impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
             specific_rules: v.specific_rules,
 
             misc_generic_selectors: v.misc_generic_selectors,
+            generic_stylesheet: Default::default(),
+            tag_index: Default::default(),
 
             scriptlets: v.scriptlets,
         })
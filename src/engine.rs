@@ -1,15 +1,76 @@
-use crate::blocker::{Blocker, BlockerError, BlockerOptions, BlockerResult};
-use crate::cosmetic_filter_cache::{CosmeticFilterCache, UrlSpecificResources};
-use crate::lists::{FilterSet, ParseOptions};
-use crate::request::Request;
-use crate::resources::{Resource, RedirectResource};
+use crate::blocker::{Blocker, BlockerError, BlockerOptions, BlockerResult, CheckTiming, RegexStats, ValidationReport};
+use crate::cosmetic_filter_cache::{CosmeticFilterCache, HideSource, UrlSpecificResources};
+use crate::filters::network::NetworkFilter;
+use crate::lists::{FilterSet, ParseOptions, SkipReason};
+use crate::request::{inferred_request_type, ExtensionSourcePolicy, Request, RequestType};
+use crate::resources::{Resource, RedirectResource, ResolvedScriptlet, ScriptletCall};
+use crate::testing::Decision;
+use crate::url_parser::{self, RequestUrl};
+
+use std::collections::{HashMap, HashSet};
+
+pub use crate::data_format::{peek_dat_header, DatHeader, DeserializationError};
+
+/// Summary of how many rules target a particular site, as returned by `Engine::site_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SiteReport {
+    /// Number of hostname-specific cosmetic rules (hiding, styling, or scriptlet injection) that
+    /// apply to pages on the site.
+    pub cosmetic_rules: usize,
+    /// Number of network rules that block requests to the site itself, e.g. `||example.com^`.
+    pub hostname_network_rules: usize,
+    /// Number of network rules that only apply to requests made from pages on the site, e.g.
+    /// `/ads.js$domain=example.com`.
+    pub domain_scoped_network_rules: usize,
+}
+
+/// A single consideration in the decision made for a network request. See `DecisionChain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecisionStep {
+    /// Whether the request is first-party and third-party to its source, or `None` if the party
+    /// is unknown. Rules with `$first-party` or `$third-party` options depend on these.
+    Party {
+        first_party: Option<bool>,
+        third_party: Option<bool>,
+    },
+    /// An `$important` rule matched, so exceptions don't apply.
+    Important(String),
+    /// A blocking rule matched.
+    Blocked(String),
+    /// An exception rule matched.
+    Excepted(String),
+    /// A rule with a `$redirect` or `$redirect-url` option matched.
+    Redirected(String),
+    /// Content security policies to be injected into the response, for document requests.
+    Csp(String),
+}
 
-use std::collections::HashSet;
+/// The considerations leading to the decision made for a network request, as returned by
+/// `Engine::decision_chain`.
+#[derive(Debug)]
+pub struct DecisionChain {
+    /// Every consideration, in the order they're made: party classification, then each matching
+    /// important, blocking, exception and redirect rule, then any injected CSP. Rules are named by
+    /// their original text if the engine was built in debug mode, and matching rules are listed
+    /// even if they're overruled by a later step.
+    pub steps: Vec<DecisionStep>,
+    /// The final decision, as returned by `Blocker::check`.
+    pub result: BlockerResult,
+}
 
 /// Main adblocking engine that allows efficient querying of resources to block.
 pub struct Engine {
     pub blocker: Blocker,
     cosmetic_cache: CosmeticFilterCache,
+    first_party_generic_cosmetics_only: bool,
+    generic_stylesheet: bool,
+    generic_cosmetic_limit: Option<usize>,
+    extension_source_policy: ExtensionSourcePolicy,
+    collapse_hints: bool,
+    type_inference: bool,
+    /// The `FilterSet` this engine was created from, retained only if it contains lists tracked by
+    /// id so that they can be replaced later.
+    list_sources: Option<FilterSet>,
 }
 
 impl Default for Engine {
@@ -32,6 +93,13 @@ impl Engine {
         Self {
             blocker: Blocker::new(vec![], &blocker_options),
             cosmetic_cache: CosmeticFilterCache::new(),
+            first_party_generic_cosmetics_only: false,
+            generic_stylesheet: false,
+            generic_cosmetic_limit: None,
+            extension_source_policy: ExtensionSourcePolicy::default(),
+            collapse_hints: false,
+            type_inference: false,
+            list_sources: None,
         }
     }
 
@@ -55,17 +123,74 @@ impl Engine {
 
     /// Loads rules from the given `FilterSet`. It is recommended to use a `FilterSet` when adding
     /// rules from multiple sources.
+    ///
+    /// If any rules were added with `FilterSet::add_list`, a copy of the `FilterSet` is kept so
+    /// that those lists can later be updated with `replace_list`.
     pub fn from_filter_set(set: FilterSet, optimize: bool) -> Self {
-        let FilterSet { network_filters, cosmetic_filters, .. } = set;
+        Self::from_filter_set_with_skipped(set, optimize).0
+    }
+
+    /// Like `from_filter_set`, but also returns every rule that was dropped from the `FilterSet`
+    /// while parsing, along with the reason why, e.g. the specific option that isn't supported.
+    /// Comments and rules written in unsupported syntaxes are not included.
+    pub fn from_filter_set_with_skipped(mut set: FilterSet, optimize: bool) -> (Self, Vec<(String, SkipReason)>) {
+        let skipped = std::mem::take(&mut set.skipped);
+        let list_sources = if set.lists.is_empty() { None } else { Some(set.clone()) };
+        let (network_filters, cosmetic_filters) = set.into_filters();
 
         let blocker_options = BlockerOptions {
             enable_optimizations: optimize,
         };
 
-        Self {
+        let engine = Self {
             blocker: Blocker::new(network_filters, &blocker_options),
             cosmetic_cache: CosmeticFilterCache::from_rules(cosmetic_filters),
+            first_party_generic_cosmetics_only: false,
+            generic_stylesheet: false,
+            generic_cosmetic_limit: None,
+            extension_source_policy: ExtensionSourcePolicy::default(),
+            collapse_hints: false,
+            type_inference: false,
+            list_sources,
+        };
+        (engine, skipped)
+    }
+
+    /// Replaces all rules from the list that was added to the original `FilterSet` as `id` with
+    /// `new_lines`, leaving rules from all other sources untouched. Returns `false` if no list was
+    /// added with that id.
+    ///
+    /// The engine's filters are recompiled so that `$badfilter` rules and optimizations are
+    /// applied across all lists again. Enabled tags, resources, the loopback exemption, and any
+    /// limit set by `set_generic_cosmetic_limit` are preserved.
+    pub fn replace_list(&mut self, id: &str, new_lines: &[String]) -> bool {
+        let sources = match self.list_sources.as_mut() {
+            Some(sources) => sources,
+            None => return false,
+        };
+        if !sources.replace_list(id, new_lines) {
+            return false;
         }
+        let (network_filters, cosmetic_filters) = sources.clone().into_filters();
+
+        let blocker_options = BlockerOptions {
+            enable_optimizations: self.blocker.enable_optimizations,
+        };
+        let current_tags = self.blocker.tags_enabled();
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        blocker.use_tags(&current_tags.iter().map(|s| &**s).collect::<Vec<_>>());
+        blocker.resources = std::mem::take(&mut self.blocker.resources);
+        blocker.set_loopback_exempt(self.blocker.loopback_exempt);
+        self.blocker = blocker;
+
+        let mut cosmetic_cache = CosmeticFilterCache::from_rules(cosmetic_filters);
+        cosmetic_cache.scriptlets = std::mem::take(&mut self.cosmetic_cache.scriptlets);
+        if let Some(limit) = self.generic_cosmetic_limit {
+            cosmetic_cache.limit_generic_rules(limit);
+        }
+        self.cosmetic_cache = cosmetic_cache;
+
+        true
     }
 
     /// Serializes the `Engine` into a binary format so that it can be quickly reloaded later.
@@ -106,32 +231,136 @@ impl Engine {
         let deserialize_format = DeserializeFormat::deserialize(serialized).map_err(|_e| {
             BlockerError::DeserializationError
         })?;
-        let (blocker, cosmetic_cache) = deserialize_format.build();
+        let (mut blocker, mut cosmetic_cache) = deserialize_format.build();
+        if let Some(limit) = self.generic_cosmetic_limit {
+            cosmetic_cache.limit_generic_rules(limit);
+        }
+        blocker.set_loopback_exempt(self.blocker.loopback_exempt);
         self.blocker = blocker;
         self.blocker.use_tags(&current_tags.iter().map(|s| &**s).collect::<Vec<_>>());
         self.cosmetic_cache = cosmetic_cache;
+        // Any tracked lists no longer correspond to the engine's contents
+        self.list_sources = None;
         Ok(())
     }
 
     /// Check if a request for a network resource from `url`, of type `request_type`, initiated by
     /// `source_url`, should be blocked.
     pub fn check_network_urls(&self, url: &str, source_url: &str, request_type: &str) -> BlockerResult {
-        Request::from_urls(&url, &source_url, &request_type)
+        let request_type = self.request_type_for(url, request_type);
+        let parsed_source = url_parser::parse_url(source_url);
+        Request::from_url_and_parsed_source(url, parsed_source.as_ref(), request_type, self.extension_source_policy)
         .map(|request| {
-            self.blocker.check(&request)
+            let result = self.blocker.check(&request);
+            self.with_collapse_hint(result, &request, || source_page_request(parsed_source.as_ref()))
         })
         .unwrap_or_else(|_e| {
             BlockerResult {
                 matched: false,
                 important: false,
                 redirect: None,
+                redirect_resource: None,
                 exception: None,
                 filter: None,
-                error: Some("Error parsing request".to_owned())
+                error: Some("Error parsing request".to_owned()),
+                should_collapse: false,
             }
         })
     }
 
+    /// Like `check_network_urls`, but also returns how long different phases of the check took.
+    /// Timing is only collected when using this method; `check_network_urls` is unaffected.
+    pub fn check_network_urls_with_timing(&self, url: &str, source_url: &str, request_type: &str) -> (BlockerResult, CheckTiming) {
+        let request_type = self.request_type_for(url, request_type);
+        let parsed_source = url_parser::parse_url(source_url);
+        Request::from_url_and_parsed_source(url, parsed_source.as_ref(), request_type, self.extension_source_policy)
+        .map(|request| {
+            let (result, timing) = self.blocker.check_with_timing(&request);
+            (self.with_collapse_hint(result, &request, || source_page_request(parsed_source.as_ref())), timing)
+        })
+        .unwrap_or_else(|_e| {
+            (BlockerResult {
+                matched: false,
+                important: false,
+                redirect: None,
+                redirect_resource: None,
+                exception: None,
+                filter: None,
+                error: Some("Error parsing request".to_owned()),
+                should_collapse: false,
+            }, CheckTiming::default())
+        })
+    }
+
+    /// Like `check_network_urls`, but `$first-party` and `$third-party` options are matched
+    /// relative to the top-level frame at `top_frame_url` rather than the document at
+    /// `source_url`, as in browsers which partition storage by top-level site. See
+    /// `Request::from_urls_partitioned`.
+    pub fn check_network_urls_partitioned(&self, url: &str, source_url: &str, top_frame_url: &str, request_type: &str) -> BlockerResult {
+        let request_type = self.request_type_for(url, request_type);
+        let parsed_source = url_parser::parse_url(source_url);
+        Request::from_url_and_parsed_source(url, parsed_source.as_ref(), request_type, self.extension_source_policy)
+            .map(|request| request.with_top_frame(top_frame_url, self.extension_source_policy))
+            .map(|request| {
+                let result = self.blocker.check(&request);
+                self.with_collapse_hint(result, &request, || source_page_request(parsed_source.as_ref()))
+            })
+            .unwrap_or_else(|_e| BlockerResult {
+                error: Some("Error parsing request".to_owned()),
+                ..Default::default()
+            })
+    }
+
+    /// Checks several requests of the same `request_type` initiated by the same `source_url`, such
+    /// as each candidate URL from an image's `srcset`. Equivalent to calling `check_network_urls`
+    /// for each of `urls`, but the source URL is only parsed once.
+    pub fn check_network_urls_batch(&self, urls: &[&str], source_url: &str, request_type: &str) -> Vec<BlockerResult> {
+        let parsed_source = url_parser::parse_url(source_url);
+        urls.iter()
+            .map(|url| Request::from_url_and_parsed_source(url, parsed_source.as_ref(), self.request_type_for(url, request_type), self.extension_source_policy))
+            .map(|request| match request {
+                Ok(request) => {
+                    let result = self.blocker.check(&request);
+                    self.with_collapse_hint(result, &request, || source_page_request(parsed_source.as_ref()))
+                }
+                Err(_e) => BlockerResult {
+                    error: Some("Error parsing request".to_owned()),
+                    ..Default::default()
+                },
+            })
+            .collect()
+    }
+
+    /// Applies type inference to `request_type`, if enabled.
+    fn request_type_for<'a>(&self, url: &str, request_type: &'a str) -> &'a str {
+        if self.type_inference {
+            inferred_request_type(url, request_type)
+        } else {
+            request_type
+        }
+    }
+
+    /// Sets `should_collapse` on the result if collapse hints are enabled and `request` was
+    /// blocked, based on the hide rules that apply to the page from `source_page`. The page's
+    /// request is only built if the hint is needed.
+    fn with_collapse_hint(&self, mut result: BlockerResult, request: &Request, source_page: impl FnOnce() -> Option<Request>) -> BlockerResult {
+        if !self.collapse_hints || !result.matched {
+            return result;
+        }
+        let tags: &[&str] = match request.request_type {
+            RequestType::Image => &["img", "picture"],
+            RequestType::Media => &["audio", "video"],
+            RequestType::Object => &["embed", "object"],
+            RequestType::Subdocument => &["frame", "iframe"],
+            _ => return result,
+        };
+        if let Some(source) = source_page() {
+            let generichide = self.blocker.check_generic_hide(&source);
+            result.should_collapse = self.cosmetic_cache.hides_elements_with_tags(&source.hostname, generichide, tags);
+        }
+        result
+    }
+
     pub fn check_network_urls_with_hostnames(
         &self,
         url: &str,
@@ -140,8 +369,10 @@ impl Engine {
         request_type: &str,
         third_party_request: Option<bool>
     ) -> BlockerResult {
-        let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.check(&request)
+        let request_type = self.request_type_for(url, request_type);
+        let request = Request::from_urls_with_hostname_and_extension_policy(url, hostname, source_hostname, request_type, third_party_request, self.extension_source_policy);
+        let result = self.blocker.check(&request);
+        self.with_collapse_hint(result, &request, || source_hostname_request(source_hostname))
     }
 
     pub fn check_network_urls_with_hostnames_subset(
@@ -154,8 +385,10 @@ impl Engine {
         previously_matched_rule: bool,
         force_check_exceptions: bool,
     ) -> BlockerResult {
-        let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.check_parameterised(&request, previously_matched_rule, force_check_exceptions)
+        let request_type = self.request_type_for(url, request_type);
+        let request = Request::from_urls_with_hostname_and_extension_policy(url, hostname, source_hostname, request_type, third_party_request, self.extension_source_policy);
+        let result = self.blocker.check_parameterised(&request, previously_matched_rule, force_check_exceptions);
+        self.with_collapse_hint(result, &request, || source_hostname_request(source_hostname))
     }
 
     /// Returns a string containing any additional CSP directives that should be added to this
@@ -174,6 +407,29 @@ impl Engine {
         self.blocker.get_csp_directives(&request)
     }
 
+    /// Like `get_csp_directives`, but each policy also explicitly sets the fetch directives used
+    /// by the other policies to their `default-src` fallback values. See
+    /// `Blocker::get_csp_directives_with_fallbacks` for details.
+    pub fn get_csp_directives_with_fallbacks(
+        &self,
+        url: &str,
+        hostname: &str,
+        source_hostname: &str,
+        request_type: &str,
+        third_party_request: Option<bool>,
+    ) -> Option<String> {
+        let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
+        self.blocker.get_csp_directives_with_fallbacks(&request)
+    }
+
+    /// Returns `url` with any query parameters matched by `$removeparam` rules stripped from it,
+    /// or `None` if it should be left unchanged. See `Blocker::get_removeparam_url` for details.
+    pub fn get_removeparam_url(&self, url: &str, source_url: &str, request_type: &str) -> Option<String> {
+        let request_type = self.request_type_for(url, request_type);
+        let request = Request::from_urls(url, source_url, request_type).ok()?;
+        self.blocker.get_removeparam_url(&request, url)
+    }
+
     /// Check if a given filter has been previously added to this `Engine`.
     ///
     /// Note that only network filters are currently supported by this method.
@@ -190,6 +446,102 @@ impl Engine {
         }
     }
 
+    /// Returns a combined count of the cosmetic and network rules that specifically target the
+    /// given `hostname`. Generic rules which apply to every site are not included, and any port in
+    /// `hostname` is ignored.
+    pub fn site_report(&self, hostname: &str) -> SiteReport {
+        let (hostname, _) = crate::filters::network::split_hostname_port(hostname);
+        let (hostname_network_rules, domain_scoped_network_rules) = self.blocker.hostname_rule_counts(hostname);
+
+        SiteReport {
+            cosmetic_rules: self.cosmetic_cache.hostname_specific_rule_count(hostname),
+            hostname_network_rules,
+            domain_scoped_network_rules,
+        }
+    }
+
+    /// Lists every consideration that goes into the decision made for `request`, for display in
+    /// transparency UIs. This checks every rule rather than stopping at the first match, so it is
+    /// much slower than `Blocker::check`.
+    ///
+    /// Rules which were fused together by optimizations are listed individually, limited to the
+    /// ones that match `request`. Requests that are exempt from checking, such as loopback
+    /// requests under `set_loopback_exempt`, only have a party step.
+    pub fn decision_chain(&self, request: &Request) -> DecisionChain {
+        let blocker = &self.blocker;
+        let party = DecisionStep::Party {
+            first_party: request.is_first_party,
+            third_party: request.is_third_party,
+        };
+        if blocker.is_exempt(request) {
+            return DecisionChain {
+                steps: vec![party],
+                result: blocker.check(request),
+            };
+        }
+
+        let mut tokens = Vec::new();
+        request.get_tokens(&mut tokens);
+        let no_tags = HashSet::new();
+
+        let rules = |filters: Vec<&NetworkFilter>| {
+            let mut rules: Vec<String> = filters.iter()
+                .flat_map(|filter| Self::matching_member_rules(filter, request))
+                .collect();
+            rules.sort();
+            rules.dedup();
+            rules
+        };
+        let important = rules(blocker.importants.check_all(request, &tokens, &no_tags));
+        let mut blocking = blocker.filters_tagged.check_all(request, &tokens, &blocker.tags_enabled);
+        blocking.extend(blocker.filters.check_all(request, &tokens, &no_tags));
+        let blocking = rules(blocking);
+        let exceptions = rules(blocker.exceptions.check_all(request, &tokens, &blocker.tags_enabled));
+        let redirects = rules(blocker.redirects.check_all(request, &tokens, &no_tags));
+
+        let mut steps = vec![party];
+        steps.extend(important.into_iter().map(DecisionStep::Important));
+        steps.extend(blocking.into_iter().map(DecisionStep::Blocked));
+        steps.extend(exceptions.into_iter().map(DecisionStep::Excepted));
+        steps.extend(redirects.into_iter().map(DecisionStep::Redirected));
+        steps.extend(blocker.get_csp_directives(request).map(DecisionStep::Csp));
+
+        DecisionChain {
+            steps,
+            result: blocker.check(request),
+        }
+    }
+
+    /// Returns the text of each rule fused into `filter` which matches `request` by itself, or just
+    /// the text of `filter` if it isn't a fused filter.
+    fn matching_member_rules(filter: &NetworkFilter, request: &Request) -> Vec<String> {
+        use crate::filters::network::NetworkMatchable;
+
+        let raw_line = filter.to_string();
+        if !raw_line.contains(" <+> ") {
+            return vec![raw_line];
+        }
+        raw_line.split(" <+> ")
+            .filter(|member| match Self::parse_rule_signature(member) {
+                Some(member) => member.matches(request),
+                None => true,
+            })
+            .map(String::from)
+            .collect()
+    }
+
+    /// Counts the distinct regexes this engine compiles for its network rules, after any
+    /// optimizations. See `RegexStats`.
+    pub fn regex_stats(&self) -> RegexStats {
+        self.blocker.regex_stats()
+    }
+
+    /// Checks the internal invariants of this engine's network rules, e.g. to verify a newly
+    /// loaded engine before swapping it in for one that's in use. See `ValidationReport`.
+    pub fn validate(&self) -> ValidationReport {
+        self.blocker.validate()
+    }
+
     /// Sets this engine's tags to be _only_ the ones provided in `tags`.
     ///
     /// Tags can be used to cheaply enable or disable network rules with a corresponding `$tag`
@@ -214,6 +566,117 @@ impl Engine {
         self.blocker.disable_tags(tags);
     }
 
+    /// Stops the network rule `raw_line` from matching any requests, without rebuilding the
+    /// engine. The rule must be given exactly as it appeared in its filter list. Returns `false` if
+    /// the rule isn't present in the engine, or has already been disabled.
+    ///
+    /// Disabled rules are not included when serializing the engine.
+    pub fn disable_rule(&mut self, raw_line: &str) -> bool {
+        match Self::parse_rule_signature(raw_line) {
+            Some(filter) => self.blocker.disable_filter(&filter),
+            None => false,
+        }
+    }
+
+    /// Restores a network rule previously disabled using `disable_rule`. Returns `false` if the
+    /// rule was not disabled.
+    pub fn enable_rule(&mut self, raw_line: &str) -> bool {
+        match Self::parse_rule_signature(raw_line) {
+            Some(filter) => self.blocker.enable_filter(&filter),
+            None => false,
+        }
+    }
+
+    /// Finds a small set of this engine's network rules, along with a simplified copy of
+    /// `request`, which together reproduce the decision the engine makes for `request`. This is
+    /// useful for reporting a broken page against a filter list without attaching the whole list.
+    ///
+    /// Rules can only be recovered from engines built in debug mode, e.g. using
+    /// `from_rules_debug`; otherwise the returned set will be empty.
+    pub fn minimize_match(&self, request: &Request) -> (Vec<String>, Request) {
+        let result = self.blocker.check(request);
+        let expected = Decision::from(&result);
+        let reproduces = |rules: &[String], request: &Request| {
+            Decision::from(&self.blocker_from_rules(rules).check(request)) == expected
+        };
+
+        let all_rules = self.blocker.raw_rules();
+
+        // The rules named in the result are usually all that's needed, apart from any redirect
+        // rules, which are reported by their resource instead
+        let mut tokens = Vec::new();
+        request.get_tokens(&mut tokens);
+        let redirect_rules = self.blocker.redirects.check_all(request, &tokens, &HashSet::new());
+        let mut candidates: Vec<String> = result.filter.iter()
+            .chain(result.exception.iter())
+            .chain(redirect_rules.iter().filter_map(|filter| filter.raw_line.as_ref()))
+            .flat_map(|line| line.split(" <+> "))
+            .filter(|line| all_rules.contains(*line))
+            .map(String::from)
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        let mut rules = if reproduces(&candidates, request) {
+            candidates
+        } else {
+            all_rules.into_iter().collect()
+        };
+
+        // Drop progressively smaller chunks of rules for as long as the decision is unchanged
+        let mut chunk_size = rules.len() / 2;
+        while chunk_size > 0 {
+            let mut start = 0;
+            while start < rules.len() {
+                let end = std::cmp::min(start + chunk_size, rules.len());
+                let remaining: Vec<String> = rules[..start].iter().chain(rules[end..].iter()).cloned().collect();
+                if reproduces(&remaining, request) {
+                    rules = remaining;
+                } else {
+                    start = end;
+                }
+            }
+            chunk_size /= 2;
+        }
+        if rules.len() == 1 && reproduces(&[], request) {
+            rules.clear();
+        }
+
+        // Strip the fragment and query from the URL where they don't affect the decision
+        let mut minimized = request.clone();
+        for separator in &['#', '?'] {
+            let path = minimized.url_after_hostname();
+            if let Some(index) = path.find(*separator) {
+                let mut candidate = minimized.clone();
+                candidate.url.truncate(minimized.url.len() - path.len() + index);
+                if reproduces(&rules, &candidate) {
+                    minimized = candidate;
+                }
+            }
+        }
+
+        (rules, minimized)
+    }
+
+    /// Builds an unoptimized blocker from `rules`, with the same tags and resources as this engine.
+    fn blocker_from_rules(&self, rules: &[String]) -> Blocker {
+        let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
+        let filters = rules.iter()
+            .filter_map(|rule| NetworkFilter::parse(rule, true, opts).ok())
+            .collect();
+        let mut blocker = Blocker::new(filters, &BlockerOptions { enable_optimizations: false });
+        let current_tags = self.blocker.tags_enabled();
+        blocker.use_tags(&current_tags.iter().map(|s| &**s).collect::<Vec<_>>());
+        blocker.resources = self.blocker.resources.clone();
+        blocker.set_loopback_exempt(self.blocker.loopback_exempt);
+        blocker
+    }
+
+    /// Parses `raw_line` just enough to identify the corresponding filter in the blocker.
+    fn parse_rule_signature(raw_line: &str) -> Option<NetworkFilter> {
+        let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
+        NetworkFilter::parse(raw_line.trim(), true, opts).ok()
+    }
+
     /// Checks if a given tag exists in this engine.
     ///
     /// Tags can be used to cheaply enable or disable network rules with a corresponding `$tag`
@@ -252,20 +715,174 @@ impl Engine {
         self.cosmetic_cache.hidden_class_id_selectors(classes, ids, exceptions)
     }
 
+    /// Sets the maximum number of generic cosmetic selectors kept in memory, returning the number
+    /// of selectors that were dropped to stay under it. Hostname-specific cosmetic rules are
+    /// always retained. No limit is set by default.
+    ///
+    /// This is intended for memory-constrained platforms. The limit also applies to rules loaded
+    /// later by `replace_list` or `deserialize`. Removing it with `None` doesn't restore selectors
+    /// that were already dropped.
+    pub fn set_generic_cosmetic_limit(&mut self, limit: Option<usize>) -> usize {
+        self.generic_cosmetic_limit = limit;
+        match limit {
+            Some(limit) => self.cosmetic_cache.limit_generic_rules(limit),
+            None => 0,
+        }
+    }
+
+    /// Lists every scriptlet that would be injected into pages on `hostname` by `+js(...)` rules,
+    /// with their arguments filled in.
+    pub fn scriptlets_for_host(&self, hostname: &str) -> Vec<ResolvedScriptlet> {
+        self.cosmetic_cache.scriptlets_for_host(hostname)
+    }
+
+    /// Lists the name and arguments of every scriptlet that would be injected into pages on
+    /// `hostname` by `+js(...)` rules, so that clients can inject their own implementations.
+    pub fn scriptlet_calls_for_host(&self, hostname: &str) -> Vec<ScriptletCall> {
+        self.cosmetic_cache.scriptlet_calls_for_host(hostname)
+    }
+
+    /// Returns every class and id referenced by the hostname-specific hide and style rules for a
+    /// page, so that a client only needs to observe those tokens. The page can be given either as
+    /// a URL or as a bare hostname. Generic rules aren't included; use `hidden_class_id_selectors`
+    /// for those.
+    pub fn relevant_tokens(&self, url_or_hostname: &str) -> HashSet<String> {
+        if url_or_hostname.contains("://") {
+            match Request::from_url(url_or_hostname) {
+                Ok(request) => self.cosmetic_cache.relevant_tokens(&request.hostname),
+                Err(_) => HashSet::new(),
+            }
+        } else {
+            self.cosmetic_cache.relevant_tokens(url_or_hostname)
+        }
+    }
+
+    /// Returns a stylesheet for all generic cosmetic rules that can't be handled by
+    /// `hidden_class_id_selectors`. It's the same for every page, so clients should fetch it once
+    /// and inject it wherever `UrlSpecificResources::apply_generic_stylesheet` is set. This is
+    /// only ever set once enabled with `set_generic_stylesheet`.
+    pub fn generic_cosmetic_stylesheet(&self) -> &str {
+        self.cosmetic_cache.generic_cosmetic_stylesheet()
+    }
+
     /// Returns a set of cosmetic filter resources required for a particular url. Once this has
     /// been called, all CSS ids and classes on a page should be passed to
     /// `hidden_class_id_selectors` to obtain any stylesheets consisting of generic rules (if the
     /// returned `generichide` value is false).
     pub fn url_cosmetic_resources(&self, url: &str) -> UrlSpecificResources {
+        self.frame_cosmetic_resources(url, false)
+    }
+
+    /// Like `url_cosmetic_resources`, but for a frame at `url` which may be third-party with
+    /// respect to the top-level page.
+    ///
+    /// If `set_first_party_generic_cosmetics_only` has been enabled, third-party frames will not
+    /// receive any generic cosmetic rules, and the returned `generichide` value will be true so
+    /// that `hidden_class_id_selectors` need not be queried for them.
+    pub fn frame_cosmetic_resources(&self, url: &str, third_party_frame: bool) -> UrlSpecificResources {
         let request = Request::from_url(url);
         if request.is_err() {
             return UrlSpecificResources::empty();
         }
         let request = request.unwrap();
 
-        let generichide = self.blocker.check_generic_hide(&request);
-        self.cosmetic_cache.hostname_cosmetic_resources(&request.hostname, generichide)
+        let generichide = (third_party_frame && self.first_party_generic_cosmetics_only)
+            || self.blocker.check_generic_hide(&request);
+        self.unless_generic_stylesheet(self.cosmetic_cache.hostname_cosmetic_resources(&request.hostname, generichide))
+    }
+
+    /// Sets whether the generic rules that can't be handled by `hidden_class_id_selectors` are
+    /// left out of `UrlSpecificResources::hide_selectors` wherever the page can use the
+    /// `generic_cosmetic_stylesheet` instead, as indicated by `apply_generic_stylesheet`. This
+    /// saves sending the same selectors for every page. Disabled by default, in which case every
+    /// page's `hide_selectors` includes them as usual.
+    pub fn set_generic_stylesheet(&mut self, enabled: bool) {
+        self.generic_stylesheet = enabled;
+    }
+
+    /// Moves the generic stylesheet's selectors back into `hide_selectors`, unless
+    /// `set_generic_stylesheet` is enabled.
+    fn unless_generic_stylesheet(&self, mut resources: UrlSpecificResources) -> UrlSpecificResources {
+        if !self.generic_stylesheet && resources.apply_generic_stylesheet {
+            resources.hide_selectors.extend(self.cosmetic_cache.misc_generic_selectors.iter().cloned());
+            resources.apply_generic_stylesheet = false;
+        }
+        resources
+    }
+
+    /// Restricts generic cosmetic filtering to first-party frames in
+    /// `frame_cosmetic_resources`. This can greatly reduce the work needed for pages which embed
+    /// many third-party frames with large numbers of classes and ids.
+    pub fn set_first_party_generic_cosmetics_only(&mut self, enabled: bool) {
+        self.first_party_generic_cosmetics_only = enabled;
+    }
+
+    /// Returns the cosmetic resources for each of `hostnames`, keyed by hostname, as in
+    /// `CosmeticFilterCache::resources_for_hosts`. `$generichide` exceptions aren't considered, so
+    /// frames with one should be queried using `frame_cosmetic_resources` instead.
+    pub fn hosts_cosmetic_resources(&self, hostnames: &[&str]) -> HashMap<String, UrlSpecificResources> {
+        self.cosmetic_cache.resources_for_hosts(hostnames)
+            .into_iter()
+            .map(|(hostname, resources)| (hostname, self.unless_generic_stylesheet(resources)))
+            .collect()
+    }
+
+    /// Reports whether `selector` would be hidden on the page at `url`, and if so, by which kind
+    /// of rule. Unlike `CosmeticFilterCache::would_hide`, generic rules are disregarded if the
+    /// page has a `$generichide` exception.
+    pub fn would_hide(&self, url: &str, selector: &str) -> Option<HideSource> {
+        let request = Request::from_url(url).ok()?;
+        match self.cosmetic_cache.would_hide(&request.hostname, selector) {
+            Some(HideSource::Generic) if self.blocker.check_generic_hide(&request) => None,
+            source => source,
+        }
+    }
+
+    /// Sets whether requests to `localhost` or loopback IP addresses are exempt from all network
+    /// rules. By default, they are checked like any other request.
+    pub fn set_loopback_exempt(&mut self, exempt: bool) {
+        self.blocker.set_loopback_exempt(exempt);
+    }
+
+    /// Sets whether network requests initiated from browser extension pages are treated as
+    /// first-party or of undefined party by `check_network_urls` and its variants. By default,
+    /// they are first-party to any request. Methods which take a source hostname recognize
+    /// extension pages by an origin like `chrome-extension://abcdefghijklmnop` in its place.
+    pub fn set_extension_source_policy(&mut self, policy: ExtensionSourcePolicy) {
+        self.extension_source_policy = policy;
+    }
+
+    /// Sets whether `check_network_urls` and its variants should fill in `should_collapse` for
+    /// blocked images, media, plugins, and frames by looking for hide rules on the source page
+    /// which target elements of that kind, e.g. `example.com##img.banner`. Disabled by default.
+    ///
+    /// Methods which take a source hostname rather than a URL only know the page by its
+    /// hostname, so `$generichide` rules are checked against its root URL, `https://<hostname>/`.
+    pub fn set_collapse_hints(&mut self, enabled: bool) {
+        self.collapse_hints = enabled;
+    }
+
+    /// Sets whether `check_network_urls`, its variants, and `get_removeparam_url` should infer the
+    /// type of requests reported with a generic type, like `other`, from the file extension of the
+    /// URL. For example, webfonts loaded by CSS `@font-face` rules are then matched by `$font`
+    /// rules. Disabled by default.
+    pub fn set_type_inference(&mut self, enabled: bool) {
+        self.type_inference = enabled;
+    }
+}
+
+/// Builds the request used to check a source page for `$generichide`, from its parsed URL.
+fn source_page_request(parsed_source: Option<&RequestUrl>) -> Option<Request> {
+    parsed_source.map(|source| Request::from_urls_with_hostname(&source.url, source.hostname(), "", "", None))
+}
+
+/// Like `source_page_request`, but for a source page only known by its hostname.
+fn source_hostname_request(source_hostname: &str) -> Option<Request> {
+    if source_hostname.is_empty() || source_hostname.contains("://") {
+        return None;
     }
+    let url = format!("https://{}/", source_hostname);
+    Some(Request::from_urls_with_hostname(&url, source_hostname, "", "", None))
 }
 
 
@@ -592,6 +1209,31 @@ mod tests {
         assert!(engine.check_network_urls("https://sub.example.com", "https://sub.example.com", "document").exception.is_some());
     }
 
+    #[test]
+    fn match_case() {
+        let rules = [
+            "/static/ad.$match-case",
+            "/static/ads/*$match-case",
+            "||example.com/Banner$match-case",
+            "/pixel.",
+        ].iter().map(|rule| rule.to_string()).collect::<Vec<_>>();
+        let engine = Engine::from_rules(&rules, Default::default());
+
+        let checks = [
+            ("https://x.com/static/ad.js", "x.com", true),
+            ("https://x.com/Static/Ad.js", "x.com", false),
+            ("https://x.com/static/ads/1.png", "x.com", true),
+            ("https://x.com/STATIC/ADS/1.png", "x.com", false),
+            ("https://example.com/Banner.png", "example.com", true),
+            ("https://example.com/banner.png", "example.com", false),
+            ("https://x.com/Pixel.gif", "x.com", true),
+        ];
+        for (url, hostname, matched) in checks.iter() {
+            assert_eq!(engine.check_network_urls(url, "https://example.net", "image").matched, *matched, "{}", url);
+            assert_eq!(engine.check_network_urls_with_hostnames(url, hostname, "example.net", "image", None).matched, *matched, "{}", url);
+        }
+    }
+
     #[test]
     fn implicit_all() {
         {
@@ -640,20 +1282,837 @@ mod tests {
             String::from("@@||example2.com/test.html$generichide"),
             String::from("example2.com##.block"),
         ];
-        let url_results = vec![
+        let url_results = [
             ("https://example.com", vec![".block"], true),
             ("https://example.com/test.html", vec![".block"], true),
             ("https://example2.com", vec![".block", "a[href=\"generic.com\"]"], false),
             ("https://example2.com/test.html", vec![".block"], true),
         ];
 
-        let engine = Engine::from_rules(&filters, Default::default());
+        let mut engine = Engine::from_rules(&filters, Default::default());
 
-        url_results.into_iter().for_each(|(url, expected_result, expected_generichide)| {
+        url_results.iter().for_each(|(url, expected_result, expected_generichide)| {
             let result = engine.url_cosmetic_resources(url);
             assert_eq!(result.hide_selectors, expected_result.iter().map(|s| s.to_string()).collect::<HashSet<_>>());
-            assert_eq!(result.generichide, expected_generichide);
+            assert_eq!(result.generichide, *expected_generichide);
+            assert_eq!(result.apply_generic_stylesheet, false);
+        });
+
+        // Once enabled, the generic selectors are served in a separate stylesheet instead
+        engine.set_generic_stylesheet(true);
+        assert_eq!(engine.generic_cosmetic_stylesheet(), "a[href=\"generic.com\"]{display:none !important}\n");
+        let result = engine.url_cosmetic_resources("https://example2.com");
+        assert_eq!(result.hide_selectors, [".block"].iter().map(|s| s.to_string()).collect::<HashSet<_>>());
+        assert_eq!(result.apply_generic_stylesheet, true);
+        assert_eq!(engine.url_cosmetic_resources("https://example.com").apply_generic_stylesheet, false);
+    }
+
+    #[test]
+    fn generic_cosmetic_limit() {
+        let filters = vec![
+            String::from("##.ad"),
+            String::from("##.banner"),
+            String::from("##a[href=\"generic.com\"]"),
+            String::from("##div[data-ad]"),
+            String::from("example.com##.block"),
+            String::from("example.com##div.sponsored"),
+        ];
+
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        assert_eq!(engine.set_generic_cosmetic_limit(Some(2)), 2);
+
+        let result = engine.url_cosmetic_resources("https://example.com");
+        assert_eq!(result.hide_selectors, [".block", "div.sponsored"].iter().map(|s| s.to_string()).collect::<HashSet<_>>());
+
+        let selectors = engine.hidden_class_id_selectors(&["ad".into(), "banner".into()], &[], &result.exceptions);
+        assert_eq!(selectors.len(), 2);
+
+        // The limit is kept when rules are reloaded
+        let serialized = Engine::from_rules(&filters, Default::default()).serialize_raw().unwrap();
+        engine.deserialize(&serialized).unwrap();
+        let selectors = engine.hidden_class_id_selectors(&["ad".into(), "banner".into()], &[], &Default::default());
+        assert_eq!(selectors.len(), 2);
+        assert_eq!(engine.url_cosmetic_resources("https://example.com").hide_selectors.len(), 2);
+
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_list("generic", &filters, Default::default());
+        let mut engine = Engine::from_filter_set(filter_set, true);
+        assert_eq!(engine.set_generic_cosmetic_limit(Some(3)), 1);
+        assert_eq!(engine.replace_list("generic", &[
+            String::from("##.ad"),
+            String::from("##.banner"),
+            String::from("##.promo"),
+            String::from("##.sponsor"),
+            String::from("##.tracker"),
+        ]), true);
+        let selectors = engine.hidden_class_id_selectors(&["ad".into(), "banner".into(), "promo".into(), "sponsor".into(), "tracker".into()], &[], &Default::default());
+        assert_eq!(selectors.len(), 3);
+
+        // Without a limit, nothing is dropped
+        assert_eq!(engine.set_generic_cosmetic_limit(None), 0);
+        assert_eq!(engine.replace_list("generic", &filters), true);
+        assert_eq!(engine.url_cosmetic_resources("https://example2.com").hide_selectors.len(), 2);
+    }
+
+    #[test]
+    fn site_report() {
+        let filters = vec![
+            String::from("||example.com^"),
+            String::from("||ads.example.com^$third-party"),
+            String::from("/ads.js$domain=example.com|other.com"),
+            String::from("/tracker.js$domain=example.com,important"),
+            String::from("/unrelated.js$domain=other.com"),
+            String::from("@@||example.com/allowed.js"),
+            String::from("/generic-ad.js"),
+            String::from("##.generic"),
+            String::from("example.com##.banner"),
+            String::from("example.com##div.sponsored"),
+            String::from("sub.example.com#@#.banner"),
+            String::from("other.com##.banner"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        assert_eq!(engine.site_report("example.com"), SiteReport {
+            cosmetic_rules: 2,
+            hostname_network_rules: 1,
+            domain_scoped_network_rules: 2,
         });
+        assert_eq!(engine.site_report("sub.example.com"), SiteReport {
+            cosmetic_rules: 1,
+            hostname_network_rules: 1,
+            domain_scoped_network_rules: 2,
+        });
+        assert_eq!(engine.site_report("ads.example.com"), SiteReport {
+            cosmetic_rules: 2,
+            hostname_network_rules: 2,
+            domain_scoped_network_rules: 2,
+        });
+        assert_eq!(engine.site_report("unknown.com"), SiteReport::default());
+
+        // Ports are ignored
+        assert_eq!(engine.site_report("example.com:8080"), engine.site_report("example.com"));
+
+        // Only anchors for the hostname or its parent domains count, and only if their tag is enabled
+        let filters = vec![
+            String::from("||ample.com^"),
+            String::from("||example.com:8080^"),
+            String::from("||example.com^$tag=extra"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        assert_eq!(engine.site_report("example.com").hostname_network_rules, 1);
+        engine.enable_tags(&["extra"]);
+        assert_eq!(engine.site_report("example.com").hostname_network_rules, 2);
+        engine.disable_tags(&["extra"]);
+        assert_eq!(engine.site_report("example.com").hostname_network_rules, 1);
+    }
+
+    #[test]
+    fn skipped_rules() {
+        use crate::filters::network::NetworkFilterError;
+
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filters(&[
+            String::from("! Title: a comment"),
+            String::from(""),
+            String::from("||ads.com^"),
+            String::from("||popups.com^$popunder"),
+            String::from("||example.com^$script,~popunder,third-party"),
+            String::from("||foo.com$redirect-url=http://xyz.com"),
+            String::from("||bar.com^$badfilter,~important"),
+            String::from("example.com##+js()"),
+            String::from("example.com#$#body { color: red }"),
+        ], Default::default());
+        filter_set.add_list("list", &[String::from("/ads.js$elemhide")], Default::default());
+        let (engine, skipped) = Engine::from_filter_set_with_skipped(filter_set, true);
+
+        assert_eq!(skipped, vec![
+            (String::from("||popups.com^$popunder"), SkipReason::UnsupportedOption(String::from("popunder"))),
+            (String::from("||example.com^$script,~popunder,third-party"), SkipReason::UnsupportedOption(String::from("popunder"))),
+            (String::from("||foo.com$redirect-url=http://xyz.com"), SkipReason::UnsupportedOption(String::from("redirect-url"))),
+            (String::from("||bar.com^$badfilter,~important"), SkipReason::InvalidNetworkFilter(NetworkFilterError::NegatedImportant)),
+            (String::from("/ads.js$elemhide"), SkipReason::UnsupportedOption(String::from("elemhide"))),
+        ]);
+        assert_eq!(engine.check_network_urls("https://ads.com/", "https://example.com", "script").matched, true);
+    }
+
+    #[test]
+    fn pattern_length_limit() {
+        use crate::filters::network::NetworkFilterError;
+
+        let long_rule = format!("/{}/*$script", "a".repeat(100));
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filters(&[
+            String::from("||ads.com^"),
+            String::from("||example.com/some/path/ad.js|$script"),
+            long_rule.clone(),
+        ], ParseOptions { max_pattern_length: Some(32), ..Default::default() });
+        let (engine, skipped) = Engine::from_filter_set_with_skipped(filter_set, true);
+
+        assert_eq!(skipped, vec![(long_rule, SkipReason::InvalidNetworkFilter(NetworkFilterError::PatternTooLong))]);
+        assert_eq!(engine.check_network_urls("https://ads.com/", "https://example.com", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://example.com/some/path/ad.js", "https://example.com", "script").matched, true);
+        let long_url = format!("https://cdn.com/{}/x.js", "a".repeat(100));
+        assert_eq!(engine.check_network_urls(&long_url, "https://example.com", "script").matched, false);
+    }
+
+    #[test]
+    fn scoped_filters() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filters_scoped(&[
+            String::from("||ads.com^"),
+            String::from("/banner.png$domain=sub.a.com|c.com"),
+            String::from("##.ad"),
+            String::from("c.com,b.com##.promo"),
+        ], &[String::from("a.com"), String::from("b.com")], Default::default());
+        filter_set.add_filters_scoped(&[
+            String::from("0.0.0.0 tracker.com"),
+        ], &[String::from("a.com")], ParseOptions { format: FilterFormat::Hosts, ..Default::default() });
+        let engine = Engine::from_filter_set(filter_set, true);
+
+        assert_eq!(engine.check_network_urls("https://ads.com/x.js", "https://a.com", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://ads.com/x.js", "https://www.b.com", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://ads.com/x.js", "https://c.com", "script").matched, false);
+        assert_eq!(engine.check_network_urls("https://cdn.com/banner.png", "https://sub.a.com", "image").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/banner.png", "https://a.com", "image").matched, false);
+        assert_eq!(engine.check_network_urls("https://cdn.com/banner.png", "https://c.com", "image").matched, false);
+        assert_eq!(engine.check_network_urls("https://tracker.com/", "https://a.com", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://tracker.com/", "https://b.com", "script").matched, false);
+
+        let hide_selectors = |url| engine.url_cosmetic_resources(url).hide_selectors;
+        assert_eq!(hide_selectors("https://a.com").contains(".ad"), true);
+        assert_eq!(hide_selectors("https://a.com").contains(".promo"), false);
+        assert_eq!(hide_selectors("https://b.com").contains(".promo"), true);
+        assert_eq!(hide_selectors("https://c.com").is_empty(), true);
+        assert_eq!(engine.hidden_class_id_selectors(&[String::from("ad")], &[], &HashSet::new()).is_empty(), true);
+    }
+
+    #[test]
+    fn regex_stats() {
+        let rules = vec![
+            String::from("/ads^"),
+            String::from("=ads^"),
+            String::from("_ads^"),
+            String::from("/^https?:\\/\\/x\\.com\\/[a-z]+\\.js/"),
+            String::from("||example.com/ads/*.js"),
+            String::from("/b*.gif$domain=a.com"),
+            String::from("||plain.com^"),
+            String::from("@@||plain.com/ok^"),
+        ];
+
+        let engine = Engine::from_rules_parametrised(&rules, Default::default(), false, false);
+        assert_eq!(engine.regex_stats(), RegexStats { regexes: 7, patterns: 7, complete_regexes: 1 });
+
+        // The first three rules are fused into a single `RegexSet`
+        let engine = Engine::from_rules_parametrised(&rules, Default::default(), false, true);
+        assert_eq!(engine.regex_stats(), RegexStats { regexes: 5, patterns: 7, complete_regexes: 1 });
+    }
+
+    #[test]
+    fn validate() {
+        let rules = [
+            "||ads.example.com^",
+            "/banner/*/ad_",
+            "/ads[0-9]+\\.js/$script",
+            "||example.com/ad.js$redirect=noopjs",
+            "||example.com/ad.png$redirect=data:image/gif;base64,R0lGODlhAQABAAAAACw=",
+            "@@||example.com/ad.gif$redirect=1x1.gif",
+        ].iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        let mut engine = Engine::from_rules_debug(&rules, Default::default());
+        engine.add_resource(Resource {
+            name: "noopjs".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+        }).unwrap();
+
+        let report = engine.validate();
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(report.regexes_checked, 2);
+
+        // Corrupt the engine by adding filters to it directly
+        let mut empty_fused = NetworkFilter::parse("/tracker", true, Default::default()).unwrap();
+        empty_fused.filter = crate::filters::network::FilterPart::AnyOf(vec![]);
+        let bad_regex = NetworkFilter::parse("/pixel[/", true, Default::default()).unwrap();
+        engine.blocker.filters.add_filter(empty_fused);
+        engine.blocker.filters.add_filter(bad_regex);
+        engine.blocker.redirects.add_filter(NetworkFilter::parse("||example.com/ad.css$redirect=noopcss", true, Default::default()).unwrap());
+
+        let report = engine.validate();
+        assert!(!report.is_valid());
+        assert_eq!(report.empty_fused_filters, vec!["/tracker"]);
+        assert_eq!(report.missing_redirect_resources, vec!["noopcss"]);
+        assert_eq!(report.regex_errors, vec!["/pixel[/"]);
+        assert_eq!(report.regexes_checked, 3);
+    }
+
+    #[test]
+    fn decision_chain() {
+        let rules = [
+            "||ads.example.com^",
+            "@@||ads.example.com/allowed.js$script",
+            "||example.org^$csp=script-src 'none'",
+        ].iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        let engine = Engine::from_rules_debug(&rules, Default::default());
+
+        let request = Request::from_urls("https://ads.example.com/allowed.js", "https://example.org", "script").unwrap();
+        let chain = engine.decision_chain(&request);
+        assert_eq!(chain.steps, vec![
+            DecisionStep::Party { first_party: Some(false), third_party: Some(true) },
+            DecisionStep::Blocked("||ads.example.com^".to_owned()),
+            DecisionStep::Excepted("@@||ads.example.com/allowed.js$script".to_owned()),
+        ]);
+        assert_eq!(chain.result.matched, false);
+        assert_eq!(chain.result.exception, Some("@@||ads.example.com/allowed.js$script".to_owned()));
+
+        let request = Request::from_urls("https://ads.example.com/allowed.js", "https://example.org", "image").unwrap();
+        let chain = engine.decision_chain(&request);
+        assert_eq!(chain.steps, vec![
+            DecisionStep::Party { first_party: Some(false), third_party: Some(true) },
+            DecisionStep::Blocked("||ads.example.com^".to_owned()),
+        ]);
+        assert_eq!(chain.result.matched, true);
+
+        let request = Request::from_urls("https://example.org", "https://example.org", "document").unwrap();
+        let chain = engine.decision_chain(&request);
+        assert_eq!(chain.steps, vec![
+            DecisionStep::Party { first_party: Some(true), third_party: Some(false) },
+            DecisionStep::Csp("script-src 'none'".to_owned()),
+        ]);
+        assert_eq!(chain.result.matched, false);
+    }
+
+    #[test]
+    fn decision_chain_fused_and_exempt_requests() {
+        let rules = [
+            "/static/ad-",
+            "/static/ad.",
+            "/static/ads/*",
+            "||localhost^",
+        ].iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        let mut engine = Engine::from_rules_debug(&rules, Default::default());
+
+        // Only the fused rules which match are listed
+        let request = Request::from_urls("https://cdn.com/static/ad-1.png", "https://example.org", "image").unwrap();
+        let chain = engine.decision_chain(&request);
+        assert_eq!(chain.steps, vec![
+            DecisionStep::Party { first_party: Some(false), third_party: Some(true) },
+            DecisionStep::Blocked("/static/ad-".to_owned()),
+        ]);
+        assert_eq!(chain.result.matched, true);
+
+        // Exempt requests aren't blocked by any rule
+        engine.set_loopback_exempt(true);
+        let request = Request::from_urls("http://localhost/static/ad-1.png", "https://example.org", "image").unwrap();
+        let chain = engine.decision_chain(&request);
+        assert_eq!(chain.steps, vec![
+            DecisionStep::Party { first_party: Some(false), third_party: Some(true) },
+        ]);
+        assert_eq!(chain.result.matched, false);
+    }
+
+    #[test]
+    fn webrtc_rules() {
+        let filters = vec![
+            String::from("||stun.tracker.com^$webrtc"),
+            String::from("||turn.ads.com^$webrtc,third-party"),
+            String::from("||cdn.com^"),
+            String::from("/stun-tracker^"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        assert_eq!(engine.check_network_urls("stun:stun.tracker.com:3478", "https://example.com", "webrtc").matched, true);
+        assert_eq!(engine.check_network_urls("stun.tracker.com:19302", "https://example.com", "webrtc").matched, true);
+        assert_eq!(engine.check_network_urls("turn:turn.ads.com?transport=udp", "https://example.com", "webrtc").matched, true);
+        assert_eq!(engine.check_network_urls("turn:turn.ads.com?transport=udp", "https://ads.com", "webrtc").matched, false);
+        assert_eq!(engine.check_network_urls("stun:stun.example.com:3478", "https://example.com", "webrtc").matched, false);
+        // Rules without `$webrtc` don't apply to WebRTC connections, and vice versa
+        assert_eq!(engine.check_network_urls("stun:cdn.com:3478", "https://example.com", "webrtc").matched, false);
+        assert_eq!(engine.check_network_urls("stun://stun-tracker:3478", "https://example.com", "webrtc").matched, false);
+        assert_eq!(engine.check_network_urls("https://stun.tracker.com/", "https://example.com", "xhr").matched, false);
+    }
+
+    #[test]
+    fn check_with_timing() {
+        let filters = vec![
+            String::from("/^https:\\/\\/example\\.com\\/ad/"),
+            String::from("/banner.png"),
+            String::from("@@||example.com/ad-allowed"),
+        ];
+        let engine = Engine::from_rules_debug(&filters, Default::default());
+
+        let checks = [
+            ("https://example.com/ad.js", "https://example.com", "script"),
+            ("https://example.com/ad-allowed.js", "https://example.com", "script"),
+            ("https://example.com/banner.png", "https://example.com", "image"),
+            ("https://other.com/ad.js", "https://example.com", "script"),
+        ];
+        for (url, source_url, request_type) in checks.iter() {
+            let (result, timing) = engine.check_network_urls_with_timing(url, source_url, request_type);
+            let untimed = engine.check_network_urls(url, source_url, request_type);
+            assert_eq!(result.matched, untimed.matched);
+            assert_eq!(result.filter, untimed.filter);
+            assert_eq!(result.exception, untimed.exception);
+            assert!(timing.total >= timing.tokenization + timing.regex_evaluation);
+        }
+
+        let (result, timing) = engine.check_network_urls_with_timing("https://example.com/ad.js", "https://example.com", "script");
+        assert_eq!(result.matched, true);
+        assert!(timing.total > std::time::Duration::from_secs(0));
+        assert!(timing.filters_checked > 0);
+        assert_eq!(timing.regex_filters_checked, 1);
+        assert!(timing.regex_evaluation > std::time::Duration::from_secs(0));
+    }
+
+    #[test]
+    fn document_popup_rules() {
+        let filters = vec![
+            String::from("||popups.com^$document,popup"),
+            String::from("||ads.com/landing$popup"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        // New-tab navigations are blocked as popups
+        assert_eq!(engine.check_network_urls("https://popups.com/offer", "https://example.com", "popup").matched, true);
+        assert_eq!(engine.check_network_urls("https://ads.com/landing", "https://example.com", "popup").matched, true);
+        // `$document` still applies to same-tab navigations, but `$popup` alone does not
+        assert_eq!(engine.check_network_urls("https://popups.com/offer", "https://example.com", "document").matched, true);
+        assert_eq!(engine.check_network_urls("https://ads.com/landing", "https://example.com", "document").matched, false);
+        // Neither applies to subresources
+        assert_eq!(engine.check_network_urls("https://popups.com/offer.js", "https://example.com", "script").matched, false);
+        assert_eq!(engine.check_network_urls("https://popups.com/frame", "https://example.com", "sub_frame").matched, false);
+        assert_eq!(engine.check_network_urls("https://ads.com/landing", "https://example.com", "image").matched, false);
+    }
+
+    #[test]
+    fn would_hide() {
+        let rules = vec![
+            String::from("##.ad"),
+            String::from("example.com##.sidebar"),
+            String::from("@@||example.com^$generichide"),
+        ];
+        let engine = Engine::from_rules(&rules, Default::default());
+
+        assert_eq!(engine.would_hide("https://other.com/page", ".ad"), Some(HideSource::Generic));
+        assert_eq!(engine.would_hide("https://example.com/page", ".ad"), None);
+        assert_eq!(engine.would_hide("https://example.com/page", ".sidebar"), Some(HideSource::Specific));
+        assert_eq!(engine.would_hide("https://other.com/page", ".sidebar"), None);
+    }
+
+    #[test]
+    fn relevant_tokens() {
+        let rules = vec![
+            String::from("##.generic-ad"),
+            String::from("###generic-banner"),
+            String::from("example.com##.ad"),
+            String::from("example.com##div#promo > span"),
+            String::from("example.com##a[href^=\"https://ads.\"]"),
+            String::from("other.com##.other-ad"),
+            String::from("other.com###other-promo"),
+        ];
+        let engine = Engine::from_rules(&rules, Default::default());
+
+        let expected = [".ad", "#promo"].iter().map(|s| s.to_string()).collect::<HashSet<_>>();
+        assert_eq!(engine.relevant_tokens("https://www.example.com/page"), expected);
+        assert_eq!(engine.relevant_tokens("example.com"), expected);
+        assert_eq!(engine.relevant_tokens("https://unrelated.com/"), HashSet::new());
+        assert_eq!(engine.relevant_tokens("https://"), HashSet::new());
+    }
+
+    #[test]
+    fn partitioned_party() {
+        let rules = vec![
+            String::from("||widget.com^$third-party"),
+            String::from("||cdn.com/lib.js$first-party"),
+            String::from("||widget.com/ok.js$domain=widget.com"),
+        ];
+        let engine = Engine::from_rules(&rules, Default::default());
+
+        // Same-site to the widget frame, but third-party to the top-level page
+        assert_eq!(engine.check_network_urls("https://widget.com/ad.js", "https://widget.com/frame", "script").matched, false);
+        assert_eq!(engine.check_network_urls_partitioned("https://widget.com/ad.js", "https://widget.com/frame", "https://news.com/", "script").matched, true);
+        assert_eq!(engine.check_network_urls_partitioned("https://widget.com/ad.js", "https://news.com/", "https://widget.com/", "script").matched, false);
+
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://other.com/frame", "script").matched, false);
+        assert_eq!(engine.check_network_urls_partitioned("https://cdn.com/lib.js", "https://other.com/frame", "https://www.cdn.com/", "script").matched, true);
+
+        // `$domain` still refers to the frame which made the request
+        assert_eq!(engine.check_network_urls_partitioned("https://widget.com/ok.js", "https://widget.com/frame", "https://widget.com/", "script").matched, true);
+        assert_eq!(engine.check_network_urls_partitioned("https://widget.com/ok.js", "https://news.com/", "https://widget.com/", "script").matched, false);
+    }
+
+    #[test]
+    fn type_inference() {
+        let mut engine = Engine::from_rules(&["||fonts.example.com^$font".to_owned()], Default::default());
+        let url = "https://fonts.example.com/inter.woff2";
+
+        assert_eq!(engine.check_network_urls(url, "https://example.org", "other").matched, false);
+        engine.set_type_inference(true);
+        assert_eq!(engine.check_network_urls(url, "https://example.org", "other").matched, true);
+        assert_eq!(engine.check_network_urls(url, "https://example.org", "xmlhttprequest").matched, false);
+        assert_eq!(engine.check_network_urls("https://fonts.example.com/inter.css", "https://example.org", "other").matched, false);
+
+        // Every entry point infers the same type
+        assert_eq!(engine.check_network_urls_partitioned(url, "https://example.org", "https://example.org", "other").matched, true);
+        assert_eq!(engine.check_network_urls_batch(&[url], "https://example.org", "other")[0].matched, true);
+        assert_eq!(engine.check_network_urls_with_hostnames(url, "fonts.example.com", "example.org", "other", None).matched, true);
+        assert_eq!(engine.check_network_urls_with_hostnames_subset(url, "fonts.example.com", "example.org", "other", None, false, false).matched, true);
+    }
+
+    #[test]
+    fn removeparam() {
+        let engine = Engine::from_rules(&["||example.com^$removeparam=utm_source".to_owned()], Default::default());
+        let url = "https://example.com/Post?utm_source=feed&ID=1";
+        let expected = Some(String::from("https://example.com/Post?ID=1"));
+
+        assert_eq!(engine.get_removeparam_url(url, "https://example.org", "document"), expected);
+        assert_eq!(engine.get_removeparam_url("https://example.com/Post?ID=1", "https://example.org", "document"), None);
+
+        // `$removeparam` rules survive serialization
+        let serialized = engine.serialize_raw().unwrap();
+        let mut deserialized = Engine::new(false);
+        deserialized.deserialize(&serialized).unwrap();
+        assert_eq!(deserialized.get_removeparam_url(url, "https://example.org", "document"), expected);
+    }
+
+    #[test]
+    fn collapse_hints() {
+        let rules = vec![
+            String::from("||ads.com^"),
+            String::from("example.com##img.banner"),
+            String::from("example.com##.sidebar > iframe[src*=\"ads\"]"),
+            String::from("other.com##div.ad"),
+        ];
+        let mut engine = Engine::from_rules(&rules, Default::default());
+
+        // Hints are only computed once enabled
+        assert_eq!(engine.check_network_urls("https://ads.com/ad.png", "https://example.com", "image").should_collapse, false);
+        engine.set_collapse_hints(true);
+
+        let result = engine.check_network_urls("https://ads.com/ad.png", "https://example.com", "image");
+        assert_eq!(result.matched, true);
+        assert_eq!(result.should_collapse, true);
+        assert_eq!(engine.check_network_urls("https://ads.com/frame", "https://www.example.com", "sub_frame").should_collapse, true);
+        // No rule on the page targets the blocked element type
+        assert_eq!(engine.check_network_urls("https://ads.com/ad.mp4", "https://example.com", "media").should_collapse, false);
+        assert_eq!(engine.check_network_urls("https://ads.com/ad.png", "https://other.com", "image").should_collapse, false);
+        // Requests that weren't blocked don't need collapsing
+        let result = engine.check_network_urls("https://cdn.com/logo.png", "https://example.com", "image");
+        assert_eq!(result.matched, false);
+        assert_eq!(result.should_collapse, false);
+
+        // Every check method applies the hint
+        assert_eq!(engine.check_network_urls_with_hostnames("https://ads.com/ad.png", "ads.com", "example.com", "image", None).should_collapse, true);
+        assert_eq!(engine.check_network_urls_with_hostnames("https://ads.com/ad.png", "ads.com", "other.com", "image", None).should_collapse, false);
+        assert_eq!(engine.check_network_urls_partitioned("https://ads.com/ad.png", "https://example.com", "https://top.com", "image").should_collapse, true);
+        let results = engine.check_network_urls_batch(&["https://ads.com/a.png", "https://cdn.com/b.png"], "https://example.com", "image");
+        assert_eq!(results.iter().map(|r| r.should_collapse).collect::<Vec<_>>(), [true, false]);
+    }
+
+    #[test]
+    fn popup_exceptions() {
+        let filters = vec![
+            String::from("||ads.net^$popup"),
+            String::from("||ads.net/x.js"),
+            String::from("@@||ads.net/ok^$popup"),
+            String::from("||popups.com^$document,popup"),
+            String::from("@@||popups.com^$popup"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        // Popup exceptions cancel popup blocks
+        assert_eq!(engine.check_network_urls("https://ads.net/ok", "https://example.com", "popup").matched, false);
+        assert_eq!(engine.check_network_urls("https://ads.net/other", "https://example.com", "popup").matched, true);
+        assert_eq!(engine.check_network_urls("https://popups.com/offer", "https://example.com", "popup").matched, false);
+        // But not same-tab navigations or subresources
+        assert_eq!(engine.check_network_urls("https://popups.com/offer", "https://example.com", "document").matched, true);
+        assert_eq!(engine.check_network_urls("https://ads.net/x.js", "https://example.com", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://ads.net/x.js", "https://example.com", "popup").matched, true);
+    }
+
+    #[test]
+    fn disable_rule() {
+        let filters = vec![
+            String::from("/ad.js"),
+            String::from("-ads-"),
+            String::from("_ads_"),
+            String::from("=ads="),
+            String::from("||ads.com^"),
+            String::from("@@||ads.com/allowed"),
+            String::from("||tagged.com^$tag=stuff"),
+        ];
+        // Simple patterns are fused together with optimizations enabled
+        for optimize in [false, true].iter() {
+            let mut engine = Engine::from_rules_parametrised(&filters, Default::default(), false, *optimize);
+            engine.enable_tags(&["stuff"]);
+
+            let check = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://example.com", "image").matched;
+            let fused_filters = engine.blocker.filters.filter_map.values().flatten()
+                .filter(|f| matches!(f.filter, crate::filters::network::FilterPart::AnyOf(_)))
+                .count();
+            assert_eq!(fused_filters, if *optimize { 1 } else { 0 });
+
+            assert_eq!(engine.disable_rule("_ads_"), true);
+            assert_eq!(check(&engine, "https://example.com/x_ads_.png"), false);
+            assert_eq!(check(&engine, "https://example.com/x-ads-.png"), true);
+            assert_eq!(check(&engine, "https://example.com/?x=ads=1"), true);
+            assert_eq!(check(&engine, "https://example.com/ad.js"), true);
+
+            assert_eq!(engine.disable_rule("@@||ads.com/allowed"), true);
+            assert_eq!(check(&engine, "https://ads.com/allowed"), true);
+            assert_eq!(engine.disable_rule("||tagged.com^$tag=stuff"), true);
+            assert_eq!(check(&engine, "https://tagged.com/"), false);
+
+            // Rules can only be disabled once, and only if they exist
+            assert_eq!(engine.disable_rule("_ads_"), false);
+            assert_eq!(engine.disable_rule("/other.png"), false);
+            assert_eq!(engine.enable_rule("/ad.js"), false);
+
+            assert_eq!(engine.enable_rule("_ads_"), true);
+            assert_eq!(check(&engine, "https://example.com/x_ads_.png"), true);
+            assert_eq!(engine.enable_rule("@@||ads.com/allowed"), true);
+            assert_eq!(check(&engine, "https://ads.com/allowed"), false);
+            assert_eq!(engine.enable_rule("||tagged.com^$tag=stuff"), true);
+            assert_eq!(check(&engine, "https://tagged.com/"), true);
+            assert_eq!(engine.enable_rule("_ads_"), false);
+
+            // Tags still apply to re-enabled rules
+            engine.disable_tags(&["stuff"]);
+            assert_eq!(check(&engine, "https://tagged.com/"), false);
+
+            assert_eq!(engine.disable_rule("-ads-"), true);
+            assert_eq!(check(&engine, "https://example.com/x-ads-.png"), false);
+            assert_eq!(check(&engine, "https://example.com/x_ads_.png"), true);
+            assert_eq!(check(&engine, "https://example.com/?x=ads=1"), true);
+        }
+    }
+
+    #[test]
+    fn minimize_match() {
+        let filters = vec![
+            String::from("-ads-"),
+            String::from("_ads_"),
+            String::from("||ads.com^"),
+            String::from("/banner/*"),
+            String::from("@@||ads.com/allowed^"),
+            String::from("||cdn.com/lib.js$script,redirect=noop.js"),
+            String::from("||tracker.com^$third-party"),
+            String::from("/unrelated.png"),
+        ];
+        let mut engine = Engine::from_rules_debug(&filters, Default::default());
+        engine.add_resource(Resource {
+            name: "noop.js".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+        }).unwrap();
+
+        let requests = [
+            ("https://ads.com/allowed/banner/x.png?id=1#top", "https://example.com", "image"),
+            ("https://example.com/x_ads_.png?ref=-ads-", "https://example.com", "image"),
+            ("https://cdn.com/lib.js?v=2", "https://example.com", "script"),
+            ("https://example.com/image.png", "https://example.com", "image"),
+        ];
+        let expected_rules = [
+            vec!["/banner/*", "@@||ads.com/allowed^"],
+            vec!["_ads_"],
+            vec!["||cdn.com/lib.js$script,redirect=noop.js"],
+            vec![],
+        ];
+        for ((url, source_url, request_type), expected_rules) in requests.iter().zip(expected_rules.iter()) {
+            let request = Request::from_urls(url, source_url, request_type).unwrap();
+            let (rules, minimized) = engine.minimize_match(&request);
+
+            // `||ads.com^` would have worked just as well as `/banner/*`
+            if url.starts_with("https://ads.com") {
+                assert_eq!(rules.len(), 2);
+                assert!(rules.contains(&String::from("@@||ads.com/allowed^")));
+            } else {
+                assert_eq!(&rules, expected_rules);
+            }
+            assert_eq!(minimized.url.contains('?') || minimized.url.contains('#'), false);
+
+            let mut reproduction = Engine::from_rules_debug(&rules, Default::default());
+            reproduction.blocker.resources = engine.blocker.resources.clone();
+            assert_eq!(
+                Decision::from(&reproduction.blocker.check(&minimized)),
+                Decision::from(&engine.blocker.check(&request)),
+            );
+        }
+
+        // The query is kept if the decision depends on it
+        let request = Request::from_urls("https://example.com/x.png?ref=-ads-", "https://example.com", "image").unwrap();
+        let (rules, minimized) = engine.minimize_match(&request);
+        assert_eq!(rules, vec![String::from("-ads-")]);
+        assert_eq!(minimized.url, "https://example.com/x.png?ref=-ads-");
+    }
+
+    #[test]
+    fn replace_list() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_list("first", &[
+            String::from("/first-ad.js"),
+            String::from("||tracker.com^$badfilter"),
+            String::from("first.com##.first-ad"),
+        ], Default::default());
+        filter_set.add_list("second", &[
+            String::from("/second-ad.js"),
+            String::from("||tracker.com^"),
+        ], Default::default());
+        filter_set.add_filters(&[String::from("/untracked-ad.js")], Default::default());
+        let mut engine = Engine::from_filter_set(filter_set, true);
+        engine.use_tags(&["test"]);
+
+        let check = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://example.com", "script").matched;
+        assert_eq!(check(&engine, "https://example.com/first-ad.js"), true);
+        assert_eq!(check(&engine, "https://example.com/second-ad.js"), true);
+        assert_eq!(check(&engine, "https://example.com/untracked-ad.js"), true);
+        assert_eq!(check(&engine, "https://tracker.com/pixel.js"), false);
+        assert_eq!(engine.url_cosmetic_resources("https://first.com").hide_selectors.len(), 1);
+
+        assert_eq!(engine.replace_list("first", &[String::from("/first-new.js")]), true);
+        assert_eq!(check(&engine, "https://example.com/first-ad.js"), false);
+        assert_eq!(check(&engine, "https://example.com/first-new.js"), true);
+        assert_eq!(check(&engine, "https://example.com/second-ad.js"), true);
+        assert_eq!(check(&engine, "https://example.com/untracked-ad.js"), true);
+        // The `$badfilter` rule was removed with the old list
+        assert_eq!(check(&engine, "https://tracker.com/pixel.js"), true);
+        assert_eq!(engine.url_cosmetic_resources("https://first.com").hide_selectors.len(), 0);
+        assert_eq!(engine.tag_exists("test"), true);
+
+        assert_eq!(engine.replace_list("unknown", &[String::from("/unknown.js")]), false);
+        assert_eq!(check(&engine, "https://example.com/unknown.js"), false);
+
+        let mut engine = Engine::from_rules(&[String::from("/ad.js")], Default::default());
+        assert_eq!(engine.replace_list("first", &[String::from("/first-new.js")]), false);
+    }
+
+    #[test]
+    fn first_party_generic_cosmetics_only() {
+        let filters = vec![
+            String::from("##.ad"),
+            String::from("##div[class*=\"sponsor\"]"),
+            String::from("frame.com##.banner"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+
+        let resources = engine.frame_cosmetic_resources("https://frame.com/embed", true);
+        assert_eq!(resources.generichide, false);
+        assert_eq!(resources.hide_selectors.len(), 2);
+
+        engine.set_first_party_generic_cosmetics_only(true);
+
+        let resources = engine.frame_cosmetic_resources("https://frame.com/embed", true);
+        assert_eq!(resources.generichide, true);
+        let mut expected_hides = HashSet::new();
+        expected_hides.insert(".banner".to_owned());
+        assert_eq!(resources.hide_selectors, expected_hides);
+
+        let resources = engine.frame_cosmetic_resources("https://frame.com/embed", false);
+        assert_eq!(resources.generichide, false);
+        assert_eq!(resources.hide_selectors.len(), 2);
+        assert_eq!(engine.url_cosmetic_resources("https://frame.com/embed"), resources);
+    }
+
+    #[test]
+    fn srcset_candidates() {
+        let filters = vec![
+            String::from("||cdn.com/ads/*$image"),
+            String::from("/banner-*.jpg$image,third-party"),
+            String::from("@@||cdn.com/ads/allowed-$image"),
+            String::from("||cdn.com/photos/*.js$script"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        // `<img srcset="... 1x, ... 2x, ... 480w">` candidates, without their descriptors
+        let candidates = [
+            "https://cdn.com/ads/hero.jpg",
+            "https://cdn.com/ads/allowed-hero@2x.jpg",
+            "https://cdn.com/photos/banner-480.jpg",
+            "https://example.com/banner-480.jpg",
+            "https://cdn.com/photos/hero.js",
+            "not a url",
+        ];
+        let expected = [true, false, true, false, false, false];
+        for request_type in &["image", "imageset"] {
+            let results = engine.check_network_urls_batch(&candidates, "https://example.com", request_type);
+            assert_eq!(results.iter().map(|r| r.matched).collect::<Vec<_>>(), expected);
+            assert_eq!(results[1].exception.is_some(), true);
+            assert_eq!(results[5].error.is_some(), true);
+
+            for (candidate, result) in candidates.iter().zip(results.iter()) {
+                let single = engine.check_network_urls(candidate, "https://example.com", request_type);
+                assert_eq!(single.matched, result.matched);
+                assert_eq!(single.exception, result.exception);
+            }
+        }
+
+        // Image rules don't apply to other types of requests for the same URLs
+        let results = engine.check_network_urls_batch(&candidates, "https://example.com", "script");
+        assert_eq!(results.iter().map(|r| r.matched).collect::<Vec<_>>(), [false, false, false, false, true, false]);
+    }
+
+    #[test]
+    fn extension_source_policy() {
+        let filters = vec![
+            String::from("||tracker.com^$third-party"),
+            String::from("||cdn.com^$first-party"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        let source = "chrome-extension://abcdefghijklmnop/popup.html";
+
+        assert_eq!(engine.check_network_urls("https://tracker.com/t.js", source, "script").matched, false);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", source, "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://tracker.com/t.js", "https://example.com", "script").matched, true);
+
+        // Party options are ignored, like for requests without a source
+        engine.set_extension_source_policy(ExtensionSourcePolicy::UndefinedParty);
+        assert_eq!(engine.check_network_urls("https://tracker.com/t.js", source, "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "moz-extension://1234-5678/page.html", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://example.com", "script").matched, false);
+
+        // Methods taking a source hostname apply the policy to extension origins
+        let origin = "chrome-extension://abcdefghijklmnop";
+        assert_eq!(engine.check_network_urls_with_hostnames("https://tracker.com/t.js", "tracker.com", origin, "script", None).matched, true);
+        engine.set_extension_source_policy(ExtensionSourcePolicy::FirstParty);
+        assert_eq!(engine.check_network_urls_with_hostnames("https://tracker.com/t.js", "tracker.com", origin, "script", None).matched, false);
+        assert_eq!(engine.check_network_urls_with_hostnames("https://cdn.com/lib.js", "cdn.com", origin, "script", None).matched, true);
+    }
+
+    #[test]
+    fn entity_domain_rules() {
+        let filters = vec![
+            String::from("$script,domain=example.*"),
+            String::from("/ad.js$domain=a.*|b.*|c.*|d.*|other.*"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://www.example.co.uk", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://example.org", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://example-two.org", "script").matched, false);
+        assert_eq!(engine.check_network_urls("https://cdn.com/ad.js", "https://other.net", "script").matched, true);
+
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filters(&filters, ParseOptions { entity_expansion_limit: 64, ..Default::default() });
+        let engine = Engine::from_filter_set(filter_set, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://www.example.co.uk", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://example.org", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://example.ie", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://example.xyz", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://cdn.com/lib.js", "https://example-two.org", "script").matched, false);
+        assert_eq!(engine.check_network_urls("https://cdn.com/ad.js", "https://other.xyz", "script").matched, true);
+    }
+
+    #[test]
+    fn hostname_port_rules() {
+        let filters = vec![
+            String::from("||example.com:8080^"),
+            String::from("||other.com:443/ads/"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        assert_eq!(engine.check_network_urls("https://example.com:8080/ad.js", "https://example.com", "script").matched, true);
+        assert_eq!(engine.check_network_urls("https://example.com/ad.js", "https://example.com", "script").matched, false);
+        assert_eq!(engine.check_network_urls("https://other.com/ads/1.png", "https://other.com", "image").matched, true);
+        assert_eq!(engine.check_network_urls("http://other.com/ads/1.png", "http://other.com", "image").matched, false);
     }
 
     #[test]
@@ -5,11 +5,12 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::resources::ScriptletCall;
 use crate::utils::Hash;
 
 use css_validation::{is_valid_css_selector, is_valid_css_style};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum CosmeticFilterError {
     PunycodeError,
     InvalidStyleSpecifier,
@@ -20,6 +21,7 @@ pub enum CosmeticFilterError {
     GenericUnhide,
     GenericScriptInject,
     GenericStyle,
+    GenericShadowPiercing,
     DoubleNegation,
     EmptyRule,
 }
@@ -34,6 +36,8 @@ bitflags::bitflags! {
         const IS_CLASS_SELECTOR = 1 << 3;
         const IS_ID_SELECTOR = 1 << 4;
         const IS_SIMPLE = 1 << 5;
+        // The selector uses the `>>>` combinator to match elements inside shadow roots
+        const SHADOW_PIERCING = 1 << 6;
 
         // Careful with checking for NONE - will always match
         const NONE = 0;
@@ -256,7 +260,24 @@ impl CosmeticFilter {
                 CosmeticFilter::parse_after_sharp_nonscript(line, suffix_start_index, &mut selector, &mut style)?;
             }
 
-            if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) && !is_valid_css_selector(selector) {
+            let shadow_parts = if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) {
+                split_shadow_piercing(selector)
+            } else {
+                vec![]
+            };
+            if shadow_parts.len() > 1 {
+                // Generic rules are injected as plain stylesheets, which can't reach into shadow
+                // roots
+                if hostnames.is_none() && entities.is_none() {
+                    return Err(CosmeticFilterError::GenericShadowPiercing);
+                }
+                if style.is_some() {
+                    return Err(CosmeticFilterError::UnsupportedSyntax);
+                }
+                mask |= CosmeticFilterMask::SHADOW_PIERCING;
+            }
+
+            if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) && !shadow_parts.iter().all(|part| is_valid_css_selector(part.trim())) {
                 return Err(CosmeticFilterError::InvalidCssSelector);
             } else if let Some(ref style) = style {
                 if !is_valid_css_style(style) {
@@ -316,6 +337,17 @@ impl CosmeticFilter {
         }
     }
 
+    /// Returns the scriptlet name and arguments of a `+js(...)` rule, or `None` if this is not a
+    /// script injection rule. Exceptions like `example.com#@#+js(...)` return the scriptlet that
+    /// they except.
+    pub fn scriptlet(&self) -> Option<ScriptletCall> {
+        if self.mask.contains(CosmeticFilterMask::SCRIPT_INJECT) {
+            ScriptletCall::parse(&self.selector)
+        } else {
+            None
+        }
+    }
+
     /// Any cosmetic filter rule that specifies (possibly negated) hostnames or entities has a
     /// hostname constraint.
     pub fn has_hostname_constraint(&self) -> bool {
@@ -582,6 +614,34 @@ fn key_from_selector(selector: &str) -> Result<String, CosmeticFilterError> {
     }
 }
 
+/// Splits `selector` at each occurrence of the shadow-piercing `>>>` combinator, ignoring any
+/// within quoted strings. A selector without the combinator is returned as a single part.
+fn split_shadow_piercing(selector: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut part_start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in selector.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (_, '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '>') if i >= part_start && selector[i..].starts_with(">>>") => {
+                parts.push(&selector[part_start..i]);
+                part_start = i + 3;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&selector[part_start..]);
+    parts
+}
+
 #[cfg(test)]
 mod key_from_selector_tests {
     use super::key_from_selector;
@@ -644,6 +704,7 @@ mod parse_tests {
         is_unicode: bool,
         is_class_selector: bool,
         is_id_selector: bool,
+        shadow_piercing: bool,
     }
 
     impl From<&CosmeticFilter> for CosmeticFilterBreakdown {
@@ -662,6 +723,7 @@ mod parse_tests {
                 is_unicode: filter.mask.contains(CosmeticFilterMask::IS_UNICODE),
                 is_class_selector: filter.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR),
                 is_id_selector: filter.mask.contains(CosmeticFilterMask::IS_ID_SELECTOR),
+                shadow_piercing: filter.mask.contains(CosmeticFilterMask::SHADOW_PIERCING),
             }
         }
     }
@@ -688,6 +750,7 @@ mod parse_tests {
                 is_unicode: false,
                 is_class_selector: false,
                 is_id_selector: false,
+                shadow_piercing: false,
             }
         }
     }
@@ -1210,6 +1273,52 @@ mod parse_tests {
         );
     }
 
+    #[test]
+    fn shadow_piercing() {
+        check_parse_result(
+            "example.com##my-widget >>> .ad",
+            CosmeticFilterBreakdown {
+                selector: "my-widget >>> .ad".to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                shadow_piercing: true,
+                ..Default::default()
+            }
+        );
+        check_parse_result(
+            "example.com#@#.host >>> div >>> .ad",
+            CosmeticFilterBreakdown {
+                selector: ".host >>> div >>> .ad".to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                key: Some("host".to_string()),
+                is_class_selector: true,
+                unhide: true,
+                shadow_piercing: true,
+                ..Default::default()
+            }
+        );
+        // A combinator within a string is just part of the selector
+        check_parse_result(
+            r#"example.com##a[title=">>>"]"#,
+            CosmeticFilterBreakdown {
+                selector: r#"a[title=">>>"]"#.to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                ..Default::default()
+            }
+        );
+        check_parse_result(
+            "example.com##my-widget > .ad",
+            CosmeticFilterBreakdown {
+                selector: "my-widget > .ad".to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(CosmeticFilter::parse("##my-widget >>> .ad", false).err(), Some(CosmeticFilterError::GenericShadowPiercing));
+        assert_eq!(CosmeticFilter::parse("~example.com##my-widget >>> .ad", false).err(), Some(CosmeticFilterError::GenericShadowPiercing));
+        assert_eq!(CosmeticFilter::parse("example.com##my-widget >>> .ad:style(color: red)", false).err(), Some(CosmeticFilterError::UnsupportedSyntax));
+    }
+
     #[test]
     fn unicode() {
         check_parse_result(
@@ -1318,6 +1427,28 @@ mod parse_tests {
         let rule = CosmeticFilter::parse("test.com#@#+js(nowebrtc.js)", false).unwrap();
         assert!(rule.hidden_generic_rule().is_none());
     }
+
+    #[test]
+    fn scriptlet() {
+        let rule = CosmeticFilter::parse(r#"example.com##+js(set-constant.js, "foo.bar", 'a\, b', c\,d)"#, false).unwrap();
+        assert_eq!(rule.scriptlet(), Some(ScriptletCall {
+            name: "set-constant".to_owned(),
+            args: vec!["foo.bar".to_owned(), "a, b".to_owned(), "c,d".to_owned()],
+        }));
+
+        let rule = CosmeticFilter::parse("example.com#@#+js(nowebrtc)", false).unwrap();
+        assert_eq!(rule.mask.contains(CosmeticFilterMask::UNHIDE), true);
+        assert_eq!(rule.scriptlet(), Some(ScriptletCall { name: "nowebrtc".to_owned(), args: vec![] }));
+
+        let rule = CosmeticFilter::parse("example.com##+js()", false);
+        assert!(rule.is_err() || rule.unwrap().scriptlet().is_none());
+
+        // Selectors which merely look similar aren't scriptlets
+        let rule = CosmeticFilter::parse("example.com##.js(foo)", false);
+        assert!(rule.is_err() || rule.unwrap().scriptlet().is_none());
+        let rule = CosmeticFilter::parse("example.com##div[data-x=\"+js(a)\"]", false).unwrap();
+        assert_eq!(rule.scriptlet(), None);
+    }
 }
 
 #[cfg(test)]
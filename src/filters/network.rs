@@ -1,18 +1,56 @@
-use regex::{Regex, RegexSet};
+use regex::{Regex, RegexBuilder, RegexSet};
 use serde::{Deserialize, Serialize};
 use once_cell::sync::Lazy;
-use crate::url_parser::parse_url;
+use crate::url_parser::{normalize_path, parse_url};
 
+use std::borrow::Cow;
 use std::fmt;
 use std::sync::{Arc, RwLock};
 
 use crate::request;
 use crate::utils;
 use crate::utils::Hash;
-use crate::lists::ParseOptions;
+use crate::lists::{ParseOptions, SeparatorMode};
+use crate::resources::RedirectResource;
 
 pub const TOKENS_BUFFER_SIZE: usize = 200;
 
+/// Public suffixes that `entity.*` domains are expanded against when
+/// `ParseOptions::entity_expansion_limit` allows it.
+const ENTITY_EXPANSION_SUFFIXES: &[&str] = &[
+    "com", "net", "org", "info", "io", "co", "de", "fr", "it", "es", "nl", "pl", "ru", "be", "ch",
+    "at", "se", "dk", "no", "fi", "pt", "cz", "ca", "in", "jp", "br", "mx", "co.uk", "com.au",
+    "com.br", "co.jp", "com.tr",
+];
+
+/// Expands an entity like `example.*` into up to `limit` concrete domains, e.g. `example.com` and
+/// `example.co.uk`, for converting rules to formats that can't match entities directly.
+///
+/// Candidates are drawn in order from the same built-in list of common public suffixes used for
+/// `ParseOptions::entity_expansion_limit`, and are only kept if the public suffix list agrees that
+/// they are registrable domains. Returns `None` if `entity` is not an entity.
+pub fn expand_entity(entity: &str, limit: usize) -> Option<Vec<String>> {
+    let name = entity.strip_suffix(".*")?;
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(ENTITY_EXPANSION_SUFFIXES.iter()
+        .filter_map(|suffix| {
+            let candidate = format!("{}.{}", name, suffix);
+            // The suffix must be the candidate's entire public suffix, e.g. not `com` in `blogspot.com`
+            let (start, end) = crate::url_parser::get_host_domain(&candidate);
+            let registrable_label = candidate[start..end].strip_suffix(suffix)?.strip_suffix('.')?;
+            if end == candidate.len() && !registrable_label.is_empty() && !registrable_label.contains('.') {
+                Some(candidate)
+            } else {
+                None
+            }
+        })
+        .take(limit)
+        .collect())
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum NetworkFilterError {
     FilterParseError,
@@ -28,6 +66,8 @@ pub enum NetworkFilterError {
     GenericHideWithoutException,
     EmptyRedirection,
     RedirectionUrlInvalid,
+    RedirectionDataUrlInvalid,
+    OptionAfterDataRedirection,
     MultipleRedirections,
     UnrecognisedOption,
     NoRegex,
@@ -35,6 +75,10 @@ pub enum NetworkFilterError {
     RegexParsingError(regex::Error),
     PunycodeError,
     CspWithContentType,
+    PatternTooLong,
+    NegatedRemoveparam,
+    RemoveparamWithCspOrRedirection,
+    AmbiguousRemoveparam,
 }
 
 bitflags::bitflags! {
@@ -58,10 +102,14 @@ bitflags::bitflags! {
         const IS_REDIRECT_URL = 1 << 15;
         const THIRD_PARTY = 1 << 16;
         const FIRST_PARTY = 1 << 17;
-        const _EXPLICIT_CANCEL = 1 << 26;   // Unused
         const BAD_FILTER = 1 << 27;
         const GENERIC_HIDE = 1 << 30;
 
+        // WebRTC connections and navigations opening a new tab or window are not implied by any
+        // other types, and are only matched by rules that explicitly specify them.
+        const FROM_WEBRTC = 1 << 31;
+        const FROM_POPUP = 1 << 26;
+
         // Full document rules are not implied by negated types.
         const FROM_DOCUMENT = 1 << 29;
 
@@ -92,10 +140,14 @@ bitflags::bitflags! {
             Self::FROM_XMLHTTPREQUEST.bits;
 
         // Includes all remaining types, not implied by any negated types.
-        // TODO Could also include popup, inline-font, inline-script
+        // TODO Could also include inline-font, inline-script
         const FROM_ALL_TYPES = Self::FROM_NETWORK_TYPES.bits |
             Self::FROM_DOCUMENT.bits;
 
+        // Types which are only matched when explicitly specified.
+        const FROM_EXPLICIT_TYPES = Self::FROM_WEBRTC.bits |
+            Self::FROM_POPUP.bits;
+
         // Unless filter specifies otherwise, all these options are set by default
         const DEFAULT_OPTIONS = Self::FROM_NETWORK_TYPES.bits |
             Self::FROM_HTTP.bits |
@@ -121,16 +173,19 @@ impl From<&request::RequestType> for NetworkFilterMask {
             request::RequestType::Csp => NetworkFilterMask::UNMATCHED,
             request::RequestType::Document => NetworkFilterMask::FROM_DOCUMENT,
             request::RequestType::Dtd => NetworkFilterMask::FROM_OTHER,
-            request::RequestType::Fetch => NetworkFilterMask::FROM_OTHER,
+            // Lists don't distinguish `fetch` from `XMLHttpRequest`
+            request::RequestType::Fetch => NetworkFilterMask::FROM_XMLHTTPREQUEST,
             request::RequestType::Font => NetworkFilterMask::FROM_FONT,
             request::RequestType::Image => NetworkFilterMask::FROM_IMAGE,
             request::RequestType::Media => NetworkFilterMask::FROM_MEDIA,
             request::RequestType::Object => NetworkFilterMask::FROM_OBJECT,
             request::RequestType::Other => NetworkFilterMask::FROM_OTHER,
             request::RequestType::Ping => NetworkFilterMask::FROM_PING,
+            request::RequestType::Popup => NetworkFilterMask::FROM_POPUP,
             request::RequestType::Script => NetworkFilterMask::FROM_SCRIPT,
             request::RequestType::Stylesheet => NetworkFilterMask::FROM_STYLESHEET,
             request::RequestType::Subdocument => NetworkFilterMask::FROM_SUBDOCUMENT,
+            request::RequestType::Webrtc => NetworkFilterMask::FROM_WEBRTC,
             request::RequestType::Websocket => NetworkFilterMask::FROM_WEBSOCKET,
             request::RequestType::Xlst => NetworkFilterMask::FROM_OTHER,
             request::RequestType::Xmlhttprequest => NetworkFilterMask::FROM_XMLHTTPREQUEST,
@@ -228,6 +283,7 @@ enum NetworkFilterOption {
     Redirect(String),
     RedirectUrl(String),
     Csp(Option<String>),
+    Removeparam(String),
     Generichide,
     Document,
     Image(bool),
@@ -240,6 +296,8 @@ enum NetworkFilterOption {
     Subdocument(bool),
     XmlHttpRequest(bool),
     Websocket(bool),
+    Webrtc(bool),
+    Popup(bool),
     Font(bool),
 }
 
@@ -257,6 +315,8 @@ impl NetworkFilterOption {
             | Self::Subdocument(..)
             | Self::XmlHttpRequest(..)
             | Self::Websocket(..)
+            | Self::Webrtc(..)
+            | Self::Popup(..)
             | Self::Font(..) => true,
             _ => false,
         }
@@ -292,7 +352,7 @@ impl AbstractNetworkFilter {
             exception = true;
         }
 
-        let maybe_options_index: Option<usize> = twoway::rfind_str(&line, "$");
+        let maybe_options_index: Option<usize> = find_options_index(line);
 
         let mut options = None;
         if let Some(options_index) = maybe_options_index {
@@ -322,6 +382,9 @@ impl AbstractNetworkFilter {
         };
 
         let pattern = &line[filter_index_start..filter_index_end];
+        if opts.max_pattern_length.map(|max| pattern.len() > max).unwrap_or(false) {
+            return Err(NetworkFilterError::PatternTooLong);
+        }
 
         Ok(AbstractNetworkFilter {
             exception,
@@ -335,10 +398,67 @@ impl AbstractNetworkFilter {
     }
 }
 
+/// Finds the `$` separating a filter's pattern from its options. This is normally the last `$` in
+/// the line, but `$csp` values are treated as opaque and can contain `$` (or `;`) themselves, so
+/// the options of a rule with a `csp=` option start at the `$` before it.
+pub(crate) fn find_options_index(line: &str) -> Option<usize> {
+    let last_index = twoway::rfind_str(line, "$")?;
+
+    let is_option_char = |c: char| c.is_ascii_alphanumeric() || "-_~=|.,*".contains(c);
+    // The values of these options may themselves contain `$`
+    ["csp=", "redirect=data:"].iter()
+        .flat_map(|option| line[..last_index].match_indices(option))
+        .filter(|(i, _)| line[..*i].ends_with('$') || line[..*i].ends_with(','))
+        .filter_map(|(i, _)| {
+            let options_index = twoway::rfind_str(&line[..i], "$")?;
+            if line[options_index + 1..i].chars().all(is_option_char) {
+                Some(options_index)
+            } else {
+                None
+            }
+        })
+        .min()
+        .or(Some(last_index))
+}
+
+/// Splits raw filter options on commas, except for those escaped as `\,`, which can appear in
+/// `$removeparam` regexes. An inline `data:` redirect may contain commas of its own, so it must be
+/// the last option, and extends to the end of the options. Rules which appear to have options
+/// after it are rejected when parsing the redirect.
+pub(crate) fn split_options(raw_options: &str) -> Vec<&str> {
+    let data_redirect_start = if raw_options.starts_with("redirect=data:") {
+        Some(0)
+    } else {
+        raw_options.find(",redirect=data:").map(|i| i + 1)
+    };
+    match data_redirect_start {
+        Some(0) => vec![raw_options],
+        Some(start) => {
+            let mut options = split_unescaped_commas(&raw_options[..start - 1]);
+            options.push(&raw_options[start..]);
+            options
+        }
+        None => split_unescaped_commas(raw_options),
+    }
+}
+
+fn split_unescaped_commas(raw_options: &str) -> Vec<&str> {
+    let mut options = vec![];
+    let mut start = 0;
+    for (i, _) in raw_options.match_indices(',') {
+        if !raw_options[..i].ends_with('\\') {
+            options.push(&raw_options[start..i]);
+            start = i + 1;
+        }
+    }
+    options.push(&raw_options[start..]);
+    options
+}
+
 fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<NetworkFilterOption>, NetworkFilterError> {
     let mut result = vec![];
 
-    for raw_option in raw_options.split(',') {
+    for raw_option in split_options(raw_options) {
         // Check for negation: ~option
         let negation = raw_option.starts_with('~');
         let maybe_negated_option = raw_option.trim_start_matches('~');
@@ -379,6 +499,18 @@ fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<Net
                 if value.is_empty() {
                     return Err(NetworkFilterError::EmptyRedirection);
                 }
+                // Inline `data:` URLs are used directly instead of a named resource
+                if value.starts_with("data:") {
+                    if RedirectResource::from_data_url(value).is_none() {
+                        return Err(NetworkFilterError::RedirectionDataUrlInvalid);
+                    }
+                    // Anything after the first part of the data is taken to be a misplaced option
+                    // if it can be parsed as one, rather than letting the data absorb e.g. a
+                    // `domain=` option and apply the rule everywhere.
+                    if value.split(',').skip(2).any(|part| parse_filter_options(part, opts).is_ok()) {
+                        return Err(NetworkFilterError::OptionAfterDataRedirection);
+                    }
+                }
 
                 NetworkFilterOption::Redirect(String::from(value))
             }
@@ -404,6 +536,8 @@ fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<Net
             } else {
                 None
             }),
+            ("removeparam", true) | ("queryprune", true) => return Err(NetworkFilterError::NegatedRemoveparam),
+            ("removeparam", false) | ("queryprune", false) => NetworkFilterOption::Removeparam(normalize_removeparam(value)?),
             ("generichide", true) | ("ghide", true) => return Err(NetworkFilterError::NegatedGenericHide),
             ("generichide", false) | ("ghide", false) => NetworkFilterOption::Generichide,
             ("document", true) => return Err(NetworkFilterError::NegatedDocument),
@@ -418,6 +552,8 @@ fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<Net
             ("subdocument", negated) | ("frame", negated) => NetworkFilterOption::Subdocument(!negated),
             ("xmlhttprequest", negated) | ("xhr", negated) => NetworkFilterOption::XmlHttpRequest(!negated),
             ("websocket", negated) => NetworkFilterOption::Websocket(!negated),
+            ("webrtc", negated) => NetworkFilterOption::Webrtc(!negated),
+            ("popup", negated) => NetworkFilterOption::Popup(!negated),
             ("font", negated) => NetworkFilterOption::Font(!negated),
             (_, _) => return Err(NetworkFilterError::UnrecognisedOption),
         });
@@ -425,6 +561,62 @@ fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<Net
     Ok(result)
 }
 
+/// Converts a `$removeparam` value to the form stored in `NetworkFilter::removeparam`, so that
+/// rules written for uBlock Origin and AdGuard are treated alike. The stored value is either a
+/// parameter name or a `/regex/` with an optional `i` flag, prefixed with `~` if it's inverted,
+/// i.e. if every parameter _except_ the matching ones should be removed. Commas in regexes, which
+/// have to be escaped as `\,` so that they aren't taken as option separators, are unescaped.
+///
+/// Values that the two dialects would read differently are rejected with
+/// `NetworkFilterError::AmbiguousRemoveparam`: regexes with flags other than `i`, which uBlock
+/// Origin takes as literal names, and a lone `~`.
+fn normalize_removeparam(value: &str) -> Result<String, NetworkFilterError> {
+    let (prefix, body) = match value.strip_prefix('~') {
+        Some("") => return Err(NetworkFilterError::AmbiguousRemoveparam),
+        Some(body) => ("~", body),
+        None => ("", value),
+    };
+    let regex = body.strip_prefix('/')
+        .and_then(|rest| rest.rfind('/').map(|end| (&rest[..end], &rest[end + 1..])))
+        .filter(|(_, flags)| flags.chars().all(|c| c.is_ascii_alphabetic()));
+    let (pattern, flags) = match regex {
+        Some(regex) => regex,
+        None => return Ok(String::from(value)),
+    };
+    if flags.chars().any(|c| c != 'i') {
+        return Err(NetworkFilterError::AmbiguousRemoveparam);
+    }
+
+    let normalized = format!("{}/{}/{}", prefix, pattern.replace("\\,", ","), if flags.is_empty() { "" } else { "i" });
+    // Regexes are checked now, so that invalid ones don't silently match nothing
+    if let Some(regex) = removeparam_regex(&normalized[prefix.len()..]) {
+        regex.map_err(NetworkFilterError::RegexParsingError)?;
+    }
+    Ok(normalized)
+}
+
+/// Compiles a normalized `$removeparam` value written as a regex, e.g. `/^utm_/` or `/^UTM_/i`,
+/// without any `~` prefix. Returns `None` for literal parameter names.
+fn removeparam_regex(value: &str) -> Option<Result<Regex, regex::Error>> {
+    let (pattern, case_insensitive) = if let Some(pattern) = value.strip_suffix("/i") {
+        (pattern, true)
+    } else {
+        (value.strip_suffix('/')?, false)
+    };
+    let pattern = pattern.strip_prefix('/')?;
+    Some(RegexBuilder::new(pattern).case_insensitive(case_insensitive).build())
+}
+
+/// If `line` is a network filter which is rejected because of an unrecognised option, returns the
+/// name of the first such option, e.g. `popunder` for `||example.com^$popunder`.
+pub(crate) fn find_unrecognised_option(line: &str, opts: ParseOptions) -> Option<&str> {
+    let options_index = find_options_index(line)?;
+    split_options(&line[options_index + 1..])
+        .into_iter()
+        .find(|raw_option| matches!(parse_filter_options(raw_option, opts), Err(NetworkFilterError::UnrecognisedOption)))
+        .and_then(|raw_option| raw_option.trim_start_matches('~').split('=').next())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkFilter {
     pub mask: NetworkFilterMask,
@@ -445,6 +637,14 @@ pub struct NetworkFilter {
     pub opt_domains_union: Option<Hash>,
     pub opt_not_domains_union: Option<Hash>,
 
+    #[serde(default)]
+    pub separator_mode: SeparatorMode,
+
+    // Query parameter to strip for `$removeparam` rules, normalized by `normalize_removeparam`.
+    // Empty if every parameter is stripped.
+    #[serde(default)]
+    pub removeparam: Option<String>,
+
     // Regex compild lazily, using "Interior Mutability"
     // Arc (Atomic Reference Counter) allows for cloned NetworkFilters
     // to point to the same RwLock and what is inside.
@@ -453,7 +653,12 @@ pub struct NetworkFilter {
     // When the Regex hasn't been compiled, <None> is stored, afterwards Arc to Some<CompiledRegex>
     // to avoid expensive cloning of the Regex itself.
     #[serde(skip_serializing, skip_deserializing)]
-    pub(crate) regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>
+    pub(crate) regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>,
+
+    // `$removeparam` value written as a regex, compiled lazily in the same way as `regex`. Stays
+    // <None> for literal values.
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) removeparam_regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>,
 }
 
 // TODO - restrict the API so that this is always true - i.e. lazy-calculate IDs from actual data,
@@ -478,9 +683,12 @@ fn validate_options(options: &[NetworkFilterOption]) -> Result<(), NetworkFilter
     let mut has_csp = false;
     let mut has_content_type = false;
     let mut has_redirect = false;
+    let mut has_removeparam = false;
     for option in options {
         if matches!(option, NetworkFilterOption::Csp(..)) {
             has_csp = true;
+        } else if matches!(option, NetworkFilterOption::Removeparam(..)) {
+            has_removeparam = true;
         } else if option.is_content_type() {
             has_content_type = true;
         } else if option.is_redirection() {
@@ -493,6 +701,9 @@ fn validate_options(options: &[NetworkFilterOption]) -> Result<(), NetworkFilter
     if has_csp && has_content_type {
         return Err(NetworkFilterError::CspWithContentType);
     }
+    if has_removeparam && (has_csp || has_redirect) {
+        return Err(NetworkFilterError::RemoveparamWithCspOrRedirection);
+    }
 
     Ok(())
 }
@@ -521,6 +732,7 @@ impl NetworkFilter {
 
         let mut redirect: Option<String> = None;
         let mut csp: Option<String> = None;
+        let mut removeparam: Option<String> = None;
         let mut bug: Option<u32> = None;
         let mut tag: Option<String> = None;
 
@@ -551,12 +763,21 @@ impl NetworkFilter {
                         let mut opt_domains_array: Vec<Hash> = vec![];
                         let mut opt_not_domains_array: Vec<Hash> = vec![];
 
+                        let entity_count = domains.iter().filter(|(_, domain)| domain.ends_with(".*")).count();
+                        let expanded_count = domains.len() + entity_count * ENTITY_EXPANSION_SUFFIXES.len();
+                        let expand_entities = entity_count > 0 && expanded_count <= opts.entity_expansion_limit;
+
                         for (enabled, domain) in domains {
-                            let domain_hash = utils::fast_hash(&domain);
-                            if !enabled {
-                                opt_not_domains_array.push(domain_hash);
-                            } else {
-                                opt_domains_array.push(domain_hash);
+                            let domain_array = if enabled { &mut opt_domains_array } else { &mut opt_not_domains_array };
+                            match domain.strip_suffix('*') {
+                                Some(entity) if expand_entities && entity.ends_with('.') => {
+                                    domain_array.extend(ENTITY_EXPANSION_SUFFIXES.iter().map(|suffix| {
+                                        utils::fast_hash(&format!("{}{}", entity, suffix))
+                                    }));
+                                    // Keep the entity itself so that other suffixes still match at runtime
+                                    domain_array.push(utils::fast_hash(&domain));
+                                }
+                                _ => domain_array.push(utils::fast_hash(&domain)),
                             }
                         }
 
@@ -592,6 +813,7 @@ impl NetworkFilter {
                         mask.set(NetworkFilterMask::FROM_DOCUMENT, true);
                         csp = value;
                     }
+                    NetworkFilterOption::Removeparam(value) => removeparam = Some(value),
                     NetworkFilterOption::Generichide => mask.set(NetworkFilterMask::GENERIC_HIDE, true),
                     NetworkFilterOption::Document => cpt_mask_positive.set(NetworkFilterMask::FROM_DOCUMENT, true),
                     NetworkFilterOption::Image(enabled) => apply_content_type!(FROM_IMAGE, enabled),
@@ -604,6 +826,8 @@ impl NetworkFilter {
                     NetworkFilterOption::Subdocument(enabled) => apply_content_type!(FROM_SUBDOCUMENT, enabled),
                     NetworkFilterOption::XmlHttpRequest(enabled) => apply_content_type!(FROM_XMLHTTPREQUEST, enabled),
                     NetworkFilterOption::Websocket(enabled) => apply_content_type!(FROM_WEBSOCKET, enabled),
+                    NetworkFilterOption::Webrtc(enabled) => apply_content_type!(FROM_WEBRTC, enabled),
+                    NetworkFilterOption::Popup(enabled) => apply_content_type!(FROM_POPUP, enabled),
                     NetworkFilterOption::Font(enabled) => apply_content_type!(FROM_FONT, enabled),
                 }
             });
@@ -617,8 +841,13 @@ impl NetworkFilter {
             mask |= NetworkFilterMask::FROM_NETWORK_TYPES;
         }
         // If no positive types were set, then the filter should apply to all network types.
-        if (cpt_mask_positive & NetworkFilterMask::FROM_ALL_TYPES).is_empty() {
+        if (cpt_mask_positive & (NetworkFilterMask::FROM_ALL_TYPES | NetworkFilterMask::FROM_EXPLICIT_TYPES)).is_empty() {
             mask |= NetworkFilterMask::FROM_NETWORK_TYPES;
+            // Tracking parameters are mostly found in links to other pages, so `$removeparam`
+            // rules also apply to document requests by default.
+            if removeparam.is_some() {
+                mask.set(NetworkFilterMask::FROM_DOCUMENT, true);
+            }
         }
 
         match parsed.pattern.left_anchor {
@@ -762,7 +991,23 @@ impl NetworkFilter {
                 NetworkFilterMask::IS_REGEX,
                 check_is_regex(&pattern[filter_index_start..filter_index_end]),
             );
-            Some(String::from(&pattern[filter_index_start..filter_index_end]).to_ascii_lowercase())
+            // Request URLs are lowercased, unless matched by a `$match-case` filter
+            let filter = &pattern[filter_index_start..filter_index_end];
+            let filter = if mask.contains(NetworkFilterMask::MATCH_CASE) {
+                filter.to_owned()
+            } else {
+                filter.to_ascii_lowercase()
+            };
+            // Complete regexes are kept intact, since encoding could break their syntax.
+            if mask.contains(NetworkFilterMask::IS_COMPLETE_REGEX) {
+                Some(filter)
+            } else {
+                let normalized = match normalize_path(&filter) {
+                    Cow::Owned(normalized) => Some(normalized),
+                    Cow::Borrowed(_) => None,
+                };
+                Some(normalized.unwrap_or(filter))
+            }
         } else {
             None
         };
@@ -780,7 +1025,10 @@ impl NetworkFilter {
             let hostname = if lowercase.is_ascii() {
                 lowercase
             } else {
-                idna::domain_to_ascii(&lowercase).map_err(|_| NetworkFilterError::PunycodeError)?
+                match split_hostname_port(&lowercase) {
+                    (host, Some(port)) => format!("{}:{}", idna::domain_to_ascii(host).map_err(|_| NetworkFilterError::PunycodeError)?, port),
+                    (host, None) => idna::domain_to_ascii(host).map_err(|_| NetworkFilterError::PunycodeError)?,
+                }
             };
             Ok(hostname)
         }).transpose();
@@ -799,7 +1047,7 @@ impl NetworkFilter {
         // filter, which isn't saved in Brave unless running with filter lists compiled in "debug"
         // mode. Instead, we apply the implicit document matching more strictly, only for hostname
         // filters of the form `||example.com^`.
-        if (cpt_mask_positive & NetworkFilterMask::FROM_ALL_TYPES).is_empty() &&
+        if (cpt_mask_positive & (NetworkFilterMask::FROM_ALL_TYPES | NetworkFilterMask::FROM_EXPLICIT_TYPES)).is_empty() &&
                 (cpt_mask_negative & NetworkFilterMask::FROM_ALL_TYPES).is_empty() &&
                 mask.contains(NetworkFilterMask::IS_HOSTNAME_ANCHOR) &&
                 mask.contains(NetworkFilterMask::IS_RIGHT_ANCHOR) &&
@@ -831,7 +1079,10 @@ impl NetworkFilter {
             id: utils::fast_hash(&line),
             opt_domains_union,
             opt_not_domains_union,
-            regex: Arc::new(RwLock::new(None))
+            separator_mode: opts.separator_mode,
+            removeparam,
+            regex: Arc::new(RwLock::new(None)),
+            removeparam_regex: Arc::new(RwLock::new(None)),
         })
     }
 
@@ -869,6 +1120,7 @@ impl NetworkFilter {
         mask.set(NetworkFilterMask::BAD_FILTER, false);
         compute_filter_id(
             self.csp.as_deref(),
+            self.removeparam.as_deref(),
             mask,
             self.filter.string_view().as_deref(),
             self.hostname.as_deref(),
@@ -880,6 +1132,7 @@ impl NetworkFilter {
     pub fn get_id(&self) -> Hash {
         compute_filter_id(
             self.csp.as_deref(),
+            self.removeparam.as_deref(),
             self.mask,
             self.filter.string_view().as_deref(),
             self.hostname.as_deref(),
@@ -888,6 +1141,40 @@ impl NetworkFilter {
         )
     }
 
+    /// Scores how narrowly this filter targets requests, for callers choosing between several
+    /// filters that match the same request. Higher scores are more specific.
+    ///
+    /// The kind of anchor is weighted most heavily, followed by any restriction to particular
+    /// source domains, then any restriction to particular request types. The combined length of
+    /// the hostname and pattern breaks any remaining ties.
+    pub fn specificity(&self) -> u32 {
+        let anchor = if self.is_hostname_anchor() && self.hostname.is_some() {
+            3
+        } else if self.is_left_anchor() {
+            2
+        } else if self.is_right_anchor() {
+            1
+        } else {
+            0
+        };
+        let domains = if self.opt_domains.is_some() {
+            2
+        } else if self.opt_not_domains.is_some() {
+            1
+        } else {
+            0
+        };
+        let types = if self.mask.contains(NetworkFilterMask::FROM_NETWORK_TYPES) { 0 } else { 1 };
+        let length = self.hostname.as_ref().map(|h| h.len()).unwrap_or_default()
+            + match &self.filter {
+                FilterPart::Empty => 0,
+                FilterPart::Simple(pattern) => pattern.len(),
+                FilterPart::AnyOf(patterns) => patterns.iter().map(|p| p.len()).min().unwrap_or_default(),
+            };
+
+        anchor << 24 | domains << 16 | types << 8 | std::cmp::min(length, 0xff) as u32
+    }
+
     pub fn get_tokens(&self) -> Vec<Vec<Hash>> {
         let mut tokens: Vec<Hash> = Vec::with_capacity(TOKENS_BUFFER_SIZE);
 
@@ -912,8 +1199,12 @@ impl NetworkFilter {
                         (self.is_plain() || self.is_regex()) && !self.is_right_anchor();
                     let skip_first_token = self.is_right_anchor();
 
-                    let mut filter_tokens =
-                        utils::tokenize_filter(&f, skip_first_token, skip_last_token);
+                    // Request tokens are always taken from the lowercased URL
+                    let mut filter_tokens = if self.match_case() {
+                        utils::tokenize_filter(&f.to_ascii_lowercase(), skip_first_token, skip_last_token)
+                    } else {
+                        utils::tokenize_filter(&f, skip_first_token, skip_last_token)
+                    };
 
                     tokens.append(&mut filter_tokens);
                 }
@@ -925,7 +1216,8 @@ impl NetworkFilter {
         // Append tokens from hostname, if any
         if !self.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX) {
             if let Some(hostname) = self.hostname.as_ref()  {
-                let mut hostname_tokens = utils::tokenize(&hostname);
+                // The port may be implied by the request's scheme, so it can't be used as a token
+                let mut hostname_tokens = utils::tokenize(split_hostname_port(hostname).0);
                 tokens.append(&mut hostname_tokens);
             }
         }
@@ -1012,6 +1304,47 @@ impl NetworkFilter {
         self.mask.contains(NetworkFilterMask::IS_CSP)
     }
 
+    pub fn is_removeparam(&self) -> bool {
+        self.removeparam.is_some()
+    }
+
+    /// Whether this `$removeparam` filter strips `param`, a `name=value` pair from a query string.
+    /// Literal values must equal the parameter's name, while regex values are tested against the
+    /// whole pair. Inverted values strip every parameter that doesn't match.
+    pub(crate) fn removes_param(&self, param: &str) -> bool {
+        let value = match self.removeparam.as_deref() {
+            Some(value) => value,
+            None => return false,
+        };
+        if value.is_empty() {
+            return true;
+        }
+        let (inverted, value) = match value.strip_prefix('~') {
+            Some(value) => (true, value),
+            None => (false, value),
+        };
+        let matches = match self.get_removeparam_regex() {
+            Some(regex) => regex.is_match(param),
+            None => param.split('=').next() == Some(value),
+        };
+        matches != inverted
+    }
+
+    // Lazily get the regex of the `$removeparam` value, if it's written as one
+    fn get_removeparam_regex(&self) -> Option<Arc<CompiledRegex>> {
+        if let Some(regex) = self.removeparam_regex.as_ref().read().unwrap().as_ref() {
+            return Some(regex.clone());
+        }
+        let value = self.removeparam.as_deref()?;
+        let regex = match removeparam_regex(value.strip_prefix('~').unwrap_or(value))? {
+            Ok(regex) => CompiledRegex::Compiled(regex),
+            Err(e) => CompiledRegex::RegexParsingError(e),
+        };
+        let arc_regex = Arc::new(regex);
+        *self.removeparam_regex.as_ref().write().unwrap() = Some(arc_regex.clone());
+        Some(arc_regex)
+    }
+
     pub fn has_bug(&self) -> bool {
         self.bug.is_some()
     }
@@ -1071,6 +1404,7 @@ impl NetworkMatchable for NetworkFilter {
             self.is_right_anchor(),
             self.is_left_anchor(),
             self.is_complete_regex(),
+            self.separator_mode,
         );
         let arc_regex = Arc::new(regex);
         *cache = Some(arc_regex.clone());
@@ -1084,6 +1418,7 @@ impl NetworkMatchable for NetworkFilter {
 
 fn compute_filter_id(
     csp: Option<&str>,
+    removeparam: Option<&str>,
     mask: NetworkFilterMask,
     filter: Option<&str>,
     hostname: Option<&str>,
@@ -1099,6 +1434,14 @@ fn compute_filter_id(
         }
     };
 
+    // `$removeparam` rules have no mask bit, so an empty value still needs to change the hash
+    if let Some(s) = removeparam {
+        hash = hash.wrapping_mul(33) ^ ('$' as Hash);
+        for c in s.chars() {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    }
+
     if let Some(domains) = opt_domains {
         for d in domains {
             hash = hash.wrapping_mul(33) ^ d;
@@ -1128,6 +1471,15 @@ fn compute_filter_id(
     hash
 }
 
+/// Returns the regex fragment that a `^` separator placeholder is translated to.
+fn separator_regex(mode: SeparatorMode) -> &'static str {
+    match mode {
+        SeparatorMode::Strict => "(?:[^0-9A-Za-z_.%-]|$)",
+        // Any `%` escape of a printable ASCII character which would itself be a separator
+        SeparatorMode::Lenient => "(?:[^0-9A-Za-z_.%-]|%(?:2[0-46-9A-Ca-cFf]|3[A-Fa-f]|40|5[B-Eb-e]|60|7[B-Eb-e])|$)",
+    }
+}
+
 /// Compiles a filter pattern to a regex. This is only performed *lazily* for
 /// filters containing at least a * or ^ symbol. Because Regexes are expansive,
 /// we try to convert some patterns to plain filters.
@@ -1137,15 +1489,12 @@ pub fn compile_regex(
     is_right_anchor: bool,
     is_left_anchor: bool,
     is_complete_regex: bool,
+    separator_mode: SeparatorMode,
 ) -> CompiledRegex {
     // Escape special regex characters: |.$+?{}()[]\
     static SPECIAL_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"([\|\.\$\+\?\{\}\(\)\[\]])").unwrap());
     // * can match anything
     static WILDCARD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\*").unwrap());
-    // ^ can match any separator or the end of the pattern
-    static ANCHOR_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\^(.)").unwrap());
-    // ^ can match any separator or the end of the pattern
-    static ANCHOR_RE_EOL: Lazy<Regex> = Lazy::new(|| Regex::new(r"\^$").unwrap());
 
     let filters: Vec<String> = match filter {
         FilterPart::Empty => vec![],
@@ -1169,10 +1518,9 @@ pub fn compile_regex(
         } else {
             let repl = SPECIAL_RE.replace_all(&filter_str, "\\$1");
             let repl = WILDCARD_RE.replace_all(&repl, ".*");
-            // in adblock rules, '^' is a separator.
-            // The separator character is anything but a letter, a digit, or one of the following: _ - . %
-            let repl = ANCHOR_RE.replace_all(&repl, "(?:[^\\w\\d\\._%-])$1");
-            let repl = ANCHOR_RE_EOL.replace_all(&repl, "(?:[^\\w\\d\\._%-]|$)");
+            // in adblock rules, '^' is a separator, which also matches the end of the URL.
+            // No other '^' can be present since it isn't a special character in filters.
+            let repl = repl.replace('^', separator_regex(separator_mode));
 
             // Should match start or end of url
             let left_anchor = if is_left_anchor { "^" } else { "" };
@@ -1258,8 +1606,39 @@ fn is_anchored_by_hostname(filter_hostname: &str, hostname: &str, wildcard_filte
     }
 }
 
+/// Splits an explicit port from a filter hostname like `example.com:8080`, returning the bare
+/// hostname and the port, if one was specified.
+pub(crate) fn split_hostname_port(filter_hostname: &str) -> (&str, Option<u16>) {
+    if let Some(colon) = filter_hostname.rfind(':') {
+        let port = &filter_hostname[colon + 1..];
+        if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) {
+            if let Ok(port) = port.parse() {
+                return (&filter_hostname[..colon], Some(port));
+            }
+        }
+    }
+    (filter_hostname, None)
+}
+
+/// Checks `is_anchored_by_hostname` against the request's hostname. If the filter hostname
+/// specifies a port, the request must also be made to that port, whether explicitly or as the
+/// default port for its scheme.
+fn is_anchored_by_hostname_and_port(filter_hostname: &str, request: &request::Request, wildcard_filter_hostname: bool) -> bool {
+    match split_hostname_port(filter_hostname) {
+        (hostname, Some(port)) => request.port() == Some(port) && is_anchored_by_hostname(hostname, &request.hostname, wildcard_filter_hostname),
+        (hostname, None) => is_anchored_by_hostname(hostname, &request.hostname, wildcard_filter_hostname),
+    }
+}
+
 fn get_url_after_hostname<'a>(url: &'a str, hostname: &str) -> &'a str {
-    let start = twoway::find_str(url, hostname).unwrap_or_else(|| url.len() - hostname.len());
+    // A port in the filter hostname may be implied by the URL's scheme rather than explicit
+    let (hostname, start) = match twoway::find_str(url, hostname) {
+        Some(start) => (hostname, start),
+        None => {
+            let hostname = split_hostname_port(hostname).0;
+            (hostname, twoway::find_str(url, hostname).unwrap_or_else(|| url.len() - hostname.len()))
+        }
+    };
     &url[start + hostname.len()..]
 }
 
@@ -1267,14 +1646,31 @@ fn get_url_after_hostname<'a>(url: &'a str, hostname: &str) -> &'a str {
 // Filter matching
 // ---------------------------------------------------------------------------
 
+/// Returns the URL that the pattern of `filter` should be matched against. `$match-case` filters
+/// keep the case of their patterns, so they use the URL as it was originally given.
+fn request_url<'a>(filter: &NetworkFilter, request: &'a request::Request) -> &'a str {
+    if filter.match_case() {
+        request.original_url()
+    } else {
+        &request.url
+    }
+}
+
+/// Like `get_url_after_hostname`, but for the URL returned by `request_url`.
+fn url_after_hostname<'a>(filter: &NetworkFilter, request: &'a request::Request, hostname: &str) -> &'a str {
+    // Both forms of the URL only differ in case, so the hostname is found in the lowercased one
+    let start = request.url.len() - get_url_after_hostname(&request.url, hostname).len();
+    &request_url(filter, request)[start..]
+}
+
 // pattern
 fn check_pattern_plain_filter_filter(filter: &NetworkFilter, request: &request::Request) -> bool {
     match &filter.filter {
         FilterPart::Empty => true,
-        FilterPart::Simple(f) => twoway::find_str(&request.url, f).is_some(),
+        FilterPart::Simple(f) => twoway::find_str(request_url(filter, request), f).is_some(),
         FilterPart::AnyOf(filters) => {
             for f in filters {
-                if twoway::find_str(&request.url, f).is_some() {
+                if twoway::find_str(request_url(filter, request), f).is_some() {
                     return true;
                 }
             }
@@ -1287,10 +1683,10 @@ fn check_pattern_plain_filter_filter(filter: &NetworkFilter, request: &request::
 fn check_pattern_right_anchor_filter(filter: &NetworkFilter, request: &request::Request) -> bool {
     match &filter.filter {
         FilterPart::Empty => true,
-        FilterPart::Simple(f) => request.url.ends_with(f),
+        FilterPart::Simple(f) => request_url(filter, request).ends_with(f),
         FilterPart::AnyOf(filters) => {
             for f in filters {
-                if request.url.ends_with(f) {
+                if request_url(filter, request).ends_with(f) {
                     return true;
                 }
             }
@@ -1303,10 +1699,10 @@ fn check_pattern_right_anchor_filter(filter: &NetworkFilter, request: &request::
 fn check_pattern_left_anchor_filter(filter: &NetworkFilter, request: &request::Request) -> bool {
     match &filter.filter {
         FilterPart::Empty => true,
-        FilterPart::Simple(f) => request.url.starts_with(f),
+        FilterPart::Simple(f) => request_url(filter, request).starts_with(f),
         FilterPart::AnyOf(filters) => {
             for f in filters {
-                if request.url.starts_with(f) {
+                if request_url(filter, request).starts_with(f) {
                     return true;
                 }
             }
@@ -1322,10 +1718,10 @@ fn check_pattern_left_right_anchor_filter(
 ) -> bool {
     match &filter.filter {
         FilterPart::Empty => true,
-        FilterPart::Simple(f) => &request.url == f,
+        FilterPart::Simple(f) => request_url(filter, request) == f,
         FilterPart::AnyOf(filters) => {
             for f in filters {
-                if &request.url == f {
+                if request_url(filter, request) == f {
                     return true;
                 }
             }
@@ -1341,7 +1737,7 @@ fn check_pattern_regex_filter_at(
     start_from: usize,
 ) -> bool {
     let regex = filter.get_regex();
-    regex.is_match(&request.url[start_from..])
+    regex.is_match(&request_url(filter, request)[start_from..])
 }
 
 fn check_pattern_regex_filter(filter: &NetworkFilter, request: &request::Request) -> bool {
@@ -1357,11 +1753,11 @@ fn check_pattern_hostname_anchor_regex_filter(
         .hostname
         .as_ref()
         .map(|hostname| {
-            if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
+            if is_anchored_by_hostname_and_port(hostname, request, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 check_pattern_regex_filter_at(
                     filter,
                     request,
-                    request.url.find(hostname).unwrap_or_default() + hostname.len(),
+                    request.url.len() - get_url_after_hostname(&request.url, hostname).len(),
                 )
             } else {
                 false
@@ -1379,13 +1775,14 @@ fn check_pattern_hostname_right_anchor_filter(
         .hostname
         .as_ref()
         .map(|hostname| {
-            if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
+            if is_anchored_by_hostname_and_port(hostname, request, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 match &filter.filter {
                     // In this specific case it means that the specified hostname should match
                     // at the end of the hostname of the request. This allows to prevent false
                     // positive like ||foo.bar which would match https://foo.bar.baz where
                     // ||foo.bar^ would not.
                     FilterPart::Empty => {
+                        let hostname = split_hostname_port(hostname).0;
                         request.hostname.len() == hostname.len()        // if lengths are equal, hostname equality is implied by anchoring check
                             || request.hostname.ends_with(hostname)
                     }
@@ -1411,16 +1808,16 @@ fn check_pattern_hostname_left_right_anchor_filter(
         .hostname
         .as_ref()
         .map(|hostname| {
-            if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
+            if is_anchored_by_hostname_and_port(hostname, request, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 match &filter.filter {
                     // if no filter, we have a match
                     FilterPart::Empty => true,
                     // Since it must follow immediatly after the hostname and be a suffix of
                     // the URL, we conclude that filter must be equal to the part of the
                     // url following the hostname.
-                    FilterPart::Simple(f) => get_url_after_hostname(&request.url, hostname) == f,
+                    FilterPart::Simple(f) => url_after_hostname(filter, request, hostname) == f,
                     FilterPart::AnyOf(filters) => {
-                        let url_after_hostname = get_url_after_hostname(&request.url, hostname);
+                        let url_after_hostname = url_after_hostname(filter, request, hostname);
                         for f in filters {
                             if url_after_hostname == f {
                                 return true;
@@ -1446,7 +1843,7 @@ fn check_pattern_hostname_left_anchor_filter(
         .hostname
         .as_ref()
         .map(|hostname| {
-            if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
+            if is_anchored_by_hostname_and_port(hostname, request, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 match &filter.filter {
                     // if no filter, we have a match
                     FilterPart::Empty => true,
@@ -1454,10 +1851,10 @@ fn check_pattern_hostname_left_anchor_filter(
                     // with nothing in between. So we extract the part of the URL following
                     // after hostname and will perform the matching on it.
                     FilterPart::Simple(f) => {
-                        get_url_after_hostname(&request.url, hostname).starts_with(f)
+                        url_after_hostname(filter, request, hostname).starts_with(f)
                     }
                     FilterPart::AnyOf(filters) => {
-                        let url_after_hostname = get_url_after_hostname(&request.url, hostname);
+                        let url_after_hostname = url_after_hostname(filter, request, hostname);
                         for f in filters {
                             if url_after_hostname.starts_with(f) {
                                 return true;
@@ -1482,15 +1879,15 @@ fn check_pattern_hostname_anchor_filter(
         .hostname
         .as_ref()
         .map(|hostname| {
-            if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
+            if is_anchored_by_hostname_and_port(hostname, request, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 match &filter.filter {
                     // if no filter, we have a match
                     FilterPart::Empty => true,
                     // Filter hostname does not necessarily have to be a full, proper hostname, part of it can be lumped together with the URL
-                    FilterPart::Simple(f) => get_url_after_hostname(&request.url, hostname)
+                    FilterPart::Simple(f) => url_after_hostname(filter, request, hostname)
                         .contains(f),
                     FilterPart::AnyOf(filters) => {
-                        let url_after_hostname = get_url_after_hostname(&request.url, hostname);
+                        let url_after_hostname = url_after_hostname(filter, request, hostname);
                         for f in filters {
                             if url_after_hostname.contains(f) {
                                 return true;
@@ -1539,7 +1936,9 @@ pub fn check_cpt_allowed(filter: &NetworkFilter, cpt: &request::RequestType) ->
         // TODO this is not ideal, but required to allow regexed exception rules without an
         // explicit `$document` option to apply uBO-style.
         // See also: https://github.com/uBlockOrigin/uBlock-issues/issues/1501
-        NetworkFilterMask::FROM_DOCUMENT => filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT) || filter.is_exception(),
+        // Exceptions which only apply to explicit types like `$popup` are still excluded.
+        NetworkFilterMask::FROM_DOCUMENT => filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT)
+            || (filter.is_exception() && filter.mask.intersects(NetworkFilterMask::FROM_ALL_TYPES)),
         mask => filter.mask.contains(mask),
     }
 }
@@ -1566,20 +1965,25 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
         return false;
     }
 
-    // Source URL must be among these domains to match
+    // Source URL must be among these domains to match. If there is no source URL, a rule scoped
+    // to particular domains cannot apply.
+    // Entities like `example.*` which were not expanded at parse time are matched against the
+    // source hostname's entity hashes.
     if let Some(included_domains) = filter.opt_domains.as_ref() {
-        if let Some(source_hashes) = request.source_hostname_hashes.as_ref() {
-            // If the union of included domains is recorded
-            if let Some(included_domains_union) = filter.opt_domains_union {
-                // If there isn't any source hash that matches the union, there's no match at all
-                if source_hashes.iter().all(|h| h & included_domains_union != *h) {
-                    return false
-                }
-            }
-            if source_hashes.iter().all(|h| !utils::bin_lookup(&included_domains, *h)) {
+        let source_hashes = match request.source_hostname_hashes.as_ref() {
+            Some(source_hashes) => source_hashes,
+            None => return false,
+        };
+        // If the union of included domains is recorded
+        if let Some(included_domains_union) = filter.opt_domains_union {
+            // If there isn't any source hash that matches the union, there's no match at all
+            if source_hashes.iter().copied().chain(request.source_entity_hashes()).all(|h| h & included_domains_union != h) {
                 return false
             }
         }
+        if source_hashes.iter().copied().chain(request.source_entity_hashes()).all(|h| !utils::bin_lookup(&included_domains, h)) {
+            return false
+        }
     }
 
     if let Some(excluded_domains) = filter.opt_not_domains.as_ref() {
@@ -1587,10 +1991,10 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
             // If the union of excluded domains is recorded
             if let Some(excluded_domains_union) = filter.opt_not_domains_union {
                 // If there's any source hash that matches the union, check the actual values
-                if source_hashes.iter().any(|h| (h & excluded_domains_union == *h) && utils::bin_lookup(&excluded_domains, *h)) {
+                if source_hashes.iter().copied().chain(request.source_entity_hashes()).any(|h| (h & excluded_domains_union == h) && utils::bin_lookup(&excluded_domains, h)) {
                     return false
                 }
-            } else if source_hashes.iter().any(|h| utils::bin_lookup(&excluded_domains, *h)) {
+            } else if source_hashes.iter().copied().chain(request.source_entity_hashes()).any(|h| utils::bin_lookup(&excluded_domains, h)) {
                 return false
             }
         }
@@ -2178,6 +2582,17 @@ mod parse_tests {
             assert_eq!(filter.is_csp(), true);
             assert_eq!(filter.csp, Some(String::from(r#"self bar """#)));
         }
+        {
+            // multiple directives are kept whole, even if they contain `$`
+            let filter = NetworkFilter::parse("||foo.com$csp=default-src 'self'; script-src 'none'; img-src *", true, Default::default()).unwrap();
+            assert_eq!(filter.csp, Some(String::from("default-src 'self'; script-src 'none'; img-src *")));
+            let filter = NetworkFilter::parse("||foo.com$third-party,csp=script-src 'nonce-a$b'; object-src 'none',domain=bar.com", true, Default::default()).unwrap();
+            assert_eq!(filter.csp, Some(String::from("script-src 'nonce-a$b'; object-src 'none'")));
+            assert_eq!(filter.third_party(), true);
+            assert_eq!(filter.first_party(), false);
+            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("bar.com")]));
+            assert_eq!(filter.hostname.as_deref(), Some("foo.com"));
+        }
         {
             // parses empty CSP
             let filter = NetworkFilter::parse("||foo.com$csp", true, Default::default()).unwrap();
@@ -2192,6 +2607,38 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn specificity() {
+        let specificity = |filter: &str| NetworkFilter::parse(filter, true, Default::default()).unwrap().specificity();
+
+        assert!(specificity("||ads.com^$domain=example.com") > specificity("/banner/ads."));
+        assert!(specificity("||ads.com^$domain=example.com") > specificity("||ads.com^"));
+        assert!(specificity("||ads.com^") > specificity("|https://ads.com/$domain=example.com"));
+        assert!(specificity("|https://ads.com/") > specificity("/ads.js|"));
+        assert!(specificity("/ads.js|") > specificity("/ads.js$domain=example.com"));
+        assert!(specificity("/ads.js$domain=example.com") > specificity("/ads.js$domain=~example.com"));
+        assert!(specificity("/ads.js$domain=~example.com") > specificity("/ads.js$script"));
+        assert!(specificity("/ads.js$script") > specificity("/ads.js"));
+        assert!(specificity("/ads.js") > specificity("/ad.js"));
+        assert_eq!(specificity("/ads.js"), specificity("@@/ads.js"));
+    }
+
+    #[test]
+    fn expand_entity() {
+        let domains = super::expand_entity("example.*", 100).unwrap();
+        assert!(domains.contains(&String::from("example.com")));
+        assert!(domains.contains(&String::from("example.co.uk")));
+        assert_eq!(domains.iter().all(|domain| domain.starts_with("example.") && !domain.contains('*')), true);
+
+        let domains = super::expand_entity("www.example.*", 100).unwrap();
+        assert!(domains.contains(&String::from("www.example.co.uk")));
+
+        assert_eq!(super::expand_entity("example.*", 3).unwrap(), vec!["example.com", "example.net", "example.org"]);
+        assert_eq!(super::expand_entity("example.*", 0).unwrap(), Vec::<String>::new());
+        assert_eq!(super::expand_entity("example.com", 100), None);
+        assert_eq!(super::expand_entity(".*", 100), None);
+    }
+
     #[test]
     fn parses_domain() {
         // parses domain
@@ -2264,6 +2711,7 @@ mod parse_tests {
             let filter = NetworkFilter::parse("||foo.com$redirect-url=http://xyz.com", true, Default::default());
             let err = filter.clone().err();
             assert_eq!(err, Some(NetworkFilterError::UnrecognisedOption));
+            assert_eq!(find_unrecognised_option("||foo.com$redirect-url=http://xyz.com", Default::default()), Some("redirect-url"));
         }
         {
             let filter = NetworkFilter::parse("||foo.com$redirect-url=http://xyz.com", true, opts).unwrap();
@@ -2342,6 +2790,52 @@ mod parse_tests {
             let filter = NetworkFilter::parse("||foo.com$redirect=", true, Default::default());
             assert_eq!(filter.err(), Some(NetworkFilterError::EmptyRedirection))
         }
+        // parses inline data URLs, which extend to the end of the options
+        {
+            let filter = NetworkFilter::parse("||foo.com/ad.js$script,redirect=data:application/javascript,window.a=1,b=2", true, Default::default()).unwrap();
+            assert_eq!(filter.redirect, Some(String::from("data:application/javascript,window.a=1,b=2")));
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_SCRIPT), true);
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_IMAGE), false);
+
+            let filter = NetworkFilter::parse("||foo.com/ad.js$redirect=data:text/plain;base64,JEFCQw==", true, Default::default()).unwrap();
+            assert_eq!(filter.redirect, Some(String::from("data:text/plain;base64,JEFCQw==")));
+            assert_eq!(filter.hostname, Some(String::from("foo.com")));
+
+            let filter = NetworkFilter::parse("||foo.com$redirect=data:text/plain,$1", true, Default::default()).unwrap();
+            assert_eq!(filter.redirect, Some(String::from("data:text/plain,$1")));
+            assert_eq!(filter.hostname, Some(String::from("foo.com")));
+        }
+        {
+            for line in [
+                "||foo.com$redirect=data:text/plain",
+                "||foo.com$redirect=data:text/plain;base64,!!",
+                "||foo.com$redirect=data:nonsense,abc",
+                "||foo.com$redirect=data:text/plain,%zz",
+            ].iter() {
+                assert_eq!(NetworkFilter::parse(line, true, Default::default()).err(), Some(NetworkFilterError::RedirectionDataUrlInvalid), "{}", line);
+            }
+        }
+        // options can't follow inline data URLs
+        {
+            for line in [
+                "||foo.com$redirect=data:text/plain,abc,domain=example.com",
+                "||foo.com$redirect=data:text/plain,abc,def,third-party",
+                "||foo.com$script,redirect=data:text/plain,,~image",
+            ].iter() {
+                assert_eq!(NetworkFilter::parse(line, true, Default::default()).err(), Some(NetworkFilterError::OptionAfterDataRedirection), "{}", line);
+            }
+            // The first part of the data is never an option
+            let filter = NetworkFilter::parse("||foo.com$redirect=data:text/plain,script", true, Default::default()).unwrap();
+            assert_eq!(filter.redirect, Some(String::from("data:text/plain,script")));
+        }
+        // `$redirect-rule` is not supported, and doesn't absorb any later options as data
+        {
+            let line = "||foo.com$redirect-rule=data:text/plain,abc,domain=example.com";
+            assert_eq!(NetworkFilter::parse(line, true, Default::default()).err(), Some(NetworkFilterError::UnrecognisedOption));
+            assert_eq!(find_unrecognised_option(line, Default::default()), Some("redirect-rule"));
+            let line = "||foo.com$domain=example.com,redirect-rule=data:text/plain,abc";
+            assert_eq!(NetworkFilter::parse(line, true, Default::default()).err(), Some(NetworkFilterError::UnrecognisedOption));
+        }
         // defaults to false
         {
             let filter = NetworkFilter::parse("||foo.com", true, Default::default()).unwrap();
@@ -2534,6 +3028,108 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn parses_removeparam() {
+        {
+            let filter = NetworkFilter::parse("||foo.com^$removeparam=utm_Source", true, Default::default()).unwrap();
+            assert_eq!(filter.is_removeparam(), true);
+            assert_eq!(filter.removeparam, Some(String::from("utm_Source")));
+            // Applies to documents as well as other requests by default
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT), true);
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_NETWORK_TYPES), true);
+            assert_eq!(filter.removes_param("utm_Source=x"), true);
+            assert_eq!(filter.removes_param("utm_Source"), true);
+            assert_eq!(filter.removes_param("utm_source=x"), false);
+            assert_eq!(filter.removes_param("utm_Source_id=x"), false);
+        }
+        {
+            let filter = NetworkFilter::parse("@@||foo.com^$removeparam", true, Default::default()).unwrap();
+            assert_eq!(filter.is_exception(), true);
+            assert_eq!(filter.removeparam, Some(String::new()));
+            assert_eq!(filter.removes_param("anything=1"), true);
+        }
+        {
+            let filter = NetworkFilter::parse("$xhr,removeparam=/^utm_/", true, Default::default()).unwrap();
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT), false);
+            assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_XMLHTTPREQUEST), true);
+            assert_eq!(filter.removes_param("utm_medium=email"), true);
+            assert_eq!(filter.removes_param("UTM_medium=email"), false);
+            assert_eq!(filter.removes_param("id=utm_"), false);
+            // The regex is only compiled once, and shared with clones of the filter
+            let compiled = filter.get_removeparam_regex().unwrap();
+            assert!(Arc::ptr_eq(&compiled, &filter.clone().get_removeparam_regex().unwrap()));
+        }
+        {
+            let filter = NetworkFilter::parse("$removeparam=/^utm_/i", true, Default::default()).unwrap();
+            assert_eq!(filter.removes_param("UTM_medium=email"), true);
+        }
+        {
+            let filter = NetworkFilter::parse("||foo.com", true, Default::default()).unwrap();
+            assert_eq!(filter.is_removeparam(), false);
+            assert_eq!(filter.removes_param("utm_source=x"), false);
+        }
+        // Different parameters produce different ids, so `$badfilter` only cancels the same one
+        {
+            let a = NetworkFilter::parse("||foo.com^$removeparam=a", true, Default::default()).unwrap();
+            let b = NetworkFilter::parse("||foo.com^$removeparam=b", true, Default::default()).unwrap();
+            let all = NetworkFilter::parse("||foo.com^$removeparam", true, Default::default()).unwrap();
+            let plain = NetworkFilter::parse("||foo.com^$document", true, Default::default()).unwrap();
+            let bad_a = NetworkFilter::parse("||foo.com^$removeparam=a,badfilter", true, Default::default()).unwrap();
+            assert_ne!(a.get_id(), b.get_id());
+            assert_ne!(all.get_id(), plain.get_id());
+            assert_eq!(bad_a.get_id_without_badfilter(), a.get_id());
+        }
+        assert!(matches!(
+            NetworkFilter::parse("||foo.com^$~removeparam=a", true, Default::default()),
+            Err(NetworkFilterError::NegatedRemoveparam)
+        ));
+        assert!(matches!(
+            NetworkFilter::parse("||foo.com^$removeparam=/utm_[/", true, Default::default()),
+            Err(NetworkFilterError::RegexParsingError(_))
+        ));
+        assert!(matches!(
+            NetworkFilter::parse("||foo.com^$removeparam=a,redirect=noopjs", true, Default::default()),
+            Err(NetworkFilterError::RemoveparamWithCspOrRedirection)
+        ));
+        assert!(matches!(
+            NetworkFilter::parse("||foo.com^$csp=script-src 'none',removeparam=a", true, Default::default()),
+            Err(NetworkFilterError::RemoveparamWithCspOrRedirection)
+        ));
+    }
+
+    #[test]
+    fn normalizes_removeparam_dialects() {
+        let parse = |line: &str| NetworkFilter::parse(line, true, Default::default());
+        let removeparam = |line: &str| parse(line).unwrap().removeparam;
+
+        assert_eq!(removeparam("||foo.com^$queryprune=utm_source"), Some(String::from("utm_source")));
+        assert_eq!(removeparam("||foo.com^$queryprune"), Some(String::new()));
+        // Escaped commas are part of the regex, rather than separating options
+        assert_eq!(removeparam("||foo.com^$removeparam=/^utm_[a-z]{1\\,8}=/i,xhr"), Some(String::from("/^utm_[a-z]{1,8}=/i")));
+        assert_eq!(removeparam("||foo.com^$removeparam=/^utm_/ii"), Some(String::from("/^utm_/i")));
+        // Values which don't form a regex are parameter names
+        assert_eq!(removeparam("||foo.com^$removeparam=/utm_source"), Some(String::from("/utm_source")));
+        assert_eq!(removeparam("||foo.com^$removeparam=/a/b=c"), Some(String::from("/a/b=c")));
+
+        {
+            let filter = parse("||foo.com^$removeparam=~id").unwrap();
+            assert_eq!(filter.removeparam, Some(String::from("~id")));
+            assert_eq!(filter.removes_param("id=1"), false);
+            assert_eq!(filter.removes_param("ref=2"), true);
+        }
+        {
+            let filter = parse("||foo.com^$queryprune=~/^ID=/i").unwrap();
+            assert_eq!(filter.removeparam, Some(String::from("~/^ID=/i")));
+            assert_eq!(filter.removes_param("id=1"), false);
+            assert_eq!(filter.removes_param("ref=2"), true);
+        }
+
+        assert!(matches!(parse("||foo.com^$removeparam=/^utm_/g"), Err(NetworkFilterError::AmbiguousRemoveparam)));
+        assert!(matches!(parse("||foo.com^$removeparam=~"), Err(NetworkFilterError::AmbiguousRemoveparam)));
+        assert!(matches!(parse("||foo.com^$~queryprune=a"), Err(NetworkFilterError::NegatedRemoveparam)));
+        assert!(matches!(parse("||foo.com^$removeparam=~/utm_[/"), Err(NetworkFilterError::RegexParsingError(_))));
+    }
+
     #[test]
     fn parses_hosts_style() {
         {
@@ -2577,7 +3173,6 @@ mod parse_tests {
             "genericblock",
             "inline-script",
             "popunder",
-            "popup",
             "woot",
         ];
 
@@ -2931,6 +3526,25 @@ mod match_tests {
         filter_match_url("||s.foo.com", "https://substrings.foo.com", false);
     }
 
+    #[test]
+    fn check_unicode_path_works() {
+        let encoded = "%D1%80%D0%B5%D0%BA%D0%BB%D0%B0%D0%BC%D0%B0";
+
+        filter_match_url("/реклама/banner", "https://example.com/реклама/banner.gif", true);
+        filter_match_url("/реклама/banner", &format!("https://example.com/{}/banner.gif", encoded), true);
+        filter_match_url("/реклама/banner", &format!("https://example.com/{}/banner.gif", encoded.to_lowercase()), true);
+        filter_match_url("/реклама/banner", "https://example.com/новости/banner.gif", false);
+
+        filter_match_url("||example.com/реклама/", &format!("https://example.com/{}/", encoded), true);
+        filter_match_url(&format!("||example.com/{}/", encoded), "https://example.com/реклама/", true);
+        filter_match_url("||example.com/реклама/*.gif", "https://example.com/реклама/ad.gif", true);
+        filter_match_url("||example.com/реклама/*.gif", &format!("https://example.com/{}/ad.gif", encoded), true);
+
+        // NFD and NFC forms of the same character match each other
+        filter_match_url("/cafe\u{301}/ad", "https://example.com/caf\u{e9}/ad", true);
+        filter_match_url("/caf\u{e9}/ad", "https://example.com/cafe%CC%81/ad", true);
+    }
+
     #[test]
     fn check_hosts_style_works() {
         hosts_filter_match_url("foo.com", "https://foo.com/bar", true);
@@ -2948,6 +3562,28 @@ mod match_tests {
         hosts_filter_match_url("foo.com", "https://bar.foo.de", false);
     }
 
+    #[test]
+    // ||pattern:port
+    fn check_pattern_hostname_port_works() {
+        filter_match_url("||example.com:8080^", "https://example.com:8080/ads.js", true);
+        filter_match_url("||example.com:8080^", "http://sub.example.com:8080", true);
+        filter_match_url("||example.com:8080^", "https://example.com/ads.js", false);
+        filter_match_url("||example.com:8080^", "https://example.com:443/ads.js", false);
+        filter_match_url("||example.com:8080^", "https://example.com:80800/ads.js", false);
+        filter_match_url("||example.com:8080^", "https://example.org:8080/ads.js", false);
+
+        filter_match_url("||example.com:8080/ads", "https://example.com:8080/ads.js", true);
+        filter_match_url("||example.com:8080/ads", "https://example.com/ads.js", false);
+        filter_match_url("||example.com:8080/*.js", "https://example.com:8080/ads.js", true);
+        filter_match_url("||example.com:8080/*.js", "https://example.com:9090/ads.js", false);
+
+        // Default ports are implied by the scheme
+        filter_match_url("||example.com:443^", "https://example.com/ads.js", true);
+        filter_match_url("||example.com:443/ads", "https://example.com/ads.js", true);
+        filter_match_url("||example.com:80^", "http://example.com/ads.js", true);
+        filter_match_url("||example.com:443^", "http://example.com/ads.js", false);
+    }
+
     #[test]
     // ||pattern|
     fn check_pattern_hostname_right_anchor_filter_works() {
@@ -2968,6 +3604,74 @@ mod match_tests {
         filter_match_url("foo|", "https://bar.com/foo/baz", false);
     }
 
+    #[test]
+    fn check_pattern_match_case() {
+        filter_match_url("/static/ad.", "https://x.com/Static/Ad.js", true);
+        filter_match_url("/Static/Ad.", "https://x.com/static/ad.js", true);
+        filter_match_url("/static/ad.$match-case", "https://x.com/Static/Ad.js", false);
+        filter_match_url("/static/ad.$match-case", "https://x.com/static/ad.js", true);
+        filter_match_url("/Static/Ad.$match-case", "https://x.com/Static/Ad.js", true);
+        filter_match_url("/Static/Ad.$match-case", "https://x.com/static/ad.js", false);
+        // anchors
+        filter_match_url("|https://x.com/Ads$match-case", "https://x.com/Ads", true);
+        filter_match_url("|https://x.com/Ads|$match-case", "https://x.com/ads", false);
+        filter_match_url("||x.com/Ads$match-case", "https://x.com/Ads/1", true);
+        filter_match_url("||x.com/Ads$match-case", "https://x.com/ads/1", false);
+        filter_match_url("||x.com^*/Ads/$match-case", "https://www.x.com/a/Ads/1", true);
+        filter_match_url("||x.com^*/Ads/$match-case", "https://www.x.com/a/ads/1", false);
+        // regexes
+        filter_match_url("/Ads*^$match-case", "https://x.com/Ads/1", true);
+        filter_match_url("/Ads*^$match-case", "https://x.com/ads/1", false);
+        filter_match_url("/\\/Ads[0-9]/$match-case", "https://x.com/Ads1", true);
+        filter_match_url("/\\/Ads[0-9]/$match-case", "https://x.com/ads1", false);
+        filter_match_url("/\\/Ads[0-9]/", "https://x.com/ADS1", true);
+        // the hostname is never case-sensitive
+        filter_match_url("||X.com/Ads$match-case", "https://x.com/Ads", true);
+        filter_match_url("/Ads$match-case", "https://X.COM/Ads", true);
+    }
+
+    #[test]
+    // pattern^
+    fn check_pattern_separator_works() {
+        // end of the path
+        filter_match_url("/ads^", "https://foo.com/ads", true);
+        filter_match_url("/ads^*", "https://foo.com/ads", true);
+        filter_match_url("/ads^^", "https://foo.com/ads/", true);
+        filter_match_url("/ads^|", "https://foo.com/ads", true);
+        filter_match_url("/ads^x", "https://foo.com/ads", false);
+        // before the query
+        filter_match_url("/ads^", "https://foo.com/ads?x=1", true);
+        filter_match_url("/ads^x=1", "https://foo.com/ads?x=1", true);
+        // before a path segment
+        filter_match_url("/ads^", "https://foo.com/ads/banner.png", true);
+        filter_match_url("^ads^", "https://foo.com/ads/banner.png", true);
+        // not a separator
+        filter_match_url("/ads^", "https://foo.com/ads.png", false);
+        filter_match_url("/ads^", "https://foo.com/ads-banner", false);
+        filter_match_url("/ads^", "https://foo.com/ads_banner", false);
+        filter_match_url("/ads^", "https://foo.com/ads%2fbanner", false);
+    }
+
+    #[test]
+    fn check_pattern_lenient_separator_works() {
+        let opts = ParseOptions { separator_mode: SeparatorMode::Lenient, ..Default::default() };
+        let strict = NetworkFilter::parse("/ads^", true, Default::default()).unwrap();
+        let lenient = NetworkFilter::parse("/ads^", true, opts).unwrap();
+
+        for (url, strict_matches, lenient_matches) in [
+            ("https://foo.com/ads?x=1", true, true),
+            ("https://foo.com/ads", true, true),
+            ("https://foo.com/ads%2Fbanner.png", false, true),
+            ("https://foo.com/r?u=/ads%3fx", false, true),
+            ("https://foo.com/r?u=/ads%2e", false, false),
+            ("https://foo.com/r?u=/ads%41", false, false),
+        ].iter() {
+            let request = request::Request::from_url(url).unwrap();
+            assert_eq!(strict.matches(&request), *strict_matches, "{}", url);
+            assert_eq!(lenient.matches(&request), *lenient_matches, "{}", url);
+        }
+    }
+
     #[test]
     // |pattern
     fn check_pattern_left_anchor_filter_works() {
@@ -3165,7 +3869,28 @@ mod match_tests {
             assert_eq!(check_options(&network_filter, &request), false);
         }
 
+        {
+            let network_filter = NetworkFilter::parse("||foo$domain=foo.com", true, Default::default()).unwrap();
+            let request = request::Request::from_urls("https://foo.com/bar", "", "").unwrap();
+            assert_eq!(check_options(&network_filter, &request), false);
+        }
+        {
+            let network_filter = NetworkFilter::parse("||foo$domain=foo.com|~bar.com", true, Default::default()).unwrap();
+            let request = request::Request::from_urls("https://foo.com/bar", "", "").unwrap();
+            assert_eq!(check_options(&network_filter, &request), false);
+        }
+        {
+            let network_filter = NetworkFilter::parse("||foo", true, Default::default()).unwrap();
+            let request = request::Request::from_urls("https://foo.com/bar", "", "").unwrap();
+            assert_eq!(check_options(&network_filter, &request), true);
+        }
+
         // opt-not-domain
+        {
+            let network_filter = NetworkFilter::parse("||foo$domain=~bar.com", true, Default::default()).unwrap();
+            let request = request::Request::from_urls("https://foo.com/bar", "", "").unwrap();
+            assert_eq!(check_options(&network_filter, &request), true);
+        }
         {
             let network_filter = NetworkFilter::parse("||foo$domain=~bar.com", true, Default::default()).unwrap();
             let request =
@@ -3180,6 +3905,58 @@ mod match_tests {
         }
     }
 
+    #[test]
+    fn check_entity_domain_option_works() {
+        fn matches(filter: &NetworkFilter, source_url: &str) -> bool {
+            let request = request::Request::from_urls("https://cdn.com/ad.js", source_url, "script").unwrap();
+            check_options(filter, &request)
+        }
+
+        let unexpanded = ParseOptions::default();
+        let expanded = ParseOptions { entity_expansion_limit: 100, ..Default::default() };
+
+        // Entities are matched at runtime when not expanded
+        {
+            let filter = NetworkFilter::parse("/ad.js$domain=example.*", true, unexpanded).unwrap();
+            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("example.*")]));
+            assert_eq!(matches(&filter, "https://example.com"), true);
+            assert_eq!(matches(&filter, "https://www.example.co.uk"), true);
+            assert_eq!(matches(&filter, "https://example.xyz"), true);
+            assert_eq!(matches(&filter, "https://notexample.com"), false);
+            assert_eq!(matches(&filter, "https://example.notexample.com"), false);
+            assert_eq!(matches(&filter, ""), false);
+        }
+        // Expanded within the limit
+        {
+            let filter = NetworkFilter::parse("/ad.js$domain=example.*", true, expanded).unwrap();
+            assert_eq!(filter.opt_domains.as_ref().map(|d| d.len()), Some(ENTITY_EXPANSION_SUFFIXES.len() + 1));
+            assert_eq!(matches(&filter, "https://example.com"), true);
+            assert_eq!(matches(&filter, "https://www.example.co.uk"), true);
+            // Suffixes outside of the built-in list are still matched at runtime
+            assert_eq!(matches(&filter, "https://example.ie"), true);
+            assert_eq!(matches(&filter, "https://example.xyz"), true);
+            assert_eq!(matches(&filter, "https://notexample.com"), false);
+
+            let filter = NetworkFilter::parse("/ad.js$domain=~example.*", true, expanded).unwrap();
+            assert_eq!(filter.opt_not_domains.as_ref().map(|d| d.len()), Some(ENTITY_EXPANSION_SUFFIXES.len() + 1));
+            assert_eq!(matches(&filter, "https://example.com"), false);
+            assert_eq!(matches(&filter, "https://example.xyz"), false);
+            assert_eq!(matches(&filter, "https://other.com"), true);
+        }
+        // Too many entities to expand within the limit
+        {
+            let filter = NetworkFilter::parse("/ad.js$domain=a.*|b.*|c.*|d.*|example.*|~sub.example.*", true, expanded).unwrap();
+            assert_eq!(filter.opt_domains.as_ref().map(|d| d.len()), Some(5));
+            assert_eq!(filter.opt_not_domains, Some(vec![utils::fast_hash("sub.example.*")]));
+            assert_eq!(matches(&filter, "https://example.com"), true);
+            assert_eq!(matches(&filter, "https://www.example.co.uk"), true);
+            assert_eq!(matches(&filter, "https://example.xyz"), true);
+            assert_eq!(matches(&filter, "https://d.de"), true);
+            assert_eq!(matches(&filter, "https://sub.example.com"), false);
+            assert_eq!(matches(&filter, "https://e.com"), false);
+        }
+    }
+
     #[test]
     fn check_domain_option_subsetting_works() {
         {
@@ -13,6 +13,7 @@ pub mod url_parser;
 pub mod engine;
 pub mod resources;
 pub mod cosmetic_filter_cache;
+pub mod testing;
 mod data_format;
 #[cfg(feature = "content-blocking")]
 pub mod content_blocking;
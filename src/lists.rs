@@ -38,13 +38,34 @@ pub struct ParseOptions {
     /// `redirect-url` option will be ignored.
     #[serde(default)]
     pub include_redirect_urls: bool,
+    /// Entities like `example.*` in a `$domain` option can be expanded into concrete domains using
+    /// a built-in list of common public suffixes, which allows them to be indexed like any other
+    /// domain. Expansion only takes place if the rule would end up with no more than this many
+    /// domains; otherwise, the entity is kept as-is and matched against the source hostname at
+    /// runtime using the public suffix list. Expanded rules keep the entity too, so suffixes
+    /// outside of the built-in list are still matched at runtime. Defaults to `0`, i.e. entities
+    /// are only matched at runtime.
+    #[serde(default)]
+    pub entity_expansion_limit: usize,
+    /// Network filters whose pattern, excluding any anchors and options, is longer than this many
+    /// bytes are rejected with `NetworkFilterError::PatternTooLong`. This guards against lists
+    /// crafted to make matching expensive. Defaults to `None`, i.e. no limit.
+    #[serde(default)]
+    pub max_pattern_length: Option<usize>,
+    /// Chooses which characters the `^` separator placeholder in network filter patterns will
+    /// match. Defaults to `SeparatorMode::Strict`.
+    #[serde(default)]
+    pub separator_mode: SeparatorMode,
 }
 
 impl Default for ParseOptions {
     fn default() -> Self {
         ParseOptions {
             format: FilterFormat::Standard,
-            include_redirect_urls: false
+            include_redirect_urls: false,
+            entity_expansion_limit: 0,
+            max_pattern_length: None,
+            separator_mode: SeparatorMode::Strict,
         }
     }
 }
@@ -85,6 +106,19 @@ pub struct FilterSet {
     debug: bool,
     pub(crate) network_filters: Vec<NetworkFilter>,
     pub(crate) cosmetic_filters: Vec<CosmeticFilter>,
+    /// Filters added through `add_list`, which are tracked separately by list id.
+    pub(crate) lists: Vec<ListSource>,
+    /// Rules that could not be loaded, along with the reason why.
+    pub(crate) skipped: Vec<(String, SkipReason)>,
+}
+
+/// The filters of a single list added to a `FilterSet` using `FilterSet::add_list`.
+#[derive(Clone)]
+pub(crate) struct ListSource {
+    id: String,
+    opts: ParseOptions,
+    network_filters: Vec<NetworkFilter>,
+    cosmetic_filters: Vec<CosmeticFilter>,
 }
 
 impl Default for FilterSet {
@@ -110,6 +144,8 @@ impl FilterSet {
             debug,
             network_filters: Vec::new(),
             cosmetic_filters: Vec::new(),
+            lists: Vec::new(),
+            skipped: Vec::new(),
         }
     }
 
@@ -123,9 +159,75 @@ impl FilterSet {
     /// Adds a collection of filter rules to this `FilterSet`. Filters that cannot be parsed
     /// successfully are ignored.
     pub fn add_filters(&mut self, filters: &[String], opts: ParseOptions) {
-        let (mut parsed_network_filters, mut parsed_cosmetic_filters) = parse_filters(&filters, self.debug, opts);
+        let (mut parsed_network_filters, mut parsed_cosmetic_filters, mut skipped) = parse_filters_with_skipped(&filters, self.debug, opts);
         self.network_filters.append(&mut parsed_network_filters);
         self.cosmetic_filters.append(&mut parsed_cosmetic_filters);
+        self.skipped.append(&mut skipped);
+    }
+
+    /// Adds a collection of filter rules to this `FilterSet`, restricting every one of them to
+    /// only apply on `domains` and their subdomains. Network rules receive a `$domain=` option
+    /// and cosmetic rules receive a hostname prefix. Rules which are already limited to other
+    /// domains keep only the locations they have in common with `domains`, and are ignored if
+    /// there are none. Filters that cannot be parsed successfully are ignored.
+    pub fn add_filters_scoped(&mut self, filters: &[String], domains: &[String], opts: ParseOptions) {
+        let scoped_filters = filters.iter().filter_map(|line| match opts.format {
+            FilterFormat::Standard => scope_filter(line.trim(), domains),
+            // Hosts rules are equivalent to `||hostname^`, which can be given options
+            FilterFormat::Hosts => match parse_filter(line, false, opts) {
+                Ok(ParsedFilter::Network(NetworkFilter { hostname: Some(hostname), .. })) => {
+                    scope_filter(&format!("||{}^", hostname), domains)
+                }
+                _ => None,
+            },
+        }).collect::<Vec<_>>();
+        self.add_filters(&scoped_filters, ParseOptions { format: FilterFormat::Standard, ..opts });
+    }
+
+    /// Adds a collection of filter rules to this `FilterSet`, keeping track of them as belonging to
+    /// the list identified by `id`. An `Engine` created from this `FilterSet` can later replace
+    /// them using `Engine::replace_list`. Filters that cannot be parsed successfully are ignored.
+    ///
+    /// Adding rules with an `id` that has already been used will add them to the existing list.
+    pub fn add_list(&mut self, id: &str, filters: &[String], opts: ParseOptions) {
+        let (mut parsed_network_filters, mut parsed_cosmetic_filters, mut skipped) = parse_filters_with_skipped(filters, self.debug, opts);
+        self.skipped.append(&mut skipped);
+        if let Some(list) = self.lists.iter_mut().find(|list| list.id == id) {
+            list.network_filters.append(&mut parsed_network_filters);
+            list.cosmetic_filters.append(&mut parsed_cosmetic_filters);
+        } else {
+            self.lists.push(ListSource {
+                id: id.to_string(),
+                opts,
+                network_filters: parsed_network_filters,
+                cosmetic_filters: parsed_cosmetic_filters,
+            });
+        }
+    }
+
+    /// Replaces all filters of the list identified by `id` with `filters`, parsed with the same
+    /// options that the list was originally added with. Returns `false` if there is no such list.
+    pub(crate) fn replace_list(&mut self, id: &str, filters: &[String]) -> bool {
+        let debug = self.debug;
+        if let Some(list) = self.lists.iter_mut().find(|list| list.id == id) {
+            let (parsed_network_filters, parsed_cosmetic_filters) = parse_filters(filters, debug, list.opts);
+            list.network_filters = parsed_network_filters;
+            list.cosmetic_filters = parsed_cosmetic_filters;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes this `FilterSet`, returning all of its network and cosmetic filters, including
+    /// those from tracked lists.
+    pub(crate) fn into_filters(self) -> (Vec<NetworkFilter>, Vec<CosmeticFilter>) {
+        let FilterSet { mut network_filters, mut cosmetic_filters, lists, .. } = self;
+        for mut list in lists {
+            network_filters.append(&mut list.network_filters);
+            cosmetic_filters.append(&mut list.cosmetic_filters);
+        }
+        (network_filters, cosmetic_filters)
     }
 
     /// Adds the string representation of a single filter rule to this `FilterSet`.
@@ -155,13 +257,15 @@ impl FilterSet {
             return Err(())
         }
 
+        let (network_filters, cosmetic_filters) = self.into_filters();
+
         let mut ignore_previous_rules = vec![];
         let mut other_rules = vec![];
 
         let mut filters_used = vec![];
 
         if rule_types.loads_network_rules() {
-            self.network_filters.into_iter().for_each(|filter| {
+            network_filters.into_iter().for_each(|filter| {
                 let original_rule = filter.raw_line.clone().expect("All rules should be in debug mode");
                 if let Ok(equivalent) = TryInto::<content_blocking::CbRuleEquivalent>::try_into(filter) {
                     filters_used.push(original_rule);
@@ -176,7 +280,7 @@ impl FilterSet {
         }
 
         if rule_types.loads_cosmetic_rules() {
-            self.cosmetic_filters.into_iter().for_each(|filter| {
+            cosmetic_filters.into_iter().for_each(|filter| {
                 let original_rule = filter.raw_line.clone().expect("All rules should be in debug mode");
                 if let Ok(cb_rule) = TryInto::<content_blocking::CbRule>::try_into(filter) {
                     filters_used.push(original_rule);
@@ -198,6 +302,25 @@ impl FilterSet {
     }
 }
 
+/// Determines what the `^` separator placeholder in a network filter pattern can match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SeparatorMode {
+    /// `^` matches any single character other than an ASCII letter, a digit, or one of `_`, `-`,
+    /// `.`, or `%`. It also matches the end of the URL, wherever it appears in the pattern.
+    Strict,
+    /// Like `Strict`, but `^` will also match a percent-encoded separator such as `%2f` or `%3f`.
+    /// This allows rules to catch URLs that have been embedded in another URL's query string.
+    Lenient,
+}
+
+// Deriving this would need `#[default]`, which requires Rust 1.62
+#[allow(clippy::derivable_impls)]
+impl Default for SeparatorMode {
+    fn default() -> Self {
+        Self::Strict
+    }
+}
+
 /// Denotes the format of a particular list resource, which affects how its rules should be parsed.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum FilterFormat {
@@ -269,6 +392,57 @@ impl From<CosmeticFilterError> for FilterParseError {
     }
 }
 
+/// Describes why a rule from a list was not loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    /// The rule uses an option that isn't supported, given here by name, e.g. `popunder` for
+    /// `||example.com^$popunder`.
+    UnsupportedOption(String),
+    /// The rule is a network filter which is invalid for another reason.
+    InvalidNetworkFilter(NetworkFilterError),
+    /// The rule is a cosmetic filter which is invalid.
+    InvalidCosmeticFilter(CosmeticFilterError),
+}
+
+/// A rule from a list that could not be loaded, as returned by `parse_filters_with_report`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Zero-based index of the rule within the list.
+    pub index: usize,
+    /// The original text of the rule.
+    pub line: String,
+    pub reason: ParseErrorReason,
+}
+
+/// Describes why a rule reported by `parse_filters_with_report` could not be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorReason {
+    /// The rule uses an option that isn't known at all, given here by name, e.g. `foobar` for
+    /// `||example.com^$foobar`.
+    UnknownOption(String),
+    /// The rule uses a modifier from another content blocker's syntax which isn't supported, or
+    /// which has not been enabled in `ParseOptions`, given here by name, e.g. `removeheader`.
+    UnsupportedModifier(String),
+    /// The rule is written in a syntax that isn't supported, like AdGuard's `#$#` or `#?#`.
+    UnsupportedSyntax,
+    /// The rule has nothing to match against, like `||` or `example.com##`.
+    EmptyPattern,
+    /// The rule is a regex filter, like `/ads[0-9/`, which fails to compile.
+    BadRegex,
+    /// The rule is a network filter which is invalid for another reason.
+    InvalidNetworkFilter(NetworkFilterError),
+    /// The rule is a cosmetic filter which is invalid for another reason.
+    InvalidCosmeticFilter(CosmeticFilterError),
+}
+
+/// Options from other content blockers' syntax that are recognised by name, but not supported.
+const UNSUPPORTED_MODIFIERS: [&str; 29] = [
+    "all", "app", "cname", "content", "cookie", "denyallow", "ehide", "elemhide", "empty",
+    "extension", "from", "header", "hls", "inline-font", "inline-script", "jsinject", "jsonprune",
+    "method", "mp4", "network", "permissions", "popunder", "redirect-rule", "redirect-url",
+    "removeheader", "replace", "shide", "specifichide", "to",
+];
+
 /// Parse a single filter rule
 pub fn parse_filter(
     line: &str,
@@ -354,6 +528,193 @@ pub fn parse_filters(
     (network_filters, cosmetic_filters)
 }
 
+/// Parse only the network filters from an entire list, ignoring any errors. Cosmetic rules are
+/// recognized by their syntax and skipped without being parsed, which makes this faster than
+/// `parse_filters` for lists with many cosmetic rules. The resulting network filters are the same
+/// as those returned by `parse_filters`.
+pub fn parse_network_filters(
+    list: &[String],
+    debug: bool,
+    opts: ParseOptions,
+) -> Vec<NetworkFilter> {
+    list.iter()
+        .filter_map(|line| match opts.format {
+            FilterFormat::Standard => {
+                let filter = line.trim();
+                match detect_filter_type(filter) {
+                    FilterType::Network if !filter.is_empty() => NetworkFilter::parse(filter, debug, opts).ok(),
+                    _ => None,
+                }
+            }
+            FilterFormat::Hosts => match parse_filter(line, debug, opts) {
+                Ok(ParsedFilter::Network(f)) => Some(f),
+                _ => None,
+            },
+        })
+        .collect()
+}
+
+/// Parse an entire list of filters, also returning any rules that were dropped along with the
+/// reason why. Comments, empty lines, and rules written in an unsupported syntax are ignored
+/// without being reported.
+pub(crate) fn parse_filters_with_skipped(
+    list: &[String],
+    debug: bool,
+    opts: ParseOptions,
+) -> (Vec<NetworkFilter>, Vec<CosmeticFilter>, Vec<(String, SkipReason)>) {
+    let mut network_filters = vec![];
+    let mut cosmetic_filters = vec![];
+    let mut skipped = vec![];
+
+    for line in list {
+        match parse_filter(line, debug, opts) {
+            Ok(ParsedFilter::Network(f)) => network_filters.push(f),
+            Ok(ParsedFilter::Cosmetic(f)) => cosmetic_filters.push(f),
+            Err(FilterParseError::Network(NetworkFilterError::UnrecognisedOption)) => {
+                let reason = match crate::filters::network::find_unrecognised_option(line.trim(), opts) {
+                    Some(option) => SkipReason::UnsupportedOption(option.to_string()),
+                    None => SkipReason::InvalidNetworkFilter(NetworkFilterError::UnrecognisedOption),
+                };
+                skipped.push((line.to_string(), reason));
+            }
+            Err(FilterParseError::Network(e)) => skipped.push((line.to_string(), SkipReason::InvalidNetworkFilter(e))),
+            Err(FilterParseError::Cosmetic(e)) => skipped.push((line.to_string(), SkipReason::InvalidCosmeticFilter(e))),
+            Err(FilterParseError::Unsupported) | Err(FilterParseError::Empty) => (),
+        }
+    }
+
+    (network_filters, cosmetic_filters, skipped)
+}
+
+/// Rewrites a single `FilterFormat::Standard` rule to only apply on `scope`, as described in
+/// `FilterSet::add_filters_scoped`. Returns `None` if the rule would never apply.
+fn scope_filter(filter: &str, scope: &[String]) -> Option<String> {
+    use crate::filters::network::{find_options_index, split_options};
+
+    match detect_filter_type(filter) {
+        FilterType::Network => match find_options_index(filter) {
+            Some(options_index) => {
+                let (domain_options, other_options): (Vec<_>, Vec<_>) = split_options(&filter[options_index + 1..])
+                    .into_iter()
+                    .partition(|option| option.starts_with("domain="));
+                let locations = domain_options.iter().flat_map(|option| option["domain=".len()..].split('|'));
+                let domains = scope_locations(locations, scope)?.join("|");
+                let options = std::iter::once(format!("domain={}", domains))
+                    .chain(other_options.into_iter().map(str::to_string))
+                    .join(",");
+                Some(format!("{}${}", &filter[..options_index], options))
+            }
+            None => Some(format!("{}$domain={}", filter, scope_locations(std::iter::empty(), scope)?.join("|"))),
+        }
+        FilterType::Cosmetic => {
+            let sharp_index = filter.find('#')?;
+            let hostnames = scope_locations(filter[..sharp_index].split(','), scope)?.join(",");
+            Some(format!("{}{}", hostnames, &filter[sharp_index..]))
+        }
+        FilterType::NotSupported => None,
+    }
+}
+
+/// Combines the comma or pipe separated locations of a rule with `scope`. Negated locations are
+/// kept as-is. If the rule has no other locations, it uses `scope`; otherwise, it keeps each of
+/// its locations which are in `scope`, as well as each domain in `scope` which is within one of
+/// its locations. Entities like `example.*` can't be compared against `scope`, so they are only
+/// kept when negated. Returns `None` if no locations remain.
+fn scope_locations<'a>(locations: impl Iterator<Item=&'a str>, scope: &[String]) -> Option<Vec<String>> {
+    fn is_within(hostname: &str, domain: &str) -> bool {
+        hostname == domain
+            || hostname.ends_with(domain) && hostname[..hostname.len() - domain.len()].ends_with('.')
+    }
+
+    let (negated, locations): (Vec<_>, Vec<_>) = locations
+        .filter(|location| !location.is_empty())
+        .partition(|location| location.starts_with('~'));
+
+    let mut scoped = if locations.is_empty() {
+        scope.to_vec()
+    } else {
+        let mut scoped = locations.iter()
+            .filter(|location| scope.iter().any(|domain| is_within(location, domain)))
+            .map(|location| location.to_string())
+            .collect::<Vec<_>>();
+        scoped.extend(scope.iter()
+            .filter(|domain| locations.iter().any(|location| is_within(domain, location)))
+            .filter(|domain| !locations.contains(&domain.as_str()))
+            .cloned());
+        scoped
+    };
+    if scoped.is_empty() {
+        return None;
+    }
+
+    scoped.extend(negated.into_iter().map(str::to_string));
+    Some(scoped)
+}
+
+/// Parse an entire list of filters, also reporting every rule that could not be loaded, along with
+/// its position in the list and the reason why. Comments, headers like `[Adblock Plus 2.0]`, and
+/// empty lines are not reported.
+///
+/// Unlike `parse_filters`, regex filters which fail to compile, and network filters with no
+/// pattern or options, are reported rather than returned.
+pub fn parse_filters_with_report(
+    list: &[String],
+    debug: bool,
+    opts: ParseOptions,
+) -> (Vec<NetworkFilter>, Vec<CosmeticFilter>, Vec<ParseError>) {
+    use crate::filters::network::{CompiledRegex, NetworkMatchable};
+
+    let mut network_filters = vec![];
+    let mut cosmetic_filters = vec![];
+    let mut errors = vec![];
+
+    for (index, line) in list.iter().enumerate() {
+        let reason = match parse_filter(line, debug, opts) {
+            Ok(ParsedFilter::Network(f)) => {
+                if f.is_complete_regex() && matches!(*f.get_regex(), CompiledRegex::RegexParsingError(_)) {
+                    ParseErrorReason::BadRegex
+                } else if line.trim().trim_start_matches("@@").trim_matches('|').is_empty() {
+                    ParseErrorReason::EmptyPattern
+                } else {
+                    network_filters.push(f);
+                    continue;
+                }
+            }
+            Ok(ParsedFilter::Cosmetic(f)) => {
+                cosmetic_filters.push(f);
+                continue;
+            }
+            Err(FilterParseError::Network(NetworkFilterError::UnrecognisedOption)) => {
+                match crate::filters::network::find_unrecognised_option(line.trim(), opts) {
+                    Some(option) if UNSUPPORTED_MODIFIERS.contains(&option) => ParseErrorReason::UnsupportedModifier(option.to_string()),
+                    Some(option) => ParseErrorReason::UnknownOption(option.to_string()),
+                    None => ParseErrorReason::InvalidNetworkFilter(NetworkFilterError::UnrecognisedOption),
+                }
+            }
+            Err(FilterParseError::Network(NetworkFilterError::RegexParsingError(_))) => ParseErrorReason::BadRegex,
+            Err(FilterParseError::Network(e)) => ParseErrorReason::InvalidNetworkFilter(e),
+            Err(FilterParseError::Cosmetic(CosmeticFilterError::EmptyRule)) => ParseErrorReason::EmptyPattern,
+            Err(FilterParseError::Cosmetic(e)) => ParseErrorReason::InvalidCosmeticFilter(e),
+            Err(FilterParseError::Unsupported) => {
+                let filter = line.trim();
+                let is_comment = filter.starts_with('!')
+                    || (filter.starts_with('#') && filter[1..].starts_with(char::is_whitespace))
+                    || filter.starts_with("[Adblock");
+                // Unsupported lines in HOSTS files are comments or entries which are deliberately
+                // ignored, like `localhost`
+                if is_comment || matches!(opts.format, FilterFormat::Hosts) {
+                    continue;
+                }
+                ParseErrorReason::UnsupportedSyntax
+            }
+            Err(FilterParseError::Empty) => continue,
+        };
+        errors.push(ParseError { index, line: line.to_string(), reason });
+    }
+
+    (network_filters, cosmetic_filters, errors)
+}
+
 /// Given a single line, checks if this would likely be a cosmetic filter, a
 /// network filter or something that is not supported. This check is performed
 /// before calling a more specific parser to create an instance of
@@ -474,6 +835,112 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_network_filters_matches_parse_filters() {
+        fn ids(filters: &[NetworkFilter]) -> Vec<(crate::utils::Hash, Option<String>)> {
+            filters.iter().map(|f| (f.id, f.raw_line.clone())).collect()
+        }
+
+        let list = [
+            "||ads.com^",
+            "",
+            "! comment",
+            "[Adblock Plus 2.0]",
+            "example.com##.ad",
+            "##.banner",
+            "example.com#@#.ad",
+            "example.com#?#div:has-text(ad)",
+            "example.com#$#.ad { display: none; }",
+            "/ads/*$script,domain=a.com",
+            "/page#anchor",
+            "#",
+            "  @@||ads.com/ok^  ",
+            "||bad.com^$unknownoption",
+        ].iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let easylist = crate::utils::read_file_lines("data/easylist.to/easylist/easylist.txt");
+
+        for list in [list, easylist].iter() {
+            let (expected, _) = parse_filters(list, true, Default::default());
+            assert_eq!(ids(&parse_network_filters(list, true, Default::default())), ids(&expected));
+        }
+
+        let hosts = ["127.0.0.1 ads.com", "# comment", "0.0.0.0 localhost"].iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let opts = ParseOptions { format: FilterFormat::Hosts, ..Default::default() };
+        assert_eq!(ids(&parse_network_filters(&hosts, true, opts)), ids(&parse_filters(&hosts, true, opts).0));
+    }
+
+    #[test]
+    fn parse_filters_with_report_works() {
+        let list = [
+            "! Title: custom list",
+            "[Adblock Plus 2.0]",
+            "",
+            "   ",
+            "# not a rule",
+            "||ads.com^",
+            "||ads.com^$foobar",
+            "||ads.com^$removeheader=location",
+            "||ads.com^$redirect-url=https://example.com",
+            "/ads[0-9/",
+            "||",
+            "@@",
+            "example.com##",
+            "example.com#?#div:has-text(ad)",
+            "||ads.com^$~important",
+            "##+js(set-constant, a, 1)",
+            "example.com##.ad",
+            "$script,domain=example.com",
+        ].iter().map(|l| l.to_string()).collect::<Vec<_>>();
+
+        let (network_filters, cosmetic_filters, errors) = parse_filters_with_report(&list, true, Default::default());
+        assert_eq!(network_filters.len(), 2);
+        assert_eq!(cosmetic_filters.len(), 1);
+
+        let error = |index: usize, reason| ParseError { index, line: list[index].clone(), reason };
+        assert_eq!(errors, vec![
+            error(6, ParseErrorReason::UnknownOption("foobar".to_string())),
+            error(7, ParseErrorReason::UnsupportedModifier("removeheader".to_string())),
+            error(8, ParseErrorReason::UnsupportedModifier("redirect-url".to_string())),
+            error(9, ParseErrorReason::BadRegex),
+            error(10, ParseErrorReason::EmptyPattern),
+            error(11, ParseErrorReason::EmptyPattern),
+            error(12, ParseErrorReason::EmptyPattern),
+            error(13, ParseErrorReason::UnsupportedSyntax),
+            error(14, ParseErrorReason::InvalidNetworkFilter(NetworkFilterError::NegatedImportant)),
+            error(15, ParseErrorReason::InvalidCosmeticFilter(CosmeticFilterError::GenericScriptInject)),
+        ]);
+
+        // Enabled modifiers are no longer reported
+        let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
+        let (_, _, errors) = parse_filters_with_report(&list[8..9], true, opts);
+        assert_eq!(errors, vec![]);
+
+        let hosts = ["# comment", "127.0.0.1 localhost", "0.0.0.0 ads.com", "0.0.0.0 bad/host.com"].iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let (network_filters, _, errors) = parse_filters_with_report(&hosts, true, ParseOptions { format: FilterFormat::Hosts, ..Default::default() });
+        assert_eq!(network_filters.len(), 1);
+        assert_eq!(errors, vec![ParseError { index: 3, line: hosts[3].clone(), reason: ParseErrorReason::InvalidNetworkFilter(NetworkFilterError::FilterParseError) }]);
+    }
+
+    #[test]
+    fn scope_filter_works() {
+        let scope = ["a.com".to_string(), "b.com".to_string()];
+
+        assert_eq!(scope_filter("||ads.com^", &scope).as_deref(), Some("||ads.com^$domain=a.com|b.com"));
+        assert_eq!(scope_filter("@@||ads.com^$script", &scope).as_deref(), Some("@@||ads.com^$domain=a.com|b.com,script"));
+        assert_eq!(scope_filter("/ads$domain=sub.a.com|c.com|~x.b.com,image", &scope).as_deref(), Some("/ads$domain=sub.a.com|~x.b.com,image"));
+        assert_eq!(scope_filter("/ads$domain=com", &scope).as_deref(), Some("/ads$domain=a.com|b.com"));
+        assert_eq!(scope_filter("/ads$domain=c.com|aa.com", &scope), None);
+        assert_eq!(scope_filter("/ads$redirect=data:text/plain,a,b", &scope).as_deref(), Some("/ads$domain=a.com|b.com,redirect=data:text/plain,a,b"));
+
+        assert_eq!(scope_filter("##.ad", &scope).as_deref(), Some("a.com,b.com##.ad"));
+        assert_eq!(scope_filter("~x.a.com#@#.ad", &scope).as_deref(), Some("a.com,b.com,~x.a.com#@#.ad"));
+        assert_eq!(scope_filter("b.com,c.com,example.*##.ad", &scope).as_deref(), Some("b.com##.ad"));
+        assert_eq!(scope_filter("c.com##.ad", &scope), None);
+
+        assert_eq!(scope_filter("! comment", &scope), None);
+        assert_eq!(scope_filter("##.ad", &[]), None);
+    }
+
     #[test]
     fn parse_filter_failed_fuzz_1() {
         let input = "Ѥ";
@@ -1,36 +1,101 @@
 use crate::filters::network::{NetworkFilter, NetworkFilterMask, FilterPart};
+use crate::utils::Hash;
 use itertools::*;
 use std::collections::{HashMap, HashSet};
 
-trait Optimization {
-    fn fusion(&self, filters: &[NetworkFilter]) -> NetworkFilter;
+/// Optimized filters can be serialized, so that clients don't need to optimize them again.
+pub use crate::data_format::{serialize_filters, deserialize_filters, SerializationError, DeserializationError};
+
+/// A pass of the optimizer, which fuses groups of similar filters into single filters. Passes are
+/// run in sequence by `optimize_with`, and each pass is offered every filter that previous passes
+/// did not fuse.
+pub trait Optimization {
+    /// Fuses a group of filters into a single filter. Returns `None` if `filters` is empty, or if
+    /// they should be left unfused after all.
+    fn fusion(&self, filters: &[NetworkFilter]) -> Option<NetworkFilter>;
+    /// Key identifying the group of a selected filter. Filters with the same key are fused.
     fn group_by_criteria(&self, filter: &NetworkFilter) -> String;
+    /// Whether this pass can fuse `filter` at all.
     fn select(&self, filter: &NetworkFilter) -> bool;
 }
 
+/// Summary of the work done by `optimize_with_stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OptimizeStats {
+    /// Number of filters passed in to be optimized.
+    pub input_filters: usize,
+    /// Number of fused filters produced, each replacing a group of at least 2 input filters.
+    pub fused_groups: usize,
+    /// Number of input filters that were left as-is, because no other filter could be fused with
+    /// them.
+    pub unfused_filters: usize,
+    /// Number of input filters in the largest group fused together, or 0 if nothing was fused.
+    pub largest_group: usize,
+}
+
+/// Removes every filter cancelled by a `$badfilter` rule, along with the `$badfilter` rules
+/// themselves. A `$badfilter` rule cancels filters with the same pattern and options, other than
+/// `badfilter` itself, regardless of the order that the options are written in.
+///
+/// `Blocker::new` does this automatically, but it should also be done before calling `optimize`
+/// directly, since fused filters can no longer be cancelled individually.
+pub fn apply_badfilters(filters: Vec<NetworkFilter>) -> Vec<NetworkFilter> {
+    let badfilter_ids: HashSet<Hash> = filters.iter()
+        .filter(|f| f.is_badfilter())
+        .map(|f| f.get_id_without_badfilter())
+        .collect();
+    filters.into_iter()
+        .filter(|f| !f.is_badfilter() && !badfilter_ids.contains(&f.get_id()))
+        .collect()
+}
+
+/// The passes used by `optimize`, in order.
+pub fn default_passes() -> [&'static dyn Optimization; 3] {
+    [&UnionDomainGroup {}, &SimplePatternGroup {}, &HostnameAnchorGroup {}]
+}
+
 /// Fuse `NetworkFilter`s together by applying optimizations sequentially.
 pub fn optimize(filters: Vec<NetworkFilter>) -> Vec<NetworkFilter> {
-    let mut optimized: Vec<NetworkFilter> = Vec::new();
+    optimize_with_stats(filters).0
+}
+
+/// Like `optimize`, but also reports how many of the filters were fused together.
+pub fn optimize_with_stats(filters: Vec<NetworkFilter>) -> (Vec<NetworkFilter>, OptimizeStats) {
+    optimize_with_passes(filters, &default_passes())
+}
 
-    // let union_domain_group = UnionDomainGroup {};
-    // let (mut fused, unfused) = apply_optimisation(&union_domain_group, filters);
-    // optimized.append(&mut fused);
+/// Like `optimize`, but applies the given `passes` in order instead of `default_passes()`.
+pub fn optimize_with(filters: Vec<NetworkFilter>, passes: &[&dyn Optimization]) -> Vec<NetworkFilter> {
+    optimize_with_passes(filters, passes).0
+}
 
-    let simple_pattern_group = SimplePatternGroup {};
-    let (mut fused, mut unfused) = apply_optimisation(&simple_pattern_group, filters);
-    optimized.append(&mut fused);
+fn optimize_with_passes(filters: Vec<NetworkFilter>, passes: &[&dyn Optimization]) -> (Vec<NetworkFilter>, OptimizeStats) {
+    let mut stats = OptimizeStats {
+        input_filters: filters.len(),
+        ..Default::default()
+    };
+    let mut optimized: Vec<NetworkFilter> = Vec::new();
+
+    let mut unfused = filters;
+    for pass in passes {
+        let (mut fused, remaining) = apply_optimisation(*pass, unfused, &mut stats);
+        optimized.append(&mut fused);
+        unfused = remaining;
+    }
 
     // Append whatever is still left unfused
+    stats.unfused_filters = unfused.len();
     optimized.append(&mut unfused);
 
     // Re-sort the list, now that the order has been perturbed
     optimized.sort_by_key(|f| f.id);
-    optimized
+    (optimized, stats)
 }
 
-fn apply_optimisation<T: Optimization>(
+fn apply_optimisation<T: Optimization + ?Sized>(
     optimization: &T,
     filters: Vec<NetworkFilter>,
+    stats: &mut OptimizeStats,
 ) -> (Vec<NetworkFilter>, Vec<NetworkFilter>) {
     let (positive, mut negative): (Vec<NetworkFilter>, Vec<NetworkFilter>) =
         filters.into_iter().partition_map(|f| {
@@ -48,9 +113,16 @@ fn apply_optimisation<T: Optimization>(
 
     let mut fused = Vec::with_capacity(to_fuse.len());
     for (_, group) in to_fuse {
-        if group.len() > 1 {
+        let fused_group = if group.len() > 1 {
+            optimization.fusion(group.as_slice())
+        } else {
+            None
+        };
+        if let Some(filter) = fused_group {
             // println!("Fusing {} filters together", group.len());
-            fused.push(optimization.fusion(group.as_slice()));
+            stats.fused_groups += 1;
+            stats.largest_group = stats.largest_group.max(group.len());
+            fused.push(filter);
         } else {
             group.into_iter().for_each(|f| negative.push(f));
         }
@@ -68,13 +140,15 @@ where
     map.entry(k).or_insert_with(Vec::new).push(v)
 }
 
-struct SimplePatternGroup {}
+/// Fuses filters with the same options and no hostname anchor into a single filter matching any
+/// of their patterns.
+pub struct SimplePatternGroup {}
 
 impl Optimization for SimplePatternGroup {
     // Group simple patterns, into a single filter
 
-    fn fusion(&self, filters: &[NetworkFilter]) -> NetworkFilter {
-        let base_filter = &filters[0]; // FIXME: can technically panic, if filters list is empty
+    fn fusion(&self, filters: &[NetworkFilter]) -> Option<NetworkFilter> {
+        let base_filter = filters.first()?;
         let mut filter = base_filter.clone();
 
         // if any filter is empty (meaning matches anything), the entire combiation matches anything
@@ -114,11 +188,11 @@ impl Optimization for SimplePatternGroup {
             )
         }
 
-        filter
+        Some(filter)
     }
 
     fn group_by_criteria(&self, filter: &NetworkFilter) -> String {
-        format!("{:b}:{:?}", filter.mask, filter.is_complete_regex())
+        format!("{:b}:{:?}:{:?}", filter.mask, filter.is_complete_regex(), filter.separator_mode)
     }
     fn select(&self, filter: &NetworkFilter) -> bool {
         filter.opt_domains.is_none()
@@ -126,16 +200,94 @@ impl Optimization for SimplePatternGroup {
             && !filter.is_hostname_anchor()
             && !filter.is_redirect()
             && !filter.is_csp()
+            && !filter.is_removeparam()
+            && !filter.has_bug()
+    }
+}
+
+/// Fuses hostname anchored filters for the same hostname and options into a single filter
+/// matching any of their patterns.
+///
+/// Filters are only grouped if their hostnames are exactly equal, so rules for distinct
+/// hostnames like `||a.com^` and `||b.com^`, which make up most of lists like EasyList, are never
+/// fused. The pass only helps lists with several path patterns under the same hostname.
+pub struct HostnameAnchorGroup {}
+
+impl Optimization for HostnameAnchorGroup {
+    // Group hostname anchored patterns for the same hostname, e.g. `||example.com/ads` and
+    // `||example.com/banner`, into a single filter. Filters with no pattern after the hostname,
+    // like `||example.com^`, are only grouped with each other, since matching an empty pattern
+    // depends on the hostname alone.
+
+    fn fusion(&self, filters: &[NetworkFilter]) -> Option<NetworkFilter> {
+        let base_filter = filters.first()?;
+        let mut filter = base_filter.clone();
+
+        let mut flat_patterns: Vec<String> = Vec::with_capacity(filters.len());
+        for f in filters {
+            match &f.filter {
+                FilterPart::Empty => (),
+                FilterPart::Simple(s) => flat_patterns.push(s.clone()),
+                FilterPart::AnyOf(s) => flat_patterns.extend_from_slice(s)
+            }
+        }
+        flat_patterns.sort_unstable();
+        flat_patterns.dedup();
+
+        if flat_patterns.is_empty() {
+            filter.filter = FilterPart::Empty;
+        } else if flat_patterns.len() == 1 {
+            filter.filter = FilterPart::Simple(flat_patterns[0].clone())
+        } else {
+            filter.filter = FilterPart::AnyOf(flat_patterns)
+        }
+
+        if base_filter.raw_line.is_some() {
+            filter.raw_line = Some(
+                filters
+                    .iter()
+                    .flat_map(|f| f.raw_line.clone())
+                    .join(" <+> "),
+            )
+        }
+
+        Some(filter)
+    }
+
+    fn group_by_criteria(&self, filter: &NetworkFilter) -> String {
+        format!(
+            "{:b}:{:?}:{:?}:{}",
+            filter.mask,
+            filter.hostname.as_ref(),
+            filter.separator_mode,
+            matches!(filter.filter, FilterPart::Empty),
+        )
+    }
+    fn select(&self, filter: &NetworkFilter) -> bool {
+        filter.opt_domains.is_none()
+            && filter.opt_not_domains.is_none()
+            && filter.is_hostname_anchor()
+            && filter.hostname.is_some()
+            && !filter.is_redirect()
+            && !filter.is_csp()
+            && !filter.is_removeparam()
             && !filter.has_bug()
     }
 }
 
-struct UnionDomainGroup {}
+/// Fuses filters which only differ in their `$domain` options into a single filter, enabled on
+/// the union of their domains.
+///
+/// A filter matches if the source is one of its included domains, if it has any, and is none of
+/// its excluded domains. Taking the union of excluded domains would disable the fused filter on
+/// sources where one of the original filters matched, so filters are only fused if they exclude
+/// exactly the same domains and either all or none of them include specific domains.
+pub struct UnionDomainGroup {}
 
 impl Optimization for UnionDomainGroup {
 
-    fn fusion(&self, filters: &[NetworkFilter]) -> NetworkFilter {
-        let base_filter = &filters[0]; // FIXME: can technically panic, if filters list is empty
+    fn fusion(&self, filters: &[NetworkFilter]) -> Option<NetworkFilter> {
+        let base_filter = filters.first()?;
         let mut filter = base_filter.clone();
         let mut domains = HashSet::new();
         let mut not_domains = HashSet::new();
@@ -178,15 +330,26 @@ impl Optimization for UnionDomainGroup {
             )
         }
 
-        filter
+        Some(filter)
     }
 
     fn group_by_criteria(&self, filter: &NetworkFilter) -> String {
-        format!("{:?}:{}:{:b}:{:?}", filter.hostname.as_ref(), filter.filter.string_view().unwrap_or_default(), filter.mask, filter.redirect.as_ref())
+        format!(
+            "{:?}:{:?}:{:b}:{:?}:{:?}:{:?}:{:?}:{}",
+            filter.hostname.as_ref(),
+            filter.filter,
+            filter.mask,
+            filter.redirect.as_ref(),
+            filter.tag.as_ref(),
+            filter.separator_mode,
+            filter.opt_not_domains.as_ref(),
+            filter.opt_domains.is_some(),
+        )
     }
 
     fn select(&self, filter: &NetworkFilter) -> bool {
         !filter.is_csp()
+            && !filter.is_removeparam()
             && !filter.has_bug()
             && (filter.opt_domains.is_some() || filter.opt_not_domains.is_some())
     }
@@ -235,6 +398,40 @@ mod optimization_tests_pattern_group {
         check_regex_match(&fused_regex, "/static/adv/foobar/asd?q=1", true);
     }
 
+    #[test]
+    fn regex_set_ignores_case() {
+        let rules = vec![
+            String::from("/static/ad."),
+            String::from("/static/ads/*"),
+        ];
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let fused = SimplePatternGroup {}.fusion(&filters).unwrap();
+
+        assert!(matches!(*fused.get_regex(), CompiledRegex::CompiledSet(_)));
+        let matches = |url: &str| fused.matches(&Request::from_urls(url, "https://example.com", "script").unwrap());
+        assert!(matches("https://example.net/static/ad.js"));
+        assert!(matches("https://example.net/Static/Ad.js"));
+        assert!(matches("https://example.net/STATIC/ADS/banner"));
+        assert!(!matches("https://example.net/Static/Adv/"));
+    }
+
+    #[test]
+    fn regex_set_match_case() {
+        let rules = vec![
+            String::from("/static/ad.$match-case"),
+            String::from("/static/ads/*$match-case"),
+        ];
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let fused = SimplePatternGroup {}.fusion(&filters).unwrap();
+
+        assert!(matches!(*fused.get_regex(), CompiledRegex::CompiledSet(_)));
+        let matches = |url: &str| fused.matches(&Request::from_urls(url, "https://example.com", "script").unwrap());
+        assert!(matches("https://example.net/static/ad.js"));
+        assert!(!matches("https://example.net/Static/Ad.js"));
+        assert!(!matches("https://example.net/STATIC/ADS/banner"));
+        assert!(matches("https://example.net/static/ads/banner"));
+    }
+
     #[test]
     fn combines_simple_regex_patterns() {
         let rules = vec![
@@ -253,7 +450,7 @@ mod optimization_tests_pattern_group {
             .iter()
             .for_each(|f| assert!(optimization.select(f), "Expected rule to be selected"));
 
-        let fused = optimization.fusion(&filters);
+        let fused = optimization.fusion(&filters).unwrap();
 
         assert!(fused.is_regex(), "Expected rule to be regex");
         assert_eq!(
@@ -280,6 +477,35 @@ mod optimization_tests_pattern_group {
         check_regex_match(&fused_regex, "/static/adv/foobar/asd?q=1", true);
     }
 
+    #[test]
+    fn reports_simple_regex_pattern_stats() {
+        let rules = vec![
+            String::from("/static/ad-"),
+            String::from("/static/ad."),
+            String::from("/static/ad/*"),
+            String::from("/static/ads/*"),
+            String::from("/static/adv/*"),
+        ];
+
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let (optimized, stats) = optimize_with_stats(filters);
+
+        assert_eq!(optimized.len(), 1);
+        assert_eq!(stats, OptimizeStats {
+            input_filters: 5,
+            fused_groups: 1,
+            unfused_filters: 0,
+            largest_group: 5,
+        });
+    }
+
+    #[test]
+    fn fusion_of_nothing() {
+        assert!(SimplePatternGroup {}.fusion(&[]).is_none());
+        assert!(HostnameAnchorGroup {}.fusion(&[]).is_none());
+        assert!(UnionDomainGroup {}.fusion(&[]).is_none());
+    }
+
     #[test]
     fn separates_pattern_by_grouping() {
         let rules = vec![
@@ -294,7 +520,7 @@ mod optimization_tests_pattern_group {
 
         let optimization = SimplePatternGroup {};
 
-        let (fused, skipped) = apply_optimisation(&optimization, filters);
+        let (fused, skipped) = apply_optimisation(&optimization, filters, &mut OptimizeStats::default());
 
         assert_eq!(fused.len(), 1);
         let filter = fused.get(0).unwrap();
@@ -318,6 +544,115 @@ mod optimization_tests_pattern_group {
 }
 
 
+#[cfg(test)]
+mod optimization_tests_hostname_anchor {
+    use super::*;
+    use crate::lists;
+    use crate::request::Request;
+    use crate::filters::network::NetworkMatchable;
+
+    fn request(url: &str) -> Request {
+        Request::from_urls(url, "https://example.org", "image").unwrap()
+    }
+
+    #[test]
+    fn skips_rules_without_hostname_anchor() {
+        let rules = vec![
+            String::from("||example.com/ads"),
+            String::from("/ads/banner"),
+            String::from("||example.com/ads$domain=example.org"),
+        ];
+
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let optimization = HostnameAnchorGroup {};
+
+        assert!(optimization.select(&filters[0]));
+        assert!(!optimization.select(&filters[1]));
+        assert!(!optimization.select(&filters[2]));
+    }
+
+    #[test]
+    fn combines_patterns_for_same_hostname() {
+        let rules = vec![
+            String::from("||example.com/ads"),
+            String::from("||example.com/banner"),
+            String::from("||other.com/ads"),
+        ];
+
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let optimization = HostnameAnchorGroup {};
+        let (fused, skipped) = apply_optimisation(&optimization, filters, &mut OptimizeStats::default());
+
+        assert_eq!(fused.len(), 1);
+        let filter = &fused[0];
+        assert_eq!(filter.to_string(), "||example.com/ads <+> ||example.com/banner");
+        assert!(filter.is_hostname_anchor());
+        assert!(matches!(&filter.filter, FilterPart::AnyOf(patterns) if patterns == &["/ads", "/banner"]));
+
+        assert_eq!(filter.matches(&request("https://example.com/ads.png")), true);
+        assert_eq!(filter.matches(&request("https://cdn.example.com/banner/1.png")), true);
+        assert_eq!(filter.matches(&request("https://example.com/content/ads.png")), false);
+        assert_eq!(filter.matches(&request("https://other.com/banner.png")), false);
+        assert_eq!(filter.matches(&request("https://notexample.com/ads.png")), false);
+
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].to_string(), "||other.com/ads");
+    }
+
+    #[test]
+    fn combines_regex_patterns_for_same_hostname() {
+        let rules = vec![
+            String::from("||example.com/ads^"),
+            String::from("||example.com/banner^"),
+        ];
+
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let optimization = HostnameAnchorGroup {};
+        let (fused, skipped) = apply_optimisation(&optimization, filters, &mut OptimizeStats::default());
+
+        assert_eq!(fused.len(), 1);
+        assert!(skipped.is_empty());
+        let filter = &fused[0];
+        assert!(filter.is_regex());
+
+        assert_eq!(filter.matches(&request("https://sub.example.com/ads/1.png")), true);
+        assert_eq!(filter.matches(&request("https://example.com/banner?id=1")), true);
+        assert_eq!(filter.matches(&request("https://example.com/banners")), false);
+        assert_eq!(filter.matches(&request("https://example.com/x/ads")), false);
+    }
+
+    #[test]
+    fn keeps_empty_patterns_separate() {
+        let rules = vec![
+            String::from("||example.com^"),
+            String::from("||example.com^"),
+            String::from("||example.com/path|"),
+            String::from("||example.com/other|"),
+        ];
+
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let optimization = HostnameAnchorGroup {};
+        let (mut fused, skipped) = apply_optimisation(&optimization, filters, &mut OptimizeStats::default());
+        fused.sort_by_key(|f| f.to_string());
+
+        assert!(skipped.is_empty());
+        assert_eq!(fused.len(), 2);
+
+        let paths = &fused[0];
+        assert_eq!(paths.to_string(), "||example.com/path| <+> ||example.com/other|");
+        assert_eq!(paths.matches(&request("https://sub.example.com/other")), true);
+        assert_eq!(paths.matches(&request("https://example.com/path")), true);
+        assert_eq!(paths.matches(&request("https://example.com/other/x")), false);
+
+        let hostname = &fused[1];
+        assert_eq!(hostname.to_string(), "||example.com^ <+> ||example.com^");
+        assert!(matches!(hostname.filter, FilterPart::Empty));
+        assert_eq!(hostname.matches(&request("https://example.com/anything")), true);
+        assert_eq!(hostname.matches(&request("https://sub.example.com/anything")), true);
+        assert_eq!(hostname.matches(&request("https://example.com.evil.net/anything")), false);
+    }
+}
+
 #[cfg(test)]
 mod optimization_tests_union_domain {
     use super::*;
@@ -335,7 +670,7 @@ mod optimization_tests_union_domain {
 
         let (filters, _) = lists::parse_filters(&rules, true, Default::default());
         let optimization = UnionDomainGroup {};
-        let (fused, _) = apply_optimisation(&optimization, filters);
+        let (fused, _) = apply_optimisation(&optimization, filters, &mut OptimizeStats::default());
 
         assert_eq!(fused.len(), 1);
         let filter = fused.get(0).unwrap();
@@ -365,7 +700,7 @@ mod optimization_tests_union_domain {
 
         let (filters, _) = lists::parse_filters(&rules, true, Default::default());
         let optimization = UnionDomainGroup {};
-        let (_, skipped) = apply_optimisation(&optimization, filters);
+        let (_, skipped) = apply_optimisation(&optimization, filters, &mut OptimizeStats::default());
 
         assert_eq!(skipped.len(), 1);
         let filter = skipped.get(0).unwrap();
@@ -375,6 +710,53 @@ mod optimization_tests_union_domain {
         );
     }
 
+    #[test]
+    fn keeps_domain_positivity_separate() {
+        let rules = vec![
+            String::from("/analytics-v1$domain=a.com"),
+            String::from("/analytics-v1$domain=b.com"),
+            String::from("/analytics-v1$domain=~c.com"),
+            String::from("/analytics-v1$domain=~d.com"),
+            String::from("/analytics-v1$domain=e.com|~sub.e.com"),
+            String::from("/analytics-v1$domain=f.com|~sub.e.com"),
+            String::from("/analytics-v1$domain=g.com|~sub.g.com"),
+        ];
+
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let optimization = UnionDomainGroup {};
+        let (fused, skipped) = apply_optimisation(&optimization, filters.clone(), &mut OptimizeStats::default());
+
+        let mut fused = fused.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        fused.sort();
+        assert_eq!(fused, vec![
+            "/analytics-v1$domain=a.com <+> /analytics-v1$domain=b.com",
+            "/analytics-v1$domain=e.com|~sub.e.com <+> /analytics-v1$domain=f.com|~sub.e.com",
+        ]);
+        assert_eq!(skipped.len(), 3);
+
+        let optimized = optimize(filters.clone());
+        assert_eq!(optimized.len(), 5);
+        for source in ["a.com", "sub.a.com", "b.com", "c.com", "d.com", "e.com", "sub.e.com", "f.com", "g.com", "sub.g.com", "other.com"] {
+            let request = Request::from_urls("https://example.com/analytics-v1/foobar", &format!("https://{}", source), "script").unwrap();
+            assert_eq!(
+                optimized.iter().any(|f| f.matches(&request)),
+                filters.iter().any(|f| f.matches(&request)),
+                "{}", source,
+            );
+        }
+
+        let fused_positive = optimized.iter().find(|f| f.to_string().starts_with("/analytics-v1$domain=a.com")).unwrap();
+        for (source, matches) in [("a.com", true), ("sub.a.com", true), ("b.com", true), ("c.com", false), ("other.com", false)] {
+            let request = Request::from_urls("https://example.com/analytics-v1/foobar", &format!("https://{}", source), "script").unwrap();
+            assert_eq!(fused_positive.matches(&request), matches, "{}", source);
+        }
+        let fused_mixed = optimized.iter().find(|f| f.to_string().starts_with("/analytics-v1$domain=e.com")).unwrap();
+        for (source, matches) in [("e.com", true), ("f.com", true), ("sub.e.com", false), ("g.com", false)] {
+            let request = Request::from_urls("https://example.com/analytics-v1/foobar", &format!("https://{}", source), "script").unwrap();
+            assert_eq!(fused_mixed.matches(&request), matches, "{}", source);
+        }
+    }
+
     #[test]
     fn optimises_domains() {
         let rules = vec![
@@ -388,7 +770,7 @@ mod optimization_tests_union_domain {
 
         let optimization = UnionDomainGroup {};
 
-        let (fused, skipped) = apply_optimisation(&optimization, filters);
+        let (fused, skipped) = apply_optimisation(&optimization, filters, &mut OptimizeStats::default());
 
         assert_eq!(fused.len(), 1);
         let filter = fused.get(0).unwrap();
@@ -411,3 +793,111 @@ mod optimization_tests_union_domain {
     }
 
 }
+
+#[cfg(test)]
+mod optimization_tests_passes {
+    use super::*;
+    use crate::lists;
+    use std::cell::RefCell;
+
+    /// Fuses filters with the same hostname, recording every filter it is offered.
+    struct SameHostname {
+        offered: RefCell<Vec<String>>,
+    }
+
+    impl Optimization for SameHostname {
+        fn fusion(&self, filters: &[NetworkFilter]) -> Option<NetworkFilter> {
+            let mut filter = filters.first()?.clone();
+            filter.raw_line = Some(filters.iter().flat_map(|f| f.raw_line.clone()).join(" <+> "));
+            Some(filter)
+        }
+
+        fn group_by_criteria(&self, filter: &NetworkFilter) -> String {
+            format!("{:?}", filter.hostname)
+        }
+
+        fn select(&self, filter: &NetworkFilter) -> bool {
+            self.offered.borrow_mut().push(filter.to_string());
+            filter.hostname.is_some()
+        }
+    }
+
+    #[test]
+    fn runs_passes_in_sequence() {
+        let rules = vec![
+            String::from("/static/ad-"),
+            String::from("/static/ad."),
+            String::from("||example.com/ads$script"),
+            String::from("||example.com/banner$image"),
+            String::from("||other.com/ads"),
+        ];
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+
+        let same_hostname = SameHostname { offered: RefCell::new(vec![]) };
+        let optimized = optimize_with(filters, &[&SimplePatternGroup {}, &same_hostname]);
+
+        // Only the filters rejected by `SimplePatternGroup` reach the second pass
+        let mut offered = same_hostname.offered.into_inner();
+        offered.sort();
+        assert_eq!(offered, vec!["||example.com/ads$script", "||example.com/banner$image", "||other.com/ads"]);
+
+        let mut optimized = optimized.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        optimized.sort();
+        assert_eq!(optimized, vec![
+            "/static/ad- <+> /static/ad.",
+            "||example.com/ads$script <+> ||example.com/banner$image",
+            "||other.com/ads",
+        ]);
+    }
+
+    #[test]
+    fn no_passes() {
+        let rules = vec![String::from("/static/ad-"), String::from("/static/ad.")];
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+
+        assert_eq!(optimize_with(filters.clone(), &[]).len(), 2);
+        assert_eq!(optimize_with(filters, &default_passes()).len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod badfilter_tests {
+    use super::*;
+    use crate::lists;
+    use crate::request::Request;
+    use crate::filters::network::NetworkMatchable;
+
+    #[test]
+    fn cancels_matching_rules() {
+        let rules = vec![
+            String::from("||ads.com^$third-party,script"),
+            String::from("||ads.com/banner$domain=b.com|a.com"),
+            String::from("||ads.com/pixel$image"),
+        ];
+        let badfilters = vec![
+            String::from("||ads.com^$script,badfilter,3p"),
+            String::from("||ads.com/banner$domain=a.com|b.com,badfilter"),
+            // Doesn't cancel anything, since the options are different
+            String::from("||ads.com/pixel$script,badfilter"),
+        ];
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let (with_badfilters, _) = lists::parse_filters(&[rules.clone(), badfilters].concat(), true, Default::default());
+
+        let requests = [
+            Request::from_urls("https://ads.com/ad.js", "https://example.com", "script").unwrap(),
+            Request::from_urls("https://ads.com/banner.png", "https://a.com", "image").unwrap(),
+        ];
+        let filters = apply_badfilters(filters);
+        assert_eq!(filters.len(), 3);
+        for request in requests.iter() {
+            assert_eq!(filters.iter().any(|f| f.matches(request)), true);
+        }
+
+        let filters = apply_badfilters(with_badfilters);
+        let remaining = filters.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        assert_eq!(remaining, vec!["||ads.com/pixel$image"]);
+        for request in requests.iter() {
+            assert_eq!(filters.iter().any(|f| f.matches(request)), false);
+        }
+    }
+}
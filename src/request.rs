@@ -3,6 +3,8 @@
 use crate::url_parser;
 use crate::utils;
 
+use std::borrow::Cow;
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum RequestType {
     Beacon,
@@ -16,9 +18,11 @@ pub enum RequestType {
     Object,
     Other,
     Ping,
+    Popup,
     Script,
     Stylesheet,
     Subdocument,
+    Webrtc,
     Websocket,
     Xlst,
     Xmlhttprequest,
@@ -31,6 +35,26 @@ pub enum RequestError {
     UnicodeDecodingError,
 }
 
+/// Determines whether requests made from browser extension pages, i.e. with a `chrome-extension:`
+/// or `moz-extension:` source URL, are considered first- or third-party. Extension pages have no
+/// registrable domain, so they can't be compared to the requested hostname as usual.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExtensionSourcePolicy {
+    /// Requests from extension pages are first-party to any hostname.
+    FirstParty,
+    /// The party of requests from extension pages is unknown, as if they had no source URL at
+    /// all. `$first-party` and `$third-party` options have no effect on them.
+    UndefinedParty,
+}
+
+// Deriving this would need `#[default]`, which requires Rust 1.62
+#[allow(clippy::derivable_impls)]
+impl Default for ExtensionSourcePolicy {
+    fn default() -> Self {
+        Self::FirstParty
+    }
+}
+
 impl From<idna::Errors> for RequestError {
     fn from(_err: idna::Errors) -> RequestError {
         RequestError::UnicodeDecodingError
@@ -48,14 +72,17 @@ fn cpt_match_type(cpt: &str) -> RequestType {
         "beacon" => RequestType::Ping,
         "csp_report" => RequestType::Csp,
         "document" | "main_frame" => RequestType::Document,
+        "fetch" => RequestType::Fetch,
         "font" => RequestType::Font,
         "image" | "imageset" => RequestType::Image,
         "media" => RequestType::Media,
         "object" | "object_subrequest" => RequestType::Object,
         "ping" => RequestType::Ping,
+        "popup" => RequestType::Popup,
         "script" => RequestType::Script,
         "stylesheet" => RequestType::Stylesheet,
         "sub_frame" | "subdocument" => RequestType::Subdocument,
+        "webrtc" => RequestType::Webrtc,
         "websocket" => RequestType::Websocket,
         "xhr" | "xmlhttprequest" => RequestType::Xmlhttprequest,
         "other" => RequestType::Other,
@@ -68,6 +95,50 @@ fn cpt_match_type(cpt: &str) -> RequestType {
     }
 }
 
+/// Maps a `Sec-Fetch-Dest` header value to the equivalent request type accepted by
+/// `cpt_match_type`. Unrecognized destinations, including `empty`, are treated as `other`.
+/// Reports, e.g. of CSP violations, are sent in the background much like pings, so they're
+/// treated as `ping` rather than as `csp_report`, which no filter option can target.
+fn sec_fetch_dest_type(dest: &str) -> &'static str {
+    match dest {
+        "audio" | "track" | "video" => "media",
+        "audioworklet" | "paintworklet" | "serviceworker" | "sharedworker" | "worker" | "script" => "script",
+        "document" => "document",
+        "embed" | "object" => "object",
+        "font" => "font",
+        "frame" | "iframe" => "sub_frame",
+        "image" => "image",
+        "report" => "ping",
+        "style" => "stylesheet",
+        "xslt" => "xslt",
+        _ => "other",
+    }
+}
+
+/// Infers the request type accepted by `cpt_match_type` from the file extension of `url`'s path,
+/// for requests whose reported type is too generic to be useful. Only webfonts, as loaded by CSS
+/// `@font-face` rules, are currently inferred.
+fn extension_type(url: &str) -> Option<&'static str> {
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    let after_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let (_, path) = after_scheme.split_once('/')?;
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let (_, extension) = file_name.rsplit_once('.')?;
+    match extension.to_ascii_lowercase().as_str() {
+        "woff" | "woff2" | "ttf" | "otf" => Some("font"),
+        _ => None,
+    }
+}
+
+/// Returns `request_type`, or if it is generic, i.e. it would be matched as `other`, the type
+/// inferred from the file extension of `url` where possible.
+pub(crate) fn inferred_request_type<'a>(url: &str, request_type: &'a str) -> &'a str {
+    match cpt_match_type(request_type) {
+        RequestType::Other => extension_type(url).unwrap_or(request_type),
+        _ => request_type,
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Request {
     pub request_type: RequestType,
@@ -83,7 +154,13 @@ pub struct Request {
 
     // mutable fields, set later
     pub bug: Option<u32>,
-    hostname_end: usize
+    hostname_end: usize,
+    /// `url` in its original case, for matching `$match-case` filters. Only kept if it differs
+    /// from `url`.
+    original_url: Option<String>,
+    /// The source hostname with its public suffix replaced by `*`, e.g. `sub.example.*`, from which
+    /// `source_entity_hashes` are computed. `None` if it has no public suffix.
+    source_entity: Option<String>,
 }
 
 impl<'a> Request {
@@ -94,10 +171,85 @@ impl<'a> Request {
         token_buffer.push(0);
     }
 
+    /// Hashes of the `entity.*` forms of the source hostname, as used in `$domain` options. They
+    /// are only computed as needed, since most filters don't use entities.
+    pub(crate) fn source_entity_hashes(&self) -> impl Iterator<Item = utils::Hash> + '_ {
+        let entity = self.source_entity.as_deref().unwrap_or_default();
+        let label_starts = entity.match_indices('.')
+            .map(|(i, _)| i + 1)
+            .filter(move |start| *start < entity.len() - 1);
+        std::iter::once(0)
+            .filter(move |_| !entity.is_empty())
+            .chain(label_starts)
+            .map(move |start| utils::fast_hash(&entity[start..]))
+    }
+
+    /// The URL of the request without lowercasing, as used to match `$match-case` filters.
+    pub(crate) fn original_url(&self) -> &str {
+        match self.original_url.as_deref() {
+            // `url` is public, so it may have been changed since
+            Some(original_url) if original_url.len() == self.url.len() => original_url,
+            _ => &self.url,
+        }
+    }
+
     pub fn url_after_hostname(&self) -> &str {
         &self.url[self.hostname_end..]
     }
 
+    /// Returns a copy of this request which is sent to `hostname` instead, such as the canonical
+    /// name that the original hostname resolves to. Everything else about the request, including
+    /// its type, party, and source, is kept as-is.
+    pub fn with_hostname(&self, hostname: &str) -> Request {
+        // DNS answers are often given as fully-qualified names
+        let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+        let hostname_start = self.url.get(..self.hostname_end)
+            .and_then(|before_end| before_end.strip_suffix(self.hostname.as_str()))
+            .map(str::len);
+
+        match hostname_start {
+            Some(hostname_start) => Request {
+                url: format!("{}{}{}", &self.url[..hostname_start], hostname, &self.url[self.hostname_end..]),
+                hostname_end: hostname_start + hostname.len(),
+                original_url: self.original_url.as_ref().map(|original_url| {
+                    format!("{}{}{}", &original_url[..hostname_start], hostname, &original_url[self.hostname_end..])
+                }),
+                hostname,
+                ..self.clone()
+            },
+            None => Request { hostname, ..self.clone() },
+        }
+    }
+
+    /// Returns true if this request is made to the local machine, i.e. to `localhost` or a
+    /// loopback IP address.
+    pub fn is_loopback(&self) -> bool {
+        let hostname = self.hostname.trim_end_matches('.');
+        if hostname == "localhost" || hostname.ends_with(".localhost") {
+            return true;
+        }
+        let hostname = hostname.trim_start_matches('[').trim_end_matches(']');
+        hostname.parse::<std::net::IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false)
+    }
+
+    /// Returns the port this request is made to, either as given explicitly in the URL or as
+    /// implied by its scheme.
+    pub fn port(&self) -> Option<u16> {
+        let after_hostname = self.url.get(self.hostname_end..).unwrap_or_default();
+        if let Some(port) = after_hostname.strip_prefix(':') {
+            let end = port.find(|c: char| !c.is_ascii_digit()).unwrap_or(port.len());
+            return port[..end].parse().ok();
+        }
+
+        if self.is_https || self.url.starts_with("wss:") {
+            Some(443)
+        } else if self.is_http || self.url.starts_with("ws:") {
+            Some(80)
+        } else {
+            None
+        }
+    }
+
     pub fn new(
         raw_type: &str,
         url: &str,
@@ -118,9 +270,11 @@ impl<'a> Request {
         Self::from_detailed_parameters(
             raw_type,
             url,
+            url,
             schema,
             hostname,
             source_hostname,
+            source_domain,
             third_party,
             hostname_end
         )
@@ -130,9 +284,11 @@ impl<'a> Request {
     fn from_detailed_parameters(
         raw_type: &str,
         url: &str,
+        raw_url: &str,
         schema: &str,
         hostname: &str,
         source_hostname: &str,
+        source_domain: &str,
         third_party: Option<bool>,
         hostname_end: usize
     ) -> Request {
@@ -154,9 +310,12 @@ impl<'a> Request {
             is_https = !is_http && schema == "https";
 
             let is_websocket = !is_http && !is_https && (schema == "ws" || schema == "wss");
-            is_supported = is_http || is_https || is_websocket;
+            let is_webrtc = !is_http && !is_https && !is_websocket && is_webrtc_scheme(schema);
+            is_supported = is_http || is_https || is_websocket || is_webrtc;
             if is_websocket {
                 request_type = RequestType::Websocket;
+            } else if is_webrtc {
+                request_type = RequestType::Webrtc;
             } else {
                 request_type = cpt_match_type(raw_type);
             }
@@ -176,10 +335,19 @@ impl<'a> Request {
         } else {
             None
         };
+        let source_entity = get_entity(source_hostname, source_domain);
+
+        // Only the part after the hostname is normalized, so `hostname_end` remains valid.
+        let lowercased = url;
+        let url = match url.get(hostname_end..).map(url_parser::normalize_path) {
+            Some(Cow::Owned(path)) => url[..hostname_end].to_owned() + &path,
+            _ => url.to_owned(),
+        };
+        let original_url = original_case_url(&url, lowercased, raw_url, hostname_end);
 
         Request {
             request_type,
-            url: url.to_owned(),
+            url,
             hostname: hostname.to_owned(),
             source_hostname_hashes,
             is_first_party: first_party,
@@ -188,7 +356,9 @@ impl<'a> Request {
             is_https,
             is_supported,
             bug: None,
-            hostname_end
+            hostname_end,
+            original_url,
+            source_entity,
         }
     }
 
@@ -197,22 +367,102 @@ impl<'a> Request {
         source_url: &str,
         request_type: &str,
     ) -> Result<Request, RequestError> {
+        Self::from_urls_with_extension_policy(url, source_url, request_type, ExtensionSourcePolicy::default())
+    }
+
+    /// Like `from_urls`, but uses `extension_policy` to decide the party of the request if
+    /// `source_url` is a browser extension page.
+    pub fn from_urls_with_extension_policy(
+        url: &str,
+        source_url: &str,
+        request_type: &str,
+        extension_policy: ExtensionSourcePolicy,
+    ) -> Result<Request, RequestError> {
+        let parsed_source = url_parser::parse_url(source_url);
+        Self::from_url_and_parsed_source(url, parsed_source.as_ref(), request_type, extension_policy)
+    }
+
+    /// Like `from_urls`, but takes the request type from the value of a `Sec-Fetch-Dest` header,
+    /// e.g. `script` or `iframe`, rather than a `webRequest`-style resource type.
+    pub fn from_urls_with_sec_fetch_dest(
+        url: &str,
+        source_url: &str,
+        sec_fetch_dest: &str,
+    ) -> Result<Request, RequestError> {
+        Self::from_urls(url, source_url, sec_fetch_dest_type(sec_fetch_dest))
+    }
+
+    /// Like `from_urls`, but if `request_type` is generic, i.e. it would be matched as `other`,
+    /// the type is instead inferred from the file extension of `url` where possible. For
+    /// example, a generic request for a `.woff2` file is treated as a `font` request.
+    pub fn from_urls_with_type_inference(
+        url: &str,
+        source_url: &str,
+        request_type: &str,
+    ) -> Result<Request, RequestError> {
+        Self::from_urls(url, source_url, inferred_request_type(url, request_type))
+    }
+
+    /// Like `from_urls_with_extension_policy`, but for a browser with partitioned storage, where
+    /// `$first-party` and `$third-party` are determined relative to `top_frame_url` rather than
+    /// to the document that made the request. Other source-dependent options, like `$domain`,
+    /// still use `source_url`. If `top_frame_url` can't be parsed, the party is undefined.
+    pub fn from_urls_partitioned(
+        url: &str,
+        source_url: &str,
+        top_frame_url: &str,
+        request_type: &str,
+        extension_policy: ExtensionSourcePolicy,
+    ) -> Result<Request, RequestError> {
+        let request = Self::from_urls_with_extension_policy(url, source_url, request_type, extension_policy)?;
+        Ok(request.with_top_frame(top_frame_url, extension_policy))
+    }
+
+    /// Redetermines the party of this request relative to `top_frame_url`, as for
+    /// `from_urls_partitioned`.
+    pub(crate) fn with_top_frame(mut self, top_frame_url: &str, extension_policy: ExtensionSourcePolicy) -> Request {
+        let (domain_start, domain_end) = url_parser::get_host_domain(&self.hostname);
+        let third_party = url_parser::parse_url(top_frame_url)
+            .and_then(|top_frame| third_party_to(&top_frame, &self.hostname[domain_start..domain_end], extension_policy));
+        self.is_third_party = third_party;
+        self.is_first_party = third_party.map(|p| !p);
+        self
+    }
+
+    /// Builds requests for each of `urls`, all initiated by the same `source_url`, e.g. the
+    /// candidate URLs of an image's `srcset`. The source URL is only parsed once.
+    pub fn from_urls_batch(
+        urls: &[&str],
+        source_url: &str,
+        request_type: &str,
+        extension_policy: ExtensionSourcePolicy,
+    ) -> Vec<Result<Request, RequestError>> {
+        let parsed_source = url_parser::parse_url(source_url);
+        urls.iter()
+            .map(|url| Self::from_url_and_parsed_source(url, parsed_source.as_ref(), request_type, extension_policy))
+            .collect()
+    }
+
+    pub(crate) fn from_url_and_parsed_source(
+        url: &str,
+        parsed_source: Option<&url_parser::RequestUrl>,
+        request_type: &str,
+        extension_policy: ExtensionSourcePolicy,
+    ) -> Result<Request, RequestError> {
+        let url = if request_type == "webrtc" { normalize_webrtc_url(url) } else { Cow::Borrowed(url) };
         if let Some(parsed_url) = url_parser::parse_url(&url) {
-            if let Some(parsed_source) = url_parser::parse_url(&source_url) {
+            if let Some(parsed_source) = parsed_source {
                 let source_domain = parsed_source.domain();
-
-                let third_party = if source_domain.is_empty() {
-                    None
-                } else {
-                    Some(source_domain != parsed_url.domain())
-                };
+                let third_party = third_party_to(parsed_source, parsed_url.domain(), extension_policy);
 
                 Ok(Request::from_detailed_parameters(
                     request_type,
                     &parsed_url.url,
+                    &url,
                     parsed_url.schema(),
                     parsed_url.hostname(),
                     parsed_source.hostname(),
+                    source_domain,
                     third_party,
                     parsed_url.hostname_pos.1
                 ))
@@ -220,9 +470,11 @@ impl<'a> Request {
                 Ok(Request::from_detailed_parameters(
                     request_type,
                     &parsed_url.url,
+                    &url,
                     parsed_url.schema(),
                     parsed_url.hostname(),
                     "",
+                    "",
                     None,
                     parsed_url.hostname_pos.1
                 ))
@@ -232,6 +484,16 @@ impl<'a> Request {
         }
     }
 
+    /// Builds a request from components which are already known, e.g. from a browser's
+    /// `webRequest` API, without parsing any URLs. Unless `third_party_request` is given, the party
+    /// of the request is derived from the hostnames in the same way as `from_urls`. `url` is only
+    /// used to match filter patterns, and its path is normalized like in `from_urls`, so non-ASCII
+    /// characters match the same rules whether or not they're percent-encoded.
+    ///
+    /// `hostname` must be the hostname of `url`, and `source_hostname` the hostname of the page
+    /// that made the request, or empty if there is none. Both must be lowercase and in punycode
+    /// form, as `from_urls` would produce. Since the source URL isn't known, requests from
+    /// browser extension pages are not treated specially.
     pub fn from_urls_with_hostname(
         url: &str,
         hostname: &str,
@@ -239,6 +501,7 @@ impl<'a> Request {
         request_type: &str,
         third_party_request: Option<bool>
     ) -> Request {
+        let url = if request_type == "webrtc" { normalize_webrtc_url(url) } else { Cow::Borrowed(url) };
         let url_norm = url.to_ascii_lowercase();
 
         let (source_domain_start, source_domain_end) = url_parser::get_host_domain(&source_hostname);
@@ -262,24 +525,469 @@ impl<'a> Request {
         Request::from_detailed_parameters(
             request_type,
             &url_norm,
+            &url,
             &schema,
             &hostname,
             &source_hostname,
+            source_domain,
             third_party,
-            splitter + 2 + hostname.len()
+            splitter + 3 + hostname.len()
         )
     }
 
+    /// Like `from_urls_with_hostname`, but `source_hostname` may also be the origin of a browser
+    /// extension page, like `chrome-extension://abcdefghijklmnop`. Requests from such pages are
+    /// then given a party according to `extension_policy`, unless `third_party_request` is set.
+    pub(crate) fn from_urls_with_hostname_and_extension_policy(
+        url: &str,
+        hostname: &str,
+        source_hostname: &str,
+        request_type: &str,
+        third_party_request: Option<bool>,
+        extension_policy: ExtensionSourcePolicy,
+    ) -> Request {
+        match source_hostname.split_once("://") {
+            Some((schema, extension_id)) if is_extension_scheme(schema) => {
+                let mut request = Self::from_urls_with_hostname(url, hostname, extension_id, request_type, third_party_request);
+                if third_party_request.is_none() {
+                    request.is_third_party = match extension_policy {
+                        ExtensionSourcePolicy::FirstParty => Some(false),
+                        ExtensionSourcePolicy::UndefinedParty => None,
+                    };
+                    request.is_first_party = request.is_third_party.map(|p| !p);
+                }
+                request
+            }
+            _ => Self::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request),
+        }
+    }
+
+    /// Builds a request from URLs which have already been normalized, skipping the URL parsing
+    /// and normalization that `from_urls` performs. This is intended for callers which check many
+    /// requests and already hold their URLs in normalized form.
+    ///
+    /// # Correctness
+    ///
+    /// The input is trusted without any validation. `url`, and `source_url` if non-empty, must be
+    /// absolute URLs in the same form that `from_urls` would produce: entirely lowercase, with
+    /// any fragment and credentials removed, and with hostnames in punycode form. If this doesn't
+    /// hold, requests may fail to match rules that they should match, or match rules that they
+    /// shouldn't. Only an unparseable hostname is reported as an error.
+    pub fn from_prenormalized(
+        url: &str,
+        source_url: &str,
+        request_type: &str,
+    ) -> Result<Request, RequestError> {
+        let (schema, hostname, hostname_end) = split_prenormalized(url).ok_or(RequestError::HostnameParseError)?;
+        let (source_schema, source_hostname) = split_prenormalized(source_url)
+            .map(|(schema, hostname, _)| (schema, hostname))
+            .unwrap_or_default();
+
+        let (source_domain_start, source_domain_end) = url_parser::get_host_domain(source_hostname);
+        let source_domain = &source_hostname[source_domain_start..source_domain_end];
+        let third_party = if is_extension_scheme(source_schema) {
+            Some(false)
+        } else if source_domain.is_empty() {
+            None
+        } else {
+            let (domain_start, domain_end) = url_parser::get_host_domain(hostname);
+            Some(source_domain != &hostname[domain_start..domain_end])
+        };
+
+        Ok(Request::from_detailed_parameters(
+            request_type,
+            url,
+            url,
+            schema,
+            hostname,
+            source_hostname,
+            source_domain,
+            third_party,
+            hostname_end
+        ))
+    }
+
     pub fn from_url(url: &str) -> Result<Request, RequestError> {
         // Used in testing - assume empty source_url and default request type
         Self::from_urls(url, "", "")
     }
 }
 
+/// Recovers the original case of `url` from the `raw_url` it was built from. `lowercased` is the
+/// form of `url` before path normalization, which is assumed to match the end of `raw_url` except
+/// for case after `hostname_end`. Returns `None` if there is no uppercase character to preserve,
+/// or if the URLs can't be aligned, e.g. because surrounding whitespace was trimmed.
+fn original_case_url(url: &str, lowercased: &str, raw_url: &str, hostname_end: usize) -> Option<String> {
+    let path = lowercased.get(hostname_end..)?;
+    let raw_path = raw_url.get(raw_url.len().checked_sub(path.len())?..)?;
+    if !raw_path.bytes().any(|b| b.is_ascii_uppercase()) || !raw_path.eq_ignore_ascii_case(path) {
+        return None;
+    }
+    let original = url[..hostname_end].to_owned() + &url_parser::normalize_path(raw_path);
+    if original.eq_ignore_ascii_case(url) {
+        Some(original)
+    } else {
+        None
+    }
+}
+
+/// Determines whether a request to `domain` is third-party with respect to `source`, or `None` if
+/// that can't be determined.
+fn third_party_to(source: &url_parser::RequestUrl, domain: &str, extension_policy: ExtensionSourcePolicy) -> Option<bool> {
+    let source_domain = source.domain();
+    if is_extension_scheme(source.schema()) {
+        match extension_policy {
+            ExtensionSourcePolicy::FirstParty => Some(false),
+            ExtensionSourcePolicy::UndefinedParty => None,
+        }
+    } else if source_domain.is_empty() {
+        None
+    } else {
+        Some(source_domain != domain)
+    }
+}
+
+/// Splits a normalized URL, as accepted by `Request::from_prenormalized`, into its scheme, its
+/// hostname, and the end index of the hostname.
+fn split_prenormalized(url: &str) -> Option<(&str, &str, usize)> {
+    let schema_end = url.find(':')?;
+    let host_start = schema_end + 3;
+    if url.get(schema_end..host_start) != Some("://") {
+        return None;
+    }
+    let authority = &url[host_start..];
+    let authority = &authority[..authority.find(['/', '?']).unwrap_or(authority.len())];
+    let host_len = if authority.starts_with('[') {
+        authority.find(']')? + 1
+    } else {
+        authority.find(':').unwrap_or(authority.len())
+    };
+    if host_len == 0 {
+        return None;
+    }
+    Some((&url[..schema_end], &authority[..host_len], host_start + host_len))
+}
+
+fn is_extension_scheme(schema: &str) -> bool {
+    ["chrome-extension", "moz-extension", "safari-web-extension"].iter().any(|s| schema.eq_ignore_ascii_case(s))
+}
+
+fn is_webrtc_scheme(schema: &str) -> bool {
+    ["stun", "stuns", "turn", "turns"].iter().any(|s| schema.eq_ignore_ascii_case(s))
+}
+
+/// WebRTC connection targets are usually given as `stun:` or `turn:` URIs, which have no `//`
+/// authority component, or sometimes as a bare `host:port`. These are rewritten to
+/// `scheme://host:port` so that they can be parsed and matched like any other URL.
+fn normalize_webrtc_url(url: &str) -> Cow<'_, str> {
+    match url.find(':') {
+        Some(i) if is_webrtc_scheme(&url[..i]) => {
+            if url[i + 1..].starts_with("//") {
+                Cow::Borrowed(url)
+            } else {
+                Cow::Owned(format!("{}://{}", &url[..i], &url[i + 1..]))
+            }
+        }
+        _ if url.contains("://") => Cow::Borrowed(url),
+        _ => Cow::Owned(format!("stun://{}", url)),
+    }
+}
+
+/// Returns `hostname` with its public suffix replaced by `*`. Every label down to the registrable
+/// domain is one of its `entity.*` forms; for example, `sub.example.co.uk` becomes
+/// `sub.example.*`, so it belongs to both `sub.example.*` and `example.*`.
+fn get_entity(hostname: &str, domain: &str) -> Option<String> {
+    let public_suffix_start = domain.find('.')?;
+    if !hostname.ends_with(domain) {
+        return None;
+    }
+    let mut entity = String::with_capacity(hostname.len() - domain.len() + public_suffix_start + 2);
+    entity.push_str(&hostname[..hostname.len() - domain.len() + public_suffix_start]);
+    entity.push_str(".*");
+    Some(entity)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn port_works() {
+        assert_eq!(Request::from_url("https://example.com:8080/ads.js").unwrap().port(), Some(8080));
+        assert_eq!(Request::from_url("https://example.com/ads.js").unwrap().port(), Some(443));
+        assert_eq!(Request::from_url("http://example.com").unwrap().port(), Some(80));
+        assert_eq!(Request::from_url("ws://example.com:9000?a=b").unwrap().port(), Some(9000));
+        assert_eq!(Request::from_url("wss://example.com/").unwrap().port(), Some(443));
+        assert_eq!(Request::from_urls_with_hostname("https://example.com:8443/", "example.com", "", "", None).port(), Some(8443));
+    }
+
+    #[test]
+    fn webrtc_works() {
+        for url in &["stun:stun.example.com:3478", "stun://stun.example.com:3478", "stun.example.com:3478"] {
+            let request = Request::from_urls(url, "https://example.com", "webrtc").unwrap();
+            assert_eq!(request.request_type, RequestType::Webrtc);
+            assert_eq!(request.is_supported, true);
+            assert_eq!(request.hostname, "stun.example.com");
+            assert_eq!(request.port(), Some(3478));
+        }
+
+        let request = Request::from_urls("turns:turn.example.com?transport=tcp", "https://example.com", "webrtc").unwrap();
+        assert_eq!(request.request_type, RequestType::Webrtc);
+        assert_eq!(request.hostname, "turn.example.com");
+        assert_eq!(request.is_third_party, Some(false));
+
+        let request = Request::from_urls_with_hostname("stun:stun.example.com:3478", "stun.example.com", "example.com", "webrtc", None);
+        assert_eq!(request.request_type, RequestType::Webrtc);
+        assert_eq!(request.port(), Some(3478));
+    }
+
+    #[test]
+    fn loopback_works() {
+        for url in &["http://localhost:8080/", "https://api.localhost/x", "http://127.0.0.1/", "http://127.12.0.3/ads.js", "http://[::1]:3000/"] {
+            assert_eq!(Request::from_url(url).unwrap().is_loopback(), true, "{}", url);
+        }
+        for url in &["https://example.com/", "https://localhost.example.com/", "http://128.0.0.1/", "http://10.0.0.1/", "http://[::2]/"] {
+            assert_eq!(Request::from_url(url).unwrap().is_loopback(), false, "{}", url);
+        }
+    }
+
+    #[test]
+    fn extension_source_works() {
+        let request = Request::from_urls("https://ads.com/ad.js", "chrome-extension://abcdefghijklmnop/popup.html", "script").unwrap();
+        assert_eq!(request.is_third_party, Some(false));
+        assert_eq!(request.is_first_party, Some(true));
+
+        let request = Request::from_urls_with_extension_policy(
+            "https://ads.com/ad.js",
+            "moz-extension://1234-5678-90ab/popup.html",
+            "script",
+            ExtensionSourcePolicy::UndefinedParty,
+        ).unwrap();
+        assert_eq!(request.is_third_party, None);
+        assert_eq!(request.is_first_party, None);
+
+        // Extension origins are also recognized in place of a source hostname
+        let request = Request::from_urls_with_hostname_and_extension_policy(
+            "https://ads.com/ad.js",
+            "ads.com",
+            "chrome-extension://abcdefghijklmnop",
+            "script",
+            None,
+            ExtensionSourcePolicy::FirstParty,
+        );
+        assert_eq!(request.is_third_party, Some(false));
+        let request = Request::from_urls_with_hostname_and_extension_policy(
+            "https://ads.com/ad.js",
+            "ads.com",
+            "moz-extension://1234-5678-90ab",
+            "script",
+            None,
+            ExtensionSourcePolicy::UndefinedParty,
+        );
+        assert_eq!(request.is_third_party, None);
+        let request = Request::from_urls_with_hostname_and_extension_policy(
+            "https://ads.com/ad.js",
+            "ads.com",
+            "example.com",
+            "script",
+            None,
+            ExtensionSourcePolicy::UndefinedParty,
+        );
+        assert_eq!(request.is_third_party, Some(true));
+    }
+
+    #[test]
+    fn sec_fetch_dest_works() {
+        use crate::filters::network::NetworkFilterMask;
+
+        let cases = [
+            ("audio", RequestType::Media, NetworkFilterMask::FROM_MEDIA),
+            ("video", RequestType::Media, NetworkFilterMask::FROM_MEDIA),
+            ("track", RequestType::Media, NetworkFilterMask::FROM_MEDIA),
+            ("document", RequestType::Document, NetworkFilterMask::FROM_DOCUMENT),
+            ("embed", RequestType::Object, NetworkFilterMask::FROM_OBJECT),
+            ("object", RequestType::Object, NetworkFilterMask::FROM_OBJECT),
+            ("empty", RequestType::Other, NetworkFilterMask::FROM_OTHER),
+            ("font", RequestType::Font, NetworkFilterMask::FROM_FONT),
+            ("frame", RequestType::Subdocument, NetworkFilterMask::FROM_SUBDOCUMENT),
+            ("iframe", RequestType::Subdocument, NetworkFilterMask::FROM_SUBDOCUMENT),
+            ("image", RequestType::Image, NetworkFilterMask::FROM_IMAGE),
+            ("manifest", RequestType::Other, NetworkFilterMask::FROM_OTHER),
+            ("report", RequestType::Ping, NetworkFilterMask::FROM_PING),
+            ("script", RequestType::Script, NetworkFilterMask::FROM_SCRIPT),
+            ("worker", RequestType::Script, NetworkFilterMask::FROM_SCRIPT),
+            ("serviceworker", RequestType::Script, NetworkFilterMask::FROM_SCRIPT),
+            ("style", RequestType::Stylesheet, NetworkFilterMask::FROM_STYLESHEET),
+            ("xslt", RequestType::Other, NetworkFilterMask::FROM_OTHER),
+            ("unknown-destination", RequestType::Other, NetworkFilterMask::FROM_OTHER),
+        ];
+        for (dest, request_type, mask) in cases.iter() {
+            let request = Request::from_urls_with_sec_fetch_dest("https://cdn.com/res", "https://example.com", dest).unwrap();
+            assert_eq!(&request.request_type, request_type, "{}", dest);
+            assert_eq!(NetworkFilterMask::from(&request.request_type), *mask, "{}", dest);
+        }
+    }
+
+    #[test]
+    fn type_inference_works() {
+        let cases = [
+            ("https://cdn.com/fonts/a.woff2", "other", RequestType::Font),
+            ("https://cdn.com/fonts/a.WOFF?v=2", "", RequestType::Font),
+            ("https://cdn.com/fonts/a.ttf#iefix", "xmlhttprequest", RequestType::Xmlhttprequest),
+            ("https://cdn.com/fonts/a.otf", "unknown", RequestType::Font),
+            ("https://cdn.com/fonts/a.css", "other", RequestType::Other),
+            ("https://cdn.com/a.woff2/index", "other", RequestType::Other),
+            ("https://cdn.com/index?f=a.woff2", "other", RequestType::Other),
+            ("https://fonts.woff2", "other", RequestType::Other),
+        ];
+        for (url, request_type, expected) in cases.iter() {
+            let request = Request::from_urls_with_type_inference(url, "https://example.com", request_type).unwrap();
+            assert_eq!(&request.request_type, expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn prenormalized_works() {
+        let cases = [
+            ("https://ads.example.com/banner.js?id=1", "https://www.example.co.uk/page", "script"),
+            ("http://cdn.com:8080/ads/img.png", "https://cdn.com/", "image"),
+            ("https://[::1]:3000/api", "http://localhost/", "xhr"),
+            ("wss://socket.tracker.net/", "https://news.com/", "websocket"),
+            ("https://cdn.com/lib.js", "chrome-extension://abcdefghijklmnop/popup.html", "script"),
+            ("https://xn--80ak6aa92e.com/r", "", "document"),
+            ("https://example.com", "https://example.com", "sub_frame"),
+        ];
+        for (url, source_url, request_type) in cases.iter() {
+            let expected = Request::from_urls(url, source_url, request_type).unwrap();
+            let request = Request::from_prenormalized(url, source_url, request_type).unwrap();
+            assert_eq!(request.url, expected.url);
+            assert_eq!(request.hostname, expected.hostname);
+            assert_eq!(request.url_after_hostname(), expected.url_after_hostname());
+            assert_eq!(request.request_type, expected.request_type);
+            assert_eq!(request.is_supported, expected.is_supported);
+            assert_eq!(request.is_https, expected.is_https);
+            assert_eq!(request.is_third_party, expected.is_third_party, "{}", url);
+            assert_eq!(request.source_hostname_hashes, expected.source_hostname_hashes, "{}", url);
+            assert_eq!(request.source_entity_hashes().collect::<Vec<_>>(), expected.source_entity_hashes().collect::<Vec<_>>(), "{}", url);
+        }
+
+        assert_eq!(Request::from_prenormalized("example.com/ads.js", "", "script").err(), Some(RequestError::HostnameParseError));
+        assert_eq!(Request::from_prenormalized("https:///ads.js", "", "script").err(), Some(RequestError::HostnameParseError));
+    }
+
+    #[test]
+    fn from_urls_with_hostname_works() {
+        use crate::filters::network::{NetworkFilter, NetworkMatchable};
+
+        let filters = [
+            "||example.com^",
+            "||example.com^$third-party",
+            "||example.com^$first-party",
+            "||cdn.example.com/ads/$script,domain=news.com|~sub.news.com",
+            "/ads/banner.$image,third-party",
+            "|http://",
+        ].iter().map(|rule| NetworkFilter::parse(rule, true, Default::default()).unwrap()).collect::<Vec<_>>();
+
+        let requests = [
+            ("https://cdn.example.com/ads/banner.js", "https://news.com/article", "script"),
+            ("https://cdn.example.com/ads/banner.js", "https://sub.news.com/", "script"),
+            ("https://cdn.example.com/ads/banner.png", "https://www.example.com/", "image"),
+            ("http://example.com/Ads/Banner.PNG?q=1", "http://example.com", "image"),
+            ("https://example.com:8443/ads/banner.gif", "https://foo.co.uk/", "image"),
+            ("https://example.com/", "", "document"),
+            // Non-ASCII paths are normalized the same way, however they're encoded
+            ("https://example.com/caf%C3%A9/ads/banner.gif", "https://news.com/", "image"),
+            ("https://example.com/cafe%CC%81/ads/banner.gif", "https://news.com/", "image"),
+            ("https://example.com/café/ads/banner.gif", "https://news.com/", "image"),
+        ];
+
+        for (url, source_url, request_type) in requests.iter() {
+            let parsed = Request::from_urls(url, source_url, request_type).unwrap();
+            let source_hostname = url_parser::parse_url(source_url).map(|source| source.hostname().to_string()).unwrap_or_default();
+            let with_hostname = Request::from_urls_with_hostname(url, &parsed.hostname, &source_hostname, request_type, None);
+
+            assert_eq!(with_hostname.url, parsed.url);
+            assert_eq!(with_hostname.hostname, parsed.hostname);
+            assert_eq!(with_hostname.request_type, parsed.request_type);
+            assert_eq!(with_hostname.is_http, parsed.is_http);
+            assert_eq!(with_hostname.is_https, parsed.is_https);
+            assert_eq!(with_hostname.is_third_party, parsed.is_third_party);
+            assert_eq!(with_hostname.is_first_party, parsed.is_first_party);
+            assert_eq!(with_hostname.source_hostname_hashes, parsed.source_hostname_hashes);
+            for filter in filters.iter() {
+                assert_eq!(filter.matches(&with_hostname), filter.matches(&parsed), "{:?} {}", filter.raw_line, url);
+            }
+        }
+
+        let request = Request::from_urls_with_hostname("https://example.com/cafe%CC%81/", "example.com", "", "image", None);
+        assert_eq!(request.url, "https://example.com/caf%c3%a9/");
+    }
+
+    #[test]
+    fn partitioned_works() {
+        let request = Request::from_urls_partitioned(
+            "https://widget.com/ad.js",
+            "https://widget.com/frame",
+            "https://news.com/",
+            "script",
+            ExtensionSourcePolicy::default(),
+        ).unwrap();
+        assert_eq!(request.is_third_party, Some(true));
+        assert_eq!(request.is_first_party, Some(false));
+        // Source hashes still come from the requesting frame
+        assert_eq!(request.source_hostname_hashes, Some(vec![utils::fast_hash("widget.com"), utils::fast_hash("com")]));
+
+        let request = Request::from_urls_partitioned("https://cdn.news.com/a.js", "https://other.com/", "https://news.com/", "script", ExtensionSourcePolicy::default()).unwrap();
+        assert_eq!(request.is_third_party, Some(false));
+
+        let request = Request::from_urls_partitioned("https://cdn.news.com/a.js", "https://news.com/", "", "script", ExtensionSourcePolicy::default()).unwrap();
+        assert_eq!(request.is_third_party, None);
+    }
+
+    #[test]
+    fn with_hostname_works() {
+        let request = Request::from_urls("https://metrics.news.com:8443/collect?id=1", "https://news.com/", "xhr").unwrap();
+        let uncloaked = request.with_hostname("News.Tracker.net.");
+        assert_eq!(uncloaked.url, "https://news.tracker.net:8443/collect?id=1");
+        assert_eq!(uncloaked.hostname, "news.tracker.net");
+        assert_eq!(uncloaked.url_after_hostname(), ":8443/collect?id=1");
+        assert_eq!(uncloaked.port(), Some(8443));
+        assert_eq!(uncloaked.request_type, RequestType::Xmlhttprequest);
+        assert_eq!(uncloaked.is_third_party, Some(false));
+        assert_eq!(uncloaked.source_hostname_hashes, request.source_hostname_hashes);
+    }
+
+    #[test]
+    fn original_url_works() {
+        let request = Request::from_urls("https://metrics.news.com/Collect?ID=%C3%A9", "https://news.com/", "xhr").unwrap();
+        assert_eq!(request.url, "https://metrics.news.com/collect?id=%c3%a9");
+        assert_eq!(request.original_url(), "https://metrics.news.com/Collect?ID=%c3%a9");
+        assert_eq!(request.with_hostname("tracker.net").original_url(), "https://tracker.net/Collect?ID=%c3%a9");
+
+        let request = Request::from_urls_with_hostname("https://news.com/Ad", "news.com", "", "", None);
+        assert_eq!(request.original_url(), "https://news.com/Ad");
+
+        // Nothing is stored for lowercase URLs
+        let request = Request::from_urls("https://news.com/ad", "", "").unwrap();
+        assert_eq!(request.original_url, None);
+        assert_eq!(request.original_url(), "https://news.com/ad");
+    }
+
+    #[test]
+    fn entity_hashes_works() {
+        let request = Request::from_urls("https://cdn.com/ad.js", "https://sub.example.co.uk/", "script").unwrap();
+        assert_eq!(request.source_entity_hashes().collect::<Vec<_>>(), [
+            utils::fast_hash("sub.example.*"),
+            utils::fast_hash("example.*"),
+        ]);
+
+        let request = Request::from_urls("https://cdn.com/ad.js", "https://example.com/", "script").unwrap();
+        assert_eq!(request.source_entity_hashes().collect::<Vec<_>>(), [utils::fast_hash("example.*")]);
+
+        let request = Request::from_urls("https://cdn.com/ad.js", "", "script").unwrap();
+        assert_eq!(request.source_entity_hashes().next(), None);
+    }
+
     #[test]
     fn new_works() {
         let simple_example = Request::new(
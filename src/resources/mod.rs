@@ -8,6 +8,7 @@ pub mod resource_assembler;
 
 mod scriptlet_resource_storage;
 pub(crate) use scriptlet_resource_storage::ScriptletResourceStorage;
+pub use scriptlet_resource_storage::{ResolvedScriptlet, ScriptletCall};
 
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
@@ -80,7 +81,57 @@ pub struct RedirectResource {
     pub data: String
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+impl RedirectResource {
+    /// Decodes an inline `data:` URL, as used by `$redirect=data:...` filters, into the same form
+    /// as a resource from the library. Returns `None` if the URL is malformed: if it has no
+    /// payload, an invalid MIME type, or an invalid base64 or percent-encoded payload.
+    pub fn from_data_url(url: &str) -> Option<Self> {
+        let (header, payload) = url.strip_prefix("data:")?.split_once(',')?;
+        let (content_type, is_base64) = match header.strip_suffix(";base64") {
+            Some(content_type) => (content_type, true),
+            None => (header, false),
+        };
+        let content_type = if content_type.is_empty() { "text/plain" } else { content_type };
+
+        let essence = content_type.split(';').next().unwrap_or_default();
+        let valid_token = |token: &str| !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c));
+        match essence.split_once('/') {
+            Some((top_level, subtype)) if valid_token(top_level) && valid_token(subtype) => (),
+            _ => return None,
+        }
+
+        let data = if is_base64 {
+            base64::decode(payload).ok()?;
+            payload.to_owned()
+        } else {
+            let bytes = payload.as_bytes();
+            let valid_escapes = bytes.iter().enumerate()
+                .filter(|(_, b)| **b == b'%')
+                .all(|(i, _)| bytes.len() > i + 2 && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit());
+            if !valid_escapes {
+                return None;
+            }
+            base64::encode(percent_encoding::percent_decode_str(payload).collect::<Vec<u8>>())
+        };
+
+        Some(Self {
+            content_type: content_type.to_owned(),
+            data,
+        })
+    }
+
+    /// Encodes this resource as a base64 `data:` URL, e.g. `data:text/plain;base64,`.
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.content_type, &self.data)
+    }
+
+    /// Decodes the body of this resource from base64.
+    pub fn decoded_data(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        base64::decode(&self.data)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default, Clone)]
 pub struct RedirectResourceStorage {
     #[serde(serialize_with = "crate::data_format::utils::stabilize_hashmap_serialization")]
     pub resources: HashMap<String, RedirectResource>,
@@ -137,10 +188,24 @@ impl RedirectResourceStorage {
         }
     }
 
+    /// Builds a resource library from resources keyed by name, which must each have a valid
+    /// base64 body.
+    pub fn from_map(resources: HashMap<String, RedirectResource>) -> Result<Self, AddResourceError> {
+        for resource in resources.values() {
+            resource.decoded_data()?;
+        }
+        Ok(Self { resources })
+    }
+
     pub fn get_resource(&self, name: &str) -> Option<&RedirectResource> {
         self.resources.get(name)
     }
 
+    /// Looks up the resource called `name`, and encodes it as a usable `data:` URL.
+    pub fn get_data_url(&self, name: &str) -> Option<String> {
+        self.get_resource(name).map(RedirectResource::data_url)
+    }
+
     /// Adds a resource. Only has an effect for mimetyped scriptlets.
     pub fn add_resource(&mut self, resource: &Resource) -> Result<(), AddResourceError> {
         if let ResourceType::Mime(ref content_type) = resource.kind {
@@ -218,6 +283,26 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn storage_from_map() {
+        let mut resources = HashMap::new();
+        resources.insert("noopjs".to_owned(), RedirectResource {
+            content_type: "application/javascript".to_owned(),
+            data: base64::encode("(function() {})()"),
+        });
+        let storage = RedirectResourceStorage::from_map(resources.clone()).unwrap();
+
+        assert_eq!(storage.get_data_url("noopjs"), Some("data:application/javascript;base64,KGZ1bmN0aW9uKCkge30pKCk=".to_owned()));
+        assert_eq!(storage.get_resource("noopjs").unwrap().decoded_data().unwrap(), b"(function() {})()".to_vec());
+        assert_eq!(storage.get_data_url("noopcss"), None);
+
+        resources.insert("bad".to_owned(), RedirectResource {
+            content_type: "text/plain".to_owned(),
+            data: "not base64!".to_owned(),
+        });
+        assert!(RedirectResourceStorage::from_map(resources).is_err());
+    }
+
     #[test]
     fn get_resource_by_alias() {
         let mut storage = RedirectResourceStorage::default();
@@ -233,5 +318,27 @@ mod tests {
             data: base64::encode("resource data"),
         }));
     }
+
+    #[test]
+    fn resource_from_data_url() {
+        assert_eq!(RedirectResource::from_data_url("data:application/javascript,(function()%7B%7D)()"), Some(RedirectResource {
+            content_type: "application/javascript".to_owned(),
+            data: base64::encode("(function(){})()"),
+        }));
+        assert_eq!(RedirectResource::from_data_url("data:text/html;charset=utf-8;base64,PGI+aGk8L2I+"), Some(RedirectResource {
+            content_type: "text/html;charset=utf-8".to_owned(),
+            data: "PGI+aGk8L2I+".to_owned(),
+        }));
+        assert_eq!(RedirectResource::from_data_url("data:,a,b"), Some(RedirectResource {
+            content_type: "text/plain".to_owned(),
+            data: base64::encode("a,b"),
+        }));
+
+        assert_eq!(RedirectResource::from_data_url("data:text/plain"), None);
+        assert_eq!(RedirectResource::from_data_url("data:text/plain;base64,not base64!"), None);
+        assert_eq!(RedirectResource::from_data_url("data:javascript,void(0)"), None);
+        assert_eq!(RedirectResource::from_data_url("data:text/plain,100%"), None);
+        assert_eq!(RedirectResource::from_data_url("noopjs"), None);
+    }
 }
 
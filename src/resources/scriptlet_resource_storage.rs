@@ -31,6 +31,42 @@ pub enum ScriptletResourceError {
     MissingScriptletName,
 }
 
+/// A scriptlet to be injected by a `+js(...)` rule, with the rule's arguments substituted into its
+/// template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedScriptlet {
+    /// The name of the scriptlet as written in the rule, without any `.js` extension.
+    pub name: String,
+    /// The code to be injected.
+    pub content: String,
+}
+
+/// The scriptlet name and arguments of a `+js(...)` rule, e.g. `set-constant` with arguments
+/// `["foo", "false"]` for `example.com##+js(set-constant.js, foo, false)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptletCall {
+    /// The name of the scriptlet as written in the rule, without any `.js` extension.
+    pub name: String,
+    /// The arguments to the scriptlet, parsed as described in `parse_scriptlet_args`.
+    pub args: Vec<String>,
+}
+
+impl ScriptletCall {
+    /// Parses the inner contents of a `+js(...)` block. Returns `None` if no scriptlet name is
+    /// given.
+    pub fn parse(scriptlet_args: &str) -> Option<Self> {
+        let mut args = parse_scriptlet_args(scriptlet_args).into_iter();
+        let name = without_js_extension(&args.next()?).to_owned();
+        if name.is_empty() {
+            return None;
+        }
+        Some(Self {
+            name,
+            args: args.map(String::from).collect(),
+        })
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ScriptletResource {
     scriptlet: String,
@@ -40,7 +76,8 @@ impl ScriptletResource {
     /// Omit the 0th element of `args` (the scriptlet name) when calling this method.
     fn patch<'a>(&self, args: &[Cow<'a, str>]) -> String {
         let mut scriptlet = self.scriptlet.to_owned();
-        args.iter().enumerate().for_each(|(i, arg)| {
+        // Templates can only refer to the first few arguments
+        args.iter().take(TEMPLATE_ARGUMENT_RE.len()).enumerate().for_each(|(i, arg)| {
             scriptlet = TEMPLATE_ARGUMENT_RE[i].replace(&scriptlet, arg as &str).to_string();
         });
         scriptlet
@@ -91,6 +128,11 @@ impl ScriptletResourceStorage {
     }
 
     pub fn get_scriptlet(&self, scriptlet_args: &str) -> Result<String, ScriptletResourceError> {
+        self.resolve_scriptlet(scriptlet_args).map(|scriptlet| scriptlet.content)
+    }
+
+    /// Like `get_scriptlet`, but also returns the name of the scriptlet that was used.
+    pub fn resolve_scriptlet(&self, scriptlet_args: &str) -> Result<ResolvedScriptlet, ScriptletResourceError> {
         let scriptlet_args = parse_scriptlet_args(scriptlet_args);
         if scriptlet_args.is_empty() {
             return Err(ScriptletResourceError::MissingScriptletName);
@@ -101,7 +143,10 @@ impl ScriptletResourceStorage {
             .get(scriptlet_name)
             .ok_or(ScriptletResourceError::NoMatchingScriptlet)?;
 
-        Ok(template.patch(args))
+        Ok(ResolvedScriptlet {
+            name: scriptlet_name.to_owned(),
+            content: template.patch(args),
+        })
     }
 }
 
@@ -123,7 +168,7 @@ pub fn parse_scriptlet_args(args: &str) -> Vec<Cow<str>> {
     let mut after_last_delim = 0;
     while let Some(comma_loc) = args[find_start..].find(',') {
         let comma_loc = find_start + comma_loc;
-        if &args[comma_loc - 1..comma_loc] == "\\" {
+        if args[..comma_loc].ends_with('\\') {
             find_start = comma_loc + 1;
             continue;
         }
@@ -166,6 +211,12 @@ mod tests {
         assert_eq!(args, vec!["scriptletname", "one, two, three", "four"]);
     }
 
+    #[test]
+    fn parse_argslist_leading_comma() {
+        let args = parse_scriptlet_args(",é, one");
+        assert_eq!(args, vec!["", "é", "one"]);
+    }
+
     #[test]
     fn parse_argslist_badchars() {
         let args = parse_scriptlet_args(r##"scriptlet, "; window.location.href = bad.com; , '; alert("you're\, hacked");    ,    \u\r\l(bad.com) "##);
@@ -187,6 +238,7 @@ mod tests {
         assert_eq!(scriptlets.get_scriptlet("alert, All systems are go!! "), Ok("alert('All systems are go!!')".into()));
         assert_eq!(scriptlets.get_scriptlet("alert, Uh oh\\, check the logs..."), Ok("alert('Uh oh, check the logs...')".into()));
         assert_eq!(scriptlets.get_scriptlet("blocktimer, 3000"), Ok("setTimeout(blockAds, 3000)".into()));
+        assert_eq!(scriptlets.get_scriptlet("alert, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10"), Ok("alert('1')".into()));
         assert_eq!(scriptlets.get_scriptlet("null"), Ok("(()=>{})()".into()));
         assert_eq!(scriptlets.get_scriptlet("null, null"), Ok("(()=>{})()".into()));
         assert_eq!(scriptlets.get_scriptlet("greet, everybody"), Ok("console.log('Hello everybody, my name is {{2}}')".into()));
@@ -0,0 +1,124 @@
+//! Utilities for verifying the behavior of `Engine`s, e.g. to assert that an engine built after a
+//! refactor makes the same decisions as one built before it.
+
+use crate::blocker::{BlockerResult, Redirection};
+use crate::engine::Engine;
+
+use serde::{Deserialize, Serialize};
+
+/// A single network request to check, in the same form accepted by
+/// `Engine::check_network_urls`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SampleRequest {
+    pub url: String,
+    pub source_url: String,
+    pub request_type: String,
+}
+
+impl SampleRequest {
+    pub fn new(url: &str, source_url: &str, request_type: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            source_url: source_url.to_owned(),
+            request_type: request_type.to_owned(),
+        }
+    }
+}
+
+/// The parts of a `BlockerResult` which determine how a request is handled.
+///
+/// The text of matched rules is deliberately not included, since it depends on whether an engine
+/// was built in debug mode or with optimizations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub matched: bool,
+    pub important: bool,
+    pub exception: bool,
+    pub redirect: Option<Redirection>,
+}
+
+impl From<&BlockerResult> for Decision {
+    fn from(result: &BlockerResult) -> Self {
+        Self {
+            matched: result.matched,
+            important: result.important,
+            exception: result.exception.is_some(),
+            redirect: result.redirect.clone(),
+        }
+    }
+}
+
+/// The first request for which two engines made different decisions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub request: SampleRequest,
+    pub left: Decision,
+    pub right: Decision,
+}
+
+/// Checks each of the given `requests` against both `a` and `b`, returning `None` if the engines
+/// made identical decisions for all of them, or the first `Divergence` otherwise.
+pub fn engines_equivalent(a: &Engine, b: &Engine, requests: &[SampleRequest]) -> Option<Divergence> {
+    requests.iter().find_map(|request| {
+        let left = Decision::from(&a.check_network_urls(&request.url, &request.source_url, &request.request_type));
+        let right = Decision::from(&b.check_network_urls(&request.url, &request.source_url, &request.request_type));
+        if left != right {
+            Some(Divergence {
+                request: request.clone(),
+                left,
+                right,
+            })
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_requests() -> Vec<SampleRequest> {
+        vec![
+            SampleRequest::new("https://example.com/ad.js", "https://example.com", "script"),
+            SampleRequest::new("https://tracker.net/pixel.gif", "https://example.com", "image"),
+            SampleRequest::new("https://example.com/ads/banner.png", "https://example.com", "image"),
+            SampleRequest::new("https://cdn.com/ads/allowed.png", "https://example.com", "image"),
+        ]
+    }
+
+    #[test]
+    fn identical_engines_are_equivalent() {
+        let rules = vec![
+            String::from("/ad.js"),
+            String::from("||tracker.net^"),
+            String::from("/ads/*"),
+            String::from("@@||cdn.com/ads/$image"),
+        ];
+        let a = Engine::from_rules(&rules, Default::default());
+        let b = Engine::from_rules_parametrised(&rules, Default::default(), true, false);
+
+        assert_eq!(engines_equivalent(&a, &b, &sample_requests()), None);
+    }
+
+    #[test]
+    fn engines_differing_by_one_rule_diverge() {
+        let rules = vec![
+            String::from("/ad.js"),
+            String::from("/ads/*"),
+            String::from("@@||cdn.com/ads/$image"),
+        ];
+        let a = Engine::from_rules(&rules, Default::default());
+
+        let mut rules_b = rules.clone();
+        rules_b.push(String::from("||tracker.net^"));
+        let b = Engine::from_rules(&rules_b, Default::default());
+
+        let divergence = engines_equivalent(&a, &b, &sample_requests()).expect("engines should diverge");
+        assert_eq!(divergence.request, sample_requests()[1]);
+        assert_eq!(divergence.left, Decision { matched: false, important: false, exception: false, redirect: None });
+        assert_eq!(divergence.right, Decision { matched: true, important: false, exception: false, redirect: None });
+
+        assert_eq!(engines_equivalent(&a, &b, &sample_requests()[2..]), None);
+    }
+}
@@ -1,6 +1,11 @@
 //! Simplified URL parsing infrastructure, including the domain resolver
 //! implementation if the `embedded-domain-resolver` feature is disabled.
 
+use std::borrow::Cow;
+use std::fmt::Write;
+
+use unicode_normalization::UnicodeNormalization;
+
 mod parser;
 // mod parser_regex;
 
@@ -130,6 +135,71 @@ pub fn parse_url(url: &str) -> Option<RequestUrl> {
     })
 }
 
+/// Normalizes the path portion of a URL or network filter pattern so that non-ASCII characters
+/// compare consistently, whether they were written in decoded or percent-encoded form.
+///
+/// Percent-encoded non-ASCII bytes are decoded, the result is converted to Unicode NFC form, and
+/// every non-ASCII character is then re-encoded using lowercase hex digits. ASCII characters,
+/// including percent-encoded ASCII, are left untouched.
+pub(crate) fn normalize_path(path: &str) -> Cow<'_, str> {
+    fn hex_value(byte: u8) -> Option<u8> {
+        match byte {
+            b'0'..=b'9' => Some(byte - b'0'),
+            b'a'..=b'f' => Some(byte - b'a' + 10),
+            b'A'..=b'F' => Some(byte - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let bytes = path.as_bytes();
+    // Plain ASCII without any percent-encoding is by far the most common case
+    if !bytes.iter().any(|&b| b == b'%' || !b.is_ascii()) {
+        return Cow::Borrowed(path);
+    }
+
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut has_encoded_unicode = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                let value = high << 4 | low;
+                if value >= 0x80 {
+                    decoded.push(value);
+                    has_encoded_unicode = true;
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    if path.is_ascii() && !has_encoded_unicode {
+        return Cow::Borrowed(path);
+    }
+
+    // Encoded bytes that aren't valid UTF-8 can't be normalized, so they're kept as they were.
+    let decoded = match String::from_utf8(decoded) {
+        Ok(decoded) => Cow::Owned(decoded),
+        Err(_) => Cow::Borrowed(path),
+    };
+
+    let mut normalized = String::with_capacity(decoded.len() * 2);
+    let mut buf = [0; 4];
+    for c in decoded.nfc() {
+        if c.is_ascii() {
+            normalized.push(c);
+        } else {
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                write!(normalized, "%{:02x}", byte).unwrap();
+            }
+        }
+    }
+    Cow::Owned(normalized)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,4 +217,25 @@ mod tests {
         assert_eq!(domain("a.b.co.uk"), "b.co.uk");
         assert_eq!(domain("foo.bar"), "foo.bar");
     }
+
+    #[test]
+    fn normalize_path_works() {
+        assert_eq!(normalize_path("/ads/banner.gif?id=%2F1"), "/ads/banner.gif?id=%2F1");
+        assert!(matches!(normalize_path("/ads/banner.gif"), Cow::Borrowed(_)));
+        assert!(matches!(normalize_path("/ads/banner.gif?id=%2F1"), Cow::Borrowed(_)));
+
+        let encoded = "/%d1%80%d0%b5%d0%ba%d0%bb%d0%b0%d0%bc%d0%b0/";
+        assert_eq!(normalize_path("/реклама/"), encoded);
+        assert_eq!(normalize_path(encoded), encoded);
+        assert_eq!(normalize_path("/%D1%80%D0%B5%D0%BA%D0%BB%D0%B0%D0%BC%D0%B0/"), encoded);
+        assert_eq!(normalize_path("/%d1%80екл%d0%b0ма/"), encoded);
+
+        // Decomposed and precomposed forms normalize identically
+        assert_eq!(normalize_path("/cafe\u{301}"), "/caf%c3%a9");
+        assert_eq!(normalize_path("/caf\u{e9}"), "/caf%c3%a9");
+        assert_eq!(normalize_path("/cafe%cc%81"), "/caf%c3%a9");
+
+        // Encoded bytes which are not valid UTF-8 are left alone
+        assert_eq!(normalize_path("/%e9t%e9"), "/%e9t%e9");
+    }
 }
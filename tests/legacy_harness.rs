@@ -527,11 +527,27 @@ mod legacy_check_options {
         ]);
     }
 
+    #[test]
+    fn check_options_xmlhttprequest() {
+        // `fetch` requests are matched by XHR rules
+        check_option_rule(&["||example.com^$xmlhttprequest"], FilterFormat::Standard, &[
+            ("http://example.com", "http://example.com", "xmlhttprequest", true),
+            ("http://example.com", "http://example.com", "fetch", true),
+            ("http://example.com", "http://example.com", "image", false),
+        ]);
+        check_option_rule(&["||example.com^$~xhr"], FilterFormat::Standard, &[
+            ("http://example.com", "http://example.com", "fetch", false),
+            ("http://example.com", "http://example.com", "image", true),
+        ]);
+    }
+
     #[test]
     fn check_options_popup() {
-        // Make sure we ignore popup rules for now
+        // Popup rules only apply to navigations opening a new tab or window
         check_option_rule(&["||example.com^$popup"], FilterFormat::Standard, &[
-               ("http://example.com", "http://example.com", "popup", false),
+               ("http://example.com", "http://example.com", "popup", true),
+               ("http://example.com", "http://example.com", "document", false),
+               ("http://example.com", "http://example.com", "script", false),
         ]);
     }
 
@@ -49,6 +49,12 @@ fn build_resources_from_filters(filters: &[String]) -> Vec<Resource> {
         .collect()
 }
 
+/// The dataset was generated when `fetch` requests were treated as `other`, but they are now
+/// matched as `xmlhttprequest`.
+fn expects_fetch_as_other(req: &TestRuleRequest, filter: &str) -> bool {
+    req.r#type == "fetch" && filter.contains("~xmlhttprequest")
+}
+
 
 #[test]
 fn check_filter_matching() {
@@ -64,8 +70,11 @@ fn check_filter_matching() {
     };
 
     for req in requests {
-        for filter in req.filters {
-            let network_filter_res = NetworkFilter::parse(&filter, true, opts);
+        for filter in req.filters.iter() {
+            if expects_fetch_as_other(&req, filter) {
+                continue;
+            }
+            let network_filter_res = NetworkFilter::parse(filter, true, opts);
             assert!(network_filter_res.is_ok(), "Could not parse filter {}", filter);
             let network_filter = network_filter_res.unwrap();
 
@@ -79,7 +88,7 @@ fn check_filter_matching() {
         }
     }
 
-    assert_eq!(requests_checked, 9382); // A catch for regressions
+    assert_eq!(requests_checked, 9381); // A catch for regressions
 }
 
 #[test]
@@ -92,13 +101,16 @@ fn check_engine_matching() {
         if req.url == "http://" || req.url == "https://" {
             continue;
         }
-        for filter in req.filters {
+        for filter in req.filters.iter() {
+            if expects_fetch_as_other(&req, filter) {
+                continue;
+            }
             let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
             let mut engine = Engine::from_rules_debug(&[filter.clone()], opts);
             let resources = build_resources_from_filters(&[filter.clone()]);
             engine.use_resources(&resources);
 
-            let network_filter_res = NetworkFilter::parse(&filter, true, opts);
+            let network_filter_res = NetworkFilter::parse(filter, true, opts);
             assert!(network_filter_res.is_ok(), "Could not parse filter {}", filter);
             let network_filter = network_filter_res.unwrap();
 
@@ -134,3 +146,52 @@ fn check_engine_matching() {
         }
     }
 }
+
+/// Decides whether `request` should be blocked by trying every filter in turn, without making use
+/// of the token index the `Blocker` is built on.
+fn exhaustive_check(filters: &[NetworkFilter], request: &Request) -> bool {
+    let applies = |f: &&NetworkFilter| !f.is_csp() && !f.is_generic_hide() && !f.is_removeparam() && f.tag.is_none();
+
+    let mut blocking = filters.iter().filter(applies).filter(|f| !f.is_exception());
+    if blocking.clone().any(|f| f.is_important() && f.matches(request)) {
+        return true;
+    }
+    blocking.any(|f| f.matches(request))
+        && !filters.iter().filter(applies).any(|f| f.is_exception() && f.matches(request))
+}
+
+#[test]
+fn check_token_index_matches_exhaustive_scan() {
+    use adblock::blocker::{Blocker, BlockerOptions};
+
+    #[allow(non_snake_case)]
+    #[derive(Deserialize)]
+    struct BenchRequest {
+        frameUrl: String,
+        url: String,
+        cpt: String,
+    }
+
+    let rules = adblock::utils::rules_from_lists(&["data/easylist.to/easylist/easylist.txt".to_owned()]);
+    let (filters, _) = adblock::lists::parse_filters(&rules, true, Default::default());
+    let blocker = Blocker::new(filters.clone(), &BlockerOptions { enable_optimizations: true });
+    let filters = adblock::optimizer::apply_badfilters(filters);
+
+    let lines = adblock::utils::read_file_lines("data/requests.json");
+    let requests: Vec<Request> = lines.iter()
+        .step_by(lines.len() / 300)
+        .filter_map(|line| serde_json::from_str::<BenchRequest>(line).ok())
+        .filter_map(|r| Request::from_urls(&r.url, &r.frameUrl, &r.cpt).ok())
+        .collect();
+    assert!(requests.len() >= 250);
+
+    let mut blocked = 0;
+    for request in requests.iter() {
+        let expected = exhaustive_check(&filters, request);
+        assert_eq!(blocker.check(request).matched, expected, "Mismatch for {} ({:?})", request.url, request.request_type);
+        if expected {
+            blocked += 1;
+        }
+    }
+    assert!(blocked > 0, "No sampled request was blocked");
+}